@@ -25,16 +25,24 @@ pub enum Tag {
     /// A notes post, with a disclaimer at the top.
     Note,
 }
+impl Tag {
+    /// Gets the tag's name, as written in posts.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Note => "note",
+        }
+    }
+}
 
 /// The content of a blog post, consisting of (for now) only the `Element`s that make it up.
 #[derive(Serialize, Debug, Clone)]
 pub struct Content {
     /// The body of the content, consisting of several `Element`s.
-    content: Vec<Element>,
+    pub content: Vec<Element>,
     /// Any footnotes, each containing a tag for cross-referencing and some content.
     ///
     /// Should be numbered in order, starting at 1.
-    footnotes: Vec<(String, Vec<Element>)>,
+    pub footnotes: Vec<(String, Vec<Element>)>,
 }
 
 /// Deserialization for blog post content from a string.
@@ -388,7 +396,7 @@ impl std::fmt::Display for BlogPost {
             tags: _,
         } = self;
         writeln!(f, "=== {} ===", title)?;
-        writeln!(f, "https://{}/projects/{}", crate::CONFIG.domain, url)?;
+        writeln!(f, "https://{}/blog/{}", crate::CONFIG.domain, url)?;
         writeln!(f, "{}", date.date())?;
         writeln!(f, "\n{}", content)
     }
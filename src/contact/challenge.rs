@@ -0,0 +1,327 @@
+//! A hashcash-style proof-of-work challenge for creating contact threads, enabled by setting `CONFIG.msg_pow_difficulty`.
+//!
+//! The server issues a nonce (an expiry time and some randomness, signed with `CONFIG.ssh_key` so we don't need to store it), and the
+//! client must find a solution such that `SHA-256("{nonce}:{solution}")` starts with `difficulty` zero bits. Each nonce is accepted
+//! once, until it expires. This costs a real user's browser a second or so but makes bulk thread creation expensive.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use color_eyre::{eyre::eyre, Result};
+use ed25519_dalek::{Signature, Signer};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// How long an issued challenge remains valid.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// The highest difficulty `CONFIG.msg_pow_difficulty` can be set to (checked when the config loads), beyond which solving a challenge
+/// would take clients (and `solve`) far too long.
+pub const MAX_DIFFICULTY: u32 = 32;
+/// How long `solve_blocking` spends on a challenge before giving up.
+const SOLVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Nonces that have already been used, along with their expiry times (so we can forget them once they'd be rejected anyway).
+type UsedNonces = Mutex<Option<HashMap<String, u64>>>;
+static USED_NONCES: UsedNonces = Mutex::new(None);
+
+/// A challenge issued to a client, to be solved before creating a thread.
+#[derive(Clone, Debug, Serialize)]
+pub struct Challenge {
+    /// The signed nonce, to be hashed along with the solution and returned in the `Proof`.
+    pub nonce: String,
+    /// The number of leading zero bits required in the hash (0 if challenges are disabled).
+    pub difficulty: u32,
+}
+
+/// A client's solution to a `Challenge`.
+#[derive(Clone, Debug)]
+pub struct Proof {
+    pub nonce: String,
+    pub solution: String,
+}
+
+/// Checks a difficulty for `CONFIG.msg_pow_difficulty`, rejecting any above `MAX_DIFFICULTY`.
+pub fn check_difficulty(difficulty: u32) -> Result<u32> {
+    if difficulty > MAX_DIFFICULTY {
+        return Err(eyre!(
+            "Invalid MSG_POW_DIFFICULTY env var: {difficulty} is above the maximum of {MAX_DIFFICULTY}"
+        ));
+    }
+    Ok(difficulty)
+}
+
+/// Issues a new challenge.
+pub fn issue() -> Challenge {
+    let payload = format!(
+        "{}.{:016x}",
+        (SystemTime::now() + CHALLENGE_LIFETIME)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        rand::random::<u64>()
+    );
+    let signature = crate::CONFIG.ssh_key.sign(payload.as_bytes());
+    Challenge {
+        nonce: format!(
+            "{payload}.{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ),
+        difficulty: crate::CONFIG.msg_pow_difficulty,
+    }
+}
+
+/// Solves a challenge by brute force, giving up (returning `None`) if `cancel` is set or no solution is found. This is meant for clients we
+/// trust not to spam (i.e. SSH sessions, which already cost a handshake), and can take a while, so see `solve_blocking`.
+pub fn solve(challenge: &Challenge, cancel: &AtomicBool) -> Option<Proof> {
+    let solution = (0u64..).find(|i| {
+        cancel.load(Ordering::Relaxed)
+            || leading_zero_bits(&hash(&challenge.nonce, &i.to_string())) >= challenge.difficulty
+    })?;
+    (!cancel.load(Ordering::Relaxed)).then(|| Proof {
+        nonce: challenge.nonce.clone(),
+        solution: solution.to_string(),
+    })
+}
+
+/// Solves a challenge on a blocking thread, cancelling it and returning `None` if it takes longer than `SOLVE_TIMEOUT`.
+pub async fn solve_blocking(challenge: Challenge) -> Option<Proof> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let solver = tokio::task::spawn_blocking({
+        let cancel = cancel.clone();
+        move || solve(&challenge, &cancel)
+    });
+    match tokio::time::timeout(SOLVE_TIMEOUT, solver).await {
+        Ok(result) => result.ok().flatten(),
+        Err(_) => {
+            cancel.store(true, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Verifies a proof if challenges are enabled, marking its nonce as used. Returns whether the proof is acceptable.
+pub fn verify(proof: Option<&Proof>) -> bool {
+    verify_with(proof, crate::CONFIG.msg_pow_difficulty, &USED_NONCES)
+}
+
+/// Verifies a proof against the given difficulty, marking its nonce as used in `used`.
+fn verify_with(proof: Option<&Proof>, difficulty: u32, used: &UsedNonces) -> bool {
+    if difficulty == 0 {
+        return true;
+    }
+    let Some(Proof { nonce, solution }) = proof else {
+        return false;
+    };
+
+    // Check signature and expiry, so we know we issued this nonce recently
+    let Some((payload, signature)) = nonce.rsplit_once('.') else {
+        return false;
+    };
+    let Some(signature) = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|s| Signature::from_bytes(&s).ok())
+    else {
+        return false;
+    };
+    if crate::CONFIG
+        .ssh_key
+        .verify(payload.as_bytes(), &signature)
+        .is_err()
+    {
+        return false;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let Some(expiry) = payload
+        .split_once('.')
+        .and_then(|(expiry, _)| expiry.parse::<u64>().ok())
+        .filter(|&expiry| expiry > now)
+    else {
+        return false;
+    };
+
+    // Check the work was actually done
+    if leading_zero_bits(&hash(nonce, solution)) < difficulty {
+        return false;
+    }
+
+    // Finally, check the nonce hasn't been used before (and mark it used), forgetting any expired nonces while we're at it
+    let mut used = used.lock().expect("poison");
+    let used = used.get_or_insert_with(HashMap::new);
+    used.retain(|_, &mut expiry| expiry > now);
+    used.insert(nonce.clone(), expiry).is_none()
+}
+
+/// Hashes a nonce with a candidate solution.
+fn hash(nonce: &str, solution: &str) -> [u8; 32] {
+    Sha256::digest(format!("{nonce}:{solution}").as_bytes()).into()
+}
+
+/// Counts the leading zero bits of a hash.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The difficulty challenges are tested at, low enough to solve quickly.
+    const DIFFICULTY: u32 = 8;
+
+    fn challenge() -> Challenge {
+        crate::init_test_config();
+        Challenge {
+            difficulty: DIFFICULTY,
+            ..issue()
+        }
+    }
+
+    fn solved() -> Proof {
+        solve(&challenge(), &AtomicBool::new(false)).expect("solvable")
+    }
+
+    /// Signs a nonce payload as `issue` would, so tests can pick its expiry.
+    fn sign(payload: &str) -> String {
+        let signature = crate::CONFIG.ssh_key.sign(payload.as_bytes());
+        format!(
+            "{payload}.{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0]), 0);
+        assert_eq!(leading_zero_bits(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zero_bits(&[0, 0x20, 0]), 10);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn difficulty_is_capped() {
+        assert_eq!(check_difficulty(0).unwrap(), 0);
+        assert_eq!(check_difficulty(MAX_DIFFICULTY).unwrap(), MAX_DIFFICULTY);
+        assert!(check_difficulty(MAX_DIFFICULTY + 1).is_err());
+    }
+
+    #[test]
+    fn disabled_accepts_anything() {
+        let used = UsedNonces::default();
+        assert!(verify_with(None, 0, &used));
+    }
+
+    #[test]
+    fn solutions_verify() {
+        let used = UsedNonces::default();
+        let proof = solved();
+        assert!(leading_zero_bits(&hash(&proof.nonce, &proof.solution)) >= DIFFICULTY);
+        assert!(verify_with(Some(&proof), DIFFICULTY, &used));
+    }
+
+    #[test]
+    fn nonces_cannot_be_replayed() {
+        let used = UsedNonces::default();
+        let proof = solved();
+        assert!(verify_with(Some(&proof), DIFFICULTY, &used));
+        assert!(!verify_with(Some(&proof), DIFFICULTY, &used));
+
+        // Nor with a different solution to the same nonce
+        let other = (0u64..)
+            .map(|i| i.to_string())
+            .find(|solution| {
+                *solution != proof.solution
+                    && leading_zero_bits(&hash(&proof.nonce, solution)) >= DIFFICULTY
+            })
+            .expect("another solution");
+        let other = Proof {
+            solution: other,
+            ..proof
+        };
+        assert!(!verify_with(Some(&other), DIFFICULTY, &used));
+    }
+
+    #[test]
+    fn bad_proofs_are_rejected() {
+        let used = UsedNonces::default();
+        assert!(!verify_with(None, DIFFICULTY, &used));
+
+        // Not enough work
+        let challenge = challenge();
+        let solution = (0u64..)
+            .map(|i| i.to_string())
+            .find(|solution| leading_zero_bits(&hash(&challenge.nonce, solution)) < DIFFICULTY)
+            .expect("a non-solution");
+        let proof = Proof {
+            nonce: challenge.nonce,
+            solution,
+        };
+        assert!(!verify_with(Some(&proof), DIFFICULTY, &used));
+
+        // A nonce we didn't sign (the solution is checked after the signature, so it needn't be valid)
+        let proof = solved();
+        let (payload, signature) = proof.nonce.rsplit_once('.').unwrap();
+        let forged = Proof {
+            nonce: format!("{payload}0.{signature}"),
+            ..proof.clone()
+        };
+        assert!(!verify_with(Some(&forged), DIFFICULTY, &used));
+        let unsigned = Proof {
+            nonce: payload.to_string(),
+            ..proof
+        };
+        assert!(!verify_with(Some(&unsigned), DIFFICULTY, &used));
+    }
+
+    #[test]
+    fn expired_nonces_are_rejected() {
+        crate::init_test_config();
+        let used = UsedNonces::default();
+        let expired = Challenge {
+            nonce: sign("1.0123456789abcdef"),
+            difficulty: DIFFICULTY,
+        };
+        let proof = solve(&expired, &AtomicBool::new(false)).expect("solvable");
+        assert!(!verify_with(Some(&proof), DIFFICULTY, &used));
+    }
+
+    #[test]
+    fn expired_nonces_are_forgotten() {
+        let used = UsedNonces::default();
+        used.lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert("old".to_string(), 1);
+        assert!(verify_with(Some(&solved()), DIFFICULTY, &used));
+        let used = used.lock().unwrap();
+        let used = used.as_ref().unwrap();
+        assert_eq!(used.len(), 1);
+        assert!(!used.contains_key("old"));
+    }
+
+    #[test]
+    fn solving_can_be_cancelled() {
+        let challenge = Challenge {
+            difficulty: MAX_DIFFICULTY,
+            ..challenge()
+        };
+        assert!(solve(&challenge, &AtomicBool::new(true)).is_none());
+    }
+}
@@ -0,0 +1,655 @@
+//! An opt-in email bridge for contact threads: users can attach an email address to a thread to get my replies by email, and reply to
+//! those emails to send messages on the thread. A new address is first sent a one-time confirmation link (of the form
+//! `https://{domain}/api/message/confirm/{selector}.{mac}`, whose page confirms it with a button, so email scanners opening the link
+//! don't), and nothing else is sent to it until it's been confirmed, so we can't be used to email whoever someone types in.
+//!
+//! Replies are sent through the SMTP relay at `CONFIG.msg_smtp_relay` (e.g. a local MTA; we don't do TLS or authentication), with a
+//! `Reply-To` address of the form `reply+{selector}.{mac}@{domain}`. The selector is random and regenerated whenever the address
+//! changes, and the MAC (an HMAC keyed with our secret key, covering the user's address) means reply addresses can't be guessed, and stop
+//! working once the user changes or removes their address or I rotate the thread's tokens. Incoming emails to those addresses are
+//! received by `crate::smtp` and sent on the thread like any other message, including spam checks and rate limits.
+
+use std::convert::Infallible;
+
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use hmac::{Hmac, Mac};
+use rusqlite::OptionalExtension;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::broadcast::error::RecvError,
+};
+use tracing::{debug, error, warn};
+
+use super::{Message, MessageSendError, SqlResult, ThreadId};
+
+/// The local part of the reply addresses we hand out, before the `+`.
+const REPLY_LOCAL_PART: &str = "reply";
+/// What the MACs in reply addresses and confirmation links are for, so one can't be used as the other.
+const REPLY_MAC: &[u8] = b"contact email reply";
+const CONFIRMATION_MAC: &[u8] = b"contact email confirmation";
+
+/// Sends my replies by email to users who've opted in, forever. Does nothing if `CONFIG.msg_smtp_relay` isn't set.
+pub async fn main() -> Result<Infallible> {
+    let Some(relay) = &crate::CONFIG.msg_smtp_relay else {
+        // Nowhere to send email, so just stop task (sleeping forever, as in `watch_path`)
+        return Ok(futures::future::pending::<Infallible>().await);
+    };
+
+    let mut receiver = super::subscribe();
+    loop {
+        match receiver.recv().await {
+            Ok((thread, message)) if message.response => {
+                if let Err(err) = forward(relay, thread, &message).await {
+                    error!("Failed to email reply on thread {thread}: {err}");
+                }
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Fell behind on new messages, {missed} weren't checked for emailing")
+            }
+            Err(RecvError::Closed) => bail!("New messages channel closed"),
+        }
+    }
+}
+
+/// Emails a reply of mine to the user, if they've set (and confirmed) an email address for the thread.
+async fn forward(relay: &str, thread: ThreadId, message: &Message) -> Result<()> {
+    let Some((to, selector)) = super::get_email(thread).await.map_err(|e| eyre!("{e}"))? else {
+        return Ok(());
+    };
+    let domain = &crate::CONFIG.domain;
+    let reply_to = reply_address(selector, &to);
+    let time = Utc
+        .timestamp_opt(message.timestamp, 0)
+        .single()
+        .unwrap_or_default();
+    let body = format!(
+        "{}\n\n-- \nReply to this email to respond on the thread. To stop getting these emails, turn them off on the contact page\nat https://{domain}/contact or with `msg email <THREAD> off` over SSH.\n",
+        message.contents
+    );
+
+    let email = compose(
+        &to,
+        Some(&reply_to),
+        &format!("Reply to your message on {domain}"),
+        time,
+        selector,
+        &body,
+    );
+    send(relay, &to, &email).await?;
+    debug!("Emailed reply on thread {thread}");
+    Ok(())
+}
+
+/// Emails a link to confirm a new address for a thread (with the given selector) to it.
+pub async fn send_confirmation(relay: &str, to: &str, selector: u64) -> Result<()> {
+    let domain = &crate::CONFIG.domain;
+    let body = format!(
+        "Someone (hopefully you!) asked for replies to their message on {domain} to be emailed to this address. To confirm, follow\nthis link and press the button there:\n\nhttps://{domain}/api/message/confirm/{selector:016x}.{}\n\nIf that wasn't you, just ignore this email, and you won't get any more.\n",
+        mac(CONFIRMATION_MAC, selector, to)
+    );
+    let email = compose(
+        to,
+        None,
+        &format!("Confirm your email for {domain}"),
+        Utc::now(),
+        selector,
+        &body,
+    );
+    send(relay, to, &email).await?;
+    debug!("Emailed confirmation link for selector {selector:016x}");
+    Ok(())
+}
+
+/// Assembles an email (with `\r\n` newlines) from me to the given address, about the thread with the given selector.
+fn compose(
+    to: &str,
+    reply_to: Option<&str>,
+    subject: &str,
+    time: DateTime<Utc>,
+    selector: u64,
+    body: &str,
+) -> String {
+    // Assemble email (with a base64 body, so we don't need to worry about line lengths or encodings)
+    let domain = &crate::CONFIG.domain;
+    let mut email = String::new();
+    email.push_str(&format!("From: Fletch Rydell <contact@{domain}>\r\n"));
+    email.push_str(&format!("To: <{to}>\r\n"));
+    if let Some(reply_to) = reply_to {
+        email.push_str(&format!("Reply-To: <{reply_to}>\r\n"));
+    }
+    email.push_str(&format!("Subject: {subject}\r\n"));
+    email.push_str(&format!("Date: {}\r\n", time.to_rfc2822()));
+    email.push_str(&format!(
+        "Message-ID: <{selector:016x}.{:016x}@{domain}>\r\n",
+        rand::random::<u64>()
+    ));
+    email.push_str(&format!("X-Contact-Thread: {selector:016x}\r\n"));
+    email.push_str("MIME-Version: 1.0\r\n");
+    email.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+    email.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+    let encoded = base64::engine::general_purpose::STANDARD.encode(body.replace('\n', "\r\n"));
+    for line in encoded.as_bytes().chunks(76) {
+        email.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        email.push_str("\r\n");
+    }
+    email
+}
+
+/// Sends an email (with `\r\n` newlines) to the given address through an SMTP relay.
+async fn send(relay: &str, to: &str, email: &str) -> Result<()> {
+    let mut connection = TcpStream::connect(relay).await?;
+    let (reader, mut writer) = connection.split();
+    let mut reader = BufReader::new(reader);
+
+    expect_reply(&mut reader, 220).await?;
+    let domain = &crate::CONFIG.domain;
+    for (command, code) in [
+        (format!("EHLO {domain}"), 250),
+        (format!("MAIL FROM:<contact@{domain}>"), 250),
+        (format!("RCPT TO:<{to}>"), 250),
+        ("DATA".to_string(), 354),
+    ] {
+        writer
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await?;
+        expect_reply(&mut reader, code).await?;
+    }
+
+    // Send email, byte-stuffing lines starting with `.`
+    for line in email.split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            writer.write_all(b".").await?;
+        }
+        writer.write_all(line.as_bytes()).await?;
+    }
+    writer.write_all(b".\r\n").await?;
+    expect_reply(&mut reader, 250).await?;
+
+    // The email's been accepted, so don't worry about how the server handles quitting
+    let _ = writer.write_all(b"QUIT\r\n").await;
+    Ok(())
+}
+
+/// Reads a (possibly multiline) SMTP reply, returning an error if it doesn't have the expected code.
+async fn expect_reply(reader: &mut (impl AsyncBufRead + Unpin), code: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("SMTP relay closed connection");
+        }
+        // Continuation lines have a `-` after the code
+        if line.get(3..4) == Some("-") {
+            continue;
+        }
+        return match line.get(..3).and_then(|c| c.parse::<u16>().ok()) {
+            Some(c) if c == code => Ok(()),
+            _ => Err(eyre!(
+                "Unexpected SMTP reply (expected {code}): {}",
+                line.trim_end()
+            )),
+        };
+    }
+}
+
+/// Gets the reply address for emails to the given address about the thread with the given selector.
+pub fn reply_address(selector: u64, to: &str) -> String {
+    format!(
+        "{REPLY_LOCAL_PART}+{selector:016x}.{}@{}",
+        mac(REPLY_MAC, selector, to),
+        crate::CONFIG.domain
+    )
+}
+
+/// A reply address we handed out, identifying a thread.
+#[derive(Clone, Debug)]
+pub struct ReplyAddress {
+    selector: u64,
+    mac: String,
+}
+impl ReplyAddress {
+    /// Parses a reply address (ignoring case), returning `None` if it isn't one of ours. Doesn't check it's valid.
+    pub fn parse(address: &str) -> Option<Self> {
+        let address = address.to_lowercase();
+        let (local, domain) = address.rsplit_once('@')?;
+        if domain != crate::CONFIG.domain.to_lowercase() {
+            return None;
+        }
+        let (selector, mac) = local
+            .strip_prefix(REPLY_LOCAL_PART)?
+            .strip_prefix('+')?
+            .split_once('.')?;
+        Some(Self {
+            selector: u64::from_str_radix(selector, 16).ok()?,
+            mac: mac.to_string(),
+        })
+    }
+}
+
+/// Sends a message received by email on the thread given by its reply address. Errors as in `super::send_message`, with
+/// `MessageSendError::NoSuchThread` if the reply address is invalid (e.g. if it's been revoked).
+pub async fn receive(address: ReplyAddress, message: String) -> Result<(), MessageSendError> {
+    super::send_message_with(move |conn| resolve(conn, &address), message).await
+}
+
+/// Gets the thread a reply address is for, if it's valid (which needs the thread's address to be confirmed).
+fn resolve(conn: &rusqlite::Connection, address: &ReplyAddress) -> SqlResult<Option<ThreadId>> {
+    lookup(conn, REPLY_MAC, address.selector, &address.mac, true)
+}
+
+/// Gets the thread a confirmation link (given the code at the end of it) is for, if it's valid and hasn't been used yet.
+pub(super) fn resolve_confirmation(
+    conn: &rusqlite::Connection,
+    code: &str,
+) -> SqlResult<Option<ThreadId>> {
+    let Some((selector, mac)) = code
+        .to_lowercase()
+        .split_once('.')
+        .and_then(|(s, m)| Some((u64::from_str_radix(s, 16).ok()?, m.to_string())))
+    else {
+        return Ok(None);
+    };
+    lookup(conn, CONFIRMATION_MAC, selector, &mac, false)
+}
+
+/// Gets the thread with the given selector, if its address's confirmation is as given and the MAC (for the given purpose) is right.
+fn lookup(
+    conn: &rusqlite::Connection,
+    purpose: &[u8],
+    selector: u64,
+    given_mac: &str,
+    confirmed: bool,
+) -> SqlResult<Option<ThreadId>> {
+    let Some((thread, email)) = conn
+        .query_row(
+            "SELECT id, email FROM threads WHERE email_selector = ?1 AND email IS NOT NULL AND email_confirmed = ?2;",
+            (selector as i64, confirmed),
+            |row| Ok((ThreadId(row.get(0)?), row.get::<_, String>(1)?)),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    let expected = mac(purpose, selector, &email);
+    Ok(bool::from(expected.as_bytes().ct_eq(given_mac.as_bytes())).then_some(thread))
+}
+
+/// Computes the MAC in a reply address or confirmation link (depending on `purpose`), as 32 hex digits: an HMAC-SHA256 keyed with our
+/// secret key.
+fn mac(purpose: &[u8], selector: u64, email: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(crate::CONFIG.ssh_key.secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(&[purpose.len() as u8]);
+    mac.update(purpose);
+    mac.update(&selector.to_be_bytes());
+    mac.update(email.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())[..32].to_string()
+}
+
+/// Checks an email address is reasonable to send to: a plain `local@domain` with no whitespace, quoting or other characters that
+/// could be misinterpreted in SMTP commands or headers.
+pub fn is_valid_address(address: &str) -> bool {
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+    address.len() <= 254
+        && !local.is_empty()
+        && local
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"<>()[]\\,;:\"@".contains(c))
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Extracts the new text of a reply from a raw email (with `\n` newlines): the first `text/plain` part, decoded, without any quoted
+/// text or signature. Returns `None` if there isn't any.
+pub fn extract_reply(email: &str) -> Option<String> {
+    let text = extract_text(email)?;
+
+    // Take lines up to the first quoted line or signature
+    let mut lines: Vec<&str> = text
+        .lines()
+        .take_while(|line| {
+            !line.starts_with('>')
+                && *line != "-- "
+                && *line != "--"
+                && !line.trim().starts_with("-----Original Message-----")
+        })
+        .collect();
+
+    // Drop the attribution line before the quote (e.g. "On Mon, Jan 1, 2024, Fletch wrote:", possibly wrapped over two lines)
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    if lines
+        .last()
+        .is_some_and(|line| line.trim_end().ends_with("wrote:"))
+    {
+        let attribution = lines.pop().unwrap_or_default();
+        if !attribution.trim_start().starts_with("On ")
+            && lines
+                .last()
+                .is_some_and(|line| line.trim_start().starts_with("On "))
+        {
+            lines.pop();
+        }
+    }
+
+    let reply = lines.join("\n").trim().to_string();
+    (!reply.is_empty()).then_some(reply)
+}
+
+/// Gets the first `text/plain` part of an email (or MIME part), decoded.
+fn extract_text(email: &str) -> Option<String> {
+    let (headers, body) = email.split_once("\n\n").unwrap_or((email, ""));
+
+    // Unfold headers, then get the ones we care about
+    let mut content_type = String::new();
+    let mut encoding = String::new();
+    for header in headers.replace("\n ", " ").replace("\n\t", " ").lines() {
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_lowercase().as_str() {
+            "content-type" => content_type = value.trim().to_string(),
+            "content-transfer-encoding" => encoding = value.trim().to_lowercase(),
+            _ => {}
+        }
+    }
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    if mime_type.starts_with("multipart/") {
+        // Check each part in turn
+        let boundary = parameter(&content_type, "boundary")?;
+        let delimiter = format!("--{boundary}");
+        return body
+            .split(&delimiter)
+            .skip(1) // preamble
+            .take_while(|part| !part.starts_with("--"))
+            .find_map(|part| extract_text(part.strip_prefix('\n').unwrap_or(part)));
+    }
+    if !mime_type.is_empty() && mime_type != "text/plain" {
+        return None;
+    }
+
+    let bytes = match encoding.as_str() {
+        "base64" => base64::engine::general_purpose::STANDARD
+            .decode(body.split_whitespace().collect::<String>())
+            .ok()?,
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.as_bytes().to_vec(),
+    };
+    let text = match parameter(&content_type, "charset")
+        .map(|c| c.to_lowercase())
+        .as_deref()
+    {
+        Some("iso-8859-1" | "latin1") => bytes.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    Some(text.replace("\r\n", "\n"))
+}
+
+/// Gets a parameter (e.g. `boundary`) from a header value like `Content-Type`, removing any quotes.
+fn parameter(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Decodes a quoted-printable body, leaving any invalid escapes as they are.
+fn decode_quoted_printable(body: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(body.len());
+    for line in body.split('\n') {
+        // Trailing whitespace isn't part of the encoded text, and a trailing `=` is a soft line break
+        let line = line.trim_end();
+        let (line, soft_break) = match line.strip_suffix('=') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = (bytes[i] == b'=')
+                .then(|| line.get(i + 1..i + 3))
+                .flatten()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) => {
+                    result.push(byte);
+                    i += 3;
+                }
+                None => {
+                    result.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        if !soft_break {
+            result.push(b'\n');
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The address and selector of the thread in `database`.
+    const ADDRESS: &str = "someone@example.com";
+    const SELECTOR: u64 = 0x0123_4567_89ab_cdef;
+
+    /// Opens an in-memory database with the current schema and one thread (ID 1) with `ADDRESS` set, confirmed or not.
+    fn database(confirmed: bool) -> rusqlite::Connection {
+        let conn = super::super::tests::database();
+        conn.execute(
+            "INSERT INTO threads (id, source_key, email, email_selector, email_confirmed) VALUES (1, 'test', ?1, ?2, ?3);",
+            (ADDRESS, SELECTOR as i64, confirmed),
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn extract_reply_drops_quote_and_attribution() {
+        let email = "From: someone@example.com\nSubject: Re: Reply\n\nThanks, that helps!\n\nOn Mon, Jan 1, 2024 at 9:00 AM, Fletch Rydell wrote:\n> Here's my reply\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Thanks, that helps!"));
+
+        // Attributions can be wrapped over two lines
+        let email = "Subject: Re: Reply\n\nSounds good.\nSee you then\n\nOn Mon, Jan 1, 2024 at 9:00 AM Fletch Rydell <\ncontact@example.org> wrote:\n>\n> Here's my reply\n";
+        assert_eq!(
+            extract_reply(email).as_deref(),
+            Some("Sounds good.\nSee you then")
+        );
+    }
+
+    #[test]
+    fn extract_reply_drops_signatures() {
+        let email = "Subject: Re: Reply\n\nHi!\n\n-- \nSomeone\nsomeone@example.com\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Hi!"));
+        let email =
+            "Subject: RE: Reply\n\nHello\n\n-----Original Message-----\nFrom: Fletch Rydell\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn extract_reply_decodes_parts() {
+        let email = "Content-Type: multipart/alternative; boundary=\"b\"\n\nThis is a multipart message\n--b\nContent-Type: text/html\n\n<p>Café time</p>\n--b\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: quoted-printable\n\nCaf=C3=A9 time\n--b--\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Café time"));
+
+        let email =
+            "Content-Type: text/plain\nContent-Transfer-Encoding: base64\n\nSGVsbG8g\ndGhlcmU=\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Hello there"));
+
+        // With a folded header
+        let email = "Content-Type: text/plain;\n charset=\"ISO-8859-1\"\nContent-Transfer-Encoding: Quoted-Printable\n\nCaf=E9\n";
+        assert_eq!(extract_reply(email).as_deref(), Some("Café"));
+    }
+
+    #[test]
+    fn extract_reply_needs_text() {
+        assert_eq!(
+            extract_reply("Content-Type: text/html\n\n<p>Hi</p>\n"),
+            None
+        );
+        assert_eq!(
+            extract_reply("Subject: Re: Reply\n\n> Only a quote\n"),
+            None
+        );
+        assert_eq!(extract_reply("Subject: Re: Reply\n\n  \n\n"), None);
+        assert_eq!(
+            extract_reply("Content-Type: multipart/mixed\n\n--b\n\nNo boundary\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn decode_quoted_printable_escapes() {
+        assert_eq!(decode_quoted_printable("a=3Db"), b"a=b\n");
+        assert_eq!(
+            decode_quoted_printable("=E2=9C=93 done"),
+            "✓ done\n".as_bytes()
+        );
+        assert_eq!(decode_quoted_printable("=e2=9c=93"), "✓\n".as_bytes());
+    }
+
+    #[test]
+    fn decode_quoted_printable_lines() {
+        // Soft line breaks join lines, and trailing whitespace isn't part of the text
+        assert_eq!(decode_quoted_printable("long=\nline"), b"longline\n");
+        assert_eq!(decode_quoted_printable("long= \nline"), b"longline\n");
+        assert_eq!(decode_quoted_printable("hi   \nthere"), b"hi\nthere\n");
+    }
+
+    #[test]
+    fn decode_quoted_printable_keeps_invalid_escapes() {
+        assert_eq!(decode_quoted_printable("=ZZ and =4"), b"=ZZ and =4\n");
+        assert_eq!(decode_quoted_printable("100=%"), b"100=%\n");
+    }
+
+    #[test]
+    fn valid_addresses() {
+        for address in [
+            "someone@example.com",
+            "first.last+tag@mail.example.co.uk",
+            "o'brien@example-domain.org",
+        ] {
+            assert!(is_valid_address(address), "{address} should be valid");
+        }
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        let too_long = format!("{}@example.com", "a".repeat(250));
+        for address in [
+            "",
+            "no-at-sign",
+            "@example.com",
+            "someone@",
+            "someone@localhost",
+            "some one@example.com",
+            "\"quoted\"@example.com",
+            "<someone>@example.com",
+            "someone@exa mple.com",
+            "someone@.example.com",
+            "someone@example.com.",
+            "someone@example.com>\r\nRCPT TO:<other@example.com",
+            "someone@example.com\nBcc: other@example.com",
+            too_long.as_str(),
+        ] {
+            assert!(!is_valid_address(address), "{address:?} should be invalid");
+        }
+    }
+
+    #[test]
+    fn reply_address_round_trip() {
+        let conn = database(true);
+        let address = reply_address(SELECTOR, ADDRESS);
+        assert!(address.ends_with("@example.org"));
+        let parsed = ReplyAddress::parse(&address).unwrap();
+        assert_eq!(resolve(&conn, &parsed).unwrap(), Some(ThreadId(1)));
+
+        // Case is ignored, as MTAs may change it
+        let parsed = ReplyAddress::parse(&address.to_uppercase()).unwrap();
+        assert_eq!(resolve(&conn, &parsed).unwrap(), Some(ThreadId(1)));
+    }
+
+    #[test]
+    fn reply_address_rejects_tampering() {
+        let conn = database(true);
+        let address = reply_address(SELECTOR, ADDRESS);
+
+        let (local, domain) = address.split_once('@').unwrap();
+        let last = if local.ends_with('0') { '1' } else { '0' };
+        let tampered = format!("{}{last}@{domain}", &local[..local.len() - 1]);
+        let parsed = ReplyAddress::parse(&tampered).unwrap();
+        assert_eq!(resolve(&conn, &parsed).unwrap(), None);
+
+        let other_thread = reply_address(SELECTOR + 1, ADDRESS);
+        let parsed = ReplyAddress::parse(&other_thread).unwrap();
+        assert_eq!(resolve(&conn, &parsed).unwrap(), None);
+
+        assert!(ReplyAddress::parse(&format!("{local}@example.com")).is_none());
+        assert!(ReplyAddress::parse("someone@example.org").is_none());
+        assert!(ReplyAddress::parse("reply+nothex.abc@example.org").is_none());
+    }
+
+    #[test]
+    fn reply_address_needs_current_confirmed_address() {
+        let parsed = ReplyAddress::parse(&reply_address(SELECTOR, ADDRESS)).unwrap();
+        assert_eq!(resolve(&database(false), &parsed).unwrap(), None);
+
+        let conn = database(true);
+        conn.execute(
+            "UPDATE threads SET email = 'other@example.com' WHERE id = 1;",
+            (),
+        )
+        .unwrap();
+        assert_eq!(resolve(&conn, &parsed).unwrap(), None);
+    }
+
+    #[test]
+    fn confirmation_round_trip() {
+        let conn = database(false);
+        let code = format!(
+            "{SELECTOR:016x}.{}",
+            mac(CONFIRMATION_MAC, SELECTOR, ADDRESS)
+        );
+        assert_eq!(
+            resolve_confirmation(&conn, &code).unwrap(),
+            Some(ThreadId(1))
+        );
+        assert_eq!(
+            resolve_confirmation(&conn, &code.to_uppercase()).unwrap(),
+            Some(ThreadId(1))
+        );
+
+        // Already used
+        assert_eq!(resolve_confirmation(&database(true), &code).unwrap(), None);
+
+        // A reply address's MAC isn't a confirmation code
+        let reply_code = format!("{SELECTOR:016x}.{}", mac(REPLY_MAC, SELECTOR, ADDRESS));
+        assert_eq!(resolve_confirmation(&conn, &reply_code).unwrap(), None);
+        assert_eq!(resolve_confirmation(&conn, "not a code").unwrap(), None);
+    }
+}
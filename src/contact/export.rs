@@ -0,0 +1,201 @@
+//! Exporting threads for users to download, in a few formats.
+
+use chrono::{TimeZone, Utc};
+
+use super::{format::format_timestamp, Message, ThreadToken};
+
+/// A format a thread can be exported in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A JSON object with the thread ID and its messages, as returned by the API.
+    Json,
+    /// An mbox file with one email per message, for importing into a mail client.
+    Mbox,
+    /// Plain text, one message after another.
+    #[default]
+    Text,
+}
+impl ExportFormat {
+    /// The MIME type of exported threads.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Mbox => "application/mbox",
+            Self::Text => "text/plain; charset=utf-8",
+        }
+    }
+    /// The file extension of exported threads.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Mbox => "mbox",
+            Self::Text => "txt",
+        }
+    }
+}
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "mbox" => Ok(Self::Mbox),
+            "text" | "txt" => Ok(Self::Text),
+            _ => Err(format!(
+                "unknown export format \"{s}\" (should be json, mbox or text)"
+            )),
+        }
+    }
+}
+
+/// Exports a thread's messages in the given format, using `\n` for newlines.
+pub fn export(thread: ThreadToken, messages: &[Message], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => serde_json::json!({
+            "thread": thread.to_string(),
+            "messages": messages,
+        })
+        .to_string(),
+        ExportFormat::Mbox => export_mbox(thread, messages),
+        ExportFormat::Text => {
+            let mut result = format!("Thread {thread}:\n");
+            for message in messages {
+                result.push_str(&format!(
+                    "\n({}) {}\n{}\n",
+                    format_timestamp(message.timestamp),
+                    if message.response { "Me:" } else { "You:" },
+                    message.contents
+                ));
+            }
+            result
+        }
+    }
+}
+
+/// Exports a thread as an mbox (mboxrd variant), with each message threaded as a reply to the one before it.
+///
+/// Only the token's public ID is used in headers, so the secret isn't leaked by replying to or forwarding these emails.
+fn export_mbox(thread: ThreadToken, messages: &[Message]) -> String {
+    let domain = &crate::CONFIG.domain;
+    let thread = thread.public_id();
+    let mut result = String::new();
+    for (i, message) in messages.iter().enumerate() {
+        let time = Utc
+            .timestamp_opt(message.timestamp, 0)
+            .single()
+            .unwrap_or_default();
+        let from = if message.response {
+            format!("Fletch Rydell <contact@{domain}>")
+        } else {
+            format!("You <thread-{thread}@{domain}>")
+        };
+        result.push_str(&format!(
+            "From contact@{domain} {}\n",
+            time.format("%a %b %e %H:%M:%S %Y")
+        ));
+        result.push_str(&format!("From: {from}\n"));
+        result.push_str(&format!("Date: {}\n", time.to_rfc2822()));
+        result.push_str(&format!("Subject: Thread {thread}\n"));
+        result.push_str(&format!("Message-ID: <{thread}.{i}@{domain}>\n"));
+        if i > 0 {
+            result.push_str(&format!("In-Reply-To: <{thread}.{}@{domain}>\n", i - 1));
+        }
+        result.push_str("Content-Type: text/plain; charset=utf-8\n\n");
+        for line in message.contents.lines() {
+            // Quote lines that would otherwise look like the start of a new message (along with already-quoted ones, so this is reversible)
+            if line.trim_start_matches('>').starts_with("From ") {
+                result.push('>');
+            }
+            result.push_str(line);
+            result.push('\n');
+        }
+        result.push('\n');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECTOR: &str = "0123456789abcdef";
+    const VERIFIER: &str = "fedcba9876543210fedcba9876543210";
+
+    fn token() -> ThreadToken {
+        format!("{SELECTOR}{VERIFIER}").parse().unwrap()
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message {
+                contents: "Hi!\nFrom here, it looks great.\n>From before".to_string(),
+                timestamp: 1_700_000_000,
+                response: false,
+            },
+            Message {
+                contents: "Thanks".to_string(),
+                timestamp: 1_700_003_600,
+                response: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn formats_parse() {
+        assert_eq!("json".parse(), Ok(ExportFormat::Json));
+        assert_eq!("mbox".parse(), Ok(ExportFormat::Mbox));
+        assert_eq!("txt".parse(), Ok(ExportFormat::Text));
+        assert_eq!("text".parse(), Ok(ExportFormat::Text));
+        assert!("JSON".parse::<ExportFormat>().is_err());
+        assert!("".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn text_lists_messages() {
+        assert_eq!(
+            export(token(), &messages(), ExportFormat::Text),
+            format!(
+                "Thread {SELECTOR}{VERIFIER}:\n\
+                 \n(2023-11-14 22:13 UTC) You:\nHi!\nFrom here, it looks great.\n>From before\n\
+                 \n(2023-11-14 23:13 UTC) Me:\nThanks\n"
+            )
+        );
+    }
+
+    #[test]
+    fn json_has_the_thread_and_messages() {
+        let json: serde_json::Value =
+            serde_json::from_str(&export(token(), &messages(), ExportFormat::Json)).unwrap();
+        assert_eq!(json["thread"], format!("{SELECTOR}{VERIFIER}"));
+        assert_eq!(json["messages"][1]["contents"], "Thanks");
+        assert_eq!(json["messages"][1]["response"], true);
+    }
+
+    #[test]
+    fn mbox_threads_messages() {
+        crate::init_test_config();
+        let mbox = export(token(), &messages(), ExportFormat::Mbox);
+        assert!(mbox.starts_with("From contact@example.org Tue Nov 14 22:13:20 2023\n"));
+        assert!(mbox.contains(&format!("From: You <thread-{SELECTOR}@example.org>\n")));
+        assert!(mbox.contains("From: Fletch Rydell <contact@example.org>\n"));
+        assert!(mbox.contains(&format!("Message-ID: <{SELECTOR}.1@example.org>\n")));
+        assert!(mbox.contains(&format!("In-Reply-To: <{SELECTOR}.0@example.org>\n")));
+        assert_eq!(mbox.matches("In-Reply-To").count(), 1);
+        // The secret part of the token is never included
+        assert!(!mbox.contains(VERIFIER));
+    }
+
+    #[test]
+    fn mbox_quotes_from_lines() {
+        crate::init_test_config();
+        let mbox = export(token(), &messages(), ExportFormat::Mbox);
+        assert!(mbox.contains("\n>From here, it looks great.\n>>From before\n"));
+        // So the only unquoted "From " lines start messages
+        assert_eq!(
+            mbox.lines()
+                .filter(|line| line.starts_with("From "))
+                .count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Formatting for contact messages, supporting a safe subset of djot: emphasis, strong emphasis, inline code, code blocks and autolinks.
+//!
+//! Messages are rendered to sanitized HTML for the web contact page, or to ANSI styling for the SSH `msg view`. Any other syntax is
+//! rendered as the source text it was written with, so a message written without formatting in mind comes out as it was typed.
+
+use chrono::{TimeZone, Utc};
+use jotdown::{Container, Event, LinkType};
+
+/// Renders a message to HTML, escaping everything outside of the supported formatting.
+pub fn to_html(message: &str) -> String {
+    render(message, Html(String::new())).0
+}
+
+/// Renders a message to text styled with ANSI escape codes, using `\n` for newlines.
+pub fn to_ansi(message: &str) -> String {
+    let mut result = render(message, Ansi(String::new())).0;
+    result.truncate(result.trim_end().len());
+    result
+}
+
+/// Formats a message timestamp (a unix time) to be human-readable.
+pub fn format_timestamp(timestamp: i64) -> String {
+    match Utc.timestamp_opt(timestamp, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// An output format for messages.
+trait Target {
+    /// Adds plain text to the output.
+    fn text(&mut self, text: &str);
+    /// Adds a line break to the output.
+    fn line_break(&mut self);
+    /// Starts a supported formatting container.
+    fn start(&mut self, format: Format);
+    /// Ends a supported formatting container.
+    fn end(&mut self, format: Format);
+}
+
+/// The formatting supported in messages.
+#[derive(Clone, Debug)]
+enum Format {
+    Paragraph,
+    Emphasis,
+    Strong,
+    InlineCode,
+    CodeBlock,
+    /// An autolink to the given URL, which is guaranteed to be a `http`, `https` or `mailto` link.
+    Link(String),
+}
+impl Format {
+    /// Gets the supported formatting for a container, if any.
+    fn from_container(container: &Container) -> Option<Self> {
+        Some(match container {
+            Container::Paragraph => Self::Paragraph,
+            Container::Emphasis => Self::Emphasis,
+            Container::Strong => Self::Strong,
+            Container::Verbatim => Self::InlineCode,
+            Container::CodeBlock { .. } | Container::RawBlock { .. } => Self::CodeBlock,
+            Container::Link(url, LinkType::AutoLink)
+                if ["http://", "https://", "mailto:"]
+                    .iter()
+                    .any(|scheme| url.starts_with(scheme)) =>
+            {
+                Self::Link(url.to_string())
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Renders a message to the given target, returning it.
+fn render<T: Target>(message: &str, mut target: T) -> T {
+    // Markers of unsupported blocks (e.g. `>` or `-`), to be added as text at the start of the next paragraph
+    let mut prefix = String::new();
+    for (event, range) in jotdown::Parser::new(message).into_offset_iter() {
+        let source = &message[range];
+        match event {
+            Event::Start(container, _) => match Format::from_container(&container) {
+                Some(format) => {
+                    target.start(format);
+                    target.text(&std::mem::take(&mut prefix));
+                }
+                None if is_leaf_block(&container) => {
+                    target.start(Format::Paragraph);
+                    target.text(&std::mem::take(&mut prefix));
+                    target.text(source.trim_end());
+                    target.text(" ");
+                }
+                None if container.is_block() => {
+                    if !source.trim().is_empty() {
+                        prefix.push_str(source.trim());
+                        prefix.push(' ');
+                    }
+                }
+                None => target.text(source),
+            },
+            Event::End(container) => match Format::from_container(&container) {
+                Some(format) => target.end(format),
+                None if is_leaf_block(&container) => target.end(Format::Paragraph),
+                None if matches!(container, Container::TableCell { .. }) => target.text(source),
+                None if container.is_block() => {}
+                None => target.text(source),
+            },
+            Event::ThematicBreak(_) => {
+                target.start(Format::Paragraph);
+                target.text(source.trim());
+                target.end(Format::Paragraph);
+            }
+            Event::Str(text) => target.text(&text),
+            Event::Softbreak | Event::Hardbreak => target.line_break(),
+            Event::Escape | Event::Blankline => {}
+            // Smart punctuation, symbols, footnote references etc. are kept as written
+            _ => target.text(source),
+        }
+    }
+    target
+}
+
+/// Whether a container is an unsupported block containing only inline elements, which are rendered as paragraphs.
+fn is_leaf_block(container: &Container) -> bool {
+    matches!(
+        container,
+        Container::Heading { .. }
+            | Container::TableRow { .. }
+            | Container::Caption
+            | Container::DescriptionTerm
+            | Container::LinkDefinition { .. }
+    )
+}
+
+/// Renders to HTML.
+struct Html(String);
+impl Target for Html {
+    fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '&' => self.0.push_str("&amp;"),
+                '<' => self.0.push_str("&lt;"),
+                '>' => self.0.push_str("&gt;"),
+                '"' => self.0.push_str("&quot;"),
+                '\'' => self.0.push_str("&#39;"),
+                c => self.0.push(c),
+            }
+        }
+    }
+    fn line_break(&mut self) {
+        self.0.push_str("<br>");
+    }
+    fn start(&mut self, format: Format) {
+        match format {
+            Format::Paragraph => self.0.push_str("<p>"),
+            Format::Emphasis => self.0.push_str("<em>"),
+            Format::Strong => self.0.push_str("<strong>"),
+            Format::InlineCode => self.0.push_str("<code>"),
+            Format::CodeBlock => self.0.push_str("<pre><code>"),
+            Format::Link(url) => {
+                self.0.push_str("<a href=\"");
+                self.text(&url);
+                self.0.push_str("\" rel=\"nofollow noopener noreferrer\">");
+            }
+        }
+    }
+    fn end(&mut self, format: Format) {
+        self.0.push_str(match format {
+            Format::Paragraph => "</p>",
+            Format::Emphasis => "</em>",
+            Format::Strong => "</strong>",
+            Format::InlineCode => "</code>",
+            Format::CodeBlock => "</code></pre>",
+            Format::Link(_) => "</a>",
+        });
+    }
+}
+
+/// Renders to ANSI-styled text.
+struct Ansi(String);
+impl Target for Ansi {
+    fn text(&mut self, text: &str) {
+        // Drop control characters so messages can't mess with the terminal, keeping newlines (from code blocks)
+        self.0
+            .extend(text.chars().filter(|&c| c == '\n' || !c.is_control()));
+    }
+    fn line_break(&mut self) {
+        self.0.push('\n');
+    }
+    fn start(&mut self, format: Format) {
+        match format {
+            Format::Paragraph => {}
+            Format::Emphasis => self.0.push_str("\x1b[3m"),
+            Format::Strong => self.0.push_str("\x1b[1m"),
+            Format::InlineCode | Format::CodeBlock => self.0.push_str("\x1b[36m"),
+            Format::Link(_) => self.0.push_str("\x1b[4m"),
+        }
+    }
+    fn end(&mut self, format: Format) {
+        if let Format::CodeBlock = format {
+            // Code blocks already end in a newline
+            self.0.truncate(self.0.trim_end_matches('\n').len());
+        }
+        self.0.push_str(match format {
+            Format::Paragraph => "\n\n",
+            Format::Emphasis => "\x1b[23m",
+            Format::Strong => "\x1b[22m",
+            Format::InlineCode => "\x1b[39m",
+            Format::CodeBlock => "\x1b[39m\n\n",
+            Format::Link(_) => "\x1b[24m",
+        });
+    }
+}
@@ -0,0 +1,1071 @@
+use std::{
+    convert::Infallible,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use color_eyre::Result;
+
+use once_cell::sync::Lazy;
+use rusqlite::{OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tokio_rusqlite::Connection;
+use tracing::{error, info};
+
+pub mod challenge;
+pub mod email;
+pub mod export;
+pub mod format;
+mod spam;
+mod token;
+
+pub use token::ThreadToken;
+
+type SqlResult<T> = rusqlite::Result<T>;
+
+/// A SQL connection to use for async queries; can be cheaply cloned while sharing one underlying connection in a separate thread.
+static CONN: Mutex<Option<Connection>> = Mutex::new(None);
+
+/// Broadcasts every message (along with its thread) as it's committed to the database, whether by us or another connection.
+static NEW_MESSAGES: Lazy<broadcast::Sender<(ThreadId, Message)>> =
+    Lazy::new(|| broadcast::channel(64).0);
+/// The ID (rowid) of the last message broadcast on `NEW_MESSAGES`, relying on message IDs never being reused (see
+/// `migrate_message_ids`). Only accessed from the database thread.
+static LAST_BROADCAST: AtomicI64 = AtomicI64::new(0);
+/// How often to check for changes to the database made by other connections (e.g. me replying to messages).
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The minimum time between confirmation emails for a thread (see `set_email`).
+const CONFIRMATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Sets up the messages database for the contact page at startup, then polls for changes from other connections forever. Continues indefinitely after that while holding DB connection so we close connection on program exit via cancellation.
+pub async fn main() -> Result<Infallible> {
+    // Initialize DB
+    let conn = Connection::open(&crate::CONFIG.msg_database).await?;
+    conn.call(|conn| {
+        // Global config (enable foreign keys if not already on)
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+
+        // Bring the schema up to date, running each migration we haven't yet in its own transaction
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            info!("Migrated messages database to version {}", i + 1);
+        }
+
+        // Only broadcast messages sent from now on
+        LAST_BROADCAST.store(
+            conn.query_row("SELECT COALESCE(MAX(rowid), 0) FROM messages;", (), |row| {
+                row.get(0)
+            })?,
+            Ordering::Relaxed,
+        );
+        Ok(())
+    })
+    .await?;
+
+    // Set `CONN` and make guard to unset/drop when cancelled (TODO: is this pointless? connection closed when file descriptor drops at process exit anyway? and not sure if dropping connection actually does anything either, despite docs claiming it does? ideally would close connection in thread, but tokio_rusqlite doesn't support).
+    *CONN.lock().expect("poison") = Some(conn.clone());
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            CONN.lock().expect("poison").take();
+        }
+    }
+    let _guard = Guard;
+
+    // Poll for commits from other connections, broadcasting any new messages (our own commits are broadcast as they happen).
+    // Sqlite's `data_version` changes exactly when another connection commits.
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut data_version = None;
+    loop {
+        interval.tick().await;
+        let result = conn
+            .call(move |conn| {
+                let new_version: i64 =
+                    conn.query_row("PRAGMA data_version;", (), |row| row.get(0))?;
+                if data_version != Some(new_version) {
+                    broadcast_new_messages(conn)?;
+                }
+                Ok(new_version)
+            })
+            .await;
+        match result {
+            Ok(new_version) => data_version = Some(new_version),
+            Err(err) => error!("Database error while polling for changes: {err}"),
+        }
+    }
+}
+
+/// Schema migrations for the messages database, where running `MIGRATIONS[i]` brings the schema from `user_version` `i` to `i + 1`.
+///
+/// Each runs in its own transaction at startup. Never edit a migration once it's deployed; add a new one instead.
+const MIGRATIONS: &[fn(&rusqlite::Connection) -> SqlResult<()>] = &[
+    migrate_initial,
+    migrate_spam,
+    migrate_source_key,
+    migrate_tokens,
+    migrate_email,
+    migrate_message_ids,
+    migrate_spam_training,
+    migrate_email_confirmation,
+];
+
+/// Creates the initial tables and triggers. Uses `IF NOT EXISTS` throughout, as databases predating migrations are at `user_version` 0 but already have this schema.
+fn migrate_initial(conn: &rusqlite::Connection) -> SqlResult<()> {
+    // Create tables for threads and messages, along with index to speed up foreign key lookups (e.g. avoid full messages scan when deleting thread)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS threads (
+            id          INTEGER PRIMARY KEY,
+            source_ip   TEXT NOT NULL,
+            unread      INTEGER NOT NULL DEFAULT 0
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            thread      INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE ON UPDATE CASCADE,
+            contents    TEXT NOT NULL,
+            response    INTEGER NOT NULL CHECK(response = 0 OR response = 1),
+            time        INTEGER NOT NULL
+        );",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS message_thread_index ON messages(thread);",
+        (),
+    )?;
+
+    // Keep unread count up to date (mark all as read once responded to) as messages are inserted
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS unread_increment BEFORE INSERT ON messages WHEN (NEW.response = 0) BEGIN
+            UPDATE threads SET unread = unread + 1 WHERE id = NEW.thread;
+        END;",
+        ()
+    )?;
+    conn.execute(
+        "CREATE TRIGGER IF NOT EXISTS unread_reset AFTER INSERT ON messages WHEN (NEW.response = 1) BEGIN
+            UPDATE threads SET unread = 0 WHERE id = NEW.thread;
+        END;",
+        ()
+    )?;
+    Ok(())
+}
+
+/// Adds spam filtering state: each message's spam score, content hash (for finding duplicates), and whether it's quarantined, plus a
+/// per-thread count of quarantined messages (kept separate from `unread`) and a label I can set on threads (`1` for spam, `0` for not
+/// spam) to train the classifier in `spam`. Releasing a message from quarantine is a manual database edit.
+fn migrate_spam(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute_batch(
+        "ALTER TABLE threads ADD COLUMN quarantined INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE threads ADD COLUMN spam_label INTEGER CHECK(spam_label IS NULL OR spam_label = 0 OR spam_label = 1);
+        ALTER TABLE messages ADD COLUMN quarantined INTEGER NOT NULL DEFAULT 0 CHECK(quarantined = 0 OR quarantined = 1);
+        ALTER TABLE messages ADD COLUMN spam_score REAL;
+        ALTER TABLE messages ADD COLUMN hash TEXT;
+        CREATE INDEX message_hash_index ON messages(hash);
+
+        DROP TRIGGER unread_increment;
+        DROP TRIGGER unread_reset;
+        CREATE TRIGGER unread_increment BEFORE INSERT ON messages WHEN (NEW.response = 0 AND NEW.quarantined = 0) BEGIN
+            UPDATE threads SET unread = unread + 1 WHERE id = NEW.thread;
+        END;
+        CREATE TRIGGER quarantined_increment BEFORE INSERT ON messages WHEN (NEW.response = 0 AND NEW.quarantined = 1) BEGIN
+            UPDATE threads SET quarantined = quarantined + 1 WHERE id = NEW.thread;
+        END;
+        CREATE TRIGGER unread_reset AFTER INSERT ON messages WHEN (NEW.response = 1) BEGIN
+            UPDATE threads SET unread = 0, quarantined = 0 WHERE id = NEW.thread;
+        END;",
+    )
+}
+
+/// Replaces each thread's raw source IP with its rate limiting key (see `source_key`), so IPs are no longer stored.
+///
+/// Keys depend on the configured prefixes at the time they're computed, so changing `CONFIG.msg_ipv4_prefix` or `CONFIG.msg_ipv6_prefix`
+/// later only affects new threads.
+fn migrate_source_key(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute("ALTER TABLE threads ADD COLUMN source_key TEXT;", ())?;
+    let threads = conn
+        .prepare("SELECT id, source_ip FROM threads;")?
+        .query_map((), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+    for (id, ip) in threads {
+        // IPs were always stored via `IpAddr::to_string`, but use an unspecified address if parsing somehow fails rather than aborting startup
+        let ip = ip.parse().unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        conn.execute(
+            "UPDATE threads SET source_key = ?1 WHERE id = ?2;",
+            (source_key(ip), id),
+        )?;
+    }
+    conn.execute_batch(
+        "ALTER TABLE threads DROP COLUMN source_ip;
+        CREATE INDEX thread_source_index ON threads(source_key);",
+    )
+}
+
+/// Adds capability tokens for accessing threads (see `token`). Existing threads keep access by legacy ID (see `CONFIG.msg_legacy_thread_ids`) until their tokens are rotated, while new threads are only accessible by token.
+fn migrate_tokens(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute_batch(
+        "ALTER TABLE threads ADD COLUMN legacy_access INTEGER NOT NULL DEFAULT 0 CHECK(legacy_access = 0 OR legacy_access = 1);
+        UPDATE threads SET legacy_access = 1;
+        CREATE TABLE thread_tokens (
+            selector    INTEGER PRIMARY KEY,
+            thread      INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE ON UPDATE CASCADE,
+            hash        BLOB NOT NULL,
+            created     INTEGER NOT NULL
+        );
+        CREATE INDEX thread_token_thread_index ON thread_tokens(thread);",
+    )
+}
+
+/// Adds the email address (if any) that the user has opted in to receive my replies at on each thread, along with a random selector
+/// identifying the thread in reply addresses (see `email`), regenerated whenever the address changes.
+fn migrate_email(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute("ALTER TABLE threads ADD COLUMN email TEXT;", ())?;
+    conn.execute("ALTER TABLE threads ADD COLUMN email_selector INTEGER;", ())?;
+    conn.execute(
+        "CREATE UNIQUE INDEX email_selector_index ON threads(email_selector);",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Gives messages `AUTOINCREMENT` IDs (aliasing their rowids, which they keep), so the IDs of deleted messages are never reused. New
+/// messages are found by ID (see `LAST_BROADCAST`), so reusing the ID of a deleted message would hide them.
+fn migrate_message_ids(conn: &rusqlite::Connection) -> SqlResult<()> {
+    // Sqlite can't add `AUTOINCREMENT` to a table, so rebuild it (along with its indexes and triggers)
+    conn.execute_batch(
+        "CREATE TABLE messages_new (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            thread      INTEGER NOT NULL REFERENCES threads(id) ON DELETE CASCADE ON UPDATE CASCADE,
+            contents    TEXT NOT NULL,
+            response    INTEGER NOT NULL CHECK(response = 0 OR response = 1),
+            time        INTEGER NOT NULL,
+            quarantined INTEGER NOT NULL DEFAULT 0 CHECK(quarantined = 0 OR quarantined = 1),
+            spam_score  REAL,
+            hash        TEXT
+        );
+        INSERT INTO messages_new (id, thread, contents, response, time, quarantined, spam_score, hash)
+            SELECT rowid, thread, contents, response, time, quarantined, spam_score, hash FROM messages;
+        DROP TABLE messages;
+        ALTER TABLE messages_new RENAME TO messages;
+        CREATE INDEX message_thread_index ON messages(thread);
+        CREATE INDEX message_hash_index ON messages(hash);
+
+        CREATE TRIGGER unread_increment BEFORE INSERT ON messages WHEN (NEW.response = 0 AND NEW.quarantined = 0) BEGIN
+            UPDATE threads SET unread = unread + 1 WHERE id = NEW.thread;
+        END;
+        CREATE TRIGGER quarantined_increment BEFORE INSERT ON messages WHEN (NEW.response = 0 AND NEW.quarantined = 1) BEGIN
+            UPDATE threads SET quarantined = quarantined + 1 WHERE id = NEW.thread;
+        END;
+        CREATE TRIGGER unread_reset AFTER INSERT ON messages WHEN (NEW.response = 1) BEGIN
+            UPDATE threads SET unread = 0, quarantined = 0 WHERE id = NEW.thread;
+        END;",
+    )
+}
+
+/// Adds a generation counter for the classifier's training data in `spam`, bumped by triggers whenever a thread's label is set, changed
+/// or removed (including by hand), so the classifier knows when to retrain. New messages on labeled threads don't bump it, so a busy
+/// labeled thread doesn't retrain the classifier on every message, and are only trained on once a label next changes.
+fn migrate_spam_training(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute_batch(
+        "CREATE TABLE spam_training (generation INTEGER NOT NULL);
+        INSERT INTO spam_training (generation) VALUES (0);
+
+        CREATE TRIGGER spam_label_insert AFTER INSERT ON threads WHEN (NEW.spam_label IS NOT NULL) BEGIN
+            UPDATE spam_training SET generation = generation + 1;
+        END;
+        CREATE TRIGGER spam_label_update AFTER UPDATE OF spam_label ON threads WHEN (OLD.spam_label IS NOT NEW.spam_label) BEGIN
+            UPDATE spam_training SET generation = generation + 1;
+        END;
+        CREATE TRIGGER spam_label_delete AFTER DELETE ON threads WHEN (OLD.spam_label IS NOT NULL) BEGIN
+            UPDATE spam_training SET generation = generation + 1;
+        END;",
+    )
+}
+
+/// Adds whether each thread's email address has been confirmed (with the link emailed to it when it's set, see `email`), as my replies
+/// are only sent to confirmed addresses, and when a confirmation email was last sent for the thread, to limit how often they're sent.
+/// Addresses set before this need confirming like any other.
+fn migrate_email_confirmation(conn: &rusqlite::Connection) -> SqlResult<()> {
+    conn.execute_batch(
+        "ALTER TABLE threads ADD COLUMN email_confirmed INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE threads ADD COLUMN email_requested INTEGER;",
+    )
+}
+
+/// Backs up the whole messages database to a new file alongside it (or in the working directory for an in-memory database), returning the backup's path.
+///
+/// Uses Sqlite's online backup API, copying all pages in one step so the backup is a consistent snapshot even while messages are being sent.
+pub async fn backup() -> Result<String> {
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or_else(|| color_eyre::eyre::eyre!("Messages database not initialized"))?;
+    let path = format!(
+        "{}.{}.bak",
+        match crate::CONFIG.msg_database.as_str() {
+            ":memory:" => "messages",
+            path => path,
+        },
+        chrono::Utc::now().format("%Y%m%d-%H%M%S")
+    );
+    let dest = path.clone();
+    conn.call(move |conn| {
+        let mut dest = rusqlite::Connection::open(dest)?;
+        rusqlite::backup::Backup::new(conn, &mut dest)?.step(-1)?;
+        Ok(())
+    })
+    .await?;
+    info!("Backed up messages database to {path}");
+    Ok(path)
+}
+
+/// Gets the thread a token grants access to, e.g. to pick out its messages from `subscribe`.
+pub async fn resolve(token: ThreadToken) -> Result<ThreadId, MessagesLoadError> {
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessagesLoadError::DatabaseError)?;
+    conn.call(move |conn| Ok(token::resolve(conn, &token)?.ok_or(MessagesLoadError::NoSuchThread)))
+        .await
+        .unwrap_or_else(|err| {
+            error!("Database error on thread token resolution: {err}");
+            Err(MessagesLoadError::DatabaseError)
+        })
+}
+
+/// Exchanges a legacy thread ID for a new capability token on the same thread, so access survives the end of the migration period. Tokens are returned unchanged.
+pub async fn upgrade(token: ThreadToken) -> Result<ThreadToken, MessagesLoadError> {
+    let ThreadToken::Legacy(_) = token else {
+        return resolve(token).await.map(|_| token);
+    };
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessagesLoadError::DatabaseError)?;
+    conn.call(move |conn| {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let Some(thread) = token::resolve(&tx, &token)? else {
+            return Ok(Err(MessagesLoadError::NoSuchThread));
+        };
+        let new_token = token::issue(&tx, thread)?;
+        tx.commit()?;
+        Ok(Ok(new_token))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on thread token upgrade: {err}");
+        Err(MessagesLoadError::DatabaseError)
+    })
+}
+
+/// Revokes all tokens for the thread the given token grants access to (and access by its legacy ID), returning a single new token.
+/// For when a token leaks.
+pub async fn rotate_token(token: ThreadToken) -> Result<ThreadToken, MessagesLoadError> {
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessagesLoadError::DatabaseError)?;
+    conn.call(move |conn| {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let Some(thread) = token::resolve(&tx, &token)? else {
+            return Ok(Err(MessagesLoadError::NoSuchThread));
+        };
+        let Some(new_token) = token::rotate(&tx, thread)? else {
+            return Ok(Err(MessagesLoadError::NoSuchThread));
+        };
+        tx.commit()?;
+        info!("Rotated tokens for thread {thread}");
+        Ok(Ok(new_token))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on thread token rotation: {err}");
+        Err(MessagesLoadError::DatabaseError)
+    })
+}
+
+/// Gets all messages on the thread the given token grants access to.
+pub async fn get_messages(token: ThreadToken) -> Result<Vec<Message>, MessagesLoadError> {
+    // Get connection and run rest of function in Sqlite thread
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessagesLoadError::DatabaseError)?;
+    conn
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+
+            // Check token grants access to a thread
+            let Some(thread) = token::resolve(&tx, &token)? else {
+                return Ok(Err(MessagesLoadError::NoSuchThread));
+            };
+
+            // Load messages
+            let result: Vec<_> = tx.prepare_cached("SELECT contents, response, time FROM messages WHERE thread = ?1 ORDER BY time ASC;")?
+                .query_map([thread.0], |row|
+                    Ok(Message{
+                        contents: row.get(0)?,
+                        response: row.get(1)?,
+                        timestamp: row.get(2)?,
+                    })
+                )?
+                .collect::<Result<Vec<Message>,_>>()?;
+
+            // Commit transaction and return results
+            tx.commit()?;
+            Ok(Ok(result))
+        })
+        .await
+        .unwrap_or_else(|err| {
+            error!("Database error on thread retrieval: {err}");
+            Err(MessagesLoadError::DatabaseError)
+        })
+}
+
+/// Creates a new thread of messages starting with the given one, returning a token for the thread on success. Errors on database issues, a missing or invalid proof of work (if `CONFIG.msg_pow_difficulty` is set), a message exceeding the max size, or too many unresponded threads (globally or for the IP).
+pub async fn create_thread(
+    ip: IpAddr,
+    first_message: String,
+    proof: Option<challenge::Proof>,
+) -> Result<ThreadToken, MessageSendError> {
+    // Get connection
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessageSendError::DatabaseError)?;
+
+    // Check message size
+    if first_message.chars().count() > crate::CONFIG.msg_max_size {
+        return Err(MessageSendError::TooLong);
+    }
+
+    // Check proof of work, if required
+    if !challenge::verify(proof.as_ref()) {
+        return Err(MessageSendError::BadChallenge);
+    }
+
+    // Get the rate limiting key for this IP's subnet (never storing the IP itself)
+    let source = source_key(ip);
+
+    // Rest of action is single transaction updating database, just send entire thing to background thread (could separately begin transaction, check validity, and write, but silly to do here since only have one connection anyway and if Sqlite is bottleneck have more to think about).
+    // TODO: doing everything at once atomically ensures only one transaction at a time, but if we don't, we need to consider concurrent writers, as our writes start out as read transactions due to validity check (UPDATE: added behavior mode immediate to fix)
+    conn.call(move |conn| {
+        // Start write transaction
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        // Score message for spam, then check inbox limits accordingly, returning error (but not database error) if checks fail
+        let verdict = spam::check(&tx, None, &first_message)?;
+        if let Err(e) = check_inbox_limits(&tx, &source, &verdict)? {
+            return Ok(Err(e));
+        }
+
+        // Generate random ID for thread
+        let thread_id = ThreadId(rand::random());
+
+        // Create thread and add message
+        tx.execute(
+            "INSERT INTO threads (id, source_key) VALUES (?1, ?2);",
+            (thread_id.0, source),
+        )?;
+        add_message(&tx, thread_id, first_message, &verdict)?;
+        let token = token::issue(&tx, thread_id)?;
+
+        // Commit transaction if no errors occurred (will rollback if thread count checks fail in addition to on database errors, which is fine as we haven't written and don't want to write)
+        tx.commit()?;
+        broadcast_new_messages_logged(conn);
+
+        // Return token if everything was successful (no database error, no `MessageSendError`)
+        Ok(Ok(token))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on thread creation: {err}");
+        Err(MessageSendError::DatabaseError)
+    })
+}
+
+/// Sends a message on the thread the given token grants access to. Errors on database issues or rate limiting as described by `MessageSendError` variants.
+pub async fn send_message(token: ThreadToken, message: String) -> Result<(), MessageSendError> {
+    send_message_with(move |conn| token::resolve(conn, &token), message).await
+}
+
+/// Sends a message on the thread given by `resolve` (run in the message's transaction, returning `None` if there's no such thread or the user doesn't have access), as in `send_message`.
+async fn send_message_with(
+    resolve: impl FnOnce(&rusqlite::Connection) -> SqlResult<Option<ThreadId>> + Send + 'static,
+    message: String,
+) -> Result<(), MessageSendError> {
+    // Get connection
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessageSendError::DatabaseError)?;
+
+    // Check message size
+    if message.chars().count() > crate::CONFIG.msg_max_size {
+        return Err(MessageSendError::TooLong);
+    }
+
+    // Rest of action is single transaction updating database, just send entire thing to background thread as in `create_thread`
+    conn.call(move |conn| {
+        // Start write transaction
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        // Check the user has access to a thread
+        let Some(thread_id) = resolve(&tx)? else {
+            return Ok(Err(MessageSendError::NoSuchThread));
+        };
+
+        // Check number of unread messages on this thread (quarantined or not), verifying thread exists in the process and getting source for next check
+        let source: String = match tx
+            .query_row(
+                "SELECT unread + quarantined, source_key FROM threads WHERE (id = ?1);",
+                [thread_id.0],
+                |row| Ok((row.get::<_, usize>(0)?, row.get(1)?)),
+            )
+            .optional()?
+        {
+            None => return Ok(Err(MessageSendError::NoSuchThread)),
+            Some((c, _)) if c >= crate::CONFIG.msg_max_unread_messages => {
+                return Ok(Err(MessageSendError::ThreadFull))
+            }
+            Some((_, source)) => source,
+        };
+
+        // Score message for spam, then check inbox limits accordingly, returning error (but not database error) if checks fail
+        let verdict = spam::check(&tx, Some(thread_id), &message)?;
+        if let Err(e) = check_inbox_limits(&tx, &source, &verdict)? {
+            return Ok(Err(e));
+        }
+
+        // Actually send message
+        add_message(&tx, thread_id, message, &verdict)?;
+
+        // Commit transaction if no errors occurred (will rollback if thread count checks fail in addition to on database errors, which is fine as we haven't written and don't want to write)
+        tx.commit()?;
+        broadcast_new_messages_logged(conn);
+
+        // Return no database error and no `MessageSendError` for successful send
+        Ok(Ok(()))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on thread creation: {err}");
+        Err(MessageSendError::DatabaseError)
+    })
+}
+
+/// Sets the email address my replies on the thread the given token grants access to are sent to, or stops sending them with `None`.
+/// A new address gets a confirmation link (see `confirm_email`), and replies are only sent once it's been used.
+/// Errors on database issues, an invalid address, if email is disabled (i.e. `CONFIG.msg_smtp_relay` isn't set), if a confirmation
+/// email was sent for the thread too recently, or if the confirmation email can't be sent.
+pub async fn set_email(token: ThreadToken, address: Option<String>) -> Result<(), EmailError> {
+    // Get DB connection
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(EmailError::DatabaseError)?;
+
+    // Check address (if any) before touching the database
+    let relay = match &address {
+        Some(address) => {
+            let Some(relay) = &crate::CONFIG.msg_smtp_relay else {
+                return Err(EmailError::Disabled);
+            };
+            if !email::is_valid_address(address) {
+                return Err(EmailError::InvalidAddress);
+            }
+            Some(relay)
+        }
+        None => None,
+    };
+
+    let to = address.clone();
+    let selector = conn
+        .call(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(thread_id) = token::resolve(&tx, &token)? else {
+                return Ok(Err(EmailError::NoSuchThread));
+            };
+            // Only send confirmation emails every so often, so they can't be used to flood someone's inbox
+            let recently_requested: bool = tx.query_row(
+                "SELECT email_requested > unixepoch() - ?2 FROM threads WHERE id = ?1;",
+                (thread_id.0, CONFIRMATION_INTERVAL.as_secs()),
+                |row| Ok(row.get::<_, Option<bool>>(0)?.unwrap_or(false)),
+            )?;
+            if address.is_some() && recently_requested {
+                return Ok(Err(EmailError::TooSoon));
+            }
+            // A fresh selector each time means reply addresses and confirmation links sent to a previous address stop working
+            let selector = address.is_some().then(rand::random::<u64>);
+            tx.execute(
+                "UPDATE threads SET email = ?2, email_selector = ?3, email_confirmed = 0,
+                    email_requested = IIF(?2 IS NULL, email_requested, unixepoch()) WHERE id = ?1;",
+                (thread_id.0, address, selector.map(|s| s as i64)),
+            )?;
+            tx.commit()?;
+            Ok(Ok(selector))
+        })
+        .await
+        .unwrap_or_else(|err| {
+            error!("Database error on setting thread email: {err}");
+            Err(EmailError::DatabaseError)
+        })?;
+
+    // Ask the user to confirm the new address
+    if let (Some(relay), Some(to), Some(selector)) = (relay, to, selector) {
+        email::send_confirmation(relay, &to, selector)
+            .await
+            .map_err(|err| {
+                error!("Failed to send email confirmation: {err}");
+                EmailError::SendFailed
+            })?;
+    }
+    Ok(())
+}
+
+/// Confirms the email address a confirmation link was sent to (given the code from the link), so my replies on its thread start being
+/// emailed to it. Each link only works once, and stops working if the thread's address changes.
+pub async fn confirm_email(code: String) -> Result<(), EmailError> {
+    // Get DB connection
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(EmailError::DatabaseError)?;
+
+    conn.call(move |conn| {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let Some(thread_id) = email::resolve_confirmation(&tx, &code)? else {
+            return Ok(Err(EmailError::InvalidConfirmation));
+        };
+        tx.execute(
+            "UPDATE threads SET email_confirmed = 1 WHERE id = ?1;",
+            [thread_id.0],
+        )?;
+        tx.commit()?;
+        Ok(Ok(()))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on confirming thread email: {err}");
+        Err(EmailError::DatabaseError)
+    })
+}
+
+/// Gets the email address (and reply address selector) my replies on a thread are sent to, if the user has set and confirmed one.
+async fn get_email(thread: ThreadId) -> Result<Option<(String, u64)>, MessagesLoadError> {
+    // Get DB connection
+    let conn = CONN
+        .lock()
+        .expect("poison")
+        .clone()
+        .ok_or(MessagesLoadError::DatabaseError)?;
+    conn.call(move |conn| {
+        let row = conn
+            .query_row(
+                "SELECT email, email_selector FROM threads WHERE id = ?1 AND email IS NOT NULL AND email_confirmed = 1;",
+                [thread.0],
+                |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?;
+        Ok(Ok(row))
+    })
+    .await
+    .unwrap_or_else(|err| {
+        error!("Database error on loading thread email: {err}");
+        Err(MessagesLoadError::DatabaseError)
+    })
+}
+
+/// Subscribes to messages on all threads as they're committed to the database. Messages may be missed if the receiver lags too far behind.
+pub fn subscribe() -> broadcast::Receiver<(ThreadId, Message)> {
+    NEW_MESSAGES.subscribe()
+}
+
+/// Broadcasts all messages committed since the last broadcast on `NEW_MESSAGES`.
+fn broadcast_new_messages(conn: &rusqlite::Connection) -> SqlResult<()> {
+    let mut statement = conn.prepare_cached(
+        "SELECT rowid, thread, contents, response, time FROM messages WHERE rowid > ?1 ORDER BY rowid ASC;",
+    )?;
+    let mut rows = statement.query([LAST_BROADCAST.load(Ordering::Relaxed)])?;
+    while let Some(row) = rows.next()? {
+        LAST_BROADCAST.store(row.get(0)?, Ordering::Relaxed);
+        let message = Message {
+            contents: row.get(2)?,
+            response: row.get(3)?,
+            timestamp: row.get(4)?,
+        };
+        // Sending only fails if no one is subscribed, which is fine
+        let _ = NEW_MESSAGES.send((ThreadId(row.get(1)?), message));
+    }
+    Ok(())
+}
+
+/// Broadcasts new messages after we commit some, logging rather than returning errors since the commit itself already succeeded.
+fn broadcast_new_messages_logged(conn: &rusqlite::Connection) {
+    if let Err(err) = broadcast_new_messages(conn) {
+        error!("Database error while broadcasting new messages: {err}");
+    }
+}
+
+/// Adds a message to the given thread (always setting `response = 0` and the time to Sqlite's current time) along with its spam verdict, not checking any constraints.
+///
+/// Like all utilities that follow, this is a non-`async` method to run on `rusqlite::Connection`s within closures sent via `tokio_rusqlite`, rather than sending such a closure via the async interface within this function.
+fn add_message(
+    conn: &rusqlite::Connection,
+    thread_id: ThreadId,
+    message: String,
+    verdict: &spam::Verdict,
+) -> SqlResult<()> {
+    if verdict.quarantined {
+        info!(
+            "Quarantining message on thread {thread_id} (spam score {:.2})",
+            verdict.score
+        );
+    }
+    conn.execute(
+        "INSERT INTO messages (thread, contents, response, time, quarantined, spam_score, hash) VALUES (?1, ?2, 0, unixepoch(), ?3, ?4, ?5)",
+        (thread_id.0, message, verdict.quarantined, verdict.score, &verdict.hash),
+    )
+    .map(|_| ())
+}
+
+/// Checks the inbox limits that apply to a message with the given spam verdict, returning `Err(MessageSendError::InboxFull)` if any are exceeded.
+///
+/// Quarantined messages don't count toward my visible inbox, so they're only limited by `CONFIG.msg_max_quarantined_threads`. Other messages must fit within both the global and per-source (see `source_key`) unread thread limits.
+fn check_inbox_limits(
+    conn: &rusqlite::Connection,
+    source: &str,
+    verdict: &spam::Verdict,
+) -> SqlResult<Result<(), MessageSendError>> {
+    if verdict.quarantined {
+        return Ok(check_quarantined_thread_count(conn)?.map(|_| ()));
+    }
+    if let Err(e) = check_unread_thread_count(conn)? {
+        return Ok(Err(e));
+    }
+    Ok(check_unread_thread_count_ip(conn, source)?.map(|_| ()))
+}
+
+/// Gets the number of threads with unread messages, checking if we've exceeded `CONFIG.msg_max_unread_threads_global`. Returns `Ok(count)` if the count is within the allowed range, and `Err(MessageSendError::InboxFull)` otherwise.
+///
+/// This should be done in a transaction to avoid TOCTOU races.
+fn check_unread_thread_count(
+    conn: &rusqlite::Connection,
+) -> SqlResult<Result<usize, MessageSendError>> {
+    // Get count from database connection
+    let count: usize = conn.query_row(
+        "SELECT COUNT(*) FROM threads WHERE (unread > 0);",
+        (),
+        |row| row.get(0),
+    )?;
+    // Check count is under max
+    Ok(if count >= crate::CONFIG.msg_max_unread_threads_global {
+        Err(MessageSendError::InboxFull)
+    } else {
+        Ok(count)
+    })
+}
+
+/// Gets the number of threads with unread messages for the given source (an IP subnet, as keyed by `source_key`), checking if we've exceeded `CONFIG.msg_max_unread_threads_ip`. Returns `Ok(count)` if the count is within the allowed range, and `Err(MessageSendError::InboxFull)` otherwise (using the same error type to keep rate limiting somewhat opaque to user, especially as other users on IP could clog connection).
+fn check_unread_thread_count_ip(
+    conn: &rusqlite::Connection,
+    source: &str,
+) -> SqlResult<Result<usize, MessageSendError>> {
+    // Get count from database connection
+    let count: usize = conn.query_row(
+        "SELECT COUNT(*) FROM threads WHERE (unread > 0 AND source_key = ?1);",
+        [source],
+        |row| row.get(0),
+    )?;
+    // Check count is under max
+    Ok(if count >= crate::CONFIG.msg_max_unread_threads_ip {
+        Err(MessageSendError::InboxFull)
+    } else {
+        Ok(count)
+    })
+}
+
+/// Gets the number of threads with quarantined messages awaiting review, checking if we've exceeded `CONFIG.msg_max_quarantined_threads`. Returns `Ok(count)` if the count is within the allowed range, and `Err(MessageSendError::InboxFull)` otherwise (so spam is rejected outright once quarantine is full, without affecting real messages).
+fn check_quarantined_thread_count(
+    conn: &rusqlite::Connection,
+) -> SqlResult<Result<usize, MessageSendError>> {
+    // Get count from database connection
+    let count: usize = conn.query_row(
+        "SELECT COUNT(*) FROM threads WHERE (quarantined > 0);",
+        (),
+        |row| row.get(0),
+    )?;
+    // Check count is under max
+    Ok(if count >= crate::CONFIG.msg_max_quarantined_threads {
+        Err(MessageSendError::InboxFull)
+    } else {
+        Ok(count)
+    })
+}
+
+/// Gets the key identifying a message's source for rate limiting: a keyed hash of the sender's subnet, i.e. their IP truncated to
+/// `CONFIG.msg_ipv4_prefix` or `CONFIG.msg_ipv6_prefix` bits. IPv4-mapped IPv6 addresses are treated as IPv4.
+///
+/// Grouping by subnet stops IPv6 users from rotating through their /64 to avoid per-IP limits, and hashing (keyed with our secret key so
+/// the small IPv4 space can't be brute forced) means we never store IPs themselves.
+fn source_key(ip: IpAddr) -> String {
+    source_key_with(
+        ip,
+        crate::CONFIG.msg_ipv4_prefix,
+        crate::CONFIG.msg_ipv6_prefix,
+    )
+}
+
+/// Gets the key identifying a message's source, grouping IPs by the given prefix lengths.
+fn source_key_with(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> String {
+    let (octets, prefix) = match ip.to_canonical() {
+        IpAddr::V4(ip) => (ip.octets().to_vec(), ipv4_prefix.min(32)),
+        IpAddr::V6(ip) => (ip.octets().to_vec(), ipv6_prefix.min(128)),
+    };
+    // Zero all bits past the prefix
+    let masked: Vec<u8> = octets
+        .iter()
+        .enumerate()
+        .map(|(i, octet)| match (prefix as usize).saturating_sub(i * 8) {
+            0 => 0,
+            bits @ 1..=7 => octet & (0xff << (8 - bits)),
+            _ => *octet,
+        })
+        .collect();
+    let hash = Sha256::new()
+        .chain_update(b"contact source key")
+        .chain_update(crate::CONFIG.ssh_key.secret.as_bytes())
+        .chain_update([masked.len() as u8, prefix])
+        .chain_update(&masked)
+        .finalize();
+    format!("{:x}", hash)[..32].to_string()
+}
+
+/// A wrapper for a thread ID, represented internally (for Sqlite) as an `i64`. Represented as case-insensitive twos-complement hexadecimal for the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThreadId(i64);
+impl std::fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+impl std::str::FromStr for ThreadId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(|i| ThreadId(i as i64))
+    }
+}
+
+/// Represents a single message, including its contents, (unix) timestamp, and whether it was a response (from me; non-responses are from users).
+///
+/// Serializes with an additional `html` field containing the contents rendered by `format::to_html`.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub contents: String,
+    pub timestamp: i64,
+    pub response: bool,
+}
+impl Serialize for Message {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("Message", 4)?;
+        s.serialize_field("contents", &self.contents)?;
+        s.serialize_field("html", &format::to_html(&self.contents))?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        s.serialize_field("response", &self.response)?;
+        s.end()
+    }
+}
+
+/// Possible errors occurring when retrieving a thread's messages.
+#[derive(Debug)]
+pub enum MessagesLoadError {
+    /// An internal error occured with a database query and was logged internally.
+    DatabaseError,
+    /// Tried to load a thread that doesn't exist.
+    NoSuchThread,
+}
+impl std::fmt::Display for MessagesLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessagesLoadError::DatabaseError => write!(f, "internal server error, sorry :("),
+            MessagesLoadError::NoSuchThread => write!(f, "invalid or revoked thread ID"),
+        }
+    }
+}
+
+/// Possible errors that can occur while sending a message or thread.
+#[derive(Debug)]
+pub enum MessageSendError {
+    /// An internal error occured with a database query and was logged internally.
+    DatabaseError,
+    /// The message is too large, exceeding `CONFIG.msg_max_size`.
+    TooLong,
+    /// The thread already contains too many messages without a reply, exceeding `CONFIG.msg_max_unread_messages`.
+    ThreadFull,
+    /// There are too many unread threads in my inbox, so rate limiting is in effect. This may be due to the sending IP exceeding `CONFIG.msg_max_unread_threads_ip`, or all users exceeding `CONFIG.msg_max_unread_threads_global`.
+    InboxFull,
+    /// Tried to send a message on a thread that doesn't exist.
+    NoSuchThread,
+    /// Tried to create a thread without a valid solution to a `challenge::Challenge`.
+    BadChallenge,
+}
+impl std::fmt::Display for MessageSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageSendError::DatabaseError => write!(f, "internal server error, sorry :("),
+            MessageSendError::TooLong => write!(
+                f,
+                "your message is too long (max size: {} characters)",
+                crate::CONFIG.msg_max_size
+            ),
+            MessageSendError::ThreadFull => write!(
+                f,
+                "too many messages in a row without a reply (max {}), be patient!",
+                crate::CONFIG.msg_max_unread_messages
+            ),
+            MessageSendError::InboxFull => write!(
+                f,
+                "sorry, I'm overwhelmed with unread messages right now, check back later"
+            ),
+            MessageSendError::NoSuchThread => write!(f, "invalid or revoked thread ID"),
+            MessageSendError::BadChallenge => write!(
+                f,
+                "missing or expired anti-spam challenge, try reloading the page"
+            ),
+        }
+    }
+}
+
+/// Possible errors that can occur while setting the email address for a thread.
+#[derive(Debug)]
+pub enum EmailError {
+    /// An internal error occured with a database query and was logged internally.
+    DatabaseError,
+    /// Tried to set the email address for a thread that doesn't exist.
+    NoSuchThread,
+    /// The email address isn't one we're willing to send to (see `email::is_valid_address`).
+    InvalidAddress,
+    /// Email is disabled, as `CONFIG.msg_smtp_relay` isn't set.
+    Disabled,
+    /// A confirmation email was sent for the thread less than `CONFIRMATION_INTERVAL` ago.
+    TooSoon,
+    /// The confirmation email couldn't be sent (the error was logged internally).
+    SendFailed,
+    /// Tried to confirm an address with a link that's invalid, already used, or for an address that's since been changed.
+    InvalidConfirmation,
+}
+impl std::fmt::Display for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailError::DatabaseError => write!(f, "internal server error, sorry :("),
+            EmailError::NoSuchThread => write!(f, "invalid or revoked thread ID"),
+            EmailError::InvalidAddress => write!(f, "invalid email address"),
+            EmailError::Disabled => write!(f, "replies by email aren't available right now"),
+            EmailError::TooSoon => write!(
+                f,
+                "a confirmation email was sent for this thread recently, try again in a few minutes"
+            ),
+            EmailError::SendFailed => write!(
+                f,
+                "couldn't send the confirmation email, try again in a few minutes"
+            ),
+            EmailError::InvalidConfirmation => {
+                write!(f, "invalid, already used, or outdated confirmation link")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opens an in-memory messages database with the current schema (and the test config loaded).
+    pub(super) fn database() -> rusqlite::Connection {
+        crate::init_test_config();
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS {
+            migration(&conn).unwrap();
+        }
+        conn
+    }
+
+    fn key(ip: &str, ipv4_prefix: u8, ipv6_prefix: u8) -> String {
+        crate::init_test_config();
+        source_key_with(ip.parse().unwrap(), ipv4_prefix, ipv6_prefix)
+    }
+
+    #[test]
+    fn source_keys_group_ipv4_subnets() {
+        // By default each IPv4 address is its own source
+        assert_ne!(key("192.0.2.1", 32, 64), key("192.0.2.2", 32, 64));
+        assert_eq!(key("192.0.2.1", 32, 64), key("192.0.2.1", 32, 64));
+
+        assert_eq!(key("192.0.2.1", 24, 64), key("192.0.2.254", 24, 64));
+        assert_ne!(key("192.0.2.1", 24, 64), key("192.0.3.1", 24, 64));
+
+        // Prefixes needn't be whole octets
+        assert_eq!(key("192.0.2.1", 20, 64), key("192.0.15.1", 20, 64));
+        assert_ne!(key("192.0.2.1", 20, 64), key("192.0.16.1", 20, 64));
+        assert_eq!(key("10.0.0.1", 0, 64), key("192.0.2.1", 0, 64));
+    }
+
+    #[test]
+    fn source_keys_group_ipv6_subnets() {
+        assert_eq!(
+            key("2001:db8:1:2::1", 32, 64),
+            key("2001:db8:1:2:ffff:ffff:ffff:ffff", 32, 64)
+        );
+        assert_ne!(
+            key("2001:db8:1:2::1", 32, 64),
+            key("2001:db8:1:3::1", 32, 64)
+        );
+
+        assert_eq!(
+            key("2001:db8:1:2::1", 32, 48),
+            key("2001:db8:1:3::1", 32, 48)
+        );
+        assert_ne!(
+            key("2001:db8:1:2::1", 32, 128),
+            key("2001:db8:1:2::2", 32, 128)
+        );
+    }
+
+    #[test]
+    fn source_keys_treat_mapped_ipv6_as_ipv4() {
+        assert_eq!(key("::ffff:192.0.2.1", 32, 64), key("192.0.2.1", 32, 64));
+        assert_ne!(
+            key("::ffff:192.0.2.1", 32, 64),
+            key("::ffff:192.0.2.2", 32, 64)
+        );
+        assert_eq!(key("::ffff:192.0.2.1", 24, 64), key("192.0.2.99", 24, 64));
+    }
+
+    #[test]
+    fn source_keys_clamp_prefixes() {
+        assert_eq!(key("192.0.2.1", 32, 64), key("192.0.2.1", 200, 64));
+        assert_eq!(key("2001:db8::1", 32, 128), key("2001:db8::1", 32, 255));
+    }
+}
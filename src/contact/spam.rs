@@ -0,0 +1,376 @@
+//! Spam scoring for incoming contact messages, run on every message before it's added to the database.
+//!
+//! A message's score is the sum of several independent checks: link density, known spam phrases (`CONFIG.msg_spam_phrases`),
+//! duplicates of messages on other threads, and a naive Bayes classifier trained on threads I've labeled by setting `threads.spam_label`.
+//! Messages scoring at least `CONFIG.msg_spam_threshold` are quarantined: still stored (and shown to the sender), but not counted toward
+//! my visible inbox.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use sha2::{Digest, Sha256};
+
+use super::{SqlResult, ThreadId};
+
+/// Score added for a message made up entirely of links, scaled down linearly for lower link densities.
+const LINK_WEIGHT: f64 = 0.6;
+/// Link density (links per word) at or above which the full `LINK_WEIGHT` is added.
+const LINK_DENSITY_MAX: f64 = 0.2;
+/// Score added for each known spam phrase in a message.
+const PHRASE_WEIGHT: f64 = 0.5;
+/// Score added if the exact message (ignoring case and whitespace) was already sent on another thread.
+const DUPLICATE_WEIGHT: f64 = 1.0;
+/// Score added when the classifier is certain a message is spam, scaled down to zero as its confidence drops to 50%.
+const BAYES_WEIGHT: f64 = 1.0;
+/// The number of labeled messages needed in each class before the classifier is used.
+const BAYES_MIN_TRAINING: usize = 5;
+
+/// The result of scoring a message, recorded alongside it in the database.
+#[derive(Debug)]
+pub struct Verdict {
+    /// The total spam score of the message.
+    pub score: f64,
+    /// A hash of the message's normalized contents, for detecting duplicates.
+    pub hash: String,
+    /// Whether the message should be quarantined, i.e. `score >= CONFIG.msg_spam_threshold`.
+    pub quarantined: bool,
+}
+
+/// Scores a message for spam, given the thread it's being sent on (`None` for a new thread).
+///
+/// Like the utilities in the parent module, this runs on a `rusqlite::Connection` within a `tokio_rusqlite` closure.
+pub fn check(
+    conn: &rusqlite::Connection,
+    thread: Option<ThreadId>,
+    message: &str,
+) -> SqlResult<Verdict> {
+    let hash = content_hash(message);
+    let mut score = link_score(message) + phrase_score(message);
+
+    // Check for the same message on a different thread (bots tend to send the same thing over and over)
+    let duplicate: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM messages WHERE hash = ?1 AND thread IS NOT ?2);",
+        (&hash, thread.map(|t| t.0)),
+        |row| row.get(0),
+    )?;
+    if duplicate {
+        score += DUPLICATE_WEIGHT;
+    }
+
+    score += classifier_score(&CLASSIFIER, conn, message)?;
+    Ok(Verdict {
+        score,
+        hash,
+        quarantined: score >= crate::CONFIG.msg_spam_threshold,
+    })
+}
+
+/// Hashes a message's contents, ignoring case and differences in whitespace.
+fn content_hash(message: &str) -> String {
+    let normalized = message
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+/// Scores a message based on the proportion of its words that are links.
+fn link_score(message: &str) -> f64 {
+    let (mut words, mut links) = (0, 0);
+    for word in message.split_whitespace() {
+        words += 1;
+        if word.contains("http://") || word.contains("https://") || word.starts_with("www.") {
+            links += 1;
+        }
+    }
+    if words == 0 {
+        return 0.0;
+    }
+    LINK_WEIGHT * (links as f64 / words as f64 / LINK_DENSITY_MAX).min(1.0)
+}
+
+/// Scores a message based on how many known spam phrases it contains.
+fn phrase_score(message: &str) -> f64 {
+    let message = message.to_lowercase();
+    let matches = crate::CONFIG
+        .msg_spam_phrases
+        .iter()
+        .filter(|phrase| message.contains(phrase.as_str()))
+        .count();
+    PHRASE_WEIGHT * matches as f64
+}
+
+/// A trained classifier, along with the training generation (see `migrate_spam_training`) it was trained at, so we know when to retrain.
+type CachedClassifier = Mutex<Option<(i64, Classifier)>>;
+
+/// The classifier trained on the messages database.
+static CLASSIFIER: CachedClassifier = Mutex::new(None);
+
+/// Scores a message using the naive Bayes classifier in `cache`, retraining it first if any labels have changed since it was last
+/// trained.
+fn classifier_score(
+    cache: &CachedClassifier,
+    conn: &rusqlite::Connection,
+    message: &str,
+) -> SqlResult<f64> {
+    let generation: i64 = conn.query_row("SELECT generation FROM spam_training;", (), |row| {
+        row.get(0)
+    })?;
+    let mut cached = cache.lock().expect("poison");
+    let classifier = match cached.take() {
+        Some((trained_at, classifier)) if trained_at == generation => classifier,
+        _ => Classifier::train(conn)?,
+    };
+    let score = classifier
+        .spam_probability(message)
+        .map_or(0.0, |p| BAYES_WEIGHT * (2.0 * p - 1.0).max(0.0));
+    *cached = Some((generation, classifier));
+    Ok(score)
+}
+
+/// A naive Bayes classifier over the set of words in each message.
+#[derive(Debug, Default)]
+struct Classifier {
+    /// The number of training messages in each class, indexed by whether the class is spam.
+    messages: [usize; 2],
+    /// The number of training messages containing each token, per class.
+    tokens: HashMap<String, [usize; 2]>,
+}
+impl Classifier {
+    /// Trains a classifier on all user messages in threads I've labeled as spam or not spam.
+    fn train(conn: &rusqlite::Connection) -> SqlResult<Self> {
+        let mut classifier = Self::default();
+        let mut statement = conn.prepare_cached(
+            "SELECT messages.contents, threads.spam_label FROM messages JOIN threads ON messages.thread = threads.id
+            WHERE threads.spam_label IS NOT NULL AND messages.response = 0;",
+        )?;
+        let mut rows = statement.query(())?;
+        while let Some(row) = rows.next()? {
+            let contents: String = row.get(0)?;
+            let spam = row.get::<_, bool>(1)? as usize;
+            classifier.messages[spam] += 1;
+            for token in tokenize(&contents) {
+                classifier.tokens.entry(token).or_default()[spam] += 1;
+            }
+        }
+        Ok(classifier)
+    }
+
+    /// Gets the probability that a message is spam, or `None` if there isn't enough training data to say.
+    fn spam_probability(&self, message: &str) -> Option<f64> {
+        if self.messages.iter().any(|&n| n < BAYES_MIN_TRAINING) {
+            return None;
+        }
+        // Sum log-likelihoods for each class, with add-one smoothing, ignoring tokens we've never seen
+        let total = (self.messages[0] + self.messages[1]) as f64;
+        let mut log_likelihood = [0, 1].map(|class| (self.messages[class] as f64 / total).ln());
+        for token in tokenize(message) {
+            if let Some(counts) = self.tokens.get(&token) {
+                for class in [0, 1] {
+                    log_likelihood[class] +=
+                        ((counts[class] + 1) as f64 / (self.messages[class] + 2) as f64).ln();
+                }
+            }
+        }
+        Some(1.0 / (1.0 + (log_likelihood[0] - log_likelihood[1]).exp()))
+    }
+}
+
+/// Splits a message into its set of distinct lowercase words, ignoring very short or long ones.
+fn tokenize(message: &str) -> std::collections::HashSet<String> {
+    message
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (2..=30).contains(&word.len()))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages that look like spam, and ones that don't.
+    const SPAM: &[&str] = &[
+        "Cheap pills, buy now at our online pharmacy",
+        "Buy cheap followers now, best prices online",
+        "Limited offer: cheap pills shipped free, buy now",
+        "Our pharmacy has the cheapest prices, buy today",
+        "Best online casino bonus, buy chips now cheap",
+    ];
+    const HAM: &[&str] = &[
+        "Loved the post about the flight computer, how did you tune the PID loop?",
+        "Your rocket project is really cool, what motor did you use for the flight?",
+        "Thanks for writing up the telemetry radio, it helped with my own project",
+        "Is the source for the flight computer firmware available anywhere?",
+        "Great write up on the parachute deployment, the photos are awesome",
+    ];
+
+    /// Opens an in-memory database with a thread for each of `SPAM` and `HAM` (labeled accordingly), starting the training generation
+    /// at `generation` so caches can be checked against it.
+    fn database(generation: i64) -> rusqlite::Connection {
+        let conn = super::super::tests::database();
+        conn.execute("UPDATE spam_training SET generation = ?1;", [generation])
+            .unwrap();
+        for (i, (message, spam)) in SPAM
+            .iter()
+            .map(|m| (m, true))
+            .chain(HAM.iter().map(|m| (m, false)))
+            .enumerate()
+        {
+            conn.execute(
+                "INSERT INTO threads (id, source_key) VALUES (?1, 'test');",
+                [i as i64 + 1],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO messages (thread, contents, response, time, hash) VALUES (?1, ?2, 0, 0, ?3);",
+                (i as i64 + 1, message, content_hash(message)),
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE threads SET spam_label = ?2 WHERE id = ?1;",
+                (i as i64 + 1, spam),
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    /// Gets the current training generation.
+    fn generation(conn: &rusqlite::Connection) -> i64 {
+        conn.query_row("SELECT generation FROM spam_training;", (), |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn content_hash_ignores_case_and_whitespace() {
+        assert_eq!(
+            content_hash("Hello   there,\n\tWORLD "),
+            content_hash("hello there, world")
+        );
+        assert_ne!(content_hash("hello there"), content_hash("hello where"));
+    }
+
+    #[test]
+    fn link_score_scales_with_density() {
+        assert_eq!(link_score(""), 0.0);
+        assert_eq!(link_score("no links here at all"), 0.0);
+        assert_eq!(
+            link_score("https://a.example http://b.example"),
+            LINK_WEIGHT
+        );
+        // One link in ten words is half of `LINK_DENSITY_MAX`
+        let half = link_score("see www.example.com for more about the little thing I made");
+        assert!((half - LINK_WEIGHT / 2.0).abs() < 1e-9, "{half}");
+    }
+
+    #[test]
+    fn duplicates_on_other_threads_score() {
+        let conn = database(0);
+        let single = check(&conn, Some(ThreadId(1)), SPAM[0]).unwrap();
+        let other = check(&conn, Some(ThreadId(2)), SPAM[0]).unwrap();
+        let new = check(&conn, None, &SPAM[0].to_uppercase()).unwrap();
+        assert_eq!(other.hash, single.hash);
+        assert!((other.score - single.score - DUPLICATE_WEIGHT).abs() < 1e-9);
+        assert!((new.score - single.score - DUPLICATE_WEIGHT).abs() < 1e-9);
+        assert!(other.quarantined);
+    }
+
+    #[test]
+    fn classifier_needs_enough_training() {
+        let classifier = Classifier {
+            messages: [BAYES_MIN_TRAINING, BAYES_MIN_TRAINING - 1],
+            ..Classifier::default()
+        };
+        assert_eq!(classifier.spam_probability("cheap pills"), None);
+    }
+
+    #[test]
+    fn classifier_separates_spam() {
+        let classifier = Classifier::train(&database(0)).unwrap();
+        assert_eq!(classifier.messages, [HAM.len(), SPAM.len()]);
+        let spam = classifier
+            .spam_probability("Buy cheap pills now from our pharmacy")
+            .unwrap();
+        let ham = classifier
+            .spam_probability("How did you build the flight computer for the rocket?")
+            .unwrap();
+        assert!(spam > 0.9, "{spam}");
+        assert!(ham < 0.1, "{ham}");
+        // Words it's never seen don't count either way
+        let unknown = classifier.spam_probability("zzz qqq").unwrap();
+        assert!((unknown - 0.5).abs() < 1e-9, "{unknown}");
+    }
+
+    #[test]
+    fn generation_only_changes_with_labels() {
+        let conn = database(0);
+        let start = generation(&conn);
+        assert_eq!(start, (SPAM.len() + HAM.len()) as i64);
+
+        // Messages on labeled threads (and unlabeled threads) don't change it
+        conn.execute_batch(
+            "INSERT INTO messages (thread, contents, response, time) VALUES (1, 'more cheap pills', 0, 0);
+            INSERT INTO messages (thread, contents, response, time) VALUES (1, 'a reply', 1, 0);
+            UPDATE messages SET contents = 'edited' WHERE thread = 2;
+            DELETE FROM messages WHERE thread = 3;
+            INSERT INTO threads (id, source_key) VALUES (100, 'test');
+            UPDATE threads SET spam_label = 1 WHERE id = 1;",
+        )
+        .unwrap();
+        assert_eq!(generation(&conn), start);
+
+        // Setting, changing or removing a label (directly or by deleting its thread) does
+        conn.execute("UPDATE threads SET spam_label = 0 WHERE id = 100;", ())
+            .unwrap();
+        assert_eq!(generation(&conn), start + 1);
+        conn.execute("UPDATE threads SET spam_label = 1 WHERE id = 100;", ())
+            .unwrap();
+        assert_eq!(generation(&conn), start + 2);
+        conn.execute("UPDATE threads SET spam_label = NULL WHERE id = 100;", ())
+            .unwrap();
+        assert_eq!(generation(&conn), start + 3);
+        conn.execute("DELETE FROM threads WHERE id = 1;", ())
+            .unwrap();
+        assert_eq!(generation(&conn), start + 4);
+        conn.execute(
+            "INSERT INTO threads (id, source_key, spam_label) VALUES (101, 'test', 0);",
+            (),
+        )
+        .unwrap();
+        assert_eq!(generation(&conn), start + 5);
+    }
+
+    #[test]
+    fn classifier_is_cached_until_labels_change() {
+        let conn = database(0);
+        let message = "Buy cheap pills now from our pharmacy";
+
+        // Trains on first use
+        let cache = CachedClassifier::default();
+        assert!(classifier_score(&cache, &conn, message).unwrap() > 0.0);
+        let trained_at = cache.lock().unwrap().as_ref().map(|(g, _)| *g);
+        assert_eq!(trained_at, Some(generation(&conn)));
+
+        // A cached classifier is used while the generation is the same, even though the messages changed
+        *cache.lock().unwrap() = Some((generation(&conn), Classifier::default()));
+        conn.execute(
+            "INSERT INTO messages (thread, contents, response, time) VALUES (1, 'more pills', 0, 0);",
+            (),
+        )
+        .unwrap();
+        assert_eq!(classifier_score(&cache, &conn, message).unwrap(), 0.0);
+
+        // Flipping the labels retrains it, so the same message no longer looks like spam
+        conn.execute("UPDATE threads SET spam_label = 1 - spam_label;", ())
+            .unwrap();
+        assert_eq!(classifier_score(&cache, &conn, message).unwrap(), 0.0);
+        let cached = cache.lock().unwrap();
+        let (trained_at, classifier) = cached.as_ref().unwrap();
+        assert_eq!(*trained_at, generation(&conn));
+        assert_eq!(classifier.messages, [SPAM.len() + 1, HAM.len()]);
+    }
+}
@@ -0,0 +1,300 @@
+//! Capability tokens granting access to threads, kept separate from the internal `ThreadId`.
+//!
+//! A token is a random 64-bit selector (to look it up) followed by a random 128-bit verifier (the secret), shown to users as 48 hex
+//! digits. Only a hash of the verifier is stored, and it's compared in constant time, so neither a leaked database nor response timing
+//! gives away a working token. Threads predating tokens can still be accessed by their bare `ThreadId` while
+//! `CONFIG.msg_legacy_thread_ids` is set, until I rotate their tokens.
+
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use super::{SqlResult, ThreadId};
+
+/// The maximum number of tokens kept for one thread (e.g. from upgrading a legacy ID on several devices). Issuing more drops the oldest.
+const MAX_TOKENS_PER_THREAD: usize = 16;
+
+/// A user's handle on a thread: either a capability token or (for threads predating tokens) the thread's legacy ID.
+///
+/// Represented for the user as 48 hex digits (16 for the selector, 32 for the verifier), or 16 for a legacy ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadToken {
+    Token { selector: u64, verifier: u128 },
+    Legacy(ThreadId),
+}
+impl ThreadToken {
+    /// Gets a non-secret identifier for the token's thread, for labeling exports without repeating the secret (the selector, or the ID itself for legacy IDs).
+    pub fn public_id(&self) -> String {
+        match self {
+            Self::Token { selector, .. } => format!("{selector:016x}"),
+            Self::Legacy(id) => id.to_string(),
+        }
+    }
+}
+impl std::fmt::Display for ThreadToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Token { selector, verifier } => write!(f, "{selector:016x}{verifier:032x}"),
+            Self::Legacy(id) => write!(f, "{id}"),
+        }
+    }
+}
+impl std::str::FromStr for ThreadToken {
+    type Err = ParseTokenError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only hex digits (`from_str_radix` also takes a leading `+`), so each token is written just one way
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseTokenError);
+        }
+        match (s.get(..16), s.get(16..)) {
+            (Some(selector), Some(verifier)) if s.len() == 48 => Ok(Self::Token {
+                selector: u64::from_str_radix(selector, 16).map_err(|_| ParseTokenError)?,
+                verifier: u128::from_str_radix(verifier, 16).map_err(|_| ParseTokenError)?,
+            }),
+            _ => s.parse().map(Self::Legacy).map_err(|_| ParseTokenError),
+        }
+    }
+}
+
+/// A thread ID given by a user that isn't a well-formed token or legacy ID.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseTokenError;
+
+/// Issues a new token for the given thread, keeping at most `MAX_TOKENS_PER_THREAD` tokens for it.
+pub(super) fn issue(conn: &rusqlite::Connection, thread: ThreadId) -> SqlResult<ThreadToken> {
+    let (selector, verifier) = (rand::random::<u64>(), rand::random::<u128>());
+    conn.execute(
+        "INSERT INTO thread_tokens (selector, thread, hash, created) VALUES (?1, ?2, ?3, unixepoch());",
+        (selector as i64, thread.0, hash(verifier).as_slice()),
+    )?;
+    // Keep the new token and the newest others (the rowid is the random selector, so among tokens made in the same second, which go
+    // is arbitrary, but never the new one)
+    conn.execute(
+        "DELETE FROM thread_tokens WHERE thread = ?1 AND selector != ?2 AND selector NOT IN
+            (SELECT selector FROM thread_tokens WHERE thread = ?1 AND selector != ?2 ORDER BY created DESC LIMIT ?3);",
+        (thread.0, selector as i64, MAX_TOKENS_PER_THREAD - 1),
+    )?;
+    Ok(ThreadToken::Token { selector, verifier })
+}
+
+/// Gets the thread a token grants access to, if any.
+pub(super) fn resolve(
+    conn: &rusqlite::Connection,
+    token: &ThreadToken,
+) -> SqlResult<Option<ThreadId>> {
+    match token {
+        ThreadToken::Token { selector, verifier } => {
+            let Some((thread, stored)) = conn
+                .query_row(
+                    "SELECT thread, hash FROM thread_tokens WHERE selector = ?1;",
+                    [*selector as i64],
+                    |row| Ok((ThreadId(row.get(0)?), row.get::<_, Vec<u8>>(1)?)),
+                )
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            Ok(bool::from(hash(*verifier).as_slice().ct_eq(&stored)).then_some(thread))
+        }
+        ThreadToken::Legacy(id) if crate::CONFIG.msg_legacy_thread_ids => conn
+            .query_row(
+                "SELECT id FROM threads WHERE id = ?1 AND legacy_access = 1;",
+                [id.0],
+                |row| Ok(ThreadId(row.get(0)?)),
+            )
+            .optional(),
+        ThreadToken::Legacy(_) => Ok(None),
+    }
+}
+
+/// Revokes all access to a thread (including by legacy ID and by email), returning a fresh token, or `None` if the thread doesn't exist.
+pub(super) fn rotate(
+    conn: &rusqlite::Connection,
+    thread: ThreadId,
+) -> SqlResult<Option<ThreadToken>> {
+    if conn.execute(
+        "UPDATE threads SET legacy_access = 0, email = NULL, email_selector = NULL WHERE id = ?1;",
+        [thread.0],
+    )? == 0
+    {
+        return Ok(None);
+    }
+    conn.execute("DELETE FROM thread_tokens WHERE thread = ?1;", [thread.0])?;
+    issue(conn, thread).map(Some)
+}
+
+/// Hashes a token's verifier for storage.
+fn hash(verifier: u128) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(b"contact thread token")
+        .chain_update(verifier.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opens an in-memory database with two threads, 1 (only accessible by token) and 2 (predating tokens, so also by legacy ID).
+    fn database() -> rusqlite::Connection {
+        let conn = super::super::tests::database();
+        conn.execute_batch(
+            "INSERT INTO threads (id, source_key) VALUES (1, 'test');
+            INSERT INTO threads (id, source_key, legacy_access) VALUES (2, 'test', 1);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let token = ThreadToken::Token {
+            selector: 0x0123_4567_89ab_cdef,
+            verifier: 0xfedc_ba98_7654_3210_0011_2233_4455_6677,
+        };
+        let shown = token.to_string();
+        assert_eq!(shown, "0123456789abcdeffedcba98765432100011223344556677");
+        assert_eq!(shown.parse(), Ok(token));
+        assert_eq!(shown.to_uppercase().parse(), Ok(token));
+        assert_eq!(token.public_id(), "0123456789abcdef");
+
+        let legacy = ThreadToken::Legacy(ThreadId(0x2a));
+        assert_eq!(legacy.to_string().parse(), Ok(legacy));
+    }
+
+    #[test]
+    fn parse_rejects_ill_formed() {
+        for s in [
+            "",
+            "xyz",
+            "0123456789abcdeffedcba9876543210001122334455667",
+            "0123456789abcdeffedcba987654321000112233445566778",
+            "0123456789abcdeffedcba98765432100011223344556g77",
+            "+123456789abcdeffedcba98765432100011223344556677",
+            "11111111111111111",
+        ] {
+            assert!(s.parse::<ThreadToken>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn issued_tokens_resolve() {
+        let conn = database();
+        let token = issue(&conn, ThreadId(1)).unwrap();
+        assert_eq!(resolve(&conn, &token).unwrap(), Some(ThreadId(1)));
+
+        // Only the hash of the verifier is stored
+        let ThreadToken::Token { selector, verifier } = token else {
+            panic!("issued a legacy ID");
+        };
+        let stored: Vec<u8> = conn
+            .query_row(
+                "SELECT hash FROM thread_tokens WHERE selector = ?1;",
+                [selector as i64],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(stored, hash(verifier));
+
+        // A wrong verifier, or an unknown selector, gives nothing
+        let wrong_verifier = ThreadToken::Token {
+            selector,
+            verifier: verifier ^ 1,
+        };
+        assert_eq!(resolve(&conn, &wrong_verifier).unwrap(), None);
+        let wrong_selector = ThreadToken::Token {
+            selector: selector ^ 1,
+            verifier,
+        };
+        assert_eq!(resolve(&conn, &wrong_selector).unwrap(), None);
+    }
+
+    #[test]
+    fn legacy_ids_only_resolve_for_old_threads() {
+        let conn = database();
+        assert_eq!(
+            resolve(&conn, &ThreadToken::Legacy(ThreadId(2))).unwrap(),
+            Some(ThreadId(2))
+        );
+        assert_eq!(
+            resolve(&conn, &ThreadToken::Legacy(ThreadId(1))).unwrap(),
+            None
+        );
+        assert_eq!(
+            resolve(&conn, &ThreadToken::Legacy(ThreadId(3))).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn old_tokens_are_dropped() {
+        let conn = database();
+        let tokens: Vec<_> = (0..MAX_TOKENS_PER_THREAD)
+            .map(|_| issue(&conn, ThreadId(1)).unwrap())
+            .collect();
+        // Make the first token the oldest
+        let ThreadToken::Token { selector, .. } = tokens[0] else {
+            panic!("issued a legacy ID");
+        };
+        conn.execute(
+            "UPDATE thread_tokens SET created = created - 60 WHERE selector = ?1;",
+            [selector as i64],
+        )
+        .unwrap();
+
+        let new = issue(&conn, ThreadId(1)).unwrap();
+        assert_eq!(resolve(&conn, &new).unwrap(), Some(ThreadId(1)));
+        assert_eq!(resolve(&conn, &tokens[0]).unwrap(), None);
+        for token in &tokens[1..] {
+            assert_eq!(resolve(&conn, token).unwrap(), Some(ThreadId(1)));
+        }
+    }
+
+    #[test]
+    fn new_tokens_are_kept() {
+        // Even when every token was made in the same second
+        let conn = database();
+        for _ in 0..MAX_TOKENS_PER_THREAD * 2 {
+            let token = issue(&conn, ThreadId(1)).unwrap();
+            assert_eq!(resolve(&conn, &token).unwrap(), Some(ThreadId(1)));
+        }
+        let count: usize = conn
+            .query_row("SELECT COUNT(*) FROM thread_tokens;", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, MAX_TOKENS_PER_THREAD);
+    }
+
+    #[test]
+    fn rotate_revokes_all_access() {
+        let conn = database();
+        let old = [
+            issue(&conn, ThreadId(2)).unwrap(),
+            issue(&conn, ThreadId(2)).unwrap(),
+            ThreadToken::Legacy(ThreadId(2)),
+        ];
+        let other = issue(&conn, ThreadId(1)).unwrap();
+        conn.execute(
+            "UPDATE threads SET email = 'someone@example.com', email_selector = 1 WHERE id = 2;",
+            (),
+        )
+        .unwrap();
+
+        let new = rotate(&conn, ThreadId(2)).unwrap().unwrap();
+        assert_eq!(resolve(&conn, &new).unwrap(), Some(ThreadId(2)));
+        for token in old {
+            assert_eq!(resolve(&conn, &token).unwrap(), None, "{token} still works");
+        }
+        let email: Option<String> = conn
+            .query_row("SELECT email FROM threads WHERE id = 2;", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(email, None);
+
+        // Other threads are untouched, and there's nothing to rotate for threads that don't exist
+        assert_eq!(resolve(&conn, &other).unwrap(), Some(ThreadId(1)));
+        assert_eq!(rotate(&conn, ThreadId(3)).unwrap(), None);
+    }
+}
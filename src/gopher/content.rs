@@ -83,6 +83,56 @@ I hope you enjoy it!
             )?;
         }
 
+        // Link to search
+        menu.info("")?;
+        menu.write_entry(
+            ItemType::Search,
+            "Search projects and blog posts",
+            "/search",
+            &crate::CONFIG.domain,
+            crate::CONFIG.gopher_port,
+        )?;
+
+        Ok(())
+    }
+}
+
+impl GopherContent for crate::search::SearchResult {
+    fn gopher<'a, W>(&self, menu: &GopherMenu<&'a W>) -> Result<()>
+    where
+        &'a W: std::io::Write,
+    {
+        use crate::search::Kind;
+        // Projects have menus here, but blog posts are only on the website
+        match self.kind {
+            Kind::Project => menu.write_entry(
+                ItemType::Directory,
+                &format!("{} - Project, {}", self.title, self.date),
+                &self.path,
+                &crate::CONFIG.domain,
+                crate::CONFIG.gopher_port,
+            )?,
+            Kind::BlogPost => menu.write_entry(
+                ItemType::Other('h'),
+                &format!("{} - Blog post, {} (on the website)", self.title, self.date),
+                &format!("URL:https://{}{}", crate::CONFIG.domain, self.path),
+                &crate::CONFIG.domain,
+                crate::CONFIG.gopher_port,
+            )?,
+        }
+        // Snippet, with matches marked like strong text
+        let snippet: String = self
+            .snippet
+            .iter()
+            .map(|part| {
+                if part.highlight {
+                    format!("*{}*", part.text)
+                } else {
+                    part.text.clone()
+                }
+            })
+            .collect();
+        menu.info(&format!("  {snippet}"))?;
         Ok(())
     }
 }
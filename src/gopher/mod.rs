@@ -14,6 +14,8 @@ use tracing::{error, info};
 
 use content::GopherContent;
 
+use crate::limits::ConnectionLimiter;
+
 mod content;
 
 /// Runs the gopher server, updating the content on `update_rx`.
@@ -22,17 +24,38 @@ pub async fn main(mut update_rx: broadcast::Receiver<()>) -> Result<Infallible>
     // This is basically the same as the other presenters, but without our own version of the content (yet).
     let mut content = Arc::new(crate::CONTENT.read().unwrap().clone());
     let listener = TcpListener::bind(("0.0.0.0", crate::CONFIG.gopher_port)).await?;
+    let limiter = ConnectionLimiter::new(
+        crate::CONFIG.max_connections,
+        crate::CONFIG.max_connections_ip,
+    );
     loop {
         tokio::select! {
             result = listener.accept() => {
                 // Handle new connection
                 let (stream, addr) = result?;
+                let connection = match limiter.admit(addr.ip()) {
+                    Ok(connection) => connection,
+                    Err(rejection) => {
+                        // Send the reason as an error item, which clients show in place of the menu
+                        info!("Rejected gopher request from {} ({})", addr, rejection);
+                        let Some(permit) = limiter.rejection_permit() else {
+                            continue;
+                        };
+                        tokio::task::spawn_blocking(move || {
+                            let error = format!("3{}\t\terror.host\t1\r\n.\r\n", rejection.message().trim_end());
+                            let _ = stream.into_std().and_then(|mut stream| stream.write_all(error.as_bytes()));
+                            drop(permit);
+                        });
+                        continue;
+                    }
+                };
                 info!("Gopher request from {}", addr);
                 let content = Arc::clone(&content);
                 tokio::task::spawn_blocking(move || {
                     handle(stream, content).unwrap_or_else(|e| {
                         error!("Error handling gopher request: {}", e);
-                    })
+                    });
+                    drop(connection);
                 });
             }
             _ = update_rx.recv() => {
@@ -80,6 +103,45 @@ pub fn handle(stream: TcpStream, content: Arc<crate::Content>) -> Result<()> {
             }
             menu.end()?;
         }
+    } else if let Some(query) = selector.strip_prefix("/search").and_then(|rest| {
+        // The query comes after a tab (clients ask for one when a search item is chosen), and Gopher+ clients may add more fields
+        if rest.is_empty() {
+            Some("")
+        } else {
+            rest.strip_prefix('\t')
+                .map(|query| query.split('\t').next().unwrap_or_default())
+        }
+    }) {
+        // Serve search results as a directory
+        let mut menu = GopherMenu::with_write(&stream);
+        let results = crate::search::search(query);
+        if results.is_empty() {
+            menu.info(&format!(
+                "Nothing matched \"{query}\". Try fewer or different words."
+            ))?;
+        } else {
+            menu.info(&format!("Results for \"{query}\":"))?;
+        }
+        for result in results.iter() {
+            menu.info("")?;
+            result.gopher(&menu)?;
+        }
+        menu.info("")?;
+        menu.write_entry(
+            gophermap::ItemType::Search,
+            "Search again",
+            "/search",
+            &crate::CONFIG.domain,
+            crate::CONFIG.gopher_port,
+        )?;
+        menu.write_entry(
+            gophermap::ItemType::Directory,
+            "Go Home",
+            "/",
+            &crate::CONFIG.domain,
+            crate::CONFIG.gopher_port,
+        )?;
+        menu.end()?;
     } else if let Some(image) = selector.strip_prefix("/images/") {
         // Serve image from content directory
         let image = std::path::Path::new("content/images/").join(image);
@@ -0,0 +1,417 @@
+//! The read-only JSON content API under `/api/v1/`, for building other things (such as widgets or readers) on the site's content.
+//! Its OpenAPI document, generated from the content types, is served at `/api/v1/openapi.json` (see `super::openapi`).
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use hyper::header::{
+    ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use super::HtmlServer;
+use crate::{blogpost::BlogPost, project::Project};
+
+/// The general content served by the API, copied on each refresh so it's consistent with the HTML content (which the `html` option
+/// takes items' rendered content from).
+#[derive(Default)]
+pub struct Content {
+    projects: Vec<Project>,
+    blog_posts: Vec<BlogPost>,
+    index_info: serde_json::Value,
+    themes_info: serde_json::Value,
+}
+impl Content {
+    /// Copies the content the API serves from the general content.
+    pub fn new(content: &crate::Content) -> Self {
+        Self {
+            projects: content.projects.clone(),
+            blog_posts: content.blog_posts.clone(),
+            index_info: content.index_info.clone(),
+            themes_info: content.themes_info.clone(),
+        }
+    }
+
+    /// Gets the projects with the given skill (ignoring case), or all of them if there's none.
+    fn projects_with_skill<'a>(
+        &'a self,
+        skill: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Project> + 'a {
+        self.projects.iter().filter(move |project| match skill {
+            Some(skill) => project
+                .skills
+                .skills
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(skill)),
+            None => true,
+        })
+    }
+
+    /// Gets the blog posts with the given tag, or all of them if there's none.
+    fn blog_posts_with_tag<'a>(
+        &'a self,
+        tag: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a BlogPost> + 'a {
+        self.blog_posts.iter().filter(move |post| match tag {
+            Some(tag) => post.tags.iter().any(|t| t.name() == tag),
+            None => true,
+        })
+    }
+}
+
+/// Gets a router to handle API calls for content.
+pub fn router() -> Router<Arc<HtmlServer>> {
+    Router::new()
+        .route("/projects", get(projects))
+        .route("/projects/:url", get(project))
+        .route("/blog", get(blog_posts))
+        .route("/blog/:url", get(blog_post))
+        .route("/index", get(index))
+        .route("/themes", get(themes))
+        .route("/openapi.json", get(openapi))
+}
+
+/// An error response, as JSON.
+#[derive(Serialize)]
+pub struct ApiError {
+    pub(super) error: String,
+}
+
+/// A project or blog post, along with its content rendered as HTML if it was asked for.
+#[derive(Serialize)]
+struct Item<'a, T> {
+    #[serde(flatten)]
+    item: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<&'a str>,
+}
+impl<'a, T> Item<'a, T> {
+    /// Wraps an item with the given URL, taking its rendered content from `fragments` if `html` is set.
+    fn new(item: &'a T, url: &str, fragments: &'a HashMap<String, String>, html: bool) -> Self {
+        Self {
+            item,
+            html: if html {
+                fragments.get(url).map(String::as_str)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// The query string for listing projects.
+#[derive(Deserialize)]
+struct ProjectsQuery {
+    /// Only list projects with this skill (ignoring case).
+    skill: Option<String>,
+    #[serde(default)]
+    html: bool,
+}
+
+/// The query string for listing blog posts.
+#[derive(Deserialize)]
+struct BlogPostsQuery {
+    /// Only list posts with this tag.
+    tag: Option<String>,
+    #[serde(default)]
+    html: bool,
+}
+
+/// The query string for getting a single project or blog post.
+#[derive(Deserialize)]
+struct ItemQuery {
+    #[serde(default)]
+    html: bool,
+}
+
+/// Handles a GET request to list the projects, most important first.
+async fn projects(
+    State(server): State<Arc<HtmlServer>>,
+    Query(query): Query<ProjectsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let content = server.content.read().await;
+    let projects: Vec<_> = content
+        .api
+        .projects_with_skill(query.skill.as_deref())
+        .map(|project| {
+            Item::new(
+                project,
+                &project.url,
+                &content.simple.project_fragments,
+                query.html,
+            )
+        })
+        .collect();
+    json_response(&headers, &projects)
+}
+
+/// Handles a GET request for a single project.
+async fn project(
+    State(server): State<Arc<HtmlServer>>,
+    Path(url): Path<String>,
+    Query(query): Query<ItemQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let content = server.content.read().await;
+    match content
+        .api
+        .projects
+        .iter()
+        .find(|project| project.url == url)
+    {
+        Some(project) => json_response(
+            &headers,
+            &Item::new(project, &url, &content.simple.project_fragments, query.html),
+        ),
+        None => not_found("project"),
+    }
+}
+
+/// Handles a GET request to list the blog posts, newest first.
+async fn blog_posts(
+    State(server): State<Arc<HtmlServer>>,
+    Query(query): Query<BlogPostsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let content = server.content.read().await;
+    let posts: Vec<_> = content
+        .api
+        .blog_posts_with_tag(query.tag.as_deref())
+        .map(|post| Item::new(post, &post.url, &content.simple.blog_fragments, query.html))
+        .collect();
+    json_response(&headers, &posts)
+}
+
+/// Handles a GET request for a single blog post.
+async fn blog_post(
+    State(server): State<Arc<HtmlServer>>,
+    Path(url): Path<String>,
+    Query(query): Query<ItemQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let content = server.content.read().await;
+    match content.api.blog_posts.iter().find(|post| post.url == url) {
+        Some(post) => json_response(
+            &headers,
+            &Item::new(post, &url, &content.simple.blog_fragments, query.html),
+        ),
+        None => not_found("blog post"),
+    }
+}
+
+/// Handles a GET request for the home page's text.
+async fn index(State(server): State<Arc<HtmlServer>>, headers: HeaderMap) -> Response {
+    json_response(&headers, &server.content.read().await.api.index_info)
+}
+
+/// Handles a GET request for the themes page's text.
+async fn themes(State(server): State<Arc<HtmlServer>>, headers: HeaderMap) -> Response {
+    json_response(&headers, &server.content.read().await.api.themes_info)
+}
+
+/// Handles a GET request for the API's OpenAPI document.
+async fn openapi(headers: HeaderMap) -> Response {
+    json_response(&headers, &*super::openapi::DOCUMENT)
+}
+
+/// Responds with a value as JSON, with an ETag (a hash of the body) so clients can send it back in `If-None-Match` to get an empty
+/// `304 Not Modified` response if nothing's changed.
+fn json_response(headers: &HeaderMap, value: &impl Serialize) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to serialize API response: {e}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let etag = format!("\"{}\"", &format!("{:x}", Sha256::digest(&body))[..32]);
+
+    // Clients may send several ETags, weak ones (which are as good here, as the content is the same), or `*` for any
+    let not_modified = headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+
+    let headers = [
+        (ETAG, etag),
+        (CACHE_CONTROL, "no-cache".to_string()),
+        (ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
+    ];
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, headers).into_response()
+    } else {
+        (headers, [(CONTENT_TYPE, "application/json")], body).into_response()
+    }
+}
+
+/// Responds that there's no `what` with the URL asked for.
+fn not_found(what: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        [(ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Json(ApiError {
+            error: format!("No {what} with that URL"),
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(url: &str, skills: &[&str]) -> Project {
+        let skills: String = skills
+            .iter()
+            .map(|skill| format!("<skill>{skill}</skill>"))
+            .collect();
+        quick_xml::de::from_str(&format!(
+            "<project><name>{url}</name><url>{url}</url><description/><date>2024</date>\
+             <skills>{skills}</skills><content/><thumbnail/><priority>1</priority></project>"
+        ))
+        .unwrap()
+    }
+
+    fn blog_post(url: &str, tags: &[&str]) -> BlogPost {
+        let tags: String = tags.iter().map(|tag| format!("<tag>{tag}</tag>")).collect();
+        quick_xml::de::from_str(&format!(
+            "<blogpost><title>{url}</title><url>{url}</url><date>2024-01-01T00:00:00</date>\
+             <visibility>1</visibility>{tags}<content>Hello</content></blogpost>"
+        ))
+        .unwrap()
+    }
+
+    fn content() -> Content {
+        Content {
+            projects: vec![
+                project("rocket", &["Embedded Development", "PCB Design"]),
+                project("site", &["Rust"]),
+                project("robot", &["embedded development"]),
+            ],
+            blog_posts: vec![blog_post("notes", &["note"]), blog_post("essay", &[])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn projects_filter_by_skill() {
+        let content = content();
+        let urls = |skill| {
+            content
+                .projects_with_skill(skill)
+                .map(|project| project.url.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(urls(None), ["rocket", "site", "robot"]);
+        assert_eq!(urls(Some("Embedded Development")), ["rocket", "robot"]);
+        assert_eq!(urls(Some("RUST")), ["site"]);
+        assert!(urls(Some("Embedded")).is_empty());
+        assert!(urls(Some("")).is_empty());
+    }
+
+    #[test]
+    fn blog_posts_filter_by_tag() {
+        let content = content();
+        let urls = |tag| {
+            content
+                .blog_posts_with_tag(tag)
+                .map(|post| post.url.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(urls(None), ["notes", "essay"]);
+        assert_eq!(urls(Some("note")), ["notes"]);
+        assert!(urls(Some("notes")).is_empty());
+    }
+
+    /// Gets a response for a value, with the given `If-None-Match` headers.
+    fn respond(if_none_match: &[&str], value: &serde_json::Value) -> Response {
+        let mut headers = HeaderMap::new();
+        for tag in if_none_match {
+            headers.append(IF_NONE_MATCH, tag.parse().unwrap());
+        }
+        json_response(&headers, value)
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn responses_have_etags() {
+        let value = serde_json::json!({ "hello": "world" });
+        let response = respond(&[], &value);
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_eq!(body(response).await, br#"{"hello":"world"}"#);
+
+        // The same content always gets the same tag, and different content a different one
+        assert_eq!(respond(&[], &value).headers()[ETAG], etag);
+        let other = serde_json::json!({ "hello": "there" });
+        assert_ne!(respond(&[], &other).headers()[ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn matching_etags_are_not_modified() {
+        let value = serde_json::json!([1, 2, 3]);
+        let etag = respond(&[], &value).headers()[ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        for if_none_match in [
+            vec![etag.as_str()],
+            vec!["*"],
+            vec![&format!("W/{etag}")],
+            vec![&format!("\"other\", {etag}")],
+            vec![&format!("\"other\",{etag} ,\"another\"")],
+            vec!["\"other\"", &etag],
+        ] {
+            let response = respond(&if_none_match, &value);
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_MODIFIED,
+                "{if_none_match:?}"
+            );
+            assert_eq!(response.headers()[ETAG], etag);
+            assert!(body(response).await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn other_etags_are_modified() {
+        let value = serde_json::json!([1, 2, 3]);
+        let etag = respond(&[], &value).headers()[ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        for if_none_match in [
+            "\"other\"",
+            etag.trim_matches('"'),
+            &format!("{etag}x"),
+            &etag[..etag.len() - 2],
+            "",
+        ] {
+            let response = respond(&[if_none_match], &value);
+            assert_eq!(response.status(), StatusCode::OK, "{if_none_match:?}");
+            assert!(!body(response).await.is_empty());
+        }
+    }
+}
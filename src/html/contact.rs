@@ -1,37 +1,71 @@
 use axum::{
-    extract::Path,
-    response::IntoResponse,
+    extract::{Path, Query},
+    response::{
+        sse::{Event, KeepAlive},
+        Html, IntoResponse, Sse,
+    },
     routing::{get, post},
     Json, Router,
 };
 use axum_client_ip::{SecureClientIp, SecureClientIpSource};
-use hyper::StatusCode;
+use hyper::{header, HeaderMap, StatusCode};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::error;
 
-use crate::contact::{self, MessageSendError, MessagesLoadError, ThreadId};
+use crate::contact::{
+    self, challenge, export::ExportFormat, EmailError, MessageSendError, MessagesLoadError,
+    ThreadToken,
+};
 
 /// Gets a router to handle API calls for messaging.
 pub fn router() -> Router {
     Router::new()
+        .route("/challenge", get(get_challenge))
         .route("/send", post(create_thread))
         .route("/reply/:thread", post(send_message))
         .route("/load/:thread", get(get_messages))
+        .route("/stream/:thread", get(stream_messages))
+        .route("/export/:thread", get(export_thread))
+        .route("/upgrade/:thread", post(upgrade_thread))
+        .route("/email/:thread", post(set_email))
+        .route("/confirm/:code", get(confirm_page).post(confirm_email))
         .layer(SecureClientIpSource::RightmostXForwardedFor.into_extension())
 }
 
-/// Handles a POST request to create a new thread, returning the thread ID if successful and an error message otherwise.
-async fn create_thread(ip: Option<SecureClientIp>, msg: String) -> impl IntoResponse {
+/// Handles a GET request for a new proof-of-work challenge, to be solved before creating a thread.
+async fn get_challenge() -> impl IntoResponse {
+    Json(challenge::issue())
+}
+
+/// Handles a POST request to create a new thread, returning the thread's token if successful and an error message otherwise.
+///
+/// If challenges are enabled, the solved challenge must be given in the `X-Challenge-Nonce` and `X-Challenge-Solution` headers.
+async fn create_thread(
+    ip: Option<SecureClientIp>,
+    headers: HeaderMap,
+    msg: String,
+) -> impl IntoResponse {
+    // Get proof of work from headers, if given
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let proof = header("X-Challenge-Nonce")
+        .zip(header("X-Challenge-Solution"))
+        .map(|(nonce, solution)| challenge::Proof {
+            nonce: nonce.to_string(),
+            solution: solution.to_string(),
+        });
+
     // If IP extraction failed, log error (points to error in proxy configuration) and return. Otherwise, create thread.
     let result = match ip {
         None => {
             if crate::CONFIG.msg_ignore_ip {
-                contact::create_thread(std::net::IpAddr::from([0, 0, 0, 0]), msg).await
+                contact::create_thread(std::net::IpAddr::from([0, 0, 0, 0]), msg, proof).await
             } else {
                 error!("Failed to extract IP, is proxy configured with X-Forwarded-For header?");
                 Err(MessageSendError::DatabaseError)
             }
         }
-        Some(ip) => contact::create_thread(ip.0, msg).await,
+        Some(ip) => contact::create_thread(ip.0, msg, proof).await,
     };
 
     // Return newly created thread's ID or map error to response/status code
@@ -44,10 +78,10 @@ async fn create_thread(ip: Option<SecureClientIp>, msg: String) -> impl IntoResp
 /// Handles a POST request to send a message on the given thread, returning an error message upon failure.
 async fn send_message(Path(thread): Path<String>, msg: String) -> impl IntoResponse {
     // Parse thread ID
-    let Ok(thread) = thread.parse::<ThreadId>() else {
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
         return (
             StatusCode::BAD_REQUEST,
-            "Error: ill-formed thread ID (should be a 64-bit hexadecimal integer)".to_string(),
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
         );
     };
 
@@ -61,10 +95,10 @@ async fn send_message(Path(thread): Path<String>, msg: String) -> impl IntoRespo
 /// Handles a GET request for the messages in a thread.
 async fn get_messages(Path(thread): Path<String>) -> impl IntoResponse {
     // Parse thread ID
-    let Ok(thread) = thread.parse::<ThreadId>() else {
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
         return (
             StatusCode::BAD_REQUEST,
-            "Error: ill-formed thread ID (should be a 64-bit hexadecimal integer)".to_string(),
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
         )
             .into_response();
     };
@@ -76,6 +110,158 @@ async fn get_messages(Path(thread): Path<String>) -> impl IntoResponse {
     }
 }
 
+/// Handles a GET request to stream new messages on a thread as server-sent events, each containing one message as JSON. If the stream
+/// falls behind and messages are dropped, a `lagged` event (with the number of messages dropped, on any thread) is sent instead, so
+/// the client can reload the thread.
+async fn stream_messages(Path(thread): Path<String>) -> impl IntoResponse {
+    // Parse thread ID
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
+        )
+            .into_response();
+    };
+
+    // Subscribe before checking the token, so nothing sent in between is missed
+    let receiver = contact::subscribe();
+    let thread = match contact::resolve(thread).await {
+        Ok(thread) => thread,
+        Err(e) => return (StatusCode::from(&e), format!("Error: {e}")).into_response(),
+    };
+
+    // Forward messages on this thread until the client disconnects (dropping the stream)
+    let stream = futures::stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok((t, message)) if t == thread => {
+                    return Some((Event::default().json_data(message), receiver))
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    let event = Event::default().event("lagged").data(skipped.to_string());
+                    return Some((Ok(event), receiver));
+                }
+                Ok(_) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Handles a POST request to exchange a legacy thread ID for a capability token, returning the new token.
+async fn upgrade_thread(Path(thread): Path<String>) -> impl IntoResponse {
+    // Parse thread ID
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
+        );
+    };
+
+    // Upgrade and return new token
+    match contact::upgrade(thread).await {
+        Ok(token) => (StatusCode::OK, token.to_string()),
+        Err(e) => (StatusCode::from(&e), format!("Error: {e}")),
+    }
+}
+
+/// Handles a POST request to set the email address my replies on a thread are sent to (given as the body), or to stop sending them if
+/// the body is empty.
+async fn set_email(Path(thread): Path<String>, address: String) -> impl IntoResponse {
+    // Parse thread ID
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
+        );
+    };
+
+    // Set address and report result
+    let address = Some(address.trim().to_string()).filter(|a| !a.is_empty());
+    match contact::set_email(thread, address).await {
+        Ok(()) => (StatusCode::OK, String::new()),
+        Err(e) => (StatusCode::from(&e), format!("Error: {e}")),
+    }
+}
+
+/// Handles a GET request from the link in a confirmation email, showing a button to confirm the address. Only the POST it sends
+/// confirms it, as email scanners open links automatically (which would confirm addresses for whoever entered them).
+async fn confirm_page() -> impl IntoResponse {
+    Html(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1"><title>Confirm email address</title></head>
+<body>
+<p>Press the button to have replies on your thread emailed to this address. If you didn't ask for this, just close this page.</p>
+<form method="post"><button type="submit">Confirm email address</button></form>
+</body>
+</html>
+"#,
+    )
+}
+
+/// Handles a POST request from the confirmation page, confirming the address the link was sent to.
+async fn confirm_email(Path(code): Path<String>) -> impl IntoResponse {
+    match contact::confirm_email(code).await {
+        Ok(()) => (
+            StatusCode::OK,
+            "Email address confirmed! Replies on the thread will now be emailed to you."
+                .to_string(),
+        ),
+        Err(e) => (StatusCode::from(&e), format!("Error: {e}")),
+    }
+}
+
+/// The query parameters for exporting a thread.
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// The format to export in (`json`, `mbox` or `text`), defaulting to text.
+    format: Option<String>,
+}
+
+/// Handles a GET request to download a thread in the given format.
+async fn export_thread(
+    Path(thread): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    // Parse thread ID and format
+    let Ok(thread) = thread.parse::<ThreadToken>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
+        )
+            .into_response();
+    };
+    let format = match query.format.as_deref().map(str::parse::<ExportFormat>) {
+        None => ExportFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return (StatusCode::BAD_REQUEST, format!("Error: {e}")).into_response(),
+    };
+
+    // Get messages and return as a download
+    match contact::get_messages(thread).await {
+        Ok(messages) => (
+            [
+                (header::CONTENT_TYPE, format.content_type().to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"thread-{}.{}\"",
+                        thread.public_id(),
+                        format.extension()
+                    ),
+                ),
+            ],
+            contact::export::export(thread, &messages, format),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::from(&e), format!("Error: {e}")).into_response(),
+    }
+}
+
 impl From<&MessageSendError> for StatusCode {
     fn from(err: &MessageSendError) -> Self {
         match err {
@@ -84,6 +270,21 @@ impl From<&MessageSendError> for StatusCode {
             MessageSendError::ThreadFull => StatusCode::TOO_MANY_REQUESTS,
             MessageSendError::InboxFull => StatusCode::SERVICE_UNAVAILABLE,
             MessageSendError::NoSuchThread => StatusCode::NOT_FOUND,
+            MessageSendError::BadChallenge => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl From<&EmailError> for StatusCode {
+    fn from(err: &EmailError) -> Self {
+        match err {
+            EmailError::DatabaseError => StatusCode::INTERNAL_SERVER_ERROR,
+            EmailError::NoSuchThread => StatusCode::NOT_FOUND,
+            EmailError::InvalidAddress => StatusCode::BAD_REQUEST,
+            EmailError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+            EmailError::TooSoon => StatusCode::TOO_MANY_REQUESTS,
+            EmailError::SendFailed => StatusCode::BAD_GATEWAY,
+            EmailError::InvalidConfirmation => StatusCode::NOT_FOUND,
         }
     }
 }
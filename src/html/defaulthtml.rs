@@ -2,6 +2,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use color_eyre::Result;
 use tera::Tera;
+use tracing::error;
 
 /// Stores the rendered basic HTML content, for serving previews or writing to files.
 #[derive(Default)]
@@ -16,6 +17,8 @@ pub struct Content {
     pub projects: HashMap<String, String>,
     /// Contents of `blog/` indexed by name
     pub blog: HashMap<String, String>,
+    /// `search.html` with a placeholder result, only for generating CSS (as search pages are rendered per request)
+    pub search_sample: String,
     /// CSS generated by railwind for all rendered content
     pub css: String,
     /// Templating engine
@@ -78,6 +81,21 @@ impl Content {
             );
         }
 
+        // Make a sample search page, with every part of a result, so the CSS covers search pages
+        self.search_sample = self.tera.render(
+            "search.tera",
+            &tera::Context::from_serialize(serde_json::json!({
+                "query": "sample",
+                "results": [{
+                    "kind": "project",
+                    "title": "",
+                    "path": "",
+                    "date": "",
+                    "snippet": [{ "text": "", "highlight": true }],
+                }],
+            }))?,
+        )?;
+
         // Make CSS
         self.make_css();
 
@@ -97,6 +115,7 @@ impl Content {
         for (_, blog_post) in self.blog.iter() {
             html.push_str(blog_post);
         }
+        html.push_str(&self.search_sample);
         // Parse html string (just an regex match internally, so concatenated html is fine)
         self.css = parse_to_string(
             Source::String(html, CollectionOptions::Html),
@@ -124,9 +143,21 @@ impl Content {
             Project(name) => self.projects.get(name).cloned(),
             BlogPost(name) => self.blog.get(name).cloned(),
             Contact(_) => Some(self.contact.clone()),
+            Search(query) => self
+                .search(query)
+                .map_err(|e| error!("Failed to render search page: {e}"))
+                .ok(),
         }
     }
 
+    /// Renders the search page for a query, which (unlike the other pages) has to be done per request.
+    fn search(&self, query: &str) -> Result<String> {
+        let mut context = tera::Context::new();
+        context.insert("query", query.trim());
+        context.insert("results", &crate::search::search(query));
+        Ok(self.tera.render("search.tera", &context)?)
+    }
+
     /// Serve the css.
     pub fn router() -> axum::Router<Arc<super::HtmlServer>> {
         use axum::{extract::State, routing::get, Router};
@@ -13,10 +13,13 @@ use tokio::sync::{broadcast, RwLock};
 use tower_http::{normalize_path::NormalizePath, services::ServeDir};
 use tracing::{debug, error, info, warn};
 
+mod api;
 mod contact;
 mod defaulthtml;
 mod fancyhtml;
 mod feed;
+mod openapi;
+mod search;
 mod simplehtml;
 
 /// Runs the HTML service, given a broadcast channel to notify it of content changes.
@@ -154,9 +157,20 @@ impl HtmlServer {
                     },
                 ),
             )
+            .route(
+                "/search",
+                get(
+                    |State(server): State<Arc<Self>>,
+                     Query(SearchQuery { q }): Query<SearchQuery>,
+                     version: ExtractVersion| async move {
+                        server.get_page(Page::Search(q), version).await
+                    },
+                ),
+            )
             .nest("/defaulthtml", defaulthtml::Content::router())
             .nest("/simplehtml", simplehtml::Content::router())
             .nest("/fancyhtml", fancyhtml::Content::router())
+            .nest("/api/v1", api::router())
             .route(
                 "/feed",
                 get(|State(server): State<Arc<Self>>| async move {
@@ -174,6 +188,7 @@ impl HtmlServer {
         let router = router
             .with_state(self)
             .nest("/api/message", contact::router())
+            .nest("/api/search", search::router())
             .nest_service("/images/", ServeDir::new("content/images/"))
             .layer(tower_http::trace::TraceLayer::new_for_http());
         // Redirect trailing slashes
@@ -355,6 +370,7 @@ struct HtmlContent {
     pub simple: simplehtml::Content,
     pub fancy: fancyhtml::Content,
     pub feed: feed::Feed,
+    pub api: api::Content,
 }
 
 impl HtmlContent {
@@ -365,6 +381,7 @@ impl HtmlContent {
             simple: simplehtml::Content::new(content)?,
             fancy: fancyhtml::Content::new(content)?,
             feed: feed::Feed::new(content)?,
+            api: api::Content::new(content),
         })
     }
 
@@ -375,6 +392,7 @@ impl HtmlContent {
         self.simple.refresh(content)?;
         self.fancy.refresh(content)?;
         self.feed.refresh(content)?;
+        self.api = api::Content::new(content);
         Ok(())
     }
 }
@@ -433,6 +451,15 @@ pub enum Page {
     Contact(Option<String>),
     Project(String),
     BlogPost(String),
+    /// The search page, with the query (which may be empty).
+    Search(String),
+}
+
+/// The query string of the search page.
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
 }
 
 fn get_canonical_url(page: &Page) -> String {
@@ -445,5 +472,6 @@ fn get_canonical_url(page: &Page) -> String {
         }
         Page::Project(project) => format!("https://{}/projects/{}", crate::CONFIG.domain, project),
         Page::BlogPost(post) => format!("https://{}/blog/{}", crate::CONFIG.domain, post),
+        Page::Search(_) => format!("https://{}/search", crate::CONFIG.domain),
     }
 }
@@ -0,0 +1,409 @@
+//! The OpenAPI document for the content API (see `super::api`), generated from the `Schema`s of the content types it returns.
+//!
+//! Schemas are implemented with `object_schema!` and `enum_schema!`, which check the fields and variants they list against the types
+//! (failing to compile if they don't match), so the document can't fall out of date with the content types.
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+
+use crate::{blogpost, project};
+
+/// The named schemas in the document's `components/schemas`, by name.
+type Components = Map<String, Value>;
+
+/// The OpenAPI document for the content API.
+pub static DOCUMENT: Lazy<Value> = Lazy::new(document);
+
+/// A type whose JSON form (as serialized by serde) can be described by an OpenAPI schema.
+pub trait Schema {
+    /// The name the schema is listed under in `components/schemas`, if it's listed there rather than written out wherever it's used
+    /// (which recursive types must be).
+    const NAME: Option<&'static str> = None;
+
+    /// Gets the type's schema, adding any named schemas it uses to `components`.
+    fn schema(components: &mut Components) -> Value;
+
+    /// Gets the schema to use for the type inside others: a reference if it's named, and otherwise its schema.
+    fn reference(components: &mut Components) -> Value {
+        let Some(name) = Self::NAME else {
+            return Self::schema(components);
+        };
+        if !components.contains_key(name) {
+            // Add a placeholder first, so recursive types refer to themselves rather than recursing forever
+            components.insert(name.to_string(), Value::Null);
+            let schema = Self::schema(components);
+            components.insert(name.to_string(), schema);
+        }
+        json!({ "$ref": format!("#/components/schemas/{name}") })
+    }
+}
+
+impl Schema for String {
+    fn schema(_: &mut Components) -> Value {
+        json!({ "type": "string" })
+    }
+}
+impl Schema for i32 {
+    fn schema(_: &mut Components) -> Value {
+        json!({ "type": "integer", "format": "int32" })
+    }
+}
+impl Schema for chrono::NaiveDateTime {
+    fn schema(_: &mut Components) -> Value {
+        json!({ "type": "string", "description": "A date and time, without a time zone (e.g. `2024-04-26T12:00:00`)." })
+    }
+}
+impl Schema for Value {
+    fn schema(_: &mut Components) -> Value {
+        json!({ "type": "object" })
+    }
+}
+impl<T: Schema> Schema for Vec<T> {
+    fn schema(components: &mut Components) -> Value {
+        json!({ "type": "array", "items": T::reference(components) })
+    }
+}
+impl<T: Schema> Schema for Option<T> {
+    fn schema(components: &mut Components) -> Value {
+        json!({ "anyOf": [T::reference(components), { "type": "null" }] })
+    }
+}
+impl<A: Schema, B: Schema> Schema for (A, B) {
+    fn schema(components: &mut Components) -> Value {
+        json!({
+            "type": "array",
+            "prefixItems": [A::reference(components), B::reference(components)],
+            "minItems": 2,
+            "maxItems": 2,
+        })
+    }
+}
+
+/// Gets the schema of an object with the given properties (by name), all of which are required.
+fn object(properties: Vec<(&str, Value)>) -> Value {
+    let required: Vec<&str> = properties.iter().map(|(name, _)| *name).collect();
+    let properties: Map<String, Value> = properties
+        .into_iter()
+        .map(|(name, schema)| (name.to_string(), schema))
+        .collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Implements `Schema` for a struct, named `$name` in the components, as an object of the given fields (each with its type, and
+/// renamed with `as` where serde renames it).
+macro_rules! object_schema {
+    ($name:literal, $($type:ident)::+ { $($field:ident $(as $rename:literal)?: $field_type:ty),* $(,)? }) => {
+        impl Schema for $($type)::+ {
+            const NAME: Option<&'static str> = Some($name);
+            fn schema(components: &mut Components) -> Value {
+                // Never called, but only compiles if the fields and their types are right
+                #[allow(dead_code)]
+                fn check(value: &$($type)::+) {
+                    let $($type)::+ { $($field),* } = value;
+                    $(let _: &$field_type = $field;)*
+                }
+                object(vec![
+                    $(([$($rename,)? stringify!($field)][0], <$field_type as Schema>::reference(components)),)*
+                ])
+            }
+        }
+    };
+}
+
+/// Implements `Schema` for an enum, named `$name` in the components, as one of its variants (each renamed with `as` as serde
+/// renames it). With a `tag`, it's internally tagged (each variant an object of its fields along with the tag), and otherwise it's
+/// externally tagged (each variant an object with just its name, holding its fields or, for newtype variants, its value).
+macro_rules! enum_schema {
+    (@check $value:ident, $($type:ident)::+, $variant:ident, { $($field:ident $(as $rename:literal)?: $field_type:ty),* $(,)? }) => {
+        if let $($type)::+::$variant { $($field),* } = $value {
+            $(let _: &$field_type = $field;)*
+        }
+    };
+    (@check $value:ident, $($type:ident)::+, $variant:ident, ($inner:ty)) => {
+        if let $($type)::+::$variant(inner) = $value {
+            let _: &$inner = inner;
+        }
+    };
+    (@schema $components:ident, { $($field:ident $(as $rename:literal)?: $field_type:ty),* $(,)? }) => {
+        object(vec![
+            $(([$($rename,)? stringify!($field)][0], <$field_type as Schema>::reference($components)),)*
+        ])
+    };
+    (@schema $components:ident, ($inner:ty)) => {
+        <$inner as Schema>::reference($components)
+    };
+    ($name:literal, $($type:ident)::+, tag = $tag:literal {
+        $($variant:ident as $variant_name:literal { $($field:ident $(as $rename:literal)?: $field_type:ty),* $(,)? }),* $(,)?
+    }) => {
+        impl Schema for $($type)::+ {
+            const NAME: Option<&'static str> = Some($name);
+            #[allow(unused_variables)] // `components` is unused if no variants have fields
+            fn schema(components: &mut Components) -> Value {
+                // Never called, but only compiles if the variants, their fields and the fields' types are right
+                #[allow(dead_code)]
+                fn check(value: &$($type)::+) {
+                    type This = $($type)::+;
+                    match value {
+                        $(This::$variant { $($field),* } => {
+                            $(let _: &$field_type = $field;)*
+                        })*
+                    }
+                }
+                let variants = vec![$(object(vec![
+                    ($tag, json!({ "const": $variant_name })),
+                    $(([$($rename,)? stringify!($field)][0], <$field_type as Schema>::reference(components)),)*
+                ])),*];
+                json!({ "oneOf": variants })
+            }
+        }
+    };
+    ($name:literal, $($type:ident)::+ { $($variant:ident as $variant_name:literal $body:tt),* $(,)? }) => {
+        impl Schema for $($type)::+ {
+            const NAME: Option<&'static str> = Some($name);
+            fn schema(components: &mut Components) -> Value {
+                // Never called, but only compiles if the variants, their fields and the fields' types are right
+                #[allow(dead_code)]
+                fn check(value: &$($type)::+) {
+                    type This = $($type)::+;
+                    match value {
+                        $(This::$variant { .. } => {})*
+                    }
+                    $(enum_schema!(@check value, This, $variant, $body);)*
+                }
+                let variants = vec![$(object(vec![
+                    ($variant_name, enum_schema!(@schema components, $body)),
+                ])),*];
+                json!({ "oneOf": variants })
+            }
+        }
+    };
+}
+
+object_schema!(
+    "Project",
+    project::Project {
+        name: String,
+        url: String,
+        description: String,
+        date: String,
+        content: project::Content,
+        thumbnail: String,
+        skills: project::Skills,
+        priority: i32,
+    }
+);
+object_schema!("ProjectContent", project::Content {
+    sections as "$value": Vec<project::Section>,
+});
+enum_schema!("ProjectSection", project::Section {
+    Section as "section" {
+        title: Option<String>,
+        content as "$value": Vec<project::Element>,
+    },
+    Criteria as "criteria" {
+        title: Option<String>,
+        items as "item": Vec<project::TitleDesc>,
+    },
+});
+object_schema!(
+    "TitleDesc",
+    project::TitleDesc {
+        title: String,
+        description: project::Text,
+    }
+);
+object_schema!("Skills", project::Skills {
+    skills as "skill": Vec<String>,
+});
+enum_schema!("ProjectElement", project::Element {
+    Group as "g" { content as "$value": Vec<project::Element> },
+    Gallery as "gallery" { content as "$value": Vec<project::Element> },
+    Paragraph as "p" (project::Text),
+    Image as "img" {
+        src as "@src": String,
+        alt as "@alt": String,
+        caption: Option<project::Text>,
+    },
+});
+object_schema!("Text", project::Text {
+    text as "$value": Vec<project::TextElement>,
+});
+enum_schema!("TextElement", project::TextElement {
+    Link as "a" {
+        href as "@href": String,
+        leading_space as "@lead": String,
+        trailing_space as "@trail": String,
+        text as "$value": Vec<project::TextElement>,
+    },
+    Text as "$text" (String),
+});
+
+object_schema!("BlogPost", blogpost::BlogPost {
+    title: String,
+    url: String,
+    date: chrono::NaiveDateTime,
+    visibility: i32,
+    tags as "tag": Vec<blogpost::Tag>,
+    content: blogpost::Content,
+});
+enum_schema!("BlogTag", blogpost::Tag, tag = "$text" {
+    Note as "note" {},
+});
+object_schema!("BlogContent", blogpost::Content {
+    content: Vec<blogpost::Element>,
+    footnotes: Vec<(String, Vec<blogpost::Element>)>,
+});
+enum_schema!("BlogElement", blogpost::Element, tag = "t" {
+    Paragraph as "paragraph" { text: Vec<blogpost::InlineElement> },
+    Code as "code" { lang: Option<String>, content: String },
+    Heading as "heading" {
+        text: Vec<blogpost::InlineElement>,
+        level: i32,
+        id: String,
+    },
+    Footnote as "footnote" { tag: String, body: Vec<blogpost::Element> },
+});
+enum_schema!("InlineElement", blogpost::InlineElement, tag = "t" {
+    Text as "text" { content: String },
+    Emph as "emph" { text: Vec<blogpost::InlineElement> },
+    Strong as "strong" { text: Vec<blogpost::InlineElement> },
+    Link as "link" { href: String, text: Vec<blogpost::InlineElement> },
+    InlineCode as "inline_code" { content: String },
+    FootnoteRef as "footnote_ref" { number: i32, tag: String },
+    Image as "image" { src: String, alt: String },
+});
+
+object_schema!("Error", super::api::ApiError { error: String });
+
+/// Generates the OpenAPI document.
+fn document() -> Value {
+    let mut components = Components::new();
+    let project = project::Project::reference(&mut components);
+    let blog_post = blogpost::BlogPost::reference(&mut components);
+    let error = super::api::ApiError::reference(&mut components);
+
+    // Parameters
+    let html = json!({
+        "name": "html",
+        "in": "query",
+        "description": "Whether to add an `html` field to each item, with its content rendered as HTML (as on the simple HTML version of the site).",
+        "schema": { "type": "boolean", "default": false },
+    });
+    let skill = json!({
+        "name": "skill",
+        "in": "query",
+        "description": "Only list projects with this skill (ignoring case).",
+        "schema": { "type": "string" },
+    });
+    let tag = json!({
+        "name": "tag",
+        "in": "query",
+        "description": "Only list blog posts with this tag.",
+        "schema": { "type": "string", "enum": ["note"] },
+    });
+    let url = |description: &str| {
+        json!({
+            "name": "url",
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": { "type": "string" },
+        })
+    };
+
+    // Responses, which all have ETags so they can be requested again with `If-None-Match` to only download them if they've changed
+    let ok = |description: &str, schema: Value| {
+        json!({
+            "description": description,
+            "headers": { "ETag": { "schema": { "type": "string" } } },
+            "content": { "application/json": { "schema": schema } },
+        })
+    };
+    let not_modified =
+        json!({ "description": "Not modified since the ETag given in `If-None-Match`." });
+    let not_found = json!({
+        "description": "There's nothing with that URL.",
+        "content": { "application/json": { "schema": error } },
+    });
+    let with_html = |schema: &Value| {
+        json!({
+            "allOf": [schema, {
+                "type": "object",
+                "properties": {
+                    "html": {
+                        "type": "string",
+                        "description": "The content rendered as HTML, if asked for with `html`. Links and images are relative to the site.",
+                    },
+                },
+            }],
+        })
+    };
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Fletch Rydell's website content API",
+            "version": "1",
+            "description": "Read-only access to the projects, blog posts and other content of the site, in the same structure the site is built from.",
+        },
+        "servers": [{ "url": format!("https://{}/api/v1", crate::CONFIG.domain) }],
+        "paths": {
+            "/projects": { "get": {
+                "summary": "List projects, most important first",
+                "parameters": [skill, html],
+                "responses": {
+                    "200": ok("The projects.", json!({ "type": "array", "items": with_html(&project) })),
+                    "304": not_modified,
+                },
+            } },
+            "/projects/{url}": { "get": {
+                "summary": "Get a project",
+                "parameters": [url("The project's URL (as in its `url`)."), html],
+                "responses": {
+                    "200": ok("The project.", with_html(&project)),
+                    "304": not_modified,
+                    "404": not_found,
+                },
+            } },
+            "/blog": { "get": {
+                "summary": "List blog posts, newest first",
+                "parameters": [tag, html],
+                "responses": {
+                    "200": ok("The blog posts.", json!({ "type": "array", "items": with_html(&blog_post) })),
+                    "304": not_modified,
+                },
+            } },
+            "/blog/{url}": { "get": {
+                "summary": "Get a blog post",
+                "parameters": [url("The post's URL (as in its `url`)."), html],
+                "responses": {
+                    "200": ok("The blog post.", with_html(&blog_post)),
+                    "304": not_modified,
+                    "404": not_found,
+                },
+            } },
+            "/index": { "get": {
+                "summary": "Get the home page's text",
+                "responses": {
+                    "200": ok("The home page's text, by section.", Value::schema(&mut components)),
+                    "304": not_modified,
+                },
+            } },
+            "/themes": { "get": {
+                "summary": "Get the themes page's text, including the versions of the site",
+                "responses": {
+                    "200": ok("The themes page's text.", Value::schema(&mut components)),
+                    "304": not_modified,
+                },
+            } },
+            "/openapi.json": { "get": {
+                "summary": "Get this document",
+                "responses": {
+                    "200": ok("This document.", Value::schema(&mut components)),
+                    "304": not_modified,
+                },
+            } },
+        },
+        "components": { "schemas": components },
+    })
+}
@@ -0,0 +1,13 @@
+use axum::{extract::Query, response::IntoResponse, routing::get, Json, Router};
+
+use super::SearchQuery;
+
+/// Gets a router to handle API calls for searching.
+pub fn router() -> Router {
+    Router::new().route("/", get(search))
+}
+
+/// Handles a GET request to search the projects and blog posts for the `q` parameter, returning the results (best first) as JSON.
+async fn search(Query(SearchQuery { q }): Query<SearchQuery>) -> impl IntoResponse {
+    Json(crate::search::search(&q))
+}
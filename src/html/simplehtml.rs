@@ -2,6 +2,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use color_eyre::Result;
 use tera::Tera;
+use tracing::error;
 
 /// Stores the rendered basic HTML content, for serving previews or writing to files.
 #[derive(Default)]
@@ -14,6 +15,10 @@ pub struct Content {
     pub projects: HashMap<String, String>,
     /// Contents of `blog/` indexed by name
     pub blog: HashMap<String, String>,
+    /// The content of each project on its own (without the rest of the page), indexed by name, for the API
+    pub project_fragments: HashMap<String, String>,
+    /// The content of each blog post on its own (without the rest of the page), indexed by name, for the API
+    pub blog_fragments: HashMap<String, String>,
     /// CSS loaded from a file
     pub css: String,
     /// Templating engine
@@ -50,6 +55,7 @@ impl Content {
 
         // Make project pages
         self.projects = HashMap::new();
+        self.project_fragments = HashMap::new();
         for project in content.projects.iter() {
             let mut context = tera::Context::new();
             context.insert("project", &project);
@@ -57,10 +63,15 @@ impl Content {
                 project.url.clone(),
                 self.tera.render("project.tera", &context)?,
             );
+            self.project_fragments.insert(
+                project.url.clone(),
+                self.tera.render("fragments/project.tera", &context)?,
+            );
         }
 
         // Make blog pages
         self.blog = HashMap::new();
+        self.blog_fragments = HashMap::new();
         for blog_post in content.blog_posts.iter() {
             let mut context = tera::Context::new();
             context.insert("post", &blog_post);
@@ -68,6 +79,10 @@ impl Content {
                 blog_post.url.clone(),
                 self.tera.render("blogpost.tera", &context)?,
             );
+            self.blog_fragments.insert(
+                blog_post.url.clone(),
+                self.tera.render("fragments/blogpost.tera", &context)?,
+            );
         }
 
         // Load CSS
@@ -84,6 +99,10 @@ impl Content {
             Themes => Some(self.themes.clone()),
             Project(name) => self.projects.get(name).cloned(),
             BlogPost(name) => self.blog.get(name).cloned(),
+            Search(query) => self
+                .search(query)
+                .map_err(|e| error!("Failed to render search page: {e}"))
+                .ok(),
             _ => None,
         }
         .map(|page| {
@@ -98,6 +117,14 @@ impl Content {
         })
     }
 
+    /// Renders the search page for a query, which (unlike the other pages) has to be done per request.
+    fn search(&self, query: &str) -> Result<String> {
+        let mut context = tera::Context::new();
+        context.insert("query", query.trim());
+        context.insert("results", &crate::search::search(query));
+        Ok(self.tera.render("search.tera", &context)?)
+    }
+
     /// Serve the css.
     pub fn router() -> axum::Router<Arc<super::HtmlServer>> {
         use axum::{extract::State, routing::get, Router};
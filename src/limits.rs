@@ -0,0 +1,162 @@
+//! Limits on how much of the server one client can take up: how many connections can be open at once (overall and from each IP), and
+//! how often each IP can try something like authenticating. Shared by the SSH, Gopher, POP3, QOTD and SMTP listeners.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The prefix length that IPv6 addresses are grouped by, as a single user usually has at least a /64.
+const IPV6_PREFIX: u32 = 64;
+/// The most rejected connections each listener tells why at once. Any more are closed straight away, so a flood of connections the
+/// limits turn away can't take up more than this.
+const MAX_REJECTIONS: usize = 8;
+
+/// Gets the address a client is counted under: its IP for IPv4 (including IPv4-mapped IPv6 addresses), or its /64 for IPv6.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u128::MAX >> IPV6_PREFIX)).into()),
+        ip => ip,
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The listener already has as many connections open as it allows.
+    Busy,
+    /// The client already has as many connections open as one IP is allowed.
+    TooManyFromIp,
+}
+impl Rejection {
+    /// A friendly message explaining the rejection to the client (with `\r\n` newlines).
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Busy => {
+                "Sorry, the server is busy right now. Please try again in a few minutes.\r\n"
+            }
+            Self::TooManyFromIp => {
+                "You already have too many connections open. Please close some and try again.\r\n"
+            }
+        }
+    }
+}
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Busy => write!(f, "too many connections"),
+            Self::TooManyFromIp => write!(f, "too many connections from IP"),
+        }
+    }
+}
+
+/// Counts the connections open to a listener, turning new ones away once there are too many overall or from one IP.
+pub struct ConnectionLimiter {
+    max: usize,
+    max_per_ip: usize,
+    /// The number of open connections, overall and from each client (see `client_key`).
+    counts: Mutex<(usize, HashMap<IpAddr, usize>)>,
+    /// Permits for telling rejected connections why (see `MAX_REJECTIONS`).
+    rejections: Arc<Semaphore>,
+}
+impl ConnectionLimiter {
+    pub fn new(max: usize, max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            max_per_ip,
+            counts: Mutex::new((0, HashMap::new())),
+            rejections: Arc::new(Semaphore::new(MAX_REJECTIONS)),
+        })
+    }
+    /// Admits a connection from the given IP, returning a guard that counts it as open until it's dropped, or why it can't be.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<Connection, Rejection> {
+        let key = client_key(ip);
+        let mut counts = self.counts.lock().unwrap();
+        let (total, per_ip) = &mut *counts;
+        if *total >= self.max {
+            return Err(Rejection::Busy);
+        }
+        let count = per_ip.entry(key).or_insert(0);
+        if *count >= self.max_per_ip {
+            return Err(Rejection::TooManyFromIp);
+        }
+        *count += 1;
+        *total += 1;
+        Ok(Connection {
+            limiter: Arc::clone(self),
+            key,
+        })
+    }
+    /// The number of connections open.
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().0
+    }
+    /// Gets a permit to tell a rejected connection why, to hold until it's been told, or `None` if too many are being told already (in
+    /// which case it should just be closed).
+    pub fn rejection_permit(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.rejections).try_acquire_owned().ok()
+    }
+}
+
+/// A connection admitted by a `ConnectionLimiter`, counted as open until this is dropped.
+pub struct Connection {
+    limiter: Arc<ConnectionLimiter>,
+    key: IpAddr,
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        let (total, per_ip) = &mut *counts;
+        *total -= 1;
+        if let Some(count) = per_ip.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Limits how many times each IP can try something (such as authenticating) within a sliding window of time.
+pub struct RateLimiter {
+    max: usize,
+    window: Duration,
+    /// The times of each client's attempts within the window, oldest first.
+    attempts: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+impl RateLimiter {
+    pub fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Records an attempt from the given IP, returning whether it's allowed. Attempts that aren't allowed aren't recorded, so a client
+    /// that keeps trying is allowed again once its earlier attempts leave the window.
+    pub fn attempt(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        // Forget attempts that have left the window, along with clients that have none left (so the map doesn't grow forever)
+        attempts.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|&time| now.duration_since(time) >= self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = attempts.entry(client_key(ip)).or_default();
+        if times.len() >= self.max {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
@@ -11,9 +11,12 @@ mod contact;
 mod content;
 mod gopher;
 mod html;
+mod limits;
 mod pop3;
 mod project;
 mod qotd;
+mod search;
+mod smtp;
 mod ssh;
 
 pub use content::Content;
@@ -33,12 +36,28 @@ pub struct Config {
     pub ssh_timeout: Duration,
     /// The first data timeout for ssh connections; new connections will be closed if no data is received within this time (given in seconds).
     pub ssh_first_timeout: Duration,
+    /// The ssh username reserved for me, which must log in with a password to use admin commands (e.g. backing up messages).
+    pub ssh_admin_user: String,
+    /// The SHA-256 hash (in hex) of the admin user's password. If unset, admin commands are disabled and the admin user is treated like any other.
+    pub ssh_admin_password_hash: Option<String>,
+    /// The maximum number of ssh connections open at once, beyond which new ones are turned away with a message.
+    pub ssh_max_sessions: usize,
+    /// The maximum number of ssh connections open at once from one IP (or IPv6 /64).
+    pub ssh_max_sessions_ip: usize,
+    /// The maximum number of ssh authentication attempts per minute from one IP (or IPv6 /64), mostly to slow down guessing the admin password.
+    pub ssh_max_auth_attempts: usize,
     /// The Gopher port to listen on.
     pub gopher_port: u16,
     /// The QOTD port to listen on.
     pub qotd_port: u16,
     /// The POP3 port to listen on.
     pub pop3_port: u16,
+    /// The maximum number of connections each of the Gopher, POP3, QOTD and SMTP listeners has open at once.
+    pub max_connections: usize,
+    /// The maximum number of connections each of the Gopher, POP3, QOTD and SMTP listeners has open at once from one IP (or IPv6 /64).
+    pub max_connections_ip: usize,
+    /// The SMTP port to listen on for users' email replies to contact threads. If unset, emailed replies aren't received.
+    pub smtp_port: Option<u16>,
     /// Whether to watch for changes to the content directory (as well as any HTML templates) to update content.
     ///
     /// Currently affects all filesystem watching, but may be split into separate flags in the future.
@@ -55,10 +74,27 @@ pub struct Config {
     pub msg_max_unread_messages: usize,
     /// The maximum number of outstanding threads with unread messages globally. Limits the size of my "inbox".
     pub msg_max_unread_threads_global: usize,
-    /// The maximum number of outstanding threads with unread messages for a single IP. Prevents spamming threads to get around the per-thread message limit.
+    /// The maximum number of outstanding threads with unread messages for a single IP subnet (see `msg_ipv4_prefix` and `msg_ipv6_prefix`). Prevents spamming threads to get around the per-thread message limit.
     pub msg_max_unread_threads_ip: usize,
+    /// The prefix length of the IPv4 subnets that share a rate limit for messages (32 to limit each address separately).
+    pub msg_ipv4_prefix: u8,
+    /// The prefix length of the IPv6 subnets that share a rate limit for messages. Defaults to 64, as a single user usually has at least a /64.
+    pub msg_ipv6_prefix: u8,
+    /// Whether threads created before capability tokens can still be accessed by their old 64-bit IDs. Turn off to end the migration period.
+    pub msg_legacy_thread_ids: bool,
     /// If set, all incoming messages are treated as coming from IP 0.0.0.0 for testing without a reverse proxy setting X-Forwarded-For.
     pub msg_ignore_ip: bool,
+    /// The spam score at or above which incoming messages are quarantined rather than counted toward my inbox.
+    pub msg_spam_threshold: f64,
+    /// Known spam phrases (lowercase) to check incoming messages for, loaded from the file given by `MSG_SPAM_PHRASES` (one per line, `#` for comments).
+    pub msg_spam_phrases: Vec<String>,
+    /// The maximum number of threads with quarantined messages awaiting review. Once reached, further spam is rejected instead of quarantined.
+    pub msg_max_quarantined_threads: usize,
+    /// The number of leading zero bits required in the proof of work for creating a contact thread (0 to disable the challenge, at most
+    /// `contact::challenge::MAX_DIFFICULTY`). Each bit doubles the expected work.
+    pub msg_pow_difficulty: u32,
+    /// The SMTP relay (`host:port`) to email my replies to contact threads through, for users who've opted in. If unset, users can't opt in.
+    pub msg_smtp_relay: Option<String>,
 }
 impl Config {
     /// Loads the config from env vars.
@@ -85,9 +121,32 @@ impl Config {
                 "SSH_FIRST_TIMEOUT",
                 30,
             )?),
+            ssh_admin_user: Self::parse_var_default("SSH_ADMIN_USER", "admin".to_string())?,
+            ssh_admin_password_hash: match std::env::var("SSH_ADMIN_PASSWORD_HASH") {
+                Ok(hash) => Some(hash.trim().to_lowercase()),
+                Err(_) => {
+                    warn!("Missing SSH_ADMIN_PASSWORD_HASH env var, disabling ssh admin commands");
+                    None
+                }
+            },
+            ssh_max_sessions: Self::parse_var_default("SSH_MAX_SESSIONS", 100)?,
+            ssh_max_sessions_ip: Self::parse_var_default("SSH_MAX_SESSIONS_IP", 10)?,
+            ssh_max_auth_attempts: Self::parse_var_default("SSH_MAX_AUTH_ATTEMPTS", 30)?,
             gopher_port: Self::parse_var("GOPHER_PORT")?,
             qotd_port: Self::parse_var("QOTD_PORT")?,
             pop3_port: Self::parse_var("POP3_PORT")?,
+            max_connections: Self::parse_var_default("MAX_CONNECTIONS", 100)?,
+            max_connections_ip: Self::parse_var_default("MAX_CONNECTIONS_IP", 10)?,
+            smtp_port: match std::env::var("SMTP_PORT") {
+                Ok(port) => Some(
+                    port.parse()
+                        .map_err(|e| eyre!("Invalid SMTP_PORT env var: {e}"))?,
+                ),
+                Err(_) => {
+                    warn!("Missing SMTP_PORT env var, not receiving emailed replies");
+                    None
+                }
+            },
             watch_content: Self::parse_var_default("WATCH_CONTENT", false)?,
             live_reload: Self::parse_var_default("LIVE_RELOAD", false)?,
             show_hidden: Self::parse_var_default("SHOW_HIDDEN", false)?,
@@ -99,10 +158,41 @@ impl Config {
                 200,
             )?,
             msg_max_unread_threads_ip: Self::parse_var_default("MSG_MAX_UNREAD_THREADS_IP", 5)?,
+            msg_ipv4_prefix: Self::parse_var_default("MSG_IPV4_PREFIX", 32)?,
+            msg_ipv6_prefix: Self::parse_var_default("MSG_IPV6_PREFIX", 64)?,
+            msg_legacy_thread_ids: Self::parse_var_default("MSG_LEGACY_THREAD_IDS", true)?,
             msg_ignore_ip: std::env::var("MSG_IGNORE_IP")
                 .unwrap_or("false".to_string())
                 .parse()
                 .map_err(|e| eyre!("Invalid MSG_IGNORE_IP: {e}"))?,
+            msg_spam_threshold: Self::parse_var_default("MSG_SPAM_THRESHOLD", 1.0)?,
+            msg_spam_phrases: match std::env::var("MSG_SPAM_PHRASES") {
+                Ok(path) => std::fs::read_to_string(&path)
+                    .map_err(|e| eyre!("Invalid MSG_SPAM_PHRASES file {path}: {e}"))?
+                    .lines()
+                    .map(|line| line.trim().to_lowercase())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .collect(),
+                Err(_) => {
+                    warn!("Missing MSG_SPAM_PHRASES env var, not checking for spam phrases");
+                    vec![]
+                }
+            },
+            msg_max_quarantined_threads: Self::parse_var_default(
+                "MSG_MAX_QUARANTINED_THREADS",
+                1000,
+            )?,
+            msg_pow_difficulty: contact::challenge::check_difficulty(Self::parse_var_default(
+                "MSG_POW_DIFFICULTY",
+                0,
+            )?)?,
+            msg_smtp_relay: match std::env::var("MSG_SMTP_RELAY") {
+                Ok(relay) => Some(relay),
+                Err(_) => {
+                    warn!("Missing MSG_SMTP_RELAY env var, disabling replies by email");
+                    None
+                }
+            },
         })
     }
     /// Helper to load an env var, returning an error if it's missing or invalid
@@ -141,9 +231,17 @@ impl Config {
             ssh_port,
             ssh_timeout,
             ssh_first_timeout,
+            ssh_admin_user,
+            ssh_admin_password_hash,
+            ssh_max_sessions,
+            ssh_max_sessions_ip,
+            ssh_max_auth_attempts,
             gopher_port,
             qotd_port,
             pop3_port,
+            max_connections,
+            max_connections_ip,
+            smtp_port,
             watch_content,
             live_reload,
             show_hidden,
@@ -152,7 +250,15 @@ impl Config {
             msg_max_unread_messages,
             msg_max_unread_threads_global,
             msg_max_unread_threads_ip,
+            msg_ipv4_prefix,
+            msg_ipv6_prefix,
+            msg_legacy_thread_ids,
             msg_ignore_ip,
+            msg_spam_threshold,
+            msg_spam_phrases,
+            msg_max_quarantined_threads,
+            msg_pow_difficulty,
+            msg_smtp_relay,
             ssh_key: _,
         } = self;
         debug!("Config:");
@@ -161,9 +267,24 @@ impl Config {
         debug!("  SSH_PORT: {}", ssh_port);
         debug!("  SSH_TIMEOUT: {}", ssh_timeout.as_secs());
         debug!("  SSH_FIRST_TIMEOUT: {}", ssh_first_timeout.as_secs());
+        debug!("  SSH_ADMIN_USER: {}", ssh_admin_user);
+        debug!(
+            "  SSH_ADMIN_PASSWORD_HASH: {}",
+            if ssh_admin_password_hash.is_some() {
+                "set"
+            } else {
+                "unset"
+            }
+        );
+        debug!("  SSH_MAX_SESSIONS: {}", ssh_max_sessions);
+        debug!("  SSH_MAX_SESSIONS_IP: {}", ssh_max_sessions_ip);
+        debug!("  SSH_MAX_AUTH_ATTEMPTS: {}", ssh_max_auth_attempts);
         debug!("  GOPHER_PORT: {}", gopher_port);
         debug!("  QOTD_PORT: {}", qotd_port);
         debug!("  POP3_PORT: {}", pop3_port);
+        debug!("  MAX_CONNECTIONS: {}", max_connections);
+        debug!("  MAX_CONNECTIONS_IP: {}", max_connections_ip);
+        debug!("  SMTP_PORT: {:?}", smtp_port);
         debug!("  WATCH_CONTENT: {}", watch_content);
         debug!("  LIVE_RELOAD: {}", live_reload);
         debug!("  SHOW_HIDDEN: {}", show_hidden);
@@ -175,11 +296,65 @@ impl Config {
             msg_max_unread_threads_global
         );
         debug!("  MSG_MAX_UNREAD_THREADS_IP: {}", msg_max_unread_threads_ip);
+        debug!("  MSG_IPV4_PREFIX: {}", msg_ipv4_prefix);
+        debug!("  MSG_IPV6_PREFIX: {}", msg_ipv6_prefix);
+        debug!("  MSG_LEGACY_THREAD_IDS: {}", msg_legacy_thread_ids);
         debug!("  MSG_IGNORE_IP: {}", msg_ignore_ip);
+        debug!("  MSG_SPAM_THRESHOLD: {}", msg_spam_threshold);
+        debug!("  MSG_SPAM_PHRASES: {} phrases", msg_spam_phrases.len());
+        debug!(
+            "  MSG_MAX_QUARANTINED_THREADS: {}",
+            msg_max_quarantined_threads
+        );
+        debug!("  MSG_POW_DIFFICULTY: {}", msg_pow_difficulty);
+        debug!("  MSG_SMTP_RELAY: {:?}", msg_smtp_relay);
         debug!("End config.")
     }
 }
 
+/// A listener standing in for the SMTP relay (`CONFIG.msg_smtp_relay`) in tests, for them to accept connections on.
+#[cfg(test)]
+pub static TEST_SMTP_RELAY: Lazy<std::net::TcpListener> = Lazy::new(|| {
+    std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind test SMTP relay")
+});
+
+/// Sets the env vars `CONFIG` is loaded from to values for tests (with an in-memory messages database and `TEST_SMTP_RELAY` as the
+/// relay), before it's first used.
+#[cfg(test)]
+pub fn init_test_config() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let relay = TEST_SMTP_RELAY
+            .local_addr()
+            .expect("Test SMTP relay address");
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[1; 32]).expect("32 bytes");
+        let key = ed25519_dalek::Keypair {
+            public: (&secret).into(),
+            secret,
+        };
+        for (var, value) in [
+            ("DOMAIN", "example.org".to_string()),
+            ("HTTP_PORT", "0".to_string()),
+            ("SSH_PORT", "0".to_string()),
+            (
+                "SSH_KEY",
+                base64::engine::general_purpose::STANDARD.encode(key.to_bytes()),
+            ),
+            ("GOPHER_PORT", "0".to_string()),
+            ("QOTD_PORT", "0".to_string()),
+            ("POP3_PORT", "0".to_string()),
+            ("MSG_DATABASE", ":memory:".to_string()),
+            ("MSG_SMTP_RELAY", relay.to_string()),
+        ] {
+            std::env::set_var(var, value);
+        }
+    });
+    Lazy::force(&CONFIG);
+}
+
+/// How long services get to tell their clients the server is shutting down before they're stopped.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
 static CONTENT: RwLock<Content> = RwLock::new(Content {
     projects: Vec::new(),
     blog_posts: Vec::new(),
@@ -218,29 +393,61 @@ async fn main() -> Result<Infallible> {
     // Load initial content
     *CONTENT.write().unwrap() = Content::load().await.expect("Failed to load content");
 
-    // Create broadcast channel for notifying services of content changes
+    // Create broadcast channels for notifying services of content changes, and of the server shutting down
     let (tx, rx) = broadcast::channel(1);
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
 
     // Run all services
     let mut services = tokio::task::JoinSet::new();
+    services.spawn(search::main(rx.resubscribe()));
     services.spawn(html::main(rx.resubscribe()));
-    services.spawn(ssh::main(rx.resubscribe()));
+    services.spawn(ssh::main(rx.resubscribe(), shutdown_rx));
     services.spawn(gopher::main(rx.resubscribe()));
     services.spawn(qotd::main(rx.resubscribe()));
     services.spawn(pop3::main(rx.resubscribe()));
+    services.spawn(smtp::main());
     services.spawn(contact::main());
+    services.spawn(contact::email::main());
     services.spawn(watch_content(tx));
-    services.spawn(async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to listen for Ctrl-C");
-        Err(eyre!("Ctrl-C Received"))
+    services.spawn(async move {
+        let signal = shutdown_signal().await;
+        // Give services a moment to say goodbye before everything's stopped
+        info!("{signal} received, shutting down");
+        shutdown_tx.send(()).unwrap_or_else(|e| {
+            error!("No receivers for shutdown: {}", e);
+            0
+        });
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+        Err(eyre!("{signal} Received"))
     });
     let result = services.join_next().await.unwrap()?;
     services.shutdown().await;
     result
 }
 
+/// Waits for Ctrl-C (or SIGTERM, on Unix), returning which it was.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result.expect("Failed to listen for Ctrl-C");
+                "Ctrl-C"
+            }
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+        "Ctrl-C"
+    }
+}
+
 /// Watches for changes to the shared `Content` and updates the static variable as needed. On update, sends a message on
 /// a broadcast channel passed into this function.
 async fn watch_content(broadcast_tx: broadcast::Sender<()>) -> Result<Infallible> {
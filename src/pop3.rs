@@ -10,7 +10,7 @@ use tokio::{
 };
 use tracing::debug;
 
-use crate::Content;
+use crate::{limits::ConnectionLimiter, Content};
 
 /// Runs the POP server, updating the content on `update_rx`.
 pub async fn main(_update_rx: broadcast::Receiver<()>) -> Result<Infallible> {
@@ -18,10 +18,35 @@ pub async fn main(_update_rx: broadcast::Receiver<()>) -> Result<Infallible> {
     let content = Arc::new(content);
 
     let tcp_listener = TcpListener::bind(("0.0.0.0", crate::CONFIG.pop3_port)).await?;
+    let limiter = ConnectionLimiter::new(
+        crate::CONFIG.max_connections,
+        crate::CONFIG.max_connections_ip,
+    );
     loop {
-        let new_connection = tcp_listener.accept().await?;
-        debug!("New POP3 connection from {}", new_connection.1);
-        tokio::spawn(handle_connection(new_connection.0, Arc::clone(&content)));
+        let (mut stream, addr) = tcp_listener.accept().await?;
+        let connection = match limiter.admit(addr.ip()) {
+            Ok(connection) => connection,
+            Err(rejection) => {
+                // Say why in place of the greeting, which clients show
+                debug!("Rejected POP3 connection from {} ({})", addr, rejection);
+                let Some(permit) = limiter.rejection_permit() else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let error = format!("-ERR {}", rejection.message());
+                    let _ = stream.write_all(error.as_bytes()).await;
+                    drop(permit);
+                });
+                continue;
+            }
+        };
+        debug!("New POP3 connection from {}", addr);
+        let content = Arc::clone(&content);
+        tokio::spawn(async move {
+            let result = handle_connection(stream, content).await;
+            drop(connection);
+            result
+        });
     }
 }
 
@@ -5,18 +5,38 @@ use rand::seq::SliceRandom;
 use tokio::{io::AsyncWriteExt, net::TcpListener, sync::broadcast};
 use tracing::{error, info};
 
+use crate::limits::ConnectionLimiter;
+
 /// Runs the QOTD server, updating the content on `update_rx`.
 pub async fn main(mut update_rx: broadcast::Receiver<()>) -> Result<Infallible> {
     // The possible quotes to send (kept in an `Arc` for sending to handler threads)
     let mut possible_quotes = generate_quotes(&crate::CONTENT.read().unwrap())?;
     // Initialize listeners for quote requests (currently just TCP)
     let tcp_listener = TcpListener::bind(("0.0.0.0", crate::CONFIG.qotd_port)).await?;
+    let limiter = ConnectionLimiter::new(
+        crate::CONFIG.max_connections,
+        crate::CONFIG.max_connections_ip,
+    );
     // Handle quote requests and updates
     loop {
         tokio::select! {
             result = tcp_listener.accept() => {
                 // Handle new connection
                 let (mut stream, addr) = result?;
+                let connection = match limiter.admit(addr.ip()) {
+                    Ok(connection) => connection,
+                    Err(rejection) => {
+                        info!("Rejected QOTD request (TCP) from {} ({})", addr, rejection);
+                        let Some(permit) = limiter.rejection_permit() else {
+                            continue;
+                        };
+                        tokio::task::spawn(async move {
+                            let _ = stream.write_all(rejection.message().as_bytes()).await;
+                            drop(permit);
+                        });
+                        continue;
+                    }
+                };
                 info!("QOTD request (TCP) from {}", addr);
                 // Select quote
                 let quote = possible_quotes.choose(&mut rand::thread_rng()).unwrap().clone();
@@ -25,6 +45,7 @@ pub async fn main(mut update_rx: broadcast::Receiver<()>) -> Result<Infallible>
                     if let Err(e) = stream.write_all(quote.as_bytes()).await {
                         error!("Error sending QOTD to {}: {}", addr, e);
                     }
+                    drop(connection);
                 });
             }
             _ = update_rx.recv() => {
@@ -0,0 +1,761 @@
+//! Full-text search over the projects and blog posts, shared by all the frontends. The content's structured text is indexed in memory
+//! (as an inverted index from each word's stem to where it appears) whenever the content is loaded, so searching is just a few lookups.
+//!
+//! Queries are lists of words, all of which must appear (in any form with the same stem, e.g. "compile" finds "compiling"), with
+//! phrases in double quotes matching only those words in that order. Each result comes with a snippet of its text around the matches,
+//! with the matching words highlighted.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    ops::Range,
+    sync::{Arc, RwLock},
+};
+
+use color_eyre::{eyre, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::{blogpost, project};
+
+/// The maximum number of results given for a query.
+const MAX_RESULTS: usize = 20;
+/// The maximum length of a query (in bytes), beyond which it's cut off, to bound the work one search can take.
+const MAX_QUERY_LENGTH: usize = 200;
+/// The number of words in a snippet.
+const SNIPPET_WORDS: usize = 30;
+/// The number of words a snippet shows before the first match in it.
+const SNIPPET_LEAD: usize = 8;
+/// How much more a match in a title counts than one in the text.
+const TITLE_WEIGHT: f64 = 4.0;
+
+/// The current index. It's replaced whole when the content changes, so searches never wait on a rebuild.
+static INDEX: Lazy<RwLock<Arc<Index>>> = Lazy::new(Default::default);
+
+/// Keeps the search index up to date, indexing the content now and again whenever `update_rx` says it's changed.
+pub async fn main(mut update_rx: broadcast::Receiver<()>) -> Result<Infallible> {
+    loop {
+        let index = Index::new(&crate::CONTENT.read().unwrap());
+        info!(
+            "Indexed {} documents ({} terms) for search",
+            index.documents.len(),
+            index.postings.len()
+        );
+        *INDEX.write().unwrap() = Arc::new(index);
+        match update_rx.recv().await {
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Closed) => {
+                eyre::bail!("Global content change broadcast channel closed");
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                warn!("Search index lagging behind global content changes");
+            }
+        }
+    }
+}
+
+/// Searches the projects and blog posts, returning the best matches for the query (best first). Queries without any words give no
+/// results.
+pub fn search(query: &str) -> Vec<SearchResult> {
+    let index = Arc::clone(&INDEX.read().unwrap());
+    index.search(query)
+}
+
+/// What a search result is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Project,
+    BlogPost,
+}
+
+/// A project or blog post matching a query.
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchResult {
+    pub kind: Kind,
+    pub title: String,
+    /// The project or post's URL (as in `Project::url` and `BlogPost::url`).
+    pub url: String,
+    /// The path of the project or post on the website.
+    pub path: String,
+    pub date: String,
+    /// Some of the text around the matches, in pieces that are each either all highlighted or not. Empty if there's no text.
+    pub snippet: Vec<SnippetPart>,
+}
+
+/// A piece of a snippet, highlighted if it's part of a match.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlight: bool,
+}
+
+/// An inverted index of the content.
+#[derive(Default)]
+struct Index {
+    documents: Vec<Document>,
+    /// For each term, the documents it appears in (in order), along with the positions it appears at in each (in order).
+    postings: HashMap<String, Vec<(usize, Vec<u32>)>>,
+}
+impl Index {
+    /// Indexes all the projects and blog posts in the content.
+    fn new(content: &crate::Content) -> Self {
+        let mut builders = vec![];
+        for project in content.projects.iter() {
+            let mut builder =
+                DocumentBuilder::new(Kind::Project, &project.url, &project.name, &project.date);
+            builder.block(&project.description);
+            builder.block(&project.skills.skills.join(", "));
+            for section in project.content.sections.iter() {
+                match section {
+                    project::Section::Section { title, content } => {
+                        builder.block(title.as_deref().unwrap_or_default());
+                        builder.project_elements(content);
+                    }
+                    project::Section::Criteria { title, items } => {
+                        builder.block(title.as_deref().unwrap_or_default());
+                        for item in items.iter() {
+                            builder.block(&item.title);
+                            builder.block(&project_text(&item.description));
+                        }
+                    }
+                }
+            }
+            builders.push(builder);
+        }
+        for post in content.blog_posts.iter() {
+            let mut builder = DocumentBuilder::new(
+                Kind::BlogPost,
+                &post.url,
+                &post.title,
+                &post.date.date().to_string(),
+            );
+            builder.blog_elements(&post.content.content);
+            for (_, footnote) in post.content.footnotes.iter() {
+                builder.blog_elements(footnote);
+            }
+            builders.push(builder);
+        }
+        Self::from_documents(builders)
+    }
+
+    /// Indexes built documents.
+    fn from_documents(builders: Vec<DocumentBuilder>) -> Self {
+        let mut index = Self::default();
+        for (i, builder) in builders.into_iter().enumerate() {
+            // Terms are added in order of position, so each document's positions stay sorted
+            for (term, position) in builder.terms {
+                let postings = index.postings.entry(term).or_default();
+                match postings.last_mut() {
+                    Some((document, positions)) if *document == i => positions.push(position),
+                    _ => postings.push((i, vec![position])),
+                }
+            }
+            index.documents.push(builder.document);
+        }
+        index
+    }
+
+    /// Searches the index, returning the best `MAX_RESULTS` matches for the query, best first.
+    fn search(&self, query: &str) -> Vec<SearchResult> {
+        let clauses = parse_query(query);
+        if clauses.is_empty() {
+            return vec![];
+        }
+        let matches: Vec<HashMap<usize, Vec<u32>>> =
+            clauses.iter().map(|clause| self.matches(clause)).collect();
+
+        // Score the documents every clause matches, with rarer clauses and matches in the title counting for more
+        let mut scores: Vec<(f64, usize)> = matches[0]
+            .keys()
+            .filter(|document| matches.iter().all(|m| m.contains_key(document)))
+            .map(|&document| {
+                let title_words = self.documents[document].title_words;
+                let score = matches
+                    .iter()
+                    .map(|m| {
+                        let rarity = (1.0 + self.documents.len() as f64 / m.len() as f64).ln();
+                        let weight: f64 = m[&document]
+                            .iter()
+                            .map(|&position| {
+                                if position < title_words {
+                                    TITLE_WEIGHT
+                                } else {
+                                    1.0
+                                }
+                            })
+                            .sum();
+                        rarity * (1.0 + weight).ln()
+                    })
+                    .sum();
+                (score, document)
+            })
+            .collect();
+        scores.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        scores
+            .into_iter()
+            .take(MAX_RESULTS)
+            .map(|(_, document)| {
+                // Highlight every word of every match
+                let highlighted = clauses
+                    .iter()
+                    .zip(matches.iter())
+                    .flat_map(|(clause, m)| {
+                        m[&document]
+                            .iter()
+                            .flat_map(|&start| start..start + clause.len() as u32)
+                    })
+                    .collect();
+                self.documents[document].result(&highlighted)
+            })
+            .collect()
+    }
+
+    /// Finds where a clause (a list of terms that must appear in order) matches, as the position of the first term of each match in
+    /// each document that has any.
+    fn matches(&self, clause: &[String]) -> HashMap<usize, Vec<u32>> {
+        let Some(first) = self.postings.get(&clause[0]) else {
+            return HashMap::new();
+        };
+        first
+            .iter()
+            .filter_map(|(document, positions)| {
+                let starts: Vec<u32> = positions
+                    .iter()
+                    .copied()
+                    .filter(|&start| {
+                        clause[1..].iter().zip(start + 1..).all(|(term, position)| {
+                            self.positions(term, *document)
+                                .is_some_and(|positions| positions.binary_search(&position).is_ok())
+                        })
+                    })
+                    .collect();
+                (!starts.is_empty()).then_some((*document, starts))
+            })
+            .collect()
+    }
+
+    /// Gets the positions a term appears at in a document, if it does.
+    fn positions(&self, term: &str, document: usize) -> Option<&[u32]> {
+        let postings = self.postings.get(term)?;
+        let i = postings
+            .binary_search_by_key(&document, |(document, _)| *document)
+            .ok()?;
+        Some(&postings[i].1)
+    }
+}
+
+/// A project or blog post in the index.
+struct Document {
+    kind: Kind,
+    title: String,
+    url: String,
+    date: String,
+    /// All the text but the title, with each block (a paragraph, heading, etc.) on its own line.
+    text: String,
+    /// The position and byte range in `text` of each word of the text. The title's words come first, at positions before
+    /// `title_words` (though they aren't listed here), and each block is one position after the last so phrases can't span them.
+    words: Vec<(u32, Range<usize>)>,
+    /// The number of words in the title.
+    title_words: u32,
+}
+impl Document {
+    /// Makes a search result for this document, with a snippet of the part of its text with the most highlighted words (or the
+    /// start, if only its title matched).
+    fn result(&self, highlighted: &HashSet<u32>) -> SearchResult {
+        // Start the snippet a little before a match, choosing the one that fits the most matches in the snippet (and then the first)
+        let matched: Vec<usize> = self
+            .words
+            .iter()
+            .enumerate()
+            .filter(|(_, (position, _))| highlighted.contains(position))
+            .map(|(i, _)| i)
+            .collect();
+        let start = matched
+            .iter()
+            .map(|&i| i.saturating_sub(SNIPPET_LEAD))
+            .max_by_key(|&start| {
+                let window = start..start + SNIPPET_WORDS;
+                let count = matched.iter().filter(|i| window.contains(i)).count();
+                (count, Reverse(start))
+            })
+            .unwrap_or(0);
+        let end = (start + SNIPPET_WORDS).min(self.words.len());
+
+        // Add the words and the text between them, with whitespace collapsed since blocks are on separate lines
+        let mut snippet = vec![];
+        if start > 0 {
+            push_snippet(&mut snippet, "…", false);
+        }
+        for i in start..end {
+            let (position, range) = &self.words[i];
+            let highlight = highlighted.contains(position);
+            if i > start {
+                let (previous_position, previous_range) = &self.words[i - 1];
+                push_snippet(
+                    &mut snippet,
+                    &collapse_whitespace(&self.text[previous_range.end..range.start]),
+                    highlight && highlighted.contains(previous_position),
+                );
+            }
+            push_snippet(&mut snippet, &self.text[range.clone()], highlight);
+        }
+        if let Some((_, last)) = self.words[start..end].last() {
+            // Finish with any punctuation after the last word
+            let rest = &self.text[last.end..];
+            push_snippet(
+                &mut snippet,
+                rest.split(char::is_whitespace).next().unwrap_or_default(),
+                false,
+            );
+        }
+        if end < self.words.len() {
+            push_snippet(&mut snippet, "…", false);
+        }
+
+        SearchResult {
+            kind: self.kind,
+            title: self.title.clone(),
+            url: self.url.clone(),
+            path: match self.kind {
+                Kind::Project => format!("/projects/{}", self.url),
+                Kind::BlogPost => format!("/blog/{}", self.url),
+            },
+            date: self.date.clone(),
+            snippet,
+        }
+    }
+}
+
+/// Builds up a `Document` a block of text at a time, along with the terms to index it under.
+struct DocumentBuilder {
+    document: Document,
+    /// The term of each word of the document (including its title), with its position.
+    terms: Vec<(String, u32)>,
+    /// The position of the next block.
+    next_position: u32,
+}
+impl DocumentBuilder {
+    fn new(kind: Kind, url: &str, title: &str, date: &str) -> Self {
+        let terms: Vec<(String, u32)> = words(title)
+            .into_iter()
+            .zip(0..)
+            .map(|((term, _), position)| (term, position))
+            .collect();
+        let title_words = terms.len() as u32;
+        Self {
+            document: Document {
+                kind,
+                title: title.to_string(),
+                url: url.to_string(),
+                date: date.to_string(),
+                text: String::new(),
+                words: vec![],
+                title_words,
+            },
+            terms,
+            next_position: title_words + 1,
+        }
+    }
+
+    /// Adds a block of text (e.g. a paragraph) to the document.
+    fn block(&mut self, text: &str) {
+        let words = words(text);
+        if words.is_empty() {
+            return;
+        }
+        let offset = self.document.text.len();
+        for ((term, range), position) in words.into_iter().zip(self.next_position..) {
+            self.terms.push((term, position));
+            self.document
+                .words
+                .push((position, range.start + offset..range.end + offset));
+        }
+        // Leave a gap before the next block
+        self.next_position = self.terms.last().map_or(0, |(_, position)| position + 2);
+        self.document.text.push_str(text);
+        self.document.text.push('\n');
+    }
+
+    /// Adds the text of a project's elements, with each paragraph, image description and caption as a block.
+    fn project_elements(&mut self, elements: &[project::Element]) {
+        for element in elements.iter() {
+            match element {
+                project::Element::Group { content } | project::Element::Gallery { content } => {
+                    self.project_elements(content)
+                }
+                project::Element::Paragraph(text) => self.block(&project_text(text)),
+                project::Element::Image { alt, caption, .. } => {
+                    self.block(alt);
+                    if let Some(caption) = caption {
+                        self.block(&project_text(caption));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the text of a blog post's elements, with each paragraph, heading and code block as a block.
+    fn blog_elements(&mut self, elements: &[blogpost::Element]) {
+        for element in elements.iter() {
+            match element {
+                blogpost::Element::Paragraph { text } | blogpost::Element::Heading { text, .. } => {
+                    self.block(&inline_text(text))
+                }
+                blogpost::Element::Code { content, .. } => self.block(content),
+                blogpost::Element::Footnote { body, .. } => self.blog_elements(body),
+            }
+        }
+    }
+}
+
+/// Gets the plain text of some project text, with links replaced by their text.
+fn project_text(text: &project::Text) -> String {
+    fn element_text(element: &project::TextElement) -> String {
+        match element {
+            project::TextElement::Link {
+                leading_space,
+                trailing_space,
+                text,
+                ..
+            } => format!(
+                "{leading_space}{}{trailing_space}",
+                text.iter().map(element_text).collect::<String>()
+            ),
+            project::TextElement::Text(text) => text.clone(),
+        }
+    }
+    text.text.iter().map(element_text).collect()
+}
+
+/// Gets the plain text of some blog post text, with links replaced by their text and images by their descriptions.
+fn inline_text(text: &[blogpost::InlineElement]) -> String {
+    use blogpost::InlineElement;
+    text.iter()
+        .map(|element| match element {
+            InlineElement::Text { content } | InlineElement::InlineCode { content } => {
+                content.clone()
+            }
+            InlineElement::Emph { text }
+            | InlineElement::Strong { text }
+            | InlineElement::Link { text, .. } => inline_text(text),
+            InlineElement::FootnoteRef { .. } => String::new(),
+            InlineElement::Image { alt, .. } => alt.clone(),
+        })
+        .collect()
+}
+
+/// Parses a query into clauses, each a list of terms that must appear in order: one for each quoted phrase, and one for each other
+/// word. Repeated clauses are only kept once.
+fn parse_query(query: &str) -> Vec<Vec<String>> {
+    let end = (0..=query.len().min(MAX_QUERY_LENGTH))
+        .rev()
+        .find(|&i| query.is_char_boundary(i))
+        .unwrap_or(0);
+    let mut clauses: Vec<Vec<String>> = vec![];
+    // Every other part is in quotes (with an unclosed quote running to the end)
+    for (i, part) in query[..end].split('"').enumerate() {
+        let terms = words(part).into_iter().map(|(term, _)| term);
+        let new: Vec<Vec<String>> = if i % 2 == 1 {
+            vec![terms.collect()]
+        } else {
+            terms.map(|term| vec![term]).collect()
+        };
+        for clause in new {
+            if !clause.is_empty() && !clauses.contains(&clause) {
+                clauses.push(clause);
+            }
+        }
+    }
+    clauses
+}
+
+/// Splits text into words (runs of letters and numbers, along with any apostrophes within them), returning each word's term (see
+/// `term`) and byte range.
+fn words(text: &str) -> Vec<(String, Range<usize>)> {
+    let mut result = vec![];
+    let mut start = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let in_word = c.is_alphanumeric()
+            || (start.is_some()
+                && matches!(c, '\'' | '’')
+                && chars.peek().is_some_and(|(_, next)| next.is_alphanumeric()));
+        match (in_word, start) {
+            (true, None) => start = Some(i),
+            (false, Some(word_start)) => {
+                result.push((term(&text[word_start..i]), word_start..i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(word_start) = start {
+        result.push((term(&text[word_start..]), word_start..text.len()));
+    }
+    result
+}
+
+/// Gets the term a word is indexed under: lowercased, without apostrophes, and stemmed.
+fn term(word: &str) -> String {
+    let word: String = word
+        .to_lowercase()
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '’'))
+        .collect();
+    stem(&word)
+}
+
+/// Suffixes removed (or replaced) by `stem`, with longer ones first so they're used over shorter ones they end with.
+const SUFFIXES: &[(&str, &str)] = &[
+    ("ational", "ate"),
+    ("fulness", "ful"),
+    ("iveness", "ive"),
+    ("ousness", "ous"),
+    ("ization", "ize"),
+    ("ation", "ate"),
+    ("ingly", ""),
+    ("edly", ""),
+    ("ness", ""),
+    ("ment", ""),
+    ("ing", ""),
+    ("ed", ""),
+    ("er", ""),
+    ("ly", ""),
+];
+
+/// Reduces a (lowercase) word to its stem with a much-simplified Porter stemmer, so different forms of a word match each other (e.g.
+/// "compiles", "compiled" and "compiling" all become "compil"). Stems don't need to be real words, just the same for related ones.
+/// Anything but plain ASCII letters (like numbers) is left alone.
+fn stem(word: &str) -> String {
+    fn is_vowel(c: u8) -> bool {
+        matches!(c, b'a' | b'e' | b'i' | b'o' | b'u' | b'y')
+    }
+    if !word.bytes().all(|c| c.is_ascii_lowercase()) {
+        return word.to_string();
+    }
+    let mut word = word.to_string();
+
+    // Plurals
+    if word.ends_with("sses") || word.ends_with("ies") {
+        word.truncate(word.len() - 2);
+    } else if word.len() > 3
+        && word.ends_with('s')
+        && !word.ends_with("ss")
+        && !word.ends_with("us")
+        && !word.ends_with("is")
+    {
+        word.pop();
+    }
+
+    // Other suffixes, as long as they leave a reasonable stem
+    if let Some((suffix, replacement)) = SUFFIXES.iter().find(|(suffix, _)| word.ends_with(suffix))
+    {
+        let stem = &word[..word.len() - suffix.len()];
+        if stem.len() >= 3 && stem.bytes().any(is_vowel) {
+            word = format!("{stem}{replacement}");
+        }
+    }
+
+    // Endings that change with suffixes ("make"/"making", "happy"/"happiness", "run"/"running")
+    if word.len() > 3 && word.ends_with('e') {
+        word.pop();
+    }
+    if word.len() > 3 && word.ends_with('y') {
+        word.pop();
+        word.push('i');
+    }
+    if let [.., a, b] = word.as_bytes() {
+        if word.len() > 3 && a == b && !is_vowel(*a) && !matches!(a, b'l' | b's' | b'z') {
+            word.pop();
+        }
+    }
+    word
+}
+
+/// Adds some text to a snippet, joining it onto the last part if that's highlighted the same.
+fn push_snippet(snippet: &mut Vec<SnippetPart>, text: &str, highlight: bool) {
+    if text.is_empty() {
+        return;
+    }
+    match snippet.last_mut() {
+        Some(last) if last.highlight == highlight => last.text.push_str(text),
+        _ => snippet.push(SnippetPart {
+            text: text.to_string(),
+            highlight,
+        }),
+    }
+}
+
+/// Replaces each run of whitespace in some text with a single space.
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::new();
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            space = true;
+        } else {
+            if space {
+                result.push(' ');
+                space = false;
+            }
+            result.push(c);
+        }
+    }
+    if space {
+        result.push(' ');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Indexes blog posts with the given titles and blocks of text.
+    fn index(documents: &[(&str, &[&str])]) -> Index {
+        Index::from_documents(
+            documents
+                .iter()
+                .enumerate()
+                .map(|(i, (title, blocks))| {
+                    let mut builder =
+                        DocumentBuilder::new(Kind::BlogPost, &i.to_string(), title, "2024-01-01");
+                    for block in blocks.iter() {
+                        builder.block(block);
+                    }
+                    builder
+                })
+                .collect(),
+        )
+    }
+
+    /// Searches an index, returning the URLs of the results in order.
+    fn urls(index: &Index, query: &str) -> Vec<String> {
+        index
+            .search(query)
+            .into_iter()
+            .map(|result| result.url)
+            .collect()
+    }
+
+    fn clauses(clauses: &[&[&str]]) -> Vec<Vec<String>> {
+        clauses
+            .iter()
+            .map(|clause| clause.iter().map(|term| term.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn queries_have_words_and_phrases() {
+        assert_eq!(
+            parse_query(r#"Compiling "flight computer" rust"#),
+            clauses(&[&["compil"], &["flight", "comput"], &["rust"]])
+        );
+        // An unclosed quote runs to the end
+        assert_eq!(
+            parse_query(r#"rust "flight computer"#),
+            clauses(&[&["rust"], &["flight", "comput"]])
+        );
+        // Repeats are dropped, and a one-word phrase is just the word
+        assert_eq!(
+            parse_query(r#"rust Rust "rust" rusts"#),
+            clauses(&[&["rust"]])
+        );
+        assert!(parse_query("").is_empty());
+        assert!(parse_query(r#"  "" "!?" - "#).is_empty());
+    }
+
+    #[test]
+    fn queries_are_cut_off_at_a_char_boundary() {
+        let long = format!("{} rust", "a".repeat(MAX_QUERY_LENGTH));
+        assert_eq!(parse_query(&long), vec![vec!["a".repeat(MAX_QUERY_LENGTH)]]);
+
+        // A multi-byte character across the limit is dropped whole
+        let straddling = format!("{}é", "a".repeat(MAX_QUERY_LENGTH - 1));
+        assert_eq!(
+            parse_query(&straddling),
+            vec![vec!["a".repeat(MAX_QUERY_LENGTH - 1)]]
+        );
+        let wide = "é".repeat(MAX_QUERY_LENGTH);
+        assert_eq!(
+            parse_query(&wide),
+            vec![vec!["é".repeat(MAX_QUERY_LENGTH / 2)]]
+        );
+    }
+
+    #[test]
+    fn words_are_stemmed() {
+        for (a, b) in [
+            ("compile", "compiling"),
+            ("compiles", "compiled"),
+            ("happy", "happiness"),
+            ("run", "running"),
+            ("classes", "class"),
+            ("Don't", "dont"),
+        ] {
+            assert_eq!(term(a), term(b), "{a} and {b}");
+        }
+        assert_ne!(term("bus"), term("bu"));
+        assert_eq!(term("42"), "42");
+        assert_eq!(
+            words("it's rock 'n' roll, c3po!"),
+            vec![
+                ("its".to_string(), 0..4),
+                ("rock".to_string(), 5..9),
+                ("n".to_string(), 11..12),
+                ("roll".to_string(), 14..18),
+                ("c3po".to_string(), 20..24),
+            ]
+        );
+    }
+
+    #[test]
+    fn phrases_match_words_in_order() {
+        let index = index(&[
+            ("Post", &["The flight computer runs Rust."]),
+            ("Post", &["A computer for flight."]),
+            ("Post", &["Learning to fly a flight", "computer"]),
+            ("Post", &["Nothing to see here."]),
+        ]);
+        assert_eq!(urls(&index, "flight computer"), ["0", "1", "2"]);
+        // Phrases can't be out of order, or span blocks
+        assert_eq!(urls(&index, r#""flight computer""#), ["0"]);
+        assert_eq!(urls(&index, r#""flight computers" rust"#), ["0"]);
+        // Every clause must match
+        assert!(urls(&index, r#""flight computer" fly"#).is_empty());
+        assert!(urls(&index, "").is_empty());
+    }
+
+    #[test]
+    fn title_matches_rank_higher() {
+        let index = index(&[
+            ("Rockets", &["I built a flight computer."]),
+            ("My flight computer", &["It flies."]),
+        ]);
+        assert_eq!(urls(&index, "flight computer"), ["1", "0"]);
+        assert_eq!(urls(&index, "rocket"), ["0"]);
+    }
+
+    #[test]
+    fn snippets_highlight_matches() {
+        let filler = "word ".repeat(SNIPPET_WORDS);
+        let text = format!("{filler}the flight computer, which flies {filler}");
+        let index = index(&[("Post", &[&text])]);
+        let result = &index.search(r#""flight computer""#)[0];
+        let parts: Vec<_> = result
+            .snippet
+            .iter()
+            .map(|part| (part.text.as_str(), part.highlight))
+            .collect();
+        // The snippet starts a little before the match, and the text goes on either side of it
+        assert!(parts[0].0.starts_with("…word") && parts[0].0.ends_with("the "));
+        assert!(parts.last().unwrap().0.ends_with("word…"));
+        assert!(parts.contains(&("flight computer", true)));
+        assert_eq!(parts.iter().filter(|(_, highlight)| *highlight).count(), 1);
+    }
+}
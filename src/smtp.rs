@@ -0,0 +1,588 @@
+//! Implements (a small subset of) SMTP, to receive users' email replies to contact threads (see `contact::email`).
+//!
+//! Only mail to reply addresses we've handed out is accepted, so this is never an open relay. Meant to sit behind a real MTA that
+//! forwards `reply+*@{domain}` here, as we don't support TLS or any extensions.
+
+use std::{convert::Infallible, time::Duration};
+
+use color_eyre::Result;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::debug;
+
+use crate::{
+    contact::email::{self, ReplyAddress},
+    limits::ConnectionLimiter,
+};
+
+/// The maximum size of an email's data (in bytes), well above `CONFIG.msg_max_size` to allow for headers, encoding and quoted text.
+const MAX_DATA_SIZE: usize = 1 << 20;
+/// The maximum length of a command or data line (in bytes), per RFC 5321.
+const MAX_LINE_LENGTH: u64 = 1000;
+/// The maximum number of recipients for one email.
+const MAX_RECIPIENTS: usize = 10;
+/// How long to wait for each command before closing the connection, per RFC 5321.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Runs the SMTP server forever. Does nothing if `CONFIG.smtp_port` isn't set.
+pub async fn main() -> Result<Infallible> {
+    let Some(port) = crate::CONFIG.smtp_port else {
+        // Not receiving email, so just stop task (sleeping forever, as in `watch_path`)
+        return Ok(futures::future::pending::<Infallible>().await);
+    };
+
+    let tcp_listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let limiter = ConnectionLimiter::new(
+        crate::CONFIG.max_connections,
+        crate::CONFIG.max_connections_ip,
+    );
+    loop {
+        let (mut stream, addr) = tcp_listener.accept().await?;
+        let connection = match limiter.admit(addr.ip()) {
+            Ok(connection) => connection,
+            Err(rejection) => {
+                // Say why in place of the greeting, so the sending MTA tries again later
+                debug!("Rejected SMTP connection from {} ({})", addr, rejection);
+                let Some(permit) = limiter.rejection_permit() else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let error = format!("421 {}", rejection.message());
+                    let _ = stream.write_all(error.as_bytes()).await;
+                    drop(permit);
+                });
+                continue;
+            }
+        };
+        debug!("New SMTP connection from {}", addr);
+        tokio::spawn(async move {
+            let result = handle_connection(stream).await;
+            drop(connection);
+            result
+        });
+    }
+}
+
+/// Handles one SMTP connection.
+async fn handle_connection(mut connection: TcpStream) -> Result<()> {
+    // Split connection to get `BufReader`
+    let (reader, mut writer) = connection.split();
+    let mut reader = BufReader::new(reader);
+
+    // Macro to get the next line from stream, closing the connection on timeout or EOF
+    macro_rules! get_line {
+        () => {
+            match tokio::time::timeout(COMMAND_TIMEOUT, read_line(&mut reader)).await {
+                Ok(line) => match line? {
+                    Some(line) => line,
+                    None => return Ok(()),
+                },
+                Err(_) => {
+                    writer.write_all(b"421 Timeout\r\n").await?;
+                    return Ok(());
+                }
+            }
+        };
+    }
+
+    // Send greeting, then handle commands (tracking the current email's sender and recipients)
+    let domain = &crate::CONFIG.domain;
+    writer
+        .write_all(format!("220 {domain} SMTP ready\r\n").as_bytes())
+        .await?;
+    let mut sender = None;
+    let mut recipients = Vec::new();
+    loop {
+        match SmtpCommand::new(&get_line!()) {
+            SmtpCommand::Hello => {
+                writer
+                    .write_all(format!("250 {domain}\r\n").as_bytes())
+                    .await?;
+            }
+            SmtpCommand::Mail(from) => {
+                sender = Some(from);
+                recipients.clear();
+                writer.write_all(b"250 OK\r\n").await?;
+            }
+            SmtpCommand::Recipient(_) if sender.is_none() => {
+                writer.write_all(b"503 Need MAIL first\r\n").await?;
+            }
+            SmtpCommand::Recipient(_) if recipients.len() >= MAX_RECIPIENTS => {
+                writer.write_all(b"452 Too many recipients\r\n").await?;
+            }
+            SmtpCommand::Recipient(to) => match ReplyAddress::parse(&to) {
+                Some(address) => {
+                    recipients.push(address);
+                    writer.write_all(b"250 OK\r\n").await?;
+                }
+                None => {
+                    writer.write_all(b"550 No such mailbox\r\n").await?;
+                }
+            },
+            SmtpCommand::Data if recipients.is_empty() => {
+                writer.write_all(b"503 Need RCPT first\r\n").await?;
+            }
+            SmtpCommand::Data => {
+                writer
+                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                    .await?;
+
+                // Read data up to the terminating `.`, un-stuffing lines starting with `.` and discarding everything past the size limit
+                let mut data = String::new();
+                let mut too_large = false;
+                loop {
+                    let line = get_line!();
+                    if line == "." {
+                        break;
+                    }
+                    let line = line.strip_prefix('.').unwrap_or(&line);
+                    if data.len() + line.len() > MAX_DATA_SIZE {
+                        too_large = true;
+                    } else {
+                        data.push_str(line);
+                        data.push('\n');
+                    }
+                }
+
+                // Send reply on each recipient's thread, reporting the first error (if any)
+                let reply = if too_large {
+                    "552 Message too large".to_string()
+                } else {
+                    match email::extract_reply(&data) {
+                        None => "550 No plain text reply found".to_string(),
+                        Some(reply) => {
+                            let mut result = "250 OK".to_string();
+                            for address in recipients.drain(..) {
+                                if let Err(e) = email::receive(address, reply.clone()).await {
+                                    debug!("Rejected emailed reply: {e}");
+                                    result = format!("550 Error sending message: {e}");
+                                    break;
+                                }
+                            }
+                            result
+                        }
+                    }
+                };
+                writer.write_all(format!("{reply}\r\n").as_bytes()).await?;
+                sender = None;
+                recipients.clear();
+            }
+            SmtpCommand::Reset => {
+                sender = None;
+                recipients.clear();
+                writer.write_all(b"250 OK\r\n").await?;
+            }
+            SmtpCommand::Noop => {
+                writer.write_all(b"250 OK\r\n").await?;
+            }
+            SmtpCommand::Quit => {
+                writer.write_all(b"221 Bye\r\n").await?;
+                return Ok(());
+            }
+            SmtpCommand::Invalid => {
+                writer.write_all(b"502 Command not implemented\r\n").await?;
+            }
+        }
+    }
+}
+
+/// Reads a line (of at most `MAX_LINE_LENGTH` bytes, with any more returned as the next line), without its line ending. Returns `None`
+/// on EOF.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)
+        .await?
+        == 0
+    {
+        return Ok(None);
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// All supported SMTP commands, able to be parsed from a string.
+enum SmtpCommand {
+    /// `HELO` or `EHLO` (we don't support any extensions, so these are the same).
+    Hello,
+    /// `MAIL FROM:<address>`.
+    Mail(String),
+    /// `RCPT TO:<address>`.
+    Recipient(String),
+    Data,
+    Reset,
+    Noop,
+    Quit,
+    /// An invalid or unsupported command.
+    Invalid,
+}
+impl SmtpCommand {
+    fn new(line: &str) -> Self {
+        // Get the address from a `FROM:<address>` or `TO:<address>` argument, ignoring any parameters after it
+        let address = |prefix: &str| {
+            let rest = line.get(prefix.len()..)?;
+            let rest = rest.trim_start().strip_prefix('<')?;
+            rest.split_once('>').map(|(address, _)| address.to_string())
+        };
+        let upper = line.to_uppercase();
+        let command = upper.split_whitespace().next().unwrap_or_default();
+        match command {
+            "HELO" | "EHLO" => SmtpCommand::Hello,
+            "MAIL" if upper.starts_with("MAIL FROM:") => address("MAIL FROM:")
+                .map(SmtpCommand::Mail)
+                .unwrap_or(SmtpCommand::Invalid),
+            "RCPT" if upper.starts_with("RCPT TO:") => address("RCPT TO:")
+                .map(SmtpCommand::Recipient)
+                .unwrap_or(SmtpCommand::Invalid),
+            "DATA" => SmtpCommand::Data,
+            "RSET" => SmtpCommand::Reset,
+            "NOOP" => SmtpCommand::Noop,
+            "QUIT" => SmtpCommand::Quit,
+            _ => SmtpCommand::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    use super::*;
+    use crate::contact::{self, MessageSendError};
+
+    /// The client's end of an SMTP session, handled by `handle_connection`.
+    struct Client {
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    }
+    impl Client {
+        /// Starts a new session, checking the greeting.
+        async fn connect() -> Self {
+            crate::init_test_config();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                handle_connection(stream).await
+            });
+            let (reader, writer) = TcpStream::connect(address).await.unwrap().into_split();
+            let mut client = Self {
+                reader: BufReader::new(reader),
+                writer,
+            };
+            assert_eq!(client.reply().await, "220 example.org SMTP ready");
+            client
+        }
+
+        /// Reads the next reply line.
+        async fn reply(&mut self) -> String {
+            read_line(&mut self.reader).await.unwrap().unwrap()
+        }
+
+        /// Sends a line, returning the reply.
+        async fn send(&mut self, line: &str) -> String {
+            self.writer
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+            self.reply().await
+        }
+
+        /// Sends an email's data (after `DATA`), returning the reply once it's finished.
+        async fn send_data(&mut self, lines: &[&str]) -> String {
+            for line in lines {
+                self.writer
+                    .write_all(format!("{line}\r\n").as_bytes())
+                    .await
+                    .unwrap();
+            }
+            self.send(".").await
+        }
+    }
+
+    /// A well-formed reply address (that isn't valid for any thread).
+    const REPLY_ADDRESS: &str =
+        "reply+0123456789abcdef.00112233445566778899aabbccddeeff@example.org";
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(
+            SmtpCommand::new("HELO mx.example.com"),
+            SmtpCommand::Hello
+        ));
+        assert!(matches!(
+            SmtpCommand::new("ehlo mx.example.com"),
+            SmtpCommand::Hello
+        ));
+        assert!(matches!(
+            SmtpCommand::new("MAIL FROM:<someone@example.com> SIZE=1000"),
+            SmtpCommand::Mail(from) if from == "someone@example.com"
+        ));
+        assert!(matches!(
+            SmtpCommand::new("mail from: <>"),
+            SmtpCommand::Mail(from) if from.is_empty()
+        ));
+        assert!(matches!(
+            SmtpCommand::new("RCPT TO:<Reply+1.2@Example.org>"),
+            SmtpCommand::Recipient(to) if to == "Reply+1.2@Example.org"
+        ));
+        assert!(matches!(SmtpCommand::new("DATA"), SmtpCommand::Data));
+        assert!(matches!(SmtpCommand::new("rset"), SmtpCommand::Reset));
+        assert!(matches!(SmtpCommand::new("NOOP"), SmtpCommand::Noop));
+        assert!(matches!(SmtpCommand::new("QUIT"), SmtpCommand::Quit));
+        for line in [
+            "",
+            "VRFY someone",
+            "MAIL someone@example.com",
+            "MAIL FROM:someone@example.com",
+            "RCPT TO:<unterminated",
+            "STARTTLS",
+        ] {
+            assert!(
+                matches!(SmtpCommand::new(line), SmtpCommand::Invalid),
+                "{line:?} should be invalid"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_in_order() {
+        let mut client = Client::connect().await;
+        assert_eq!(client.send("EHLO mx.example.com").await, "250 example.org");
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "503 Need MAIL first"
+        );
+        assert_eq!(client.send("DATA").await, "503 Need RCPT first");
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        assert_eq!(client.send("DATA").await, "503 Need RCPT first");
+        assert_eq!(
+            client.send("RCPT TO:<someone@example.org>").await,
+            "550 No such mailbox"
+        );
+        assert_eq!(
+            client
+                .send("RCPT TO:<reply+0123456789abcdef.00@example.com>")
+                .await,
+            "550 No such mailbox"
+        );
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "250 OK"
+        );
+
+        // Resetting (or starting another email) forgets the recipients
+        assert_eq!(client.send("RSET").await, "250 OK");
+        assert_eq!(client.send("DATA").await, "503 Need RCPT first");
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        assert_eq!(client.send("DATA").await, "503 Need RCPT first");
+
+        assert_eq!(client.send("NOOP").await, "250 OK");
+        assert_eq!(
+            client.send("VRFY someone").await,
+            "502 Command not implemented"
+        );
+        assert_eq!(client.send("QUIT").await, "221 Bye");
+        assert_eq!(read_line(&mut client.reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn recipient_limit() {
+        let mut client = Client::connect().await;
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        for _ in 0..MAX_RECIPIENTS {
+            assert_eq!(
+                client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+                "250 OK"
+            );
+        }
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "452 Too many recipients"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_data() {
+        let mut client = Client::connect().await;
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send("DATA").await,
+            "354 End data with <CR><LF>.<CR><LF>"
+        );
+        assert_eq!(
+            client
+                .send_data(&["Content-Type: text/html", "", "<p>Hi</p>"])
+                .await,
+            "550 No plain text reply found"
+        );
+
+        // The email is finished either way, so the next needs its own sender and recipients
+        assert_eq!(client.send("DATA").await, "503 Need RCPT first");
+        assert_eq!(
+            client.send("MAIL FROM:<someone@example.com>").await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{REPLY_ADDRESS}>")).await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send("DATA").await,
+            "354 End data with <CR><LF>.<CR><LF>"
+        );
+        let line = "a".repeat(MAX_LINE_LENGTH as usize - 2);
+        let lines = vec![line.as_str(); MAX_DATA_SIZE / line.len() + 1];
+        assert_eq!(client.send_data(&lines).await, "552 Message too large");
+        assert_eq!(client.send("NOOP").await, "250 OK");
+    }
+
+    /// Acts as the SMTP relay for one email, returning its data.
+    async fn relay_email() -> String {
+        let relay = crate::TEST_SMTP_RELAY.try_clone().unwrap();
+        relay.set_nonblocking(true).unwrap();
+        let (mut stream, _) = TcpListener::from_std(relay)
+            .unwrap()
+            .accept()
+            .await
+            .unwrap();
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"220 relay ready\r\n").await.unwrap();
+        let mut data = String::new();
+        while let Some(line) = read_line(&mut reader).await.unwrap() {
+            let reply = match SmtpCommand::new(&line) {
+                SmtpCommand::Data => {
+                    writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    while let Some(line) = read_line(&mut reader).await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    "250 OK"
+                }
+                SmtpCommand::Quit => break,
+                _ => "250 OK",
+            };
+            writer
+                .write_all(format!("{reply}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn reply_lands_in_thread() {
+        const ADDRESS: &str = "someone@example.com";
+        crate::init_test_config();
+        tokio::spawn(contact::main());
+
+        // Start a thread once the database is ready
+        let token = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match contact::create_thread(
+                    [127, 0, 0, 1].into(),
+                    "Hello! This is a test message.".to_string(),
+                    None,
+                )
+                .await
+                {
+                    Err(MessageSendError::DatabaseError) => {
+                        tokio::time::sleep(Duration::from_millis(10)).await
+                    }
+                    result => break result.unwrap(),
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        // Set and confirm an email address for it, with the link from the confirmation email
+        let confirmation = tokio::spawn(relay_email());
+        contact::set_email(token, Some(ADDRESS.to_string()))
+            .await
+            .unwrap();
+        let confirmation = confirmation.await.unwrap();
+        let (_, body) = confirmation.split_once("\n\n").unwrap();
+        let body = base64::engine::general_purpose::STANDARD
+            .decode(body.split_whitespace().collect::<String>())
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        let (_, code) = body
+            .lines()
+            .find_map(|line| line.split_once("/api/message/confirm/"))
+            .unwrap();
+        contact::confirm_email(code.to_string()).await.unwrap();
+
+        // Reply by email, as if to the email my reply would be sent in
+        let (selector, _) = code.split_once('.').unwrap();
+        let reply_to = email::reply_address(u64::from_str_radix(selector, 16).unwrap(), ADDRESS);
+        let mut client = Client::connect().await;
+        assert_eq!(client.send("HELO mx.example.com").await, "250 example.org");
+        assert_eq!(
+            client.send(&format!("MAIL FROM:<{ADDRESS}>")).await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send(&format!("RCPT TO:<{reply_to}>")).await,
+            "250 OK"
+        );
+        assert_eq!(
+            client.send("DATA").await,
+            "354 End data with <CR><LF>.<CR><LF>"
+        );
+        let reply = client
+            .send_data(&[
+                &format!("From: <{ADDRESS}>"),
+                "Subject: Re: Reply to your message on example.org",
+                "",
+                "Thanks for the reply!",
+                "..and a line starting with a dot",
+                "",
+                "On Mon, Jan 1, 2024 at 9:00 AM Fletch Rydell wrote:",
+                "> My reply",
+            ])
+            .await;
+        assert_eq!(reply, "250 OK");
+
+        let messages = contact::get_messages(token).await.unwrap();
+        let last = messages.last().unwrap();
+        assert_eq!(
+            last.contents,
+            "Thanks for the reply!\n.and a line starting with a dot"
+        );
+        assert!(!last.response);
+        assert_eq!(messages.len(), 2);
+    }
+}
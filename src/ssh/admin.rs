@@ -0,0 +1,29 @@
+use crate::contact::ThreadToken;
+
+/// Handles the `admin` command, returning the output to be sent to the user's terminal. Only available to the admin user (see `SshSession::admin`).
+pub async fn admin(command: &str) -> Vec<u8> {
+    let mut args = command.split(' ').skip(1);
+    let mut response = match (args.next(), args.next()) {
+        (Some("backup"), _) => match crate::contact::backup().await {
+            Ok(path) => format!("Backed up messages database to {path}"),
+            Err(e) => format!("Error backing up messages database: {e}"),
+        },
+        (Some("rotate"), Some(thread)) => match thread.parse::<ThreadToken>() {
+            Ok(thread) => match crate::contact::rotate_token(thread).await {
+                Ok(token) => format!(
+                    "Revoked all access to thread {}, new thread ID: {token}",
+                    thread.public_id()
+                ),
+                Err(e) => format!("Error rotating token: {e}"),
+            },
+            Err(_) => "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string(),
+        },
+        _ => "Usage: `admin backup` or `admin rotate <THREAD>`
+
+`admin backup` saves a consistent snapshot of the messages database alongside it on the server.
+`admin rotate` revokes all thread IDs for a thread (given any one of them, as used with `msg`), along with access by its old-style ID and by email, and prints a new thread ID."
+            .to_string(),
+    };
+    response.push_str("\n\n");
+    response.replace('\n', "\r\n").into_bytes()
+}
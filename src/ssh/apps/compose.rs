@@ -0,0 +1,396 @@
+//! A small full-screen editor for writing a message, started by `msg send` or `msg reply <THREAD>` without a body, so messages can
+//! have several lines (and be longer than is comfortable on the command line). Ctrl-S sends the message and Ctrl-C throws it away.
+
+use std::ops::Range;
+
+use super::RunningApp;
+use crate::ssh::{
+    channel::SshChannel,
+    contact::{MIN_REPLY_LENGTH, MIN_SEND_LENGTH},
+    input::Key,
+    style::{self, Capabilities, Color, Line, Span, Style},
+    terminal::TerminalUtils,
+    text,
+};
+
+/// The number of spaces Tab inserts.
+const TAB_WIDTH: usize = 4;
+
+/// The state of a running editor.
+pub struct Compose {
+    /// The command that sends the message, without its body (`msg send` or `msg reply <THREAD>`).
+    command: String,
+    /// What's being written, for the title bar.
+    title: String,
+    /// The fewest characters the message can have, as checked by the command.
+    min_length: usize,
+    /// The lines of the message. There is always at least one line.
+    lines: Vec<String>,
+    /// The cursor position, as (line, byte index into the line), always on a grapheme boundary.
+    cursor: (usize, usize),
+    /// The column to keep moving up and down in, even through shorter rows, once the cursor has moved vertically.
+    goal_column: Option<usize>,
+    /// The index of the screen row of the message at the top of the screen.
+    top: usize,
+    /// The current size of the terminal, in characters.
+    term_size: (usize, usize),
+    /// What the terminal supports, for styling the title and status bars.
+    capabilities: Capabilities,
+    /// A message to show in the status bar (e.g. why the message can't be sent yet), until the next key press.
+    message: Option<String>,
+    /// Whether the user has sent the message with Ctrl-S.
+    sent: bool,
+}
+impl Compose {
+    /// The message as it will be sent, without trailing whitespace.
+    fn body(&self) -> String {
+        self.lines.join("\n").trim_end().to_string()
+    }
+
+    /// The number of rows the message can take up on screen (leaving the first row for the title and the last for the status bar).
+    fn height(&self) -> usize {
+        self.term_size.1 - 2
+    }
+
+    /// Lays out the message in screen rows, returning the line each row is part of along with the byte range of the line it shows.
+    fn rows(&self) -> Vec<(usize, Range<usize>)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .flat_map(|(i, line)| {
+                wrap(line, self.term_size.0)
+                    .into_iter()
+                    .map(move |range| (i, range))
+            })
+            .collect()
+    }
+
+    /// Gets the screen row (by index into `rows`) and column the cursor is at.
+    fn cursor_position(&self, rows: &[(usize, Range<usize>)]) -> (usize, usize) {
+        let (line, offset) = self.cursor;
+        // At the boundary between two rows of a line, the cursor is at the start of the second
+        let row = rows
+            .iter()
+            .rposition(|(l, range)| *l == line && range.start <= offset)
+            .expect("every line has a row");
+        (
+            row,
+            text::width(&self.lines[line][rows[row].1.start..offset]),
+        )
+    }
+
+    /// Moves the cursor to the given column of a screen row (or as close as it can get), keeping it as the goal column.
+    fn move_to_row(&mut self, rows: &[(usize, Range<usize>)], row: usize, column: usize) {
+        let (line, range) = &rows[row];
+        let text = &self.lines[*line];
+        let mut offset = range.end;
+        let mut used = 0;
+        for (i, grapheme) in text::grapheme_indices(&text[range.clone()]) {
+            used += text::grapheme_width(grapheme);
+            if used > column {
+                offset = range.start + i;
+                break;
+            }
+        }
+        // The end of a row that wraps is the start of the next, so stay before its last grapheme
+        if offset == range.end && rows.get(row + 1).is_some_and(|(l, _)| l == line) {
+            offset = text::prev_boundary(text, range.end);
+        }
+        self.cursor = (*line, offset);
+        self.goal_column = Some(column);
+    }
+
+    /// Moves the cursor up or down by the given number of screen rows (negative for up), clamped to the message.
+    fn move_vertically(&mut self, by: isize) {
+        let rows = self.rows();
+        let (row, column) = self.cursor_position(&rows);
+        let column = self.goal_column.unwrap_or(column);
+        let row = row.saturating_add_signed(by).min(rows.len() - 1);
+        self.move_to_row(&rows, row, column);
+    }
+
+    /// Inserts text at the cursor, moving the cursor after it. Newlines start new lines, tabs become spaces, and other control
+    /// characters are dropped.
+    fn insert(&mut self, inserted: &str) {
+        let inserted = inserted.replace("\r\n", "\n").replace('\r', "\n");
+        for (i, part) in inserted.split('\n').enumerate() {
+            if i > 0 {
+                let (line, offset) = self.cursor;
+                let rest = self.lines[line].split_off(offset);
+                self.lines.insert(line + 1, rest);
+                self.cursor = (line + 1, 0);
+            }
+            let part: String = part
+                .chars()
+                .flat_map(|c| match c {
+                    '\t' => vec![' '; TAB_WIDTH],
+                    c if c.is_control() => vec![],
+                    c => vec![c],
+                })
+                .collect();
+            let (line, offset) = self.cursor;
+            self.lines[line].insert_str(offset, &part);
+            self.cursor.1 += part.len();
+        }
+    }
+
+    /// Deletes the grapheme before the cursor, joining the line onto the one before if the cursor is at its start.
+    fn backspace(&mut self) {
+        let (line, offset) = self.cursor;
+        if offset > 0 {
+            let start = text::prev_boundary(&self.lines[line], offset);
+            self.lines[line].replace_range(start..offset, "");
+            self.cursor.1 = start;
+        } else if line > 0 {
+            let removed = self.lines.remove(line);
+            self.cursor = (line - 1, self.lines[line - 1].len());
+            self.lines[line - 1].push_str(&removed);
+        }
+    }
+
+    /// Deletes the grapheme under the cursor, joining the next line onto this one if the cursor is at its end.
+    fn delete(&mut self) {
+        let (line, offset) = self.cursor;
+        if offset < self.lines[line].len() {
+            let end = text::next_boundary(&self.lines[line], offset);
+            self.lines[line].replace_range(offset..end, "");
+        } else if line + 1 < self.lines.len() {
+            let removed = self.lines.remove(line + 1);
+            self.lines[line].push_str(&removed);
+        }
+    }
+
+    /// Moves the cursor back a grapheme, onto the end of the previous line if it's at the start of one.
+    fn left(&mut self) {
+        let (line, offset) = self.cursor;
+        if offset > 0 {
+            self.cursor.1 = text::prev_boundary(&self.lines[line], offset);
+        } else if line > 0 {
+            self.cursor = (line - 1, self.lines[line - 1].len());
+        }
+    }
+
+    /// Moves the cursor forward a grapheme, onto the start of the next line if it's at the end of one.
+    fn right(&mut self) {
+        let (line, offset) = self.cursor;
+        if offset < self.lines[line].len() {
+            self.cursor.1 = text::next_boundary(&self.lines[line], offset);
+        } else if line + 1 < self.lines.len() {
+            self.cursor = (line + 1, 0);
+        }
+    }
+
+    /// Sends the message if it's within the length limits, or otherwise says why it can't be sent.
+    fn send(&mut self) {
+        let length = self.body().chars().count();
+        if length < self.min_length {
+            self.message = Some(format!(
+                "Too short to send (at least {} characters)",
+                self.min_length
+            ));
+        } else if length > crate::CONFIG.msg_max_size {
+            self.message = Some(format!(
+                "Too long to send (at most {} characters)",
+                crate::CONFIG.msg_max_size
+            ));
+        } else {
+            self.sent = true;
+        }
+    }
+
+    /// Clears and redraws the screen, scrolling to keep the cursor on it, and returns the response to do so.
+    fn render(&mut self) -> Vec<u8> {
+        let (width, _) = self.term_size;
+        let height = self.height();
+        let rows = self.rows();
+        let (cursor_row, cursor_column) = self.cursor_position(&rows);
+        if cursor_row < self.top {
+            self.top = cursor_row;
+        } else if cursor_row >= self.top + height {
+            self.top = cursor_row + 1 - height;
+        }
+
+        let mut response = TerminalUtils::new()
+            .hide_cursor()
+            .clear()
+            .move_cursor(0, 0)
+            .into_data();
+        response.extend(self.render_spans(&[(
+            &self.title,
+            width,
+            Style::default().reverse().bold(),
+        )]));
+        for (y, (line, range)) in rows.iter().skip(self.top).take(height).enumerate() {
+            response.extend(
+                TerminalUtils::new()
+                    .move_cursor(0, y as u16 + 1)
+                    .into_data(),
+            );
+            response.extend(self.lines[*line][range.clone()].as_bytes());
+        }
+
+        // The status bar: a message (or the keys to use), and how long the message is
+        let length = self.body().chars().count();
+        let counter = format!("{length}/{} ", crate::CONFIG.msg_max_size);
+        let counter_style = if length > crate::CONFIG.msg_max_size {
+            Style::default().reverse().color(Color::Ansi(1))
+        } else {
+            Style::default().reverse()
+        };
+        let status = self
+            .message
+            .as_deref()
+            .unwrap_or("Ctrl-S: send  Ctrl-C: cancel");
+        response.extend(
+            TerminalUtils::new()
+                .move_cursor(0, (height + 1) as u16)
+                .into_data(),
+        );
+        let counter_width = text::width(&counter).min(width);
+        response.extend(self.render_spans(&[
+            (
+                &format!(" {status}"),
+                width - counter_width,
+                Style::default().reverse(),
+            ),
+            (&counter, counter_width, counter_style),
+        ]));
+
+        response.extend(
+            TerminalUtils::new()
+                .move_cursor(cursor_column as u16, (cursor_row - self.top + 1) as u16)
+                .show_cursor()
+                .into_data(),
+        );
+        response
+    }
+
+    /// Renders spans of text, each truncated or padded to the given width, in the given style.
+    fn render_spans(&self, spans: &[(&str, usize, Style)]) -> Vec<u8> {
+        let line: Line = spans
+            .iter()
+            .map(|&(span, width, style)| {
+                let span = text::sanitize(span);
+                let span = text::truncate(&span, width);
+                Span {
+                    text: format!("{span}{}", " ".repeat(width - text::width(span))),
+                    style,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        style::render(&line, self.capabilities).into_bytes()
+    }
+}
+
+/// Wraps a line into rows at most `width` columns wide, breaking after the last space in a row where there is one so words aren't
+/// split. Returns the byte range of each row (at least one, with an empty row after a line that exactly fills its last one, for the
+/// cursor to go on).
+fn wrap(line: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(2);
+    let mut rows = vec![];
+    let (mut start, mut column) = (0, 0);
+    // The byte index just after the last space in the current row, if any
+    let mut break_at = None;
+    for (i, grapheme) in text::grapheme_indices(line) {
+        let grapheme_width = text::grapheme_width(grapheme);
+        if column + grapheme_width > width {
+            let end = break_at.filter(|&end| end > start).unwrap_or(i);
+            rows.push(start..end);
+            start = end;
+            column = text::width(&line[start..i]);
+            break_at = None;
+        }
+        column += grapheme_width;
+        if grapheme == " " {
+            break_at = Some(i + 1);
+        }
+    }
+    rows.push(start..line.len());
+    if column >= width {
+        rows.push(line.len()..line.len());
+    }
+    rows
+}
+
+impl RunningApp for Compose {
+    fn startup(
+        channel: &SshChannel,
+        command: String,
+    ) -> Result<(Box<dyn RunningApp>, Vec<u8>), Vec<u8>> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let (title, min_length) = match args.as_slice() {
+            ["msg", "send"] => (
+                format!(" New message to {}", crate::CONFIG.domain),
+                MIN_SEND_LENGTH,
+            ),
+            ["msg", "reply", thread] => (format!(" Reply on thread {thread}"), MIN_REPLY_LENGTH),
+            _ => return Err(b"Usage: `msg send` or `msg reply <THREAD>`\r\n".to_vec()),
+        };
+        let mut compose = Compose {
+            command: args.join(" "),
+            title,
+            min_length,
+            lines: vec![String::new()],
+            cursor: (0, 0),
+            goal_column: None,
+            top: 0,
+            term_size: (
+                channel.term_size.0.max(2) as usize,
+                channel.term_size.1.max(3) as usize,
+            ),
+            capabilities: channel.capabilities,
+            message: None,
+            sent: false,
+        };
+        let response = compose.render();
+        Ok((Box::new(compose), response))
+    }
+    fn key(&mut self, key: Key) -> Vec<u8> {
+        self.message = None;
+        let page = self.height() as isize;
+        match key {
+            Key::Ctrl('s') => {
+                self.send();
+                if self.sent {
+                    return vec![];
+                }
+            }
+            Key::Up(_) | Key::Ctrl('p') => self.move_vertically(-1),
+            Key::Down(_) | Key::Ctrl('n') => self.move_vertically(1),
+            Key::PageUp => self.move_vertically(-page),
+            Key::PageDown => self.move_vertically(page),
+            key => {
+                self.goal_column = None;
+                match key {
+                    Key::Char(c) => self.insert(c.encode_utf8(&mut [0; 4])),
+                    Key::Paste(pasted) => self.insert(&pasted),
+                    Key::Enter => self.insert("\n"),
+                    Key::Tab => self.insert("\t"),
+                    Key::Backspace => self.backspace(),
+                    Key::Delete | Key::Ctrl('d') => self.delete(),
+                    Key::Left(_) | Key::Ctrl('b') => self.left(),
+                    Key::Right(_) | Key::Ctrl('f') => self.right(),
+                    Key::Home(_) | Key::Ctrl('a') => self.cursor.1 = 0,
+                    Key::End(_) | Key::Ctrl('e') => self.cursor.1 = self.lines[self.cursor.0].len(),
+                    _ => return vec![],
+                }
+            }
+        }
+        self.render()
+    }
+    fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
+        self.term_size = (width.max(2) as usize, height.max(3) as usize);
+        self.render()
+    }
+    fn finished(&self) -> bool {
+        self.sent
+    }
+    fn cleanup(&self) -> Vec<u8> {
+        TerminalUtils::new().show_cursor().into_data()
+    }
+    fn exit_command(&mut self) -> Option<String> {
+        self.sent
+            .then(|| format!("{} {}", self.command, self.body()))
+    }
+}
@@ -0,0 +1,359 @@
+//! A `less`-style pager, for viewing long files and command output a screen at a time.
+
+use regex::{Regex, RegexBuilder};
+
+use super::RunningApp;
+use crate::ssh::{
+    channel::SshChannel,
+    commands::{Context, Entry},
+    image,
+    input::Key,
+    style::{self, Capabilities, Line},
+    terminal::TerminalUtils,
+    text,
+};
+
+/// The state of a running pager.
+pub struct Less {
+    /// The name of what we're viewing (e.g. the file name), shown in the status line.
+    title: Option<String>,
+    /// The lines of the text being viewed.
+    lines: Vec<Line>,
+    /// The screen rows of the text as currently wrapped, as (index into `lines`, text of the row).
+    rows: Vec<(usize, Line)>,
+    /// The index into `rows` of the top row on screen.
+    top: usize,
+    /// The current size of the terminal, in characters.
+    term_size: (usize, usize),
+    /// The current size of the terminal in pixels, if known, for scaling images.
+    pixel_size: (u32, u32),
+    /// What the terminal supports, for styling the text and showing images.
+    capabilities: Capabilities,
+    /// The last search pattern, whose matches are highlighted.
+    search: Option<Regex>,
+    /// The search pattern being typed after `/`, if any.
+    input: Option<String>,
+    /// A message to show in the status line until the next key press (e.g. "Pattern not found").
+    message: Option<String>,
+    /// Whether an image is being shown in place of the text, until the next key press.
+    viewing_image: bool,
+    /// Whether the user has quit.
+    finished: bool,
+}
+impl Less {
+    /// Starts the pager on the given lines, returning it along with its initial render.
+    pub fn new(
+        title: Option<String>,
+        lines: Vec<Line>,
+        term_size: (u32, u32),
+        pixel_size: (u32, u32),
+        capabilities: Capabilities,
+    ) -> (Self, Vec<u8>) {
+        let mut less = Self {
+            title,
+            lines,
+            rows: vec![],
+            top: 0,
+            term_size: (term_size.0.max(2) as usize, term_size.1.max(2) as usize),
+            pixel_size,
+            capabilities,
+            search: None,
+            input: None,
+            message: None,
+            viewing_image: false,
+            finished: false,
+        };
+        less.wrap();
+        let response = less.render();
+        (less, response)
+    }
+
+    /// The number of rows of text shown at once (leaving the last row for the status line).
+    fn height(&self) -> usize {
+        self.term_size.1 - 1
+    }
+
+    /// The furthest we can scroll down, with the last row of text at the bottom of the screen.
+    fn max_top(&self) -> usize {
+        self.rows.len().saturating_sub(self.height())
+    }
+
+    /// Wraps `lines` into `rows` for the current terminal width, keeping the same line at the top of the screen.
+    fn wrap(&mut self) {
+        let top_line = self.rows.get(self.top).map_or(0, |(line, _)| *line);
+        self.rows.clear();
+        for (i, line) in self.lines.iter().enumerate() {
+            for row in style::wrap(&style::sanitize(line), self.term_size.0) {
+                self.rows.push((i, row));
+            }
+        }
+        self.top = self
+            .rows
+            .iter()
+            .position(|(line, _)| *line >= top_line)
+            .unwrap_or(0)
+            .min(self.max_top());
+    }
+
+    /// Scrolls by the given number of rows (negative for up), clamped to the text.
+    fn scroll(&mut self, rows: isize) {
+        self.top = self.top.saturating_add_signed(rows).min(self.max_top());
+    }
+
+    /// Scrolls to the next (or previous, if `forward` is false) row matching the search pattern, if any.
+    fn find(&mut self, forward: bool) {
+        let Some(search) = &self.search else {
+            self.message = Some("No previous search".to_string());
+            return;
+        };
+        let found = if forward {
+            (self.top + 1..self.rows.len())
+                .find(|&i| search.is_match(&style::text(&self.rows[i].1)))
+        } else {
+            (0..self.top)
+                .rev()
+                .find(|&i| search.is_match(&style::text(&self.rows[i].1)))
+        };
+        match found {
+            // Show the match at the top of the screen, even if that scrolls past the end
+            Some(row) => self.top = row,
+            None => self.message = Some("Pattern not found".to_string()),
+        }
+    }
+
+    /// The image on the line at the top of the screen, if any (as its path relative to the images directory).
+    fn top_image(&self) -> Option<&str> {
+        let (line, _) = self.rows.get(self.top)?;
+        self.lines[*line]
+            .iter()
+            .find_map(|span| span.image.as_deref())
+    }
+
+    /// Shows the image on the line at the top of the screen in place of the text, returning the response to do so.
+    fn view_image(&mut self) -> Vec<u8> {
+        let Some(src) = self.top_image().map(str::to_string) else {
+            return vec![];
+        };
+        let term_size = (self.term_size.0 as u32, self.term_size.1 as u32);
+        let rendered = image::path(&src)
+            .ok_or_else(|| format!("{src}: no such image"))
+            .and_then(|path| {
+                tokio::task::block_in_place(|| {
+                    image::render(&path, term_size, self.pixel_size, self.capabilities)
+                })
+            });
+        match rendered {
+            Ok(rendered) => {
+                self.viewing_image = true;
+                let mut response = TerminalUtils::new().clear().into_data();
+                response.extend(rendered);
+                response.extend(
+                    TerminalUtils::new()
+                        .move_cursor(0, self.height() as u16)
+                        .into_data(),
+                );
+                let status = format!("{src} (press any key to return)");
+                let status = text::truncate(&status, self.term_size.0 - 1);
+                response.extend(format!("\x1b[7m{status}\x1b[27m").as_bytes());
+                response
+            }
+            Err(error) => {
+                self.message = Some(error);
+                self.render()
+            }
+        }
+    }
+
+    /// Clears and rerenders the screen, returning the necessary response to do so.
+    fn render(&self) -> Vec<u8> {
+        let mut response = TerminalUtils::new().clear().into_data();
+        for y in 0..self.height() {
+            response.extend(TerminalUtils::new().move_cursor(0, y as u16).into_data());
+            match self.rows.get(self.top + y) {
+                Some((_, row)) => response.extend(self.highlight(row).as_bytes()),
+                None => response.push(b'~'),
+            }
+        }
+
+        // Status line: the search being typed, a message, or our position in the text
+        response.extend(
+            TerminalUtils::new()
+                .move_cursor(0, self.height() as u16)
+                .into_data(),
+        );
+        let status = if let Some(input) = &self.input {
+            format!("/{input}")
+        } else {
+            let mut status = match &self.message {
+                Some(message) => message.clone(),
+                None => {
+                    let bottom = (self.top + self.height()).min(self.rows.len());
+                    let first_line = self.rows.get(self.top).map_or(0, |(line, _)| line + 1);
+                    let last_line = self
+                        .rows
+                        .get(bottom.max(1) - 1)
+                        .map_or(0, |(line, _)| line + 1);
+                    let percent = if self.rows.is_empty() {
+                        100
+                    } else {
+                        bottom * 100 / self.rows.len()
+                    };
+                    format!(
+                        "{}lines {first_line}-{last_line}/{} {percent}%",
+                        self.title
+                            .as_ref()
+                            .map_or(String::new(), |title| format!("{title} ")),
+                        self.lines.len()
+                    )
+                }
+            };
+            if self.top >= self.max_top() {
+                status.push_str(" (END)");
+            }
+            if self.top_image().is_some() {
+                status.push_str(" (Enter to view image)");
+            }
+            status.push_str(" (q to quit, / to search)");
+            status
+        };
+        let status = text::truncate(&status, self.term_size.0 - 1);
+        if self.input.is_some() {
+            response.extend(status.as_bytes());
+        } else {
+            response.extend(format!("\x1b[7m{status}\x1b[27m").as_bytes());
+        }
+        response
+    }
+
+    /// Renders a row for the terminal, highlighting matches of the search pattern (in reverse video).
+    fn highlight(&self, row: &[style::Span]) -> String {
+        let matches: Vec<_> = match &self.search {
+            Some(search) => search
+                .find_iter(&style::text(row))
+                .filter(|m| !m.is_empty())
+                .map(|m| m.range())
+                .collect(),
+            None => vec![],
+        };
+        style::render(&style::highlight(row, &matches), self.capabilities)
+    }
+
+    /// Processes a key press while the search pattern is being typed.
+    fn process_input(&mut self, key: Key) {
+        let input = self.input.get_or_insert_with(String::new);
+        match key {
+            Key::Enter => {
+                // Search with the pattern (ignoring case unless it has uppercase letters, like `less -i`), or repeat the last search if empty
+                let input = self.input.take().unwrap_or_default();
+                if !input.is_empty() {
+                    match RegexBuilder::new(&input)
+                        .case_insensitive(!input.chars().any(char::is_uppercase))
+                        .size_limit(1 << 16)
+                        .build()
+                    {
+                        Ok(regex) => self.search = Some(regex),
+                        Err(_) => {
+                            self.message = Some("Invalid pattern".to_string());
+                            return;
+                        }
+                    }
+                }
+                self.find(true);
+            }
+            // Backspace, cancelling the search if it's already empty
+            Key::Backspace if input.pop().is_none() => self.input = None,
+            Key::Escape | Key::Ctrl('g') => self.input = None,
+            Key::Char(c) => input.push(c),
+            Key::Paste(pasted) => {
+                input.push_str(&text::sanitize(&pasted.replace(['\r', '\n'], " ")))
+            }
+            _ => {}
+        }
+    }
+}
+impl RunningApp for Less {
+    fn startup(
+        channel: &SshChannel,
+        command: String,
+    ) -> Result<(Box<dyn RunningApp>, Vec<u8>), Vec<u8>> {
+        let path = command
+            .split(' ')
+            .nth(1)
+            .ok_or_else(|| Vec::from(b"less: usage: less <filename>\r\n" as &[u8]))?;
+        let context = Context {
+            content: &channel.content,
+            current_dir: channel.current_dir,
+            term_size: channel.term_size,
+            pixel_size: channel.pixel_size,
+            capabilities: channel.capabilities,
+        };
+        let Some(Entry::File(file)) = context.resolve(path) else {
+            return Err(format!("less: cannot open \"{path}\": No such file\r\n").into_bytes());
+        };
+        let (less, response) = Less::new(
+            Some(path.to_string()),
+            file.styled(channel.capabilities),
+            channel.term_size,
+            channel.pixel_size,
+            channel.capabilities,
+        );
+        Ok((Box::new(less), response))
+    }
+    fn key(&mut self, key: Key) -> Vec<u8> {
+        self.message = None;
+        if self.viewing_image {
+            // Any key goes back to the text
+            self.viewing_image = false;
+            let mut response = image::clear(self.capabilities).to_vec();
+            response.extend(self.render());
+            return response;
+        }
+        if self.input.is_some() {
+            self.process_input(key);
+            return self.render();
+        }
+        let page = self.height() as isize;
+        match key {
+            Key::Char('q' | 'Q') => {
+                self.finished = true;
+                return vec![];
+            }
+            Key::Enter if self.top_image().is_some() => return self.view_image(),
+            Key::Char('j' | 'e') | Key::Enter | Key::Ctrl('n' | 'e') | Key::Down(_) => {
+                self.scroll(1)
+            }
+            Key::Char('k' | 'y') | Key::Ctrl('p' | 'y') | Key::Up(_) => self.scroll(-1),
+            Key::Char(' ' | 'f') | Key::Ctrl('f' | 'v') | Key::PageDown => self.scroll(page),
+            Key::Char('b') | Key::Ctrl('b') | Key::Alt('v') | Key::PageUp => self.scroll(-page),
+            Key::Char('d') | Key::Ctrl('d') => self.scroll(page / 2),
+            Key::Char('u') | Key::Ctrl('u') => self.scroll(-page / 2),
+            Key::Char('g' | '<') | Key::Home(_) => self.top = 0,
+            Key::Char('G' | '>') | Key::End(_) => self.top = self.max_top(),
+            Key::Char('/') => self.input = Some(String::new()),
+            Key::Char('n') => self.find(true),
+            Key::Char('N') => self.find(false),
+            _ => return vec![],
+        }
+        self.render()
+    }
+    fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
+        // Keep the cell size the same, as we're only told the new size in pixels on window changes
+        let (old_width, old_height) = (self.term_size.0 as u32, self.term_size.1 as u32);
+        self.term_size = (width.max(2) as usize, height.max(2) as usize);
+        self.pixel_size = (
+            self.pixel_size.0 / old_width * self.term_size.0 as u32,
+            self.pixel_size.1 / old_height * self.term_size.1 as u32,
+        );
+        self.wrap();
+        let mut response = vec![];
+        if self.viewing_image {
+            self.viewing_image = false;
+            response.extend(image::clear(self.capabilities));
+        }
+        response.extend(self.render());
+        response
+    }
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
@@ -0,0 +1,405 @@
+//! A full-screen menu of the projects and blog posts, with a preview of the selected one, for browsing the site without knowing the
+//! shell commands. It can be used with the keyboard or the mouse, and opens entries in the pager.
+
+use std::sync::Arc;
+
+use super::{Less, RunningApp};
+use crate::ssh::{
+    channel::SshChannel,
+    content::{MenuEntry, SshContent},
+    input::{Key, MouseAction, MouseButton, MOUSE_OFF, MOUSE_ON},
+    style::{self, Capabilities, Color, Line, Span, Style, StyledText},
+    terminal::TerminalUtils,
+    text,
+};
+
+/// The narrowest the terminal can be while still showing the preview beside the list.
+const MIN_PREVIEW_WIDTH: usize = 60;
+/// How many rows each step of the scroll wheel scrolls the preview.
+const SCROLL_ROWS: usize = 3;
+
+/// A row of the list.
+enum Item {
+    /// A heading before the entries of a kind (e.g. "Project").
+    Heading(&'static str),
+    /// An entry, as its index into `SshContent::menu`.
+    Entry(usize),
+}
+
+/// The state of a running menu.
+pub struct Menu {
+    content: Arc<SshContent>,
+    /// The rows of the list: the entries, grouped under headings.
+    items: Vec<Item>,
+    /// The index into `items` of the selected entry.
+    selected: usize,
+    /// The index into `items` of the top row of the list on screen.
+    top: usize,
+    /// The preview of the selected entry, wrapped to the width of the preview.
+    preview: Vec<Line>,
+    /// The index into `preview` of the top row on screen.
+    preview_top: usize,
+    /// The current size of the terminal, in characters.
+    term_size: (usize, usize),
+    /// The current size of the terminal in pixels, if known, for the pager's images.
+    pixel_size: (u32, u32),
+    /// What the terminal supports, for styling the text.
+    capabilities: Capabilities,
+    /// The pager, while an entry is open in it (going back to the menu when it's quit).
+    pager: Option<Less>,
+    /// Whether the user has quit.
+    finished: bool,
+}
+impl Menu {
+    /// The width of the list, which is the whole terminal if it's too narrow for the preview too.
+    fn list_width(&self) -> usize {
+        if self.term_size.0 >= MIN_PREVIEW_WIDTH {
+            (self.term_size.0 * 2 / 5).clamp(24, 48)
+        } else {
+            self.term_size.0
+        }
+    }
+
+    /// The column the preview starts at (after the list and a separator), and its width. The width is 0 if there's no preview.
+    fn preview_area(&self) -> (usize, usize) {
+        let x = self.list_width() + 2;
+        (x, self.term_size.0.saturating_sub(x))
+    }
+
+    /// The number of rows for the list and preview (leaving the first row for the title and the last for the status line).
+    fn height(&self) -> usize {
+        self.term_size.1 - 2
+    }
+
+    /// The selected entry, if there are any.
+    fn entry(&self) -> Option<&MenuEntry> {
+        match self.items.get(self.selected)? {
+            Item::Entry(i) => self.content.menu.get(*i),
+            Item::Heading(_) => None,
+        }
+    }
+
+    /// Selects the entry at the given index into `items`, scrolling the list to show it (and its heading, if it's the first of its
+    /// kind).
+    fn select(&mut self, selected: usize) {
+        self.selected = selected;
+        let first = match selected.checked_sub(1).map(|i| &self.items[i]) {
+            Some(Item::Heading(_)) => selected - 1,
+            _ => selected,
+        };
+        if first < self.top {
+            self.top = first;
+        } else if selected >= self.top + self.height() {
+            self.top = selected + 1 - self.height();
+        }
+        self.update_preview();
+    }
+
+    /// Moves the selection by the given number of entries (negative for up), clamped to the list.
+    fn move_selection(&mut self, by: isize) {
+        let entries: Vec<usize> = (0..self.items.len())
+            .filter(|&i| matches!(self.items[i], Item::Entry(_)))
+            .collect();
+        let Some(current) = entries.iter().position(|&i| i == self.selected) else {
+            return;
+        };
+        let new = current.saturating_add_signed(by).min(entries.len() - 1);
+        self.select(entries[new]);
+    }
+
+    /// Scrolls the preview by the given number of rows (negative for up), clamped to the preview.
+    fn scroll_preview(&mut self, rows: isize) {
+        self.preview_top = self
+            .preview_top
+            .saturating_add_signed(rows)
+            .min(self.preview.len().saturating_sub(self.height()));
+    }
+
+    /// Rebuilds the preview of the selected entry for the current width, scrolled back to the top.
+    fn update_preview(&mut self) {
+        self.preview.clear();
+        self.preview_top = 0;
+        let (_, width) = self.preview_area();
+        let Some(entry) = self.entry() else {
+            return;
+        };
+        if width == 0 {
+            return;
+        }
+
+        // The entry's details, followed by the start of its text
+        let mut details = StyledText::default();
+        details.styled(Style::default().bold().color(Color::Ansi(3)), |text| {
+            text.text(&entry.title)
+        });
+        details.newline();
+        details.styled(Style::default().dim(), |text| {
+            text.text(&format!("{}, {}", entry.kind, entry.date))
+        });
+        details.newline();
+        if !entry.description.is_empty() {
+            details.text(&entry.description);
+            details.newline();
+        }
+        if !entry.skills.is_empty() {
+            details.strong(|text| text.text("Skills: "));
+            details.text(&entry.skills.join(", "));
+            details.newline();
+        }
+        let lines = details
+            .lines(self.capabilities)
+            .into_iter()
+            .chain(entry.preview.lines(self.capabilities));
+        let mut preview = vec![];
+        for line in lines {
+            preview.extend(style::wrap(&style::sanitize(&line), width));
+        }
+        self.preview = preview;
+    }
+
+    /// Opens the selected entry in the pager, returning the response to do so.
+    fn open(&mut self) -> Vec<u8> {
+        let Some(entry) = self.entry() else {
+            return vec![];
+        };
+        let Some(file) = self.content.get_file(0, &entry.path) else {
+            return vec![];
+        };
+        let (less, startup_resp) = Less::new(
+            Some(entry.path.clone()),
+            file.styled(self.capabilities),
+            (self.term_size.0 as u32, self.term_size.1 as u32),
+            self.pixel_size,
+            self.capabilities,
+        );
+        self.pager = Some(less);
+        // Give the mouse back to the terminal in the pager, so text can be selected
+        let mut response = MOUSE_OFF.to_vec();
+        response.extend(TerminalUtils::new().show_cursor().into_data());
+        response.extend(startup_resp);
+        response
+    }
+
+    /// Clears and rerenders the screen, returning the necessary response to do so.
+    fn render(&self) -> Vec<u8> {
+        let (width, _) = self.term_size;
+        let list_width = self.list_width();
+        let (preview_x, preview_width) = self.preview_area();
+        let mut response = TerminalUtils::new()
+            .hide_cursor()
+            .clear()
+            .move_cursor(0, 0)
+            .into_data();
+        let reverse = Style::default().reverse();
+        response.extend(self.render_spans(&[(
+            &format!(" {} - projects and blog posts", crate::CONFIG.domain),
+            width,
+            reverse.bold(),
+        )]));
+
+        for y in 0..self.height() {
+            response.extend(
+                TerminalUtils::new()
+                    .move_cursor(0, y as u16 + 1)
+                    .into_data(),
+            );
+            match self.items.get(self.top + y) {
+                Some(Item::Heading(kind)) => response.extend(self.render_spans(&[(
+                    &format!("{kind}s"),
+                    list_width,
+                    Style::default().bold().underline(),
+                )])),
+                Some(&Item::Entry(i)) => {
+                    let entry = &self.content.menu[i];
+                    // The title, with the date on the right if there's room
+                    let date = format!(" {} ", entry.date);
+                    let date_width = text::width(&date);
+                    let date_width = if date_width + 12 <= list_width {
+                        date_width
+                    } else {
+                        0
+                    };
+                    let style = if self.top + y == self.selected {
+                        reverse
+                    } else {
+                        Style::default()
+                    };
+                    response.extend(self.render_spans(&[
+                        (
+                            &format!("  {}", entry.title),
+                            list_width - date_width,
+                            style,
+                        ),
+                        (&date, date_width, style.dim()),
+                    ]));
+                }
+                None => {}
+            }
+            if preview_width > 0 {
+                response.extend(
+                    TerminalUtils::new()
+                        .move_cursor(list_width as u16, y as u16 + 1)
+                        .into_data(),
+                );
+                response.extend("│ ".as_bytes());
+                if let Some(row) = self.preview.get(self.preview_top + y) {
+                    response.extend(
+                        TerminalUtils::new()
+                            .move_cursor(preview_x as u16, y as u16 + 1)
+                            .into_data(),
+                    );
+                    response.extend(style::render(row, self.capabilities).as_bytes());
+                }
+            }
+        }
+
+        response.extend(
+            TerminalUtils::new()
+                .move_cursor(0, self.height() as u16 + 1)
+                .into_data(),
+        );
+        response.extend(self.render_spans(&[(
+            " Up/Down to select, Enter to open, q to quit (or click to select, again to open, and scroll the preview)",
+            width - 1,
+            reverse,
+        )]));
+        response
+    }
+
+    /// Renders some pieces of text, each truncated or padded with spaces to the given width and in the given style.
+    fn render_spans(&self, spans: &[(&str, usize, Style)]) -> Vec<u8> {
+        let line: Line = spans
+            .iter()
+            .map(|&(span, width, style)| {
+                let span = text::sanitize(span);
+                let span = text::truncate(&span, width);
+                Span {
+                    text: format!("{span}{}", " ".repeat(width - text::width(span))),
+                    style,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        style::render(&line, self.capabilities).into_bytes()
+    }
+}
+impl RunningApp for Menu {
+    fn startup(
+        channel: &SshChannel,
+        _command: String,
+    ) -> Result<(Box<dyn RunningApp>, Vec<u8>), Vec<u8>> {
+        // Group the entries under a heading for each kind
+        let mut items = vec![];
+        for (i, entry) in channel.content.menu.iter().enumerate() {
+            if i == 0 || channel.content.menu[i - 1].kind != entry.kind {
+                items.push(Item::Heading(entry.kind));
+            }
+            items.push(Item::Entry(i));
+        }
+        let mut menu = Menu {
+            content: channel.content.clone(),
+            items,
+            selected: 0,
+            top: 0,
+            preview: vec![],
+            preview_top: 0,
+            term_size: (
+                channel.term_size.0.max(2) as usize,
+                channel.term_size.1.max(3) as usize,
+            ),
+            pixel_size: channel.pixel_size,
+            capabilities: channel.capabilities,
+            pager: None,
+            finished: false,
+        };
+        if let Some(first) = menu
+            .items
+            .iter()
+            .position(|item| matches!(item, Item::Entry(_)))
+        {
+            menu.select(first);
+        }
+        let mut response = MOUSE_ON.to_vec();
+        response.extend(menu.render());
+        Ok((Box::new(menu), response))
+    }
+    fn key(&mut self, key: Key) -> Vec<u8> {
+        if let Some(pager) = &mut self.pager {
+            let response = pager.key(key);
+            if !pager.finished() {
+                return response;
+            }
+            // Back to the menu
+            self.pager = None;
+            let mut response = MOUSE_ON.to_vec();
+            response.extend(self.render());
+            return response;
+        }
+        let page = self.height() as isize;
+        match key {
+            Key::Char('q' | 'Q') | Key::Escape => {
+                self.finished = true;
+                return vec![];
+            }
+            Key::Char('j') | Key::Ctrl('n') | Key::Down(_) => self.move_selection(1),
+            Key::Char('k') | Key::Ctrl('p') | Key::Up(_) => self.move_selection(-1),
+            Key::PageDown | Key::Ctrl('f' | 'v') => self.move_selection(page),
+            Key::PageUp | Key::Ctrl('b') | Key::Alt('v') => self.move_selection(-page),
+            Key::Char('g') | Key::Home(_) => self.move_selection(isize::MIN),
+            Key::Char('G') | Key::End(_) => self.move_selection(isize::MAX),
+            Key::Char(' ') => self.scroll_preview(page),
+            Key::Backspace => self.scroll_preview(-page),
+            Key::Enter | Key::Char('l') | Key::Right(_) => return self.open(),
+            Key::Ctrl('l') => {}
+            Key::Mouse(mouse) => {
+                let on_list = (mouse.x as usize) < self.list_width();
+                match mouse.action {
+                    MouseAction::ScrollUp if on_list => self.move_selection(-1),
+                    MouseAction::ScrollDown if on_list => self.move_selection(1),
+                    MouseAction::ScrollUp => self.scroll_preview(-(SCROLL_ROWS as isize)),
+                    MouseAction::ScrollDown => self.scroll_preview(SCROLL_ROWS as isize),
+                    MouseAction::Press(MouseButton::Left) if on_list => {
+                        // Clicking an entry selects it, and clicking it again opens it
+                        let row = (mouse.y as usize).wrapping_sub(1);
+                        if row >= self.height() {
+                            return vec![];
+                        }
+                        let item = self.top + row;
+                        match self.items.get(item) {
+                            Some(Item::Entry(_)) if item == self.selected => return self.open(),
+                            Some(Item::Entry(_)) => self.select(item),
+                            _ => return vec![],
+                        }
+                    }
+                    _ => return vec![],
+                }
+            }
+            _ => return vec![],
+        }
+        self.render()
+    }
+    fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
+        // Keep the cell size the same, as in the pager
+        let (old_width, old_height) = (self.term_size.0 as u32, self.term_size.1 as u32);
+        self.term_size = (width.max(2) as usize, height.max(3) as usize);
+        self.pixel_size = (
+            self.pixel_size.0 / old_width * self.term_size.0 as u32,
+            self.pixel_size.1 / old_height * self.term_size.1 as u32,
+        );
+        let preview_top = self.preview_top;
+        self.select(self.selected);
+        self.scroll_preview(preview_top as isize);
+        match &mut self.pager {
+            Some(pager) => pager.resize(width, height),
+            None => self.render(),
+        }
+    }
+    fn finished(&self) -> bool {
+        self.finished
+    }
+    fn cleanup(&self) -> Vec<u8> {
+        let mut response = MOUSE_OFF.to_vec();
+        response.extend(TerminalUtils::new().show_cursor().into_data());
+        response
+    }
+}
@@ -0,0 +1,46 @@
+//! Full-screen apps run in the SSH shell, which take over the terminal until they exit.
+
+mod compose;
+mod less;
+mod menu;
+mod vim;
+
+pub use compose::Compose;
+pub use less::Less;
+pub use menu::Menu;
+pub use vim::Vim;
+
+use super::{channel::SshChannel, input::Key};
+
+/// The result of starting an app: the app along with its initial response, or a response to send as if as a normal command.
+pub type Startup = Result<(Box<dyn RunningApp>, Vec<u8>), Vec<u8>>;
+
+/// A trait providing functionality for a running app (state machine), including the ability
+/// to receive key presses and startup functionality.
+pub trait RunningApp: Send {
+    /// Starts the app, returning the initial state along with some initial reponse data
+    /// (basically a starting render) on sucess. On failure, returns a response to send
+    /// as if as a normal command (e.g. "file not found").
+    fn startup(channel: &SshChannel, command: String) -> Startup
+    where
+        Self: Sized;
+    /// Processes a key press from the user, returning the response.
+    fn key(&mut self, key: Key) -> Vec<u8>;
+    /// Processes a resize request from the client, returning the response.
+    fn resize(&mut self, width: u32, height: u32) -> Vec<u8>;
+    /// Whether the app has exited by itself (e.g. `q` in `less`), so the shell should take over again. Apps can always be exited with
+    /// Ctrl-C, which is handled by the channel.
+    fn finished(&self) -> bool {
+        false
+    }
+    /// Gets the response to put the terminal back as the shell expects when the app exits, either by itself or with Ctrl-C (e.g. to
+    /// turn off mouse reporting).
+    fn cleanup(&self) -> Vec<u8> {
+        vec![]
+    }
+    /// Gets a command for the shell to run once the app has exited by itself, if any, for apps that do something the shell
+    /// already knows how to (e.g. sending a message written in the editor).
+    fn exit_command(&mut self) -> Option<String> {
+        None
+    }
+}
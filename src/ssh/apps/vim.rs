@@ -0,0 +1,855 @@
+//! A (very) minimal vim, for viewing files.
+
+use regex::{Regex, RegexBuilder};
+use tracing::debug;
+
+use super::RunningApp;
+use crate::ssh::{channel::SshChannel, input::Key, terminal::TerminalUtils, text};
+
+/// The width of the ruler (cursor position) at the bottom right of the screen, as in vim.
+const RULER_WIDTH: usize = 18;
+/// The largest count that can be typed before a command, so it can't overflow.
+const MAX_COUNT: usize = 999_999;
+
+/// The classes of characters that words are made of, for word motions (`w`, `b`, `e`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Blank,
+    /// Letters, digits and underscores.
+    Word,
+    Punctuation,
+}
+impl CharClass {
+    fn of(c: char) -> Self {
+        if c.is_whitespace() {
+            CharClass::Blank
+        } else if c.is_alphanumeric() || c == '_' {
+            CharClass::Word
+        } else {
+            CharClass::Punctuation
+        }
+    }
+}
+
+/// A line of the file, split into graphemes and laid out for the current terminal width.
+struct Line {
+    /// The text of the line, sanitized for display.
+    text: String,
+    /// The byte offset of each grapheme in `text`.
+    offsets: Vec<usize>,
+    /// The (row, column) each grapheme starts at on screen, relative to the start of the line.
+    positions: Vec<(usize, usize)>,
+    /// The number of screen lines the line takes up.
+    rows: usize,
+}
+impl Line {
+    fn new(line: &str, width: usize) -> Self {
+        let text = text::sanitize(line);
+        let mut offset = 0;
+        let offsets = text::graphemes(&text)
+            .map(|grapheme| {
+                offset += grapheme.len();
+                offset - grapheme.len()
+            })
+            .collect();
+        let mut line = Line {
+            text,
+            offsets,
+            positions: vec![],
+            rows: 1,
+        };
+        line.layout(width);
+        line
+    }
+
+    /// Lays the line out again for a new terminal width.
+    fn layout(&mut self, width: usize) {
+        (self.positions, self.rows) = text::layout(text::graphemes(&self.text), width);
+    }
+
+    /// The number of graphemes in the line.
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Gets a grapheme of the line.
+    fn grapheme(&self, x: usize) -> Option<&str> {
+        let start = *self.offsets.get(x)?;
+        let end = self.offsets.get(x + 1).copied().unwrap_or(self.text.len());
+        Some(&self.text[start..end])
+    }
+
+    /// Gets the text on one of the line's screen lines.
+    fn row(&self, row: usize) -> &str {
+        let start = self.positions.partition_point(|&(r, _)| r < row);
+        let end = self.positions.partition_point(|&(r, _)| r <= row);
+        let byte = |x: usize| self.offsets.get(x).copied().unwrap_or(self.text.len());
+        &self.text[byte(start)..byte(end)]
+    }
+}
+
+/// The state of a running instance of vim.
+pub struct Vim {
+    /// The path of the file, as it was opened.
+    path: String,
+    /// The lines of the file. There is always at least one line.
+    lines: Vec<Line>,
+    /// Current cursor position (x,y), where (0,0) is the top left of the file and x counts graphemes. x can be past the end of
+    /// the line, to keep the column when moving through shorter lines (see `cursor_x`).
+    cursor_pos: (usize, usize),
+    /// Current scroll position (line, subline), giving the line at the top of the screen and
+    /// (if we've scrolled horizontally through a wrapping line) how many screen-widths of the
+    /// line we've scrolled past already.
+    scroll_pos: (usize, usize),
+    /// The current size of the terminal, in characters.
+    term_size: (u16, u16),
+    /// The available height for the file (not including bottom text, usually term_size.1 - 1).
+    available_height: usize,
+    /// The count typed before a command (e.g. the 5 in `5j`), if any.
+    count: Option<usize>,
+    /// Whether a `g` has been typed, waiting for the second one of `gg`.
+    pending_g: bool,
+    /// The line being typed at the bottom of the screen, as (prefix, text), where the prefix is `:` for commands or `/`/`?` for
+    /// searches.
+    command_line: Option<(char, String)>,
+    /// The last search, and whether it was forwards (`/`) rather than backwards (`?`).
+    search: Option<(Regex, bool)>,
+    /// A message to show at the bottom of the screen (e.g. an error), until the next key press.
+    message: Option<String>,
+    /// Whether the user has quit (with `:q`).
+    finished: bool,
+}
+impl Vim {
+    /// Opens a file (given its path, as typed, and its lines) on a terminal of the given size.
+    fn new(path: &str, lines: &[String], term_size: (u32, u32)) -> Self {
+        let term_size = (
+            term_size.0.clamp(2, u16::MAX as u32) as u16,
+            term_size.1.clamp(2, u16::MAX as u32) as u16,
+        );
+        let lines: Vec<Line> = lines
+            .iter()
+            .map(|line| Line::new(line, term_size.0 as usize))
+            .collect();
+        Vim {
+            path: path.to_string(),
+            message: Some(format!(
+                "\"{path}\" [readonly] {}L (:q to quit)",
+                lines.len()
+            )),
+            lines,
+            cursor_pos: (0, 0),
+            scroll_pos: (0, 0),
+            term_size,
+            available_height: term_size.1 as usize - 1,
+            count: None,
+            pending_g: false,
+            command_line: None,
+            search: None,
+            finished: false,
+        }
+    }
+
+    /// Helper method to clear and rerender the file, returning the necessary response to do so.
+    ///
+    /// Assumes that `cursor_pos` is onscreen for current `scroll_pos`.
+    fn render(&self) -> Vec<u8> {
+        // Clear the screen and move the cursor
+        let mut response = TerminalUtils::new().clear().move_cursor(0, 0).into_data();
+
+        // Output the file's contents, beginning at the scrolled location.
+        // `y` is the line of the file we're processing now, and `subline` is which of its screen lines we're on.
+        let (mut y, mut subline) = self.scroll_pos;
+        for row in 0..self.available_height {
+            response.append(&mut TerminalUtils::new().move_cursor(0, row as u16).into_data());
+            match self.lines.get(y) {
+                None => {
+                    // The file is over, print placeholder
+                    response.push(b'~');
+                }
+                Some(line) => {
+                    // Print this screen line's part of the line, stepping forward to the next line if we got to the end
+                    response.extend(line.row(subline).as_bytes());
+                    subline += 1;
+                    if subline >= line.rows {
+                        y += 1;
+                        subline = 0;
+                    }
+                }
+            }
+        }
+
+        response.extend(self.render_status());
+        response
+    }
+
+    /// Rerenders the bottom line (the command line, a message, and the ruler), then puts the cursor back, returning the
+    /// necessary response to do so.
+    fn render_status(&self) -> Vec<u8> {
+        let width = self.term_size.0 as usize;
+        let row = self.available_height as u16;
+        let mut response = TerminalUtils::new()
+            .move_cursor(0, row)
+            .clear_line()
+            .into_data();
+
+        // Left side: the command line being typed, or a message
+        let left = match (&self.command_line, &self.message) {
+            (Some((prefix, text)), _) => format!("{prefix}{text}"),
+            (None, Some(message)) => message.clone(),
+            (None, None) => String::new(),
+        };
+        let show_ruler = self.command_line.is_none() && width >= RULER_WIDTH * 2;
+        let left_width = if show_ruler {
+            width - RULER_WIDTH - 1
+        } else {
+            width - 1
+        };
+        let left = text::truncate(&left, left_width);
+        response.extend(left.as_bytes());
+
+        // Right side: the ruler, as in vim (with column "0-1" on empty lines)
+        if show_ruler {
+            let column = if self.lines[self.cursor_pos.1].is_empty() {
+                "0-1".to_string()
+            } else {
+                (self.cursor_x() + 1).to_string()
+            };
+            let ruler = format!("{},{column}", self.cursor_pos.1 + 1);
+            response.extend(
+                TerminalUtils::new()
+                    .move_cursor((width - RULER_WIDTH) as u16, row)
+                    .into_data(),
+            );
+            response.extend(format!("{ruler:<14}{}", self.position()).as_bytes());
+        }
+
+        // Put the cursor back, at the end of the command line if one's being typed.
+        // We assume that the current scroll is valid, so we cast using `as` (see `get_cursor_screen`).
+        let (screen_x, screen_y) = match self.command_line {
+            Some(_) => (text::width(left) as isize, row as isize),
+            None => self.get_cursor_screen(),
+        };
+        response.append(
+            &mut TerminalUtils::new()
+                .move_cursor(screen_x as u16, screen_y as u16)
+                .into_data(),
+        );
+        response
+    }
+
+    /// Describes how far through the file the screen is, as in vim's ruler ("All", "Top", "Bot", or a percentage).
+    fn position(&self) -> String {
+        let above = self.scroll_pos.0;
+        let below = self.lines.len() - self.bottom_line();
+        match (above, below) {
+            (0, 0) => "All".to_string(),
+            (0, _) => "Top".to_string(),
+            (_, 0) => "Bot".to_string(),
+            _ => format!("{}%", above * 100 / (above + below)),
+        }
+    }
+
+    /// Moves the cursor to the position in `cursor_pos`, scrolling if necessary. Rerenders if we scrolled (or `must_rerender` is
+    /// set), and otherwise just the status line.
+    fn update_cursor(&mut self, mut must_rerender: bool) -> Vec<u8> {
+        // Jump most of the way straight to far away lines, rather than scrolling there one line at a time
+        if self.cursor_pos.1 < self.scroll_pos.0 {
+            self.scroll_pos = (self.cursor_pos.1, 0);
+            must_rerender = true;
+        } else if self.cursor_pos.1 >= self.scroll_pos.0 + self.available_height {
+            self.scroll_pos = (self.cursor_pos.1 + 1 - self.available_height, 0);
+            must_rerender = true;
+        }
+
+        // Adjust to screen coordinates, and rescroll if they don't fit
+        loop {
+            let (_, screen_y) = self.get_cursor_screen();
+            if screen_y < 0 {
+                // Must scroll up, first by subline then by line
+                if self.scroll_pos.1 > 0 {
+                    self.scroll_pos.1 -= 1;
+                } else {
+                    self.scroll_pos.0 -= 1;
+                }
+            } else if screen_y >= self.available_height as isize {
+                // Must scroll down, by subline if possible (requires enough room in line)
+                if self.scroll_pos.1 + 1 < self.lines[self.scroll_pos.0].rows {
+                    self.scroll_pos.1 += 1;
+                } else {
+                    self.scroll_pos.1 = 0;
+                    self.scroll_pos.0 += 1;
+                }
+            } else {
+                break;
+            }
+            must_rerender = true;
+        }
+
+        // Render the new cursor (and ruler), doing a full rerender if we scrolled.
+        if must_rerender {
+            self.render()
+        } else {
+            self.render_status()
+        }
+    }
+
+    /// Helper to get the screen position of the cursor from the current `cursor_pos`, `scroll_pos`, and `term_size`.
+    /// If this returns an out-of-bounds point, scrolling should be adjusted.
+    fn get_cursor_screen(&self) -> (isize, isize) {
+        let (subline, x) = self.lines[self.cursor_pos.1]
+            .positions
+            .get(self.cursor_x())
+            .copied()
+            .unwrap_or_default();
+        // If cursor is behind first line of screen, or on it but above scroll_pos, are above screen, so return (0, -1)
+        if self.cursor_pos.1 < self.scroll_pos.0
+            || self.cursor_pos.1 == self.scroll_pos.0 && subline < self.scroll_pos.1
+        {
+            return (0, -1);
+        }
+        // Count how many screen lines there are from the top of the screen until we get to the current line, including wrapping.
+        // Start at `-self.scroll_pos.1` because first line may start above screen.
+        let mut screen_y = -(self.scroll_pos.1 as isize);
+        for line in &self.lines[self.scroll_pos.0..self.cursor_pos.1] {
+            screen_y += line.rows as isize;
+        }
+        // If the cursor's on a later screen line of its line (from wrapping), adjust y accordingly
+        screen_y += subline as isize;
+        (x as isize, screen_y)
+    }
+
+    /// The first line of the file that isn't (fully) on screen, or the number of lines if the end of the file is.
+    fn bottom_line(&self) -> usize {
+        // The first line starts `scroll_pos.1` screen lines above the screen
+        let mut remaining = self.available_height + self.scroll_pos.1;
+        let mut y = self.scroll_pos.0;
+        while y < self.lines.len() && self.lines[y].rows <= remaining {
+            remaining -= self.lines[y].rows;
+            y += 1;
+        }
+        y
+    }
+
+    /// The furthest we can scroll down (by whole lines) with the screen still full, for Ctrl-D and Ctrl-F.
+    fn max_scroll(&self) -> usize {
+        let mut rows = 0;
+        for y in (0..self.lines.len()).rev() {
+            rows += self.lines[y].rows;
+            if rows > self.available_height {
+                return (y + 1).min(self.lines.len() - 1);
+            }
+        }
+        0
+    }
+
+    /// The column the cursor is actually on, snapping back to the last character of short lines.
+    fn cursor_x(&self) -> usize {
+        self.cursor_pos
+            .0
+            .min(self.lines[self.cursor_pos.1].len().saturating_sub(1))
+    }
+
+    /// The column of the first non-blank character of a line (or the last character, if it's all blank), for `^` and line jumps.
+    fn first_non_blank(&self, y: usize) -> usize {
+        let line = &self.lines[y];
+        (0..line.len())
+            .find(|&x| self.class((x, y)) != CharClass::Blank)
+            .unwrap_or(line.len().saturating_sub(1))
+    }
+
+    /// Moves the cursor up or down by some number of lines, clamped to the file, keeping its column.
+    fn move_lines(&mut self, delta: isize) {
+        self.cursor_pos.1 = self
+            .cursor_pos
+            .1
+            .saturating_add_signed(delta)
+            .min(self.lines.len() - 1);
+    }
+
+    /// Moves the cursor to the first non-blank character of a line (clamped to the file), as for `G` and `:N`.
+    fn goto_line(&mut self, y: usize) {
+        let y = y.min(self.lines.len() - 1);
+        self.cursor_pos = (self.first_non_blank(y), y);
+    }
+
+    /// The position after `pos` in the file (moving onto the next line at the end of one), if any.
+    fn next_pos(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        if x + 1 < self.lines[y].len() {
+            Some((x + 1, y))
+        } else if y + 1 < self.lines.len() {
+            Some((0, y + 1))
+        } else {
+            None
+        }
+    }
+
+    /// The position before `pos` in the file (moving onto the end of the previous line at the start of one), if any.
+    fn prev_pos(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        if x > 0 {
+            Some((x - 1, y))
+        } else if y > 0 {
+            Some((self.lines[y - 1].len().saturating_sub(1), y - 1))
+        } else {
+            None
+        }
+    }
+
+    /// The class of the grapheme at `pos` (going by its first character, with empty lines counting as blank).
+    fn class(&self, (x, y): (usize, usize)) -> CharClass {
+        self.lines[y]
+            .grapheme(x)
+            .and_then(|grapheme| grapheme.chars().next())
+            .map_or(CharClass::Blank, CharClass::of)
+    }
+
+    /// The start of the next word after `pos`, for `w`. As in vim, empty lines count as words.
+    fn next_word_start(&self, mut pos: (usize, usize)) -> (usize, usize) {
+        let class = self.class(pos);
+        let mut passed_blank = false;
+        while let Some(next) = self.next_pos(pos) {
+            if next.1 != pos.1 {
+                // Line breaks count as blanks
+                passed_blank = true;
+                if self.lines[next.1].is_empty() {
+                    return next;
+                }
+            }
+            pos = next;
+            match self.class(pos) {
+                CharClass::Blank => passed_blank = true,
+                next_class if passed_blank || next_class != class => return pos,
+                _ => {}
+            }
+        }
+        pos
+    }
+
+    /// The end of the next word after `pos`, for `e`.
+    fn next_word_end(&self, pos: (usize, usize)) -> (usize, usize) {
+        let Some(mut pos) = self.next_pos(pos) else {
+            return pos;
+        };
+        while self.class(pos) == CharClass::Blank {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+        let class = self.class(pos);
+        while let Some(next) = self.next_pos(pos) {
+            if next.1 != pos.1 || self.class(next) != class {
+                break;
+            }
+            pos = next;
+        }
+        pos
+    }
+
+    /// The start of the word before `pos`, for `b`. As in vim, empty lines count as words.
+    fn prev_word_start(&self, pos: (usize, usize)) -> (usize, usize) {
+        let Some(mut pos) = self.prev_pos(pos) else {
+            return pos;
+        };
+        while self.class(pos) == CharClass::Blank && !self.lines[pos.1].is_empty() {
+            match self.prev_pos(pos) {
+                Some(prev) => pos = prev,
+                None => return pos,
+            }
+        }
+        let class = self.class(pos);
+        while let Some(prev) = self.prev_pos(pos) {
+            if prev.1 != pos.1 || self.class(prev) != class {
+                break;
+            }
+            pos = prev;
+        }
+        pos
+    }
+
+    /// The positions (in graphemes) of all matches of `regex` in a line, with matches starting inside a grapheme (e.g. on a combining
+    /// mark) counting as starting at it.
+    fn matches(&self, regex: &Regex, y: usize) -> Vec<usize> {
+        let line = &self.lines[y];
+        regex
+            .find_iter(&line.text)
+            .map(|m| {
+                line.offsets
+                    .partition_point(|&offset| offset <= m.start())
+                    .saturating_sub(1)
+            })
+            .collect()
+    }
+
+    /// Starts a search for a pattern (or the last one, if empty), moving to the first match.
+    fn start_search(&mut self, pattern: &str, forward: bool) {
+        if pattern.is_empty() {
+            match self.search.take() {
+                Some((regex, _)) => self.search = Some((regex, forward)),
+                None => {
+                    self.message = Some("E35: No previous regular expression".to_string());
+                    return;
+                }
+            }
+        } else {
+            match RegexBuilder::new(pattern).size_limit(1 << 16).build() {
+                Ok(regex) => self.search = Some((regex, forward)),
+                Err(_) => {
+                    self.message = Some(format!("E383: Invalid search string: {pattern}"));
+                    return;
+                }
+            }
+        }
+        self.search_next(false);
+    }
+
+    /// Moves the cursor to the next match of the last search (in the direction it was made, or the other way if `reverse`, as for
+    /// `n` and `N`), wrapping around the file like vim does.
+    fn search_next(&mut self, reverse: bool) {
+        let Some((regex, forward)) = &self.search else {
+            self.message = Some("E35: No previous regular expression".to_string());
+            return;
+        };
+        let forward = *forward != reverse;
+        let (x, y) = (self.cursor_x(), self.cursor_pos.1);
+        let lines = self.lines.len();
+
+        // Check each line in turn starting from the cursor's, coming back round to the start of it at the end
+        let found = (0..=lines).find_map(|i| {
+            let line = if forward {
+                (y + i) % lines
+            } else {
+                (y + lines - i % lines) % lines
+            };
+            let matches = self.matches(regex, line);
+            let column = match (forward, i) {
+                (true, 0) => matches.into_iter().find(|&column| column > x),
+                (true, _) => matches.first().copied(),
+                (false, 0) => matches.into_iter().rev().find(|&column| column < x),
+                (false, _) => matches.last().copied(),
+            };
+            column.map(|column| (i, (column, line)))
+        });
+        match found {
+            Some((i, pos)) => {
+                if forward && y + i >= lines {
+                    self.message = Some("search hit BOTTOM, continuing at TOP".to_string());
+                } else if !forward && i > y {
+                    self.message = Some("search hit TOP, continuing at BOTTOM".to_string());
+                }
+                self.cursor_pos = pos;
+            }
+            None => {
+                self.message = Some(format!("E486: Pattern not found: {}", regex.as_str()));
+            }
+        }
+    }
+
+    /// Runs a command typed after `:`.
+    fn run_command(&mut self, command: &str) {
+        match command.trim() {
+            "" => {}
+            "q" | "q!" | "qa" | "qa!" | "quit" | "x" => self.finished = true,
+            "w" | "w!" | "wq" | "wq!" | "write" => {
+                self.message = Some("E45: 'readonly' option is set".to_string());
+            }
+            "$" => self.goto_line(self.lines.len() - 1),
+            command => match command.parse::<usize>() {
+                Ok(line) => self.goto_line(line.saturating_sub(1)),
+                Err(_) => self.message = Some(format!("E492: Not an editor command: {command}")),
+            },
+        }
+    }
+
+    /// Processes a key press while the command line is being typed.
+    fn process_command_line(&mut self, key: Key) {
+        let Some((_, text)) = &mut self.command_line else {
+            return;
+        };
+        match key {
+            // Escape, or backspace with nothing left to delete, cancels
+            Key::Escape => self.command_line = None,
+            Key::Backspace if text.pop().is_none() => self.command_line = None,
+            Key::Enter => {
+                if let Some((prefix, text)) = self.command_line.take() {
+                    match prefix {
+                        ':' => self.run_command(&text),
+                        prefix => self.start_search(&text, prefix == '/'),
+                    }
+                }
+            }
+            Key::Char(c) => text.push(c),
+            Key::Paste(pasted) => {
+                text.push_str(&text::sanitize(&pasted.replace(['\r', '\n'], " ")))
+            }
+            _ => {}
+        }
+    }
+}
+impl RunningApp for Vim {
+    fn startup(
+        channel: &SshChannel,
+        command: String,
+    ) -> Result<(Box<dyn RunningApp>, Vec<u8>), Vec<u8>> {
+        let full_path = command
+            .split(' ')
+            .nth(1)
+            .ok_or_else(|| Vec::from(b"vi: usage: vi <filename>\r\n" as &[u8]))?;
+        let file = channel
+            .content
+            .get_file(channel.current_dir, full_path)
+            .ok_or_else(|| format!("vi: cannot open \"{}\": No such file\r\n", full_path))?;
+        let vim = Vim::new(full_path, &file.lines, channel.term_size);
+        let response = vim.render();
+        Ok((Box::new(vim), response))
+    }
+    fn key(&mut self, key: Key) -> Vec<u8> {
+        self.message = None;
+        if self.command_line.is_some() {
+            self.process_command_line(key);
+            if self.finished {
+                return vec![];
+            }
+            return self.update_cursor(false);
+        }
+
+        // Special keys do the same as their vim equivalents, with escape just cancelling any count or `g`
+        let key = match key {
+            Key::Left(_) | Key::Backspace => Key::Char('h'),
+            Key::Down(_) => Key::Char('j'),
+            Key::Up(_) => Key::Char('k'),
+            Key::Right(_) => Key::Char('l'),
+            Key::Home(_) => Key::Char('0'),
+            Key::End(_) => Key::Char('$'),
+            Key::Enter => Key::Char('+'),
+            Key::PageUp => Key::Ctrl('b'),
+            Key::PageDown => Key::Ctrl('f'),
+            Key::Escape => {
+                self.count = None;
+                self.pending_g = false;
+                return self.update_cursor(false);
+            }
+            key => key,
+        };
+
+        let old_scroll = self.scroll_pos;
+        let mut must_rerender = false;
+        let count = self.count.take();
+        let n = count.unwrap_or(1);
+        let pending_g = std::mem::take(&mut self.pending_g);
+        let half_page = (self.available_height / 2).max(1);
+        let page = self.available_height.saturating_sub(2).max(1);
+        match key {
+            Key::Char(digit @ '0'..='9') if digit != '0' || count.is_some() => {
+                // Part of a count
+                let digit = digit as usize - '0' as usize;
+                self.count = Some((count.unwrap_or(0) * 10 + digit).min(MAX_COUNT));
+                return vec![];
+            }
+            Key::Char('g') if pending_g => self.goto_line(count.map_or(0, |n| n - 1)),
+            Key::Char('g') => {
+                self.pending_g = true;
+                self.count = count;
+                return vec![];
+            }
+            Key::Char('G') => self.goto_line(count.map_or(self.lines.len() - 1, |n| n - 1)),
+            Key::Char('h') | Key::Ctrl('h') => {
+                self.cursor_pos.0 = self.cursor_x().saturating_sub(n)
+            }
+            Key::Char('l' | ' ') => {
+                let last_char = self.lines[self.cursor_pos.1].len().saturating_sub(1);
+                self.cursor_pos.0 = (self.cursor_x() + n).min(last_char);
+            }
+            Key::Char('j') | Key::Ctrl('n' | 'j') => self.move_lines(n as isize),
+            Key::Char('k') | Key::Ctrl('p') => self.move_lines(-(n as isize)),
+            Key::Char('+') => {
+                self.move_lines(n as isize);
+                self.cursor_pos.0 = self.first_non_blank(self.cursor_pos.1);
+            }
+            Key::Char('-') => {
+                self.move_lines(-(n as isize));
+                self.cursor_pos.0 = self.first_non_blank(self.cursor_pos.1);
+            }
+            Key::Char('0') => self.cursor_pos.0 = 0,
+            Key::Char('^') => self.cursor_pos.0 = self.first_non_blank(self.cursor_pos.1),
+            Key::Char('$') => {
+                // Move to end of line by setting cursor x to high value (not too high to avoid overflow), going down for counts
+                self.move_lines(n as isize - 1);
+                self.cursor_pos.0 = usize::MAX / 4;
+            }
+            Key::Char(motion @ ('w' | 'b' | 'e')) => {
+                let mut pos = (self.cursor_x(), self.cursor_pos.1);
+                for _ in 0..n {
+                    pos = match motion {
+                        'w' => self.next_word_start(pos),
+                        'b' => self.prev_word_start(pos),
+                        _ => self.next_word_end(pos),
+                    };
+                }
+                self.cursor_pos = pos;
+            }
+            Key::Ctrl('d') => {
+                // Scroll down half a screen (moving the cursor with it)
+                self.scroll_pos = ((self.scroll_pos.0 + half_page).min(self.max_scroll()), 0);
+                self.move_lines(half_page as isize);
+            }
+            Key::Ctrl('u') => {
+                // Scroll up half a screen (moving the cursor with it)
+                self.scroll_pos = (self.scroll_pos.0.saturating_sub(half_page), 0);
+                self.move_lines(-(half_page as isize));
+            }
+            Key::Ctrl('f') => {
+                // Scroll down a screen (keeping the cursor on it)
+                self.scroll_pos = ((self.scroll_pos.0 + page * n).min(self.max_scroll()), 0);
+                self.cursor_pos.1 = self.cursor_pos.1.max(self.scroll_pos.0);
+            }
+            Key::Ctrl('b') => {
+                // Scroll up a screen (keeping the cursor on it)
+                self.scroll_pos = (self.scroll_pos.0.saturating_sub(page * n), 0);
+                let last_onscreen = self.bottom_line().saturating_sub(1);
+                self.cursor_pos.1 = self.cursor_pos.1.min(last_onscreen.max(self.scroll_pos.0));
+            }
+            Key::Ctrl('l') => must_rerender = true,
+            Key::Ctrl('g') => {
+                // Show file info
+                self.message = Some(format!(
+                    "\"{}\" [readonly] {} lines --{}%--",
+                    self.path,
+                    self.lines.len(),
+                    (self.cursor_pos.1 + 1) * 100 / self.lines.len()
+                ));
+            }
+            Key::Char(prefix @ (':' | '/' | '?')) => {
+                self.command_line = Some((prefix, String::new()))
+            }
+            Key::Char(direction @ ('n' | 'N')) => {
+                for _ in 0..n {
+                    self.search_next(direction == 'N');
+                }
+            }
+            key => {
+                debug!("key {key:?} not implemented for vim");
+            }
+        }
+        self.update_cursor(must_rerender || self.scroll_pos != old_scroll)
+    }
+    fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
+        self.term_size = (
+            width.clamp(2, u16::MAX as u32) as u16,
+            height.clamp(2, u16::MAX as u32) as u16,
+        );
+        self.available_height = self.term_size.1 as usize - 1;
+        for line in &mut self.lines {
+            line.layout(self.term_size.0 as usize);
+        }
+        // Sublines depend on the width, so just scroll to the start of the top line
+        self.scroll_pos.1 = 0;
+
+        // If cursor is off screen, scroll to it
+        self.update_cursor(true)
+    }
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::ssh::input::{arbitrary_input, InputParser};
+
+    /// Something done to vim: some input, or resizing the terminal.
+    #[derive(Clone, Debug)]
+    enum Action {
+        Input(Vec<u8>),
+        Resize(u32, u32),
+    }
+
+    /// Vim's keys (with counts), any other input, and resizes.
+    fn actions() -> impl Strategy<Value = Vec<Action>> {
+        let action = prop_oneof![
+            4 => "([1-9][0-9]?)?[hjklwbeG$^+ 0-]".prop_map(|keys| Action::Input(keys.into_bytes())),
+            2 => prop::sample::select(vec![
+                &b"gg"[..],
+                b"\x04",
+                b"\x15",
+                b"\x06",
+                b"\x02",
+                b"\x07",
+                b"\x0c",
+                b"\r",
+                b"\x1b",
+                b"\x7f",
+                b"\x1b[A",
+                b"\x1b[B",
+                b"\x1b[5~",
+                b"\x1b[6~",
+                b"n",
+                b"N",
+            ])
+            .prop_map(|keys| Action::Input(keys.to_vec())),
+            1 => "[/?:][a-z.*\\\\(\\[]{0,4}\r?".prop_map(|keys| Action::Input(keys.into_bytes())),
+            1 => arbitrary_input().prop_map(Action::Input),
+            1 => (0u32..100, 0u32..40).prop_map(|(width, height)| Action::Resize(width, height)),
+        ];
+        proptest::collection::vec(action, 0..48)
+    }
+
+    /// Checks the cursor and scroll positions are in the file, and the cursor is on screen.
+    fn check_positions(vim: &Vim) -> Result<(), TestCaseError> {
+        prop_assert!(vim.cursor_pos.1 < vim.lines.len());
+        prop_assert!(vim.scroll_pos.0 <= vim.cursor_pos.1);
+        prop_assert!(vim.scroll_pos.1 < vim.lines[vim.scroll_pos.0].rows.max(1));
+        let line = &vim.lines[vim.cursor_pos.1];
+        prop_assert!(vim.cursor_x() < line.len().max(1));
+        if vim.command_line.is_none() {
+            let (x, y) = vim.get_cursor_screen();
+            prop_assert!(
+                (0..vim.term_size.0 as isize).contains(&x),
+                "cursor x {} off screen",
+                x
+            );
+            prop_assert!(
+                (0..vim.available_height as isize).contains(&y),
+                "cursor y {} off screen",
+                y
+            );
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn vim_keeps_cursor_on_screen(
+            lines in proptest::collection::vec(text::tricky_text(), 1..40),
+            term_size in (0u32..100, 0u32..40),
+            actions in actions(),
+        ) {
+            let mut vim = Vim::new("file.txt", &lines, term_size);
+            vim.render();
+            check_positions(&vim)?;
+            let mut parser = InputParser::default();
+            for action in actions {
+                let keys = match action {
+                    Action::Input(input) => parser.parse(&input),
+                    Action::Resize(width, height) => {
+                        vim.resize(width, height);
+                        vec![]
+                    }
+                };
+                for key in keys {
+                    vim.key(key);
+                    if vim.finished() {
+                        return Ok(());
+                    }
+                }
+                check_positions(&vim)?;
+            }
+        }
+    }
+}
@@ -0,0 +1,617 @@
+//! A channel in an SSH connection, running an interactive shell, a single command, an SCP download or SFTP. A client can open several
+//! at once (such as a shell alongside SFTP), each with its own terminal, working directory and running app.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use russh::{
+    server::{Handle, Session},
+    ChannelId, CryptoVec,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace};
+
+use super::{
+    apps::{Compose, Less, Menu, RunningApp, Startup, Vim},
+    commands::{self, Completer, Context, Entry, Output},
+    contact::FollowTask,
+    content::{SshContent, WELCOME_MESSAGE},
+    input::{InputParser, Key},
+    scp::ScpSource,
+    session::SshSession,
+    sftp::Sftp,
+    style::{self, Capabilities, Colors},
+    terminal::{Shell, TerminalUtils},
+};
+
+/// Turns on bracketed paste in the client's terminal.
+const BRACKETED_PASTE_ON: &[u8] = b"\x1b[?2004h";
+/// Turns bracketed paste back off, before closing the channel.
+const BRACKETED_PASTE_OFF: &[u8] = b"\x1b[?2004l";
+/// The maximum length of a line of input without a PTY (in bytes), with longer lines dropped.
+const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// What running a command line left going.
+enum Ran {
+    /// The command finished, with the given exit status.
+    Finished(u32),
+    /// The command started an app or is following a thread, which carries on until it exits (or Ctrl-C).
+    Running,
+    /// The user asked to leave, with `exit` or `logout`.
+    Exit,
+}
+
+pub struct SshChannel {
+    id: ChannelId,
+    /// The ID of the connection this is in, for logging.
+    client: usize,
+    addr: SocketAddr,
+    username: String,
+    /// Whether the user logged in as the admin, allowing admin commands.
+    admin: bool,
+    shell: Shell,
+    /// Parses input into key presses, for the shell or running app.
+    input: InputParser,
+    pub content: Arc<SshContent>,
+    pub current_dir: usize,
+    pub term_size: (u32, u32),
+    /// The size of the terminal in pixels, if the client says (for scaling images), or otherwise zeros.
+    pub pixel_size: (u32, u32),
+    /// What the client's terminal supports, from the pty request (plain text until then).
+    pub capabilities: Capabilities,
+    /// The client's `TERM`, from the pty request, for going back to guessing colours from it with `colors auto`.
+    term: String,
+    /// The colour mode the user chose with `colors`, if any, used instead of the one guessed from `TERM`.
+    colors: Option<Colors>,
+    /// Whether the client asked for a PTY. Without one, input is read a line at a time without echoing or prompting, and output has
+    /// `\n` newlines, for scripts.
+    pty: bool,
+    /// Whether the channel is running a single command (from an exec request), closing once it's done rather than prompting again.
+    exec: bool,
+    /// Input without a PTY that isn't a full line yet.
+    line: Vec<u8>,
+    /// The exit status of the last command, sent when a shell without a PTY ends.
+    status: u32,
+    /// The SFTP session, if the client started the subsystem, which then handles all input.
+    sftp: Option<Sftp>,
+    /// The SCP download in progress, if the client ran `scp -f`, which then handles all input.
+    scp: Option<ScpSource>,
+    running_app: Option<Box<dyn RunningApp>>,
+    /// The thread being followed with `msg view --follow`, if any, during which input is ignored until Ctrl-C.
+    following: Option<FollowTask>,
+    /// A channel to refresh the connection's timeout, for following a thread.
+    timeout_refresh: mpsc::Sender<()>,
+}
+impl SshChannel {
+    /// Opens a channel in the given (authenticated) session.
+    pub fn new(id: ChannelId, session: &SshSession) -> Self {
+        Self {
+            id,
+            client: session.id,
+            addr: session.addr,
+            username: session.username.clone(),
+            admin: session.admin,
+            shell: Shell::default(),
+            input: InputParser::default(),
+            content: session.content.clone(),
+            current_dir: 0,
+            term_size: (80, 24), // Just a guess, will be updated by the pty request (if there is one)
+            pixel_size: (0, 0),
+            capabilities: Capabilities::default(),
+            term: String::new(),
+            colors: None,
+            pty: false,
+            exec: false,
+            line: vec![],
+            status: 0,
+            sftp: None,
+            scp: None,
+            running_app: None,
+            following: None,
+            timeout_refresh: session.timeout_refresh.clone(),
+        }
+    }
+    /// Get the current prompt.
+    pub fn prompt(&self) -> Vec<u8> {
+        let mut prompt = self.username.as_bytes().to_vec();
+        prompt.push(b'@');
+        prompt.extend(crate::CONFIG.domain.as_bytes());
+        prompt.push(b':');
+        prompt.extend(self.content.get(self.current_dir).path.as_bytes());
+        prompt.extend(b"> ");
+        prompt
+    }
+    /// Gets the context for running commands.
+    pub fn context(&self) -> Context<'_> {
+        Context {
+            content: &self.content,
+            current_dir: self.current_dir,
+            term_size: self.term_size,
+            pixel_size: self.pixel_size,
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// Handles a pty request, setting up the terminal.
+    pub fn pty(
+        &mut self,
+        term: &str,
+        term_size: (u32, u32),
+        pixel_size: (u32, u32),
+        session: &mut Session,
+    ) {
+        self.pty = true;
+        self.term_size = term_size;
+        self.pixel_size = pixel_size;
+        self.term = term.to_string();
+        self.capabilities = Capabilities::from_term(term);
+        if let Some(colors) = self.colors.filter(|_| self.capabilities.styles) {
+            self.capabilities.colors = colors;
+        }
+        debug!(
+            "Client {} has terminal {term:?} on {:?}, with {:?}",
+            self.client, self.id, self.capabilities
+        );
+        // Ask for bracketed paste, so pasted text can't run commands by itself
+        session.data(self.id, CryptoVec::from_slice(BRACKETED_PASTE_ON));
+    }
+    /// Handles an environment variable sent by the client (after the pty request).
+    pub fn env(&mut self, name: &str, value: &str) {
+        // Some terminals can only be identified by the environment variables they set
+        self.capabilities.update_from_env(name, value);
+    }
+    /// Handles the client's terminal being resized.
+    pub fn resize(&mut self, term_size: (u32, u32), pixel_size: (u32, u32), session: &mut Session) {
+        self.term_size = term_size;
+        self.pixel_size = pixel_size;
+        if let Some(ref mut running_app) = self.running_app {
+            let resp = running_app.resize(term_size.0, term_size.1);
+            session.data(self.id, CryptoVec::from(resp));
+        }
+    }
+
+    /// Starts an interactive shell (or one reading commands from input, without a PTY).
+    pub fn shell(&mut self, session: &mut Session) {
+        session.channel_success(self.id);
+        // Without a PTY, commands are read from input without any prompting (e.g. `ssh -T` with a script)
+        if self.pty {
+            session.data(self.id, Vec::from(WELCOME_MESSAGE).into());
+            session.data(self.id, CryptoVec::from(self.prompt()));
+        }
+    }
+    /// Runs a single command, closing the channel once it's done.
+    pub async fn exec(&mut self, command: &str, session: &mut Session) {
+        session.channel_success(self.id);
+        self.exec = true;
+        if command.split(' ').next() == Some("scp") {
+            // SCP goes back and forth with the client, so carries on as it sends data
+            info!("Client {} ran scp: {:?}", self.client, command);
+            let context = self.context();
+            let scp =
+                commands::parse(&context, command).and_then(|pipeline| match pipeline.as_slice() {
+                    [args] => ScpSource::new(&context, &args[1..]),
+                    _ => Err("can't be used in a pipeline".to_string()),
+                });
+            match scp {
+                Ok(scp) => self.scp = Some(scp),
+                Err(e) => {
+                    session.data(
+                        self.id,
+                        CryptoVec::from(format!("\x01scp: {e}\n").into_bytes()),
+                    );
+                    self.close(session, 1);
+                }
+            }
+            return;
+        }
+        self.run_noninteractive(command, session).await;
+    }
+    /// Starts a subsystem, of which only SFTP is supported.
+    pub fn subsystem(&mut self, name: &str, session: &mut Session) {
+        if name == "sftp" {
+            info!("Client {} started SFTP on {:?}", self.client, self.id);
+            self.sftp = Some(Sftp::new(self.content.clone()));
+            session.channel_success(self.id);
+        } else {
+            session.channel_failure(self.id);
+        }
+    }
+    /// Handles the end of input from the client.
+    pub async fn eof(&mut self, session: &mut Session) {
+        // Without a PTY, the end of input ends the shell (after running any last line without a newline), as for a script
+        if !self.pty && !self.exec {
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+            if line.is_empty() || !self.run_noninteractive(&line, session).await {
+                self.close(session, self.status);
+            }
+        }
+    }
+
+    /// Runs a command line, adding its output to `output` and any error messages to `errors` (both with `\r\n` newlines). Apps
+    /// (and following a thread) need a PTY, so without one they're refused (or only show the thread).
+    async fn run_command(
+        &mut self,
+        command: &str,
+        output: &mut Vec<u8>,
+        errors: &mut Vec<u8>,
+        handle: Handle,
+    ) -> Ran {
+        info!("Client {} ran command: {:?}", self.client, command);
+        let command_name = command.split(' ').next().unwrap_or("");
+        // `msg` and `admin` take the rest of the line as it is (so messages don't need quoting), and everything else is parsed
+        match command_name {
+            "msg"
+                if self.pty
+                    && matches!(
+                        command.split_whitespace().collect::<Vec<_>>().as_slice(),
+                        ["msg", "send"] | ["msg", "reply", _]
+                    ) =>
+            {
+                // Without a body, the message is written in the editor (which runs the command again with the body once it's sent)
+                return self.start_app(Compose::startup(self, command.to_string()), output, errors);
+            }
+            "msg" => {
+                let (msg_output, follow) = super::contact::msg(command, self.addr.ip()).await;
+                output.extend(msg_output);
+                if let Some(follow) = follow.filter(|_| self.pty) {
+                    self.following =
+                        Some(follow.spawn(handle, self.id, self.timeout_refresh.clone()));
+                    return Ran::Running;
+                }
+                return Ran::Finished(0);
+            }
+            "admin" => {
+                if self.admin {
+                    output.extend(super::admin::admin(command).await);
+                    return Ran::Finished(0);
+                }
+                errors.extend(b"admin: permission denied\r\n");
+                return Ran::Finished(1);
+            }
+            _ => {}
+        }
+        let context = self.context();
+        let pipeline = match commands::parse(&context, command) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                errors.extend(format!("{e}\r\n").as_bytes());
+                return Ran::Finished(1);
+            }
+        };
+        match pipeline.as_slice() {
+            [] => Ran::Finished(0),
+            [args] if matches!(args[0].as_str(), "exit" | "logout") => Ran::Exit,
+            [args] if args[0] == "colors" => self.colors_command(&args[1..], output, errors),
+            [args] if args[0] == "cd" => {
+                let dir = args.get(1).map_or("/", String::as_str);
+                match context.resolve(dir) {
+                    Some(Entry::Directory(id)) => {
+                        self.current_dir = id;
+                        Ran::Finished(0)
+                    }
+                    Some(Entry::File(_)) => {
+                        errors.extend(format!("cd: {dir}: Not a directory\r\n").as_bytes());
+                        Ran::Finished(1)
+                    }
+                    None => {
+                        errors.extend(format!("\"{dir}\": no such directory\r\n").as_bytes());
+                        Ran::Finished(1)
+                    }
+                }
+            }
+            [args] if matches!(args[0].as_str(), "vi" | "menu") => {
+                if !self.pty {
+                    errors.extend(
+                        format!("{}: needs a terminal (try `ssh -t`)\r\n", args[0]).as_bytes(),
+                    );
+                    return Ran::Finished(1);
+                }
+                let startup = if args[0] == "vi" {
+                    Vim::startup(self, args.join(" "))
+                } else {
+                    Menu::startup(self, args.join(" "))
+                };
+                self.start_app(startup, output, errors)
+            }
+            pipeline => {
+                if let Some(args) = pipeline
+                    .iter()
+                    .find(|args| commands::SESSION_COMMANDS.contains(&args[0].as_str()))
+                {
+                    errors
+                        .extend(format!("{}: can't be used in a pipeline\r\n", args[0]).as_bytes());
+                    return Ran::Finished(1);
+                }
+                match commands::run(&context, pipeline) {
+                    Output::Print(print) => {
+                        output.extend(print);
+                        Ran::Finished(0)
+                    }
+                    Output::Error {
+                        output: print,
+                        error,
+                    } => {
+                        output.extend(print);
+                        errors.extend(error);
+                        Ran::Finished(1)
+                    }
+                    // Without a PTY there's no one to page for, so just print it all
+                    Output::Page { lines, .. } if !self.pty => {
+                        for line in lines {
+                            output.extend(style::render(&line, self.capabilities).as_bytes());
+                            output.extend(b"\r\n");
+                        }
+                        Ran::Finished(0)
+                    }
+                    Output::Page { lines, title } => {
+                        let (less, startup_resp) = Less::new(
+                            title,
+                            lines,
+                            self.term_size,
+                            self.pixel_size,
+                            self.capabilities,
+                        );
+                        self.running_app = Some(Box::new(less));
+                        output.extend(startup_resp);
+                        Ran::Running
+                    }
+                }
+            }
+        }
+    }
+    /// Starts running an app, if it started up successfully.
+    fn start_app(&mut self, startup: Startup, output: &mut Vec<u8>, errors: &mut Vec<u8>) -> Ran {
+        match startup {
+            Ok((running_app, startup_resp)) => {
+                self.running_app = Some(running_app);
+                output.extend(startup_resp);
+                Ran::Running
+            }
+            Err(error_resp) => {
+                errors.extend(error_resp);
+                Ran::Finished(1)
+            }
+        }
+    }
+    /// Runs the `colors` command, which shows the colour mode, or sets it (for the rest of the session) so users can fix a wrong guess
+    /// from their `TERM`.
+    fn colors_command(
+        &mut self,
+        args: &[String],
+        output: &mut Vec<u8>,
+        errors: &mut Vec<u8>,
+    ) -> Ran {
+        let colors = match args {
+            [] => {
+                let source = if self.colors.is_some() {
+                    "as chosen"
+                } else {
+                    "guessed from your terminal"
+                };
+                output.extend(
+                    format!(
+                        "Colors: {} ({source}). Use `colors <{}|auto>` to change.\r\n",
+                        self.capabilities.colors.name(),
+                        Colors::NAMES.join("|")
+                    )
+                    .as_bytes(),
+                );
+                return Ran::Finished(0);
+            }
+            [mode] if mode == "auto" => None,
+            [mode] => match Colors::from_name(mode) {
+                Some(colors) => Some(colors),
+                None => {
+                    errors.extend(format!("colors: {mode}: unknown mode\r\n").as_bytes());
+                    return Ran::Finished(1);
+                }
+            },
+            _ => {
+                errors.extend(b"colors: too many arguments\r\n");
+                return Ran::Finished(1);
+            }
+        };
+        self.colors = colors;
+        if self.capabilities.styles {
+            self.capabilities.colors = colors.unwrap_or(Capabilities::from_term(&self.term).colors);
+        }
+        Ran::Finished(0)
+    }
+    /// Runs a command outside the interactive shell (from an exec request, or a line of input without a PTY), sending its output,
+    /// with any errors on stderr. Returns whether that closed the channel, which it does once there's nothing left to run.
+    async fn run_noninteractive(&mut self, command: &str, session: &mut Session) -> bool {
+        let (mut output, mut errors) = (vec![], vec![]);
+        let ran = self
+            .run_command(command, &mut output, &mut errors, session.handle())
+            .await;
+        session.data(self.id, self.newlines(output));
+        if !errors.is_empty() {
+            session.extended_data(self.id, 1, self.newlines(errors));
+        }
+        match ran {
+            Ran::Finished(status) => {
+                self.status = status;
+                if self.exec {
+                    self.close(session, status);
+                }
+                self.exec
+            }
+            Ran::Running => false,
+            Ran::Exit => {
+                self.close(session, self.status);
+                true
+            }
+        }
+    }
+    /// Converts output to send to the client, with `\n` newlines if there's no PTY to turn them into `\r\n`.
+    fn newlines(&self, data: Vec<u8>) -> CryptoVec {
+        if self.pty {
+            return CryptoVec::from(data);
+        }
+        let mut result = Vec::with_capacity(data.len());
+        for (i, &byte) in data.iter().enumerate() {
+            if !(byte == b'\r' && data.get(i + 1) == Some(&b'\n')) {
+                result.push(byte);
+            }
+        }
+        CryptoVec::from(result)
+    }
+    /// Ends the channel, sending the exit status and closing it (rather than disconnecting, as clients drop any output still
+    /// buffered when they're disconnected, and other channels may still be going).
+    fn close(&mut self, session: &mut Session, status: u32) {
+        if self.pty {
+            session.data(self.id, CryptoVec::from_slice(BRACKETED_PASTE_OFF));
+        }
+        // Stop following, so nothing more is sent after the close
+        self.following = None;
+        session.exit_status_request(self.id, status);
+        session.eof(self.id);
+        session.close(self.id);
+    }
+
+    /// Handles data from the client: SFTP or SCP packets, lines of commands without a PTY, or key presses.
+    pub async fn data(&mut self, data: &[u8], session: &mut Session) {
+        trace!(
+            "Client {} sent data on {:?}: {:?}",
+            self.client,
+            self.id,
+            data
+        );
+
+        if let Some(sftp) = &mut self.sftp {
+            let (response, status) = sftp.data(data).await;
+            session.data(self.id, CryptoVec::from(response));
+            if let Some(status) = status {
+                self.sftp = None;
+                self.close(session, status);
+            }
+            return;
+        }
+        if let Some(scp) = &mut self.scp {
+            let (response, status) = scp.data(data).await;
+            session.data(self.id, CryptoVec::from(response));
+            if let Some(status) = status {
+                self.scp = None;
+                self.close(session, status);
+            }
+            return;
+        }
+        if !self.pty {
+            // Each line of input is a command (and commands don't take input, so anything sent to a single command is ignored)
+            if self.exec {
+                return;
+            }
+            self.line.extend(data);
+            while let Some(end) = self.line.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = self.line.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                if self
+                    .run_noninteractive(line.trim_end_matches(['\r', '\n']), session)
+                    .await
+                {
+                    return;
+                }
+            }
+            if self.line.len() > MAX_LINE_LENGTH {
+                self.line.clear();
+                session.extended_data(self.id, 1, CryptoVec::from_slice(b"line too long\n"));
+                self.status = 1;
+            }
+            return;
+        }
+
+        // Process data, as key presses
+        let mut response = vec![];
+        for key in self.input.parse(data) {
+            if self.following.is_some() {
+                if key == Key::Ctrl('c') {
+                    // CTRL-C, stop following and reprompt (or finish, for a single command)
+                    self.following = None;
+                    if self.exec {
+                        session.data(self.id, CryptoVec::from(response));
+                        self.close(session, 130);
+                        return;
+                    }
+                    response.extend(self.prompt());
+                }
+                continue;
+            }
+            match self.running_app {
+                None if self.exec => {
+                    // The command has finished, so there's nothing to take input
+                }
+                None => {
+                    // No app running, so shell handles input (completing against the current directory)
+                    let completer = Completer {
+                        context: Context {
+                            content: &self.content,
+                            current_dir: self.current_dir,
+                            term_size: self.term_size,
+                            pixel_size: self.pixel_size,
+                            capabilities: self.capabilities,
+                        },
+                        prompt: self.prompt(),
+                    };
+                    let (r, command) = self.shell.process(key, &completer);
+                    response.extend(r);
+                    if let Some(command) = command {
+                        let mut errors = vec![];
+                        match self
+                            .run_command(&command, &mut response, &mut errors, session.handle())
+                            .await
+                        {
+                            Ran::Exit => {
+                                response.extend(b"Goodbye!\r\n");
+                                session.data(self.id, CryptoVec::from(response));
+                                self.close(session, 0);
+                                return;
+                            }
+                            Ran::Finished(_) => {
+                                // Nothing was started, so reprompt
+                                response.extend(errors);
+                                response.extend(self.prompt());
+                            }
+                            Ran::Running => response.extend(errors),
+                        }
+                    }
+                }
+                Some(ref mut app) => {
+                    // CTRL-C exits, as does the app by itself (e.g. `q` in `less`)
+                    let status = if key == Key::Ctrl('c') {
+                        Some(130)
+                    } else {
+                        response.extend(app.key(key));
+                        app.finished().then_some(0)
+                    };
+                    if let Some(mut status) = status {
+                        // Clear screen, run anything the app left for the shell to do, and reprompt (or finish, for a single command)
+                        response.extend(app.cleanup());
+                        response.append(
+                            &mut TerminalUtils::new().clear().move_cursor(0, 0).into_data(),
+                        );
+                        let exit_command = app.exit_command();
+                        self.running_app = None;
+                        if let Some(command) = exit_command {
+                            let mut errors = vec![];
+                            if let Ran::Finished(command_status) = self
+                                .run_command(&command, &mut response, &mut errors, session.handle())
+                                .await
+                            {
+                                status = command_status;
+                            }
+                            response.extend(errors);
+                        }
+                        if self.exec {
+                            session.data(self.id, CryptoVec::from(response));
+                            self.close(session, status);
+                            return;
+                        }
+                        response.extend(self.prompt());
+                    }
+                }
+            }
+        }
+
+        // Send back to client
+        session.data(self.id, CryptoVec::from(response));
+    }
+}
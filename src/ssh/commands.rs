@@ -0,0 +1,806 @@
+//! The shell's command language and built-in commands, run over the virtual filesystem in `SshContent`.
+//!
+//! Command lines are split into words like a (much) simplified POSIX shell: words are separated by unquoted whitespace, single quotes
+//! keep everything literal, double quotes and backslashes quote whitespace and special characters, and unquoted `|` separates the
+//! stages of a pipeline (e.g. `cat projects/aero.txt | grep PCB | head`), with each stage's output passed to the next as input.
+//! Unquoted `*` and `?` in the last component of a path are globbed against the filesystem.
+
+use regex::RegexBuilder;
+
+use super::{
+    content::{File, SshContent},
+    image,
+    style::{self, Capabilities, Graphics, Line},
+    terminal::Complete,
+};
+
+/// All commands, for completion: those handled by the session (which can't be used in pipelines) followed by the builtins here.
+const COMMANDS: &[&str] = &[
+    "admin", "cd", "colors", "exit", "logout", "menu", "msg", "vi", "cat", "find", "grep", "head",
+    "help", "less", "ls", "search", "tail", "view", "wc",
+];
+/// Commands handled by the session rather than `run`, since they affect the session itself.
+pub const SESSION_COMMANDS: &[&str] = &[
+    "admin", "cd", "colors", "exit", "logout", "menu", "msg", "vi",
+];
+
+/// The state commands run in.
+pub struct Context<'a> {
+    pub content: &'a SshContent,
+    pub current_dir: usize,
+    /// The size of the terminal, in characters (for deciding whether output needs paging).
+    pub term_size: (u32, u32),
+    /// The size of the terminal in pixels, if known (for scaling images).
+    pub pixel_size: (u32, u32),
+    /// What the terminal supports, for styling the output of `cat` and `less` and drawing images.
+    pub capabilities: Capabilities,
+}
+
+/// The result of running a pipeline.
+pub enum Output {
+    /// Output to print, with `\r\n` newlines.
+    Print(Vec<u8>),
+    /// The pipeline failed, with the output of the stages before the one that failed and then its error message (both with `\r\n`
+    /// newlines, as for `Print`).
+    Error { output: Vec<u8>, error: Vec<u8> },
+    /// Lines to show in the pager, along with a title for them (the file name, if they're from one file).
+    Page {
+        lines: Vec<Line>,
+        title: Option<String>,
+    },
+}
+
+/// An entry in the virtual filesystem.
+pub enum Entry<'a> {
+    /// A directory, by index in `SshContent::directories`.
+    Directory(usize),
+    File(&'a File),
+}
+
+impl<'a> Context<'a> {
+    /// Resolves a path (absolute, or relative to the current directory) to an entry in the filesystem.
+    pub fn resolve(&self, path: &str) -> Option<Entry<'a>> {
+        let mut dir = if path.starts_with('/') {
+            0
+        } else {
+            self.current_dir
+        };
+        let mut parts = path
+            .split('/')
+            .filter(|p| !p.is_empty() && *p != ".")
+            .peekable();
+        while let Some(part) = parts.next() {
+            let directory = self.content.get(dir);
+            if part == ".." {
+                dir = directory.parent.unwrap_or(dir);
+            } else if let Some(&child) = directory.directories.get(part) {
+                dir = child;
+            } else {
+                // Only the last part can be a file (and not if it's followed by a `/`)
+                let file = directory.files.get(part)?;
+                return (parts.peek().is_none() && !path.ends_with('/'))
+                    .then_some(Entry::File(file));
+            }
+        }
+        Some(Entry::Directory(dir))
+    }
+
+    /// Gets the contents of the files named in `args`, or `input` if there are none, along with their names (`None` for input).
+    fn inputs(
+        &self,
+        command: &str,
+        args: &[String],
+        input: Option<&str>,
+    ) -> Result<Vec<(Option<String>, String)>, String> {
+        if args.is_empty() {
+            return match input {
+                Some(input) => Ok(vec![(None, input.to_string())]),
+                None => Err(format!(
+                    "{command}: no input (give a file or pipe something in)"
+                )),
+            };
+        }
+        args.iter()
+            .map(|path| match self.resolve(path) {
+                Some(Entry::File(file)) => Ok((Some(path.clone()), file.text())),
+                Some(Entry::Directory(_)) => Err(format!("{command}: {path}: Is a directory")),
+                None => Err(format!("{command}: {path}: No such file or directory")),
+            })
+            .collect()
+    }
+}
+
+/// Tab completion for the shell, against commands and the filesystem.
+pub struct Completer<'a> {
+    pub context: Context<'a>,
+    pub prompt: Vec<u8>,
+}
+impl Complete for Completer<'_> {
+    fn complete(&self, line: &str) -> Vec<String> {
+        let start = line.rfind([' ', '|']).map_or(0, |i| i + 1);
+        let (before, word) = line.split_at(start);
+        let before = before.trim_end();
+        if before.is_empty() || before.ends_with('|') {
+            // Completing a command name
+            return COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| c.to_string())
+                .collect();
+        }
+
+        // Completing a path, so look for entries in its directory starting with its last component
+        let (dir_path, name) = match word.rsplit_once('/') {
+            Some((dir, name)) => (format!("{dir}/"), name),
+            None => (String::new(), word),
+        };
+        let Some(Entry::Directory(dir)) =
+            self.context
+                .resolve(if dir_path.is_empty() { "." } else { &dir_path })
+        else {
+            return vec![];
+        };
+        let dir = self.context.content.get(dir);
+        let directories = dir.directories.keys().map(|d| format!("{dir_path}{d}/"));
+        let files = dir.files.keys().map(|f| format!("{dir_path}{f}"));
+        directories
+            .chain(files)
+            .filter(|c| c[dir_path.len()..].starts_with(name))
+            .collect()
+    }
+    fn prompt(&self) -> Vec<u8> {
+        self.prompt.clone()
+    }
+}
+
+/// A word of a command line being parsed, with quoted glob characters escaped (with `\`) in `pattern` so it can be globbed.
+#[derive(Default)]
+struct Word {
+    text: String,
+    pattern: String,
+    has_glob: bool,
+}
+impl Word {
+    fn push(&mut self, c: char, quoted: bool) {
+        self.text.push(c);
+        if quoted && matches!(c, '*' | '?' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+        self.has_glob |= !quoted && matches!(c, '*' | '?');
+    }
+}
+
+/// Parses a command line into a pipeline of commands (each a list of arguments, starting with the command name), expanding globs.
+/// Returns an error message on invalid syntax.
+pub fn parse(context: &Context, line: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut pipeline = vec![];
+    let mut stage = vec![];
+    let mut word: Option<Word> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // Single quotes, everything is literal until the closing quote
+                let word = word.get_or_insert_with(Word::default);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c, true),
+                        None => return Err("syntax error: unterminated quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                // Double quotes, everything is literal until the closing quote except backslash escapes of `"` and `\`
+                let word = word.get_or_insert_with(Word::default);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c, true),
+                            Some(c) => {
+                                word.push('\\', true);
+                                word.push(c, true);
+                            }
+                            None => return Err("syntax error: unterminated quote".to_string()),
+                        },
+                        Some(c) => word.push(c, true),
+                        None => return Err("syntax error: unterminated quote".to_string()),
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    word.get_or_insert_with(Word::default).push(c, true);
+                }
+            }
+            '|' => {
+                stage.extend(
+                    word.take()
+                        .map(|w| expand(context, w))
+                        .into_iter()
+                        .flatten(),
+                );
+                if stage.is_empty() {
+                    return Err("syntax error near `|`".to_string());
+                }
+                pipeline.push(std::mem::take(&mut stage));
+            }
+            c if c.is_whitespace() => {
+                stage.extend(
+                    word.take()
+                        .map(|w| expand(context, w))
+                        .into_iter()
+                        .flatten(),
+                );
+            }
+            c => word.get_or_insert_with(Word::default).push(c, false),
+        }
+    }
+    stage.extend(
+        word.take()
+            .map(|w| expand(context, w))
+            .into_iter()
+            .flatten(),
+    );
+    if stage.is_empty() && !pipeline.is_empty() {
+        return Err("syntax error near `|`".to_string());
+    }
+    if !stage.is_empty() {
+        pipeline.push(stage);
+    }
+    Ok(pipeline)
+}
+
+/// Expands a word's globs (if any) into the sorted list of matching paths, or leaves it as it is if nothing matches (like `sh`).
+fn expand(context: &Context, word: Word) -> Vec<String> {
+    if !word.has_glob {
+        return vec![word.text];
+    }
+    // Only the last component is globbed, so find the directory it's in
+    let (dir_path, pattern) = match word.pattern.rsplit_once('/') {
+        Some((dir, pattern)) => (format!("{}/", unescape(dir)), pattern),
+        None => (String::new(), word.pattern.as_str()),
+    };
+    let Some(Entry::Directory(dir)) =
+        context.resolve(if dir_path.is_empty() { "." } else { &dir_path })
+    else {
+        return vec![word.text];
+    };
+    let dir = context.content.get(dir);
+    let mut matches: Vec<String> = dir
+        .directories
+        .keys()
+        .chain(dir.files.keys())
+        .filter(|name| glob_match(pattern, name))
+        .map(|name| format!("{dir_path}{name}"))
+        .collect();
+    if matches.is_empty() {
+        return vec![word.text];
+    }
+    matches.sort();
+    matches
+}
+
+/// Removes the backslash escapes from a glob pattern.
+fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Checks whether a name matches a glob pattern, where `*` matches any string, `?` matches any character, and `\` escapes.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Backtracking match, remembering the last `*` to retry from with one more character consumed
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&name[n]) => {
+                p += 2;
+                n += 1;
+                continue;
+            }
+            Some(&c) if c != '\\' && c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((star_p, star_n)) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Runs a pipeline of builtins (not `SESSION_COMMANDS`), stopping at the first error. The output is paged if the pipeline ends in
+/// `less`, or if it's just a `cat` too long to fit on the screen.
+pub fn run(context: &Context, pipeline: &[Vec<String>]) -> Output {
+    let mut input: Option<String> = None;
+    for stage in pipeline {
+        let (name, args) = stage.split_first().expect("pipeline stages are nonempty");
+        let result = match name.as_str() {
+            "cat" | "less" => cat(context, name, args, input.as_deref()),
+            "find" => find(context, args),
+            "grep" => grep(context, args, input.as_deref()),
+            "head" => head_tail(context, name, args, input.as_deref()),
+            "help" => {
+                Ok(String::from_utf8_lossy(super::content::WELCOME_MESSAGE).replace("\r\n", "\n"))
+            }
+            "ls" => ls(context, args),
+            "search" => search(context, args)
+                .map(|lines| lines.iter().map(|line| style::text(line) + "\n").collect()),
+            "tail" => head_tail(context, name, args, input.as_deref()),
+            "view" if pipeline.len() == 1 => {
+                // Images aren't text, so are output directly rather than passed on
+                return match view(context, args) {
+                    Ok(output) => Output::Print(output),
+                    Err(e) => Output::Error {
+                        output: vec![],
+                        error: format!("{e}\r\n").into_bytes(),
+                    },
+                };
+            }
+            "view" => Err("view: can't be used in a pipeline".to_string()),
+            "wc" => wc(context, args, input.as_deref()),
+            _ => Err(format!("{name}: command not found")),
+        };
+        match result {
+            Ok(output) => input = Some(output),
+            Err(e) => {
+                return Output::Error {
+                    output: input.unwrap_or_default().replace('\n', "\r\n").into_bytes(),
+                    error: format!("{e}\r\n").into_bytes(),
+                };
+            }
+        }
+    }
+    let output = input.unwrap_or_default();
+
+    // Files shown by themselves with `cat` or `less` are styled for the terminal, and anything else (including anything piped) is plain
+    let last = pipeline
+        .last()
+        .map(|stage| (stage[0].as_str(), &stage[1..]));
+    let styled = match last {
+        Some(("cat" | "less", files)) if pipeline.len() == 1 && !files.is_empty() => files
+            .iter()
+            .filter_map(|path| match context.resolve(path) {
+                Some(Entry::File(file)) => Some(file.styled(context.capabilities)),
+                _ => None,
+            })
+            .reduce(|mut lines, file| {
+                lines.extend(file);
+                lines
+            }),
+        Some(("search", args)) if pipeline.len() == 1 => search(context, args).ok(),
+        _ => None,
+    };
+
+    // Page if asked to, or if a single `cat` or `search` would scroll off the screen
+    let (width, height) = (
+        context.term_size.0.max(1) as usize,
+        context.term_size.1 as usize,
+    );
+    let is_styled = styled.is_some();
+    let lines = styled.unwrap_or_else(|| output.lines().map(style::plain).collect());
+    let too_long = || {
+        let rows: usize = lines
+            .iter()
+            .map(|line| style::wrap(&style::sanitize(line), width).len())
+            .sum();
+        rows >= height
+    };
+    let page = match last {
+        Some(("less", _)) => true,
+        Some(("cat" | "search", _)) => pipeline.len() == 1 && too_long(),
+        _ => false,
+    };
+    match last {
+        Some((name, args)) if page => Output::Page {
+            title: match (name, args) {
+                ("search", args) => Some(format!("search {}", args.join(" "))),
+                (_, [file]) => Some(file.clone()),
+                _ => None,
+            },
+            lines,
+        },
+        _ if is_styled => Output::Print(
+            lines
+                .iter()
+                .map(|line| style::render(line, context.capabilities) + "\r\n")
+                .collect::<String>()
+                .into_bytes(),
+        ),
+        _ => Output::Print(output.replace('\n', "\r\n").into_bytes()),
+    }
+}
+
+/// `cat [FILE...]`, printing files (or input) one after another. Also used for `less`, whose output is then paged by `run`.
+fn cat(
+    context: &Context,
+    name: &str,
+    args: &[String],
+    input: Option<&str>,
+) -> Result<String, String> {
+    Ok(context
+        .inputs(name, args, input)?
+        .into_iter()
+        .map(|(_, contents)| contents)
+        .collect())
+}
+
+/// `ls [PATH...]`, listing directories (or files, which just list themselves).
+fn ls(context: &Context, args: &[String]) -> Result<String, String> {
+    let paths = if args.is_empty() {
+        vec![".".to_string()]
+    } else {
+        args.to_vec()
+    };
+    let mut result = String::new();
+    for (i, path) in paths.iter().enumerate() {
+        match context.resolve(path) {
+            Some(Entry::File(_)) => result.push_str(&format!("{path}\n")),
+            Some(Entry::Directory(dir)) => {
+                let dir = context.content.get(dir);
+                if paths.len() > 1 {
+                    result.push_str(&format!("{}{path}:\n", if i > 0 { "\n" } else { "" }));
+                }
+                for name in dir.directories.keys().chain(dir.files.keys()) {
+                    result.push_str(&format!("{name}\n"));
+                }
+            }
+            None => return Err(format!("ls: {path}: No such file or directory")),
+        }
+    }
+    Ok(result)
+}
+
+/// `grep [-i] [-v] [-n] [-c] PATTERN [FILE...]`, printing lines matching a regular expression.
+fn grep(context: &Context, args: &[String], input: Option<&str>) -> Result<String, String> {
+    let usage = "grep: usage: grep [-i] [-v] [-n] [-c] PATTERN [FILE...]";
+    let (flags, args) = flags(args, "ivnc").ok_or(usage)?;
+    let (pattern, files) = args.split_first().ok_or(usage)?;
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(flags.contains(&'i'))
+        .size_limit(1 << 16)
+        .build()
+        .map_err(|e| format!("grep: invalid pattern: {e}"))?;
+
+    let inputs = context.inputs("grep", files, input)?;
+    let mut result = String::new();
+    for (name, contents) in &inputs {
+        // Prefix lines with the file name if there's more than one
+        let prefix = match name {
+            Some(name) if inputs.len() > 1 => format!("{name}:"),
+            _ => String::new(),
+        };
+        let matches = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| regex.is_match(line) != flags.contains(&'v'));
+        if flags.contains(&'c') {
+            result.push_str(&format!("{prefix}{}\n", matches.count()));
+            continue;
+        }
+        for (i, line) in matches {
+            result.push_str(&prefix);
+            if flags.contains(&'n') {
+                result.push_str(&format!("{}:", i + 1));
+            }
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    Ok(result)
+}
+
+/// `head [-n N] [FILE...]` and `tail [-n N] [FILE...]`, printing the first or last `N` (default 10) lines.
+fn head_tail(
+    context: &Context,
+    name: &str,
+    args: &[String],
+    input: Option<&str>,
+) -> Result<String, String> {
+    // Get count from `-n N`, `-nN` or `-N`
+    let mut count = 10;
+    let mut args = args;
+    if let Some(first) = args.first() {
+        let value = match first.strip_prefix("-n") {
+            Some("") => {
+                let value = args.get(1).map(String::as_str);
+                args = &args[1..];
+                value
+            }
+            Some(value) => Some(value),
+            None => first.strip_prefix('-'),
+        };
+        if let Some(value) = value {
+            count = value
+                .parse()
+                .map_err(|_| format!("{name}: invalid number of lines: {value}"))?;
+            args = &args[1..];
+        }
+    }
+
+    let inputs = context.inputs(name, args, input)?;
+    let mut result = String::new();
+    for (i, (file, contents)) in inputs.iter().enumerate() {
+        if let (Some(file), true) = (file, inputs.len() > 1) {
+            result.push_str(&format!(
+                "{}==> {file} <==\n",
+                if i > 0 { "\n" } else { "" }
+            ));
+        }
+        let lines: Vec<&str> = contents.lines().collect();
+        let lines = if name == "head" {
+            &lines[..count.min(lines.len())]
+        } else {
+            &lines[lines.len().saturating_sub(count)..]
+        };
+        for line in lines {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    Ok(result)
+}
+
+/// `search WORD...`, searching the projects and blog posts (see `crate::search`) and listing the files of the best matches, each with
+/// a snippet of its text around the matches. Arguments with whitespace in them (i.e. quoted ones) are searched for as phrases.
+fn search(context: &Context, args: &[String]) -> Result<Vec<Line>, String> {
+    use crate::search::Kind;
+    if args.is_empty() {
+        return Err(
+            "search: usage: search WORD... (quote phrases, e.g. search 'flight computer')"
+                .to_string(),
+        );
+    }
+    let query = args
+        .iter()
+        .map(|arg| {
+            if arg.contains(char::is_whitespace) {
+                format!("\"{arg}\"")
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let results = crate::search::search(&query);
+    if results.is_empty() {
+        return Ok(vec![style::plain(&format!("No matches for {query}"))]);
+    }
+
+    let mut lines = vec![];
+    for result in results {
+        let (kind, path) = match result.kind {
+            Kind::Project => ("Project", format!("/projects/{}.txt", result.url)),
+            Kind::BlogPost => {
+                // Blog post files are named by date as well, so find the post's
+                let name = format!("{}.txt", result.url);
+                let file = match context.resolve("/blog") {
+                    Some(Entry::Directory(dir)) => context
+                        .content
+                        .get(dir)
+                        .files
+                        .keys()
+                        .find(|file| file.split_once('_').is_some_and(|(_, rest)| rest == name))
+                        .cloned(),
+                    _ => None,
+                };
+                ("Blog post", format!("/blog/{}", file.unwrap_or_default()))
+            }
+        };
+        let mut title = style::plain(&format!(" - {kind}, {} ({path})", result.date));
+        title.insert(
+            0,
+            style::Span {
+                text: result.title,
+                style: style::Style::default().bold(),
+                ..Default::default()
+            },
+        );
+        lines.push(title);
+
+        // Indent the snippet, highlighting the matches in it
+        let mut snippet = "  ".to_string();
+        let mut highlights = vec![];
+        for part in result.snippet {
+            if part.highlight {
+                highlights.push(snippet.len()..snippet.len() + part.text.len());
+            }
+            snippet.push_str(&part.text);
+        }
+        lines.push(style::highlight(&style::plain(&snippet), &highlights));
+        lines.push(vec![]);
+    }
+    lines.pop();
+    Ok(lines)
+}
+
+/// `view [-s|-k|-i|-b] IMAGE`, drawing one of the content's images with the best protocol the terminal supports, or the one given
+/// (sixel, kitty, iTerm2 or blocks of characters).
+fn view(context: &Context, args: &[String]) -> Result<Vec<u8>, String> {
+    let usage = "view: usage: view [-s|-k|-i|-b] IMAGE";
+    let (flags, args) = flags(args, "skib").ok_or(usage)?;
+    let [path] = args else {
+        return Err(usage.to_string());
+    };
+    let file = match context.resolve(path) {
+        Some(Entry::File(file)) => file,
+        Some(Entry::Directory(_)) => return Err(format!("view: {path}: Is a directory")),
+        None => return Err(format!("view: {path}: No such file or directory")),
+    };
+    let image = file
+        .image
+        .as_ref()
+        .ok_or_else(|| format!("view: {path}: Not an image (see /images)"))?;
+    let mut capabilities = context.capabilities;
+    match flags.last() {
+        Some('s') => capabilities.graphics = Graphics::Sixel,
+        Some('k') => capabilities.graphics = Graphics::Kitty,
+        Some('i') => capabilities.graphics = Graphics::Iterm,
+        Some('b') => capabilities.graphics = Graphics::None,
+        _ => {}
+    }
+    // Decoding and scaling images takes a while, so don't hold up other sessions
+    tokio::task::block_in_place(|| {
+        image::render(image, context.term_size, context.pixel_size, capabilities)
+    })
+    .map_err(|e| format!("view: {e}"))
+}
+
+/// `wc [-l] [-w] [-c] [FILE...]`, counting lines, words and bytes (all three if no flags are given).
+fn wc(context: &Context, args: &[String], input: Option<&str>) -> Result<String, String> {
+    let (mut flags, files) = flags(args, "lwc").ok_or("wc: usage: wc [-l] [-w] [-c] [FILE...]")?;
+    if flags.is_empty() {
+        flags = vec!['l', 'w', 'c'];
+    }
+    let inputs = context.inputs("wc", files, input)?;
+    let mut result = String::new();
+    let mut totals = [0; 3];
+    for (name, contents) in &inputs {
+        let counts = [
+            contents.lines().count(),
+            contents.split_whitespace().count(),
+            contents.len(),
+        ];
+        for (total, count) in totals.iter_mut().zip(counts) {
+            *total += count;
+        }
+        result.push_str(&format_counts(&flags, counts, name.as_deref()));
+    }
+    if inputs.len() > 1 {
+        result.push_str(&format_counts(&flags, totals, Some("total")));
+    }
+    Ok(result)
+}
+
+/// Formats a line of `wc` output.
+fn format_counts(flags: &[char], counts: [usize; 3], name: Option<&str>) -> String {
+    let mut line: String = ['l', 'w', 'c']
+        .iter()
+        .zip(counts)
+        .filter(|(flag, _)| flags.contains(flag))
+        .map(|(_, count)| format!("{count:>7} "))
+        .collect();
+    line.push_str(name.unwrap_or_default());
+    line.truncate(line.trim_end().len());
+    line.push('\n');
+    line
+}
+
+/// `find [PATH...] [-name PATTERN] [-type f|d]`, recursively listing paths under the given directories.
+fn find(context: &Context, args: &[String]) -> Result<String, String> {
+    let usage = "find: usage: find [PATH...] [-name PATTERN] [-type f|d]";
+    let split = args
+        .iter()
+        .position(|a| a.starts_with('-'))
+        .unwrap_or(args.len());
+    let (paths, mut options) = args.split_at(split);
+    let (mut name, mut kind) = (None, None);
+    while let Some((option, rest)) = options.split_first() {
+        let (value, rest) = rest.split_first().ok_or(usage)?;
+        match option.as_str() {
+            "-name" => name = Some(value.as_str()),
+            "-type" if value == "f" || value == "d" => kind = Some(value.as_str()),
+            _ => return Err(usage.to_string()),
+        }
+        options = rest;
+    }
+    let matches = |entry_name: &str, is_dir: bool| {
+        name.is_none_or(|pattern| glob_match(pattern, entry_name))
+            && kind.is_none_or(|kind| (kind == "d") == is_dir)
+    };
+
+    let mut result = String::new();
+    let paths = if paths.is_empty() {
+        &[".".to_string()][..]
+    } else {
+        paths
+    };
+    for path in paths {
+        match context.resolve(path) {
+            Some(Entry::File(_)) => {
+                if matches(path.rsplit('/').next().unwrap_or(path), false) {
+                    result.push_str(&format!("{path}\n"));
+                }
+            }
+            Some(Entry::Directory(dir)) => {
+                // Depth-first walk, listing each directory before its contents
+                let root_name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("/");
+                let mut stack = vec![(
+                    path.trim_end_matches('/').to_string(),
+                    root_name.to_string(),
+                    dir,
+                )];
+                while let Some((path, dir_name, dir)) = stack.pop() {
+                    if matches(&dir_name, true) {
+                        result
+                            .push_str(&format!("{}\n", if path.is_empty() { "/" } else { &path }));
+                    }
+                    let dir = context.content.get(dir);
+                    for name in dir.files.keys() {
+                        if matches(name, false) {
+                            result.push_str(&format!("{path}/{name}\n"));
+                        }
+                    }
+                    for (name, &child) in dir.directories.iter().rev() {
+                        stack.push((format!("{path}/{name}"), name.clone(), child));
+                    }
+                }
+            }
+            None => return Err(format!("find: {path}: No such file or directory")),
+        }
+    }
+    Ok(result)
+}
+
+/// Splits leading single-letter flags (e.g. `-i`, or combined like `-in`) from the rest of the arguments, returning `None` if a flag
+/// isn't in `allowed`. A `--` argument ends the flags.
+fn flags<'a>(args: &'a [String], allowed: &str) -> Option<(Vec<char>, &'a [String])> {
+    let mut flags = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--" {
+            return Some((flags, &args[i + 1..]));
+        }
+        match arg.strip_prefix('-') {
+            Some(letters) if !letters.is_empty() => {
+                for c in letters.chars() {
+                    if !allowed.contains(c) {
+                        return None;
+                    }
+                    flags.push(c);
+                }
+            }
+            _ => return Some((flags, &args[i..])),
+        }
+    }
+    Some((flags, &[]))
+}
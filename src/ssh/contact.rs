@@ -1,36 +1,105 @@
 use std::net::IpAddr;
 
-use crate::contact::ThreadId;
+use russh::{server::Handle, ChannelId, CryptoVec};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
 
-/// Handles the `msg` command, returning the output to be sent to the user's terminal.
-pub async fn msg(command: &str, ip: IpAddr) -> Vec<u8> {
+use crate::contact::{challenge, export::ExportFormat, format, Message, ThreadId, ThreadToken};
+
+/// Handles the `msg` command, returning the output to be sent to the user's terminal, along with a thread to follow if requested.
+pub async fn msg(command: &str, ip: IpAddr) -> (Vec<u8>, Option<Follow>) {
     // Split arguments and dispatch to correct handler
     let mut args = command.split(' ');
     args.next(); // command name
     let a1 = args.next();
     let a2 = args.next();
-    let mut response = match (a1, a2) {
-        (Some("send"), _) => msg_send(command, ip).await,
-        (Some("reply"), Some(thread_id)) => msg_reply(thread_id, command).await,
-        (Some("view"), Some(thread_id)) => msg_view(thread_id).await,
+    let a3 = args.next();
+    let mut follow = None;
+    let mut response = match (a1, a2, a3) {
+        (Some("send"), _, _) => msg_send(command, ip).await,
+        (Some("reply"), Some(thread_id), _) => msg_reply(thread_id, command).await,
+        (Some("view"), Some(thread_id), None) => msg_view(thread_id, None).await,
+        (Some("view"), Some(thread_id), Some("--follow" | "-f")) => {
+            msg_view(thread_id, Some(&mut follow)).await
+        }
+        (Some("export"), Some(thread_id), format) => msg_export(thread_id, format).await,
+        (Some("upgrade"), Some(thread_id), _) => msg_upgrade(thread_id).await,
+        (Some("email"), Some(thread_id), Some(address)) => msg_email(thread_id, address).await,
         _ => msg_usage(),
     };
 
-    // Add newline to response and carriage returns before each newline
-    response.push_str("\n\n");
-    response.replace('\n', "\r\n").into_bytes()
+    // Add newline to response (unless following, where new messages come next) and carriage returns before each newline
+    if follow.is_none() {
+        response.push_str("\n\n");
+    }
+    (response.replace('\n', "\r\n").into_bytes(), follow)
+}
+
+/// The fewest characters the first message on a thread can have (mostly to avoid accidentally sending something).
+pub const MIN_SEND_LENGTH: usize = 25;
+/// The fewest characters a reply can have.
+pub const MIN_REPLY_LENGTH: usize = 10;
+
+/// A thread being followed with `msg view <THREAD> --follow`, which prints new messages as they're sent until the user presses Ctrl-C.
+pub struct Follow {
+    thread: ThreadId,
+    receiver: broadcast::Receiver<(ThreadId, Message)>,
+}
+impl Follow {
+    /// Starts printing new messages to the given channel in the background (refreshing the session timeout with each one).
+    pub fn spawn(
+        mut self,
+        handle: Handle,
+        channel: ChannelId,
+        timeout_refresh: mpsc::Sender<()>,
+    ) -> FollowTask {
+        FollowTask(tokio::spawn(async move {
+            loop {
+                let output = match self.receiver.recv().await {
+                    Ok((thread, message)) if thread == self.thread => format_message(&message),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        "(some messages were missed, view the thread again to see them)\n"
+                            .to_string()
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let output = CryptoVec::from(output.replace('\n', "\r\n"));
+                if handle.data(channel, output).await.is_err() {
+                    // Channel was closed
+                    return;
+                }
+                let _ = timeout_refresh.send(()).await;
+            }
+        }))
+    }
+}
+
+/// The background task for a `Follow`, which is stopped when this is dropped.
+pub struct FollowTask(JoinHandle<()>);
+impl Drop for FollowTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 async fn msg_send(command: &str, ip: IpAddr) -> String {
     // Get message to send (splice off first two arguments) and check size lower bound
     let msg = command.splitn(3, ' ').nth(2).unwrap_or_default();
-    if msg.len() < 25 {
-        return "Usage: `msg send <BODY...>`
-Initial message body must be at least 25 characters (mostly to avoid accidentally sending something. Use `msg help` (or just `msg`) to see some usage info.".to_string();
+    if msg.len() < MIN_SEND_LENGTH {
+        return format!("Usage: `msg send <BODY...>` (or just `msg send` to write it in an editor)
+Initial message body must be at least {MIN_SEND_LENGTH} characters (mostly to avoid accidentally sending something. Use `msg help` (or just `msg`) to see some usage info.");
     }
 
-    // Send message, displaying result to user
-    match crate::contact::create_thread(ip, msg.to_string()).await {
+    // Solve the anti-spam challenge ourselves (an SSH handshake is already enough work to deter bulk spam), then send message, displaying result to user
+    let challenge = challenge::issue();
+    let Some(proof) = challenge::solve_blocking(challenge).await else {
+        return "Error sending message: couldn't solve the anti-spam challenge in time, sorry :("
+            .to_string();
+    };
+    match crate::contact::create_thread(ip, msg.to_string(), Some(proof)).await {
         Ok(id) => {
             format!("Message sent! Thread ID: {id} (don't lose that if you want a reply!)")
         }
@@ -40,15 +109,15 @@ Initial message body must be at least 25 characters (mostly to avoid accidentall
 
 async fn msg_reply(thread_id: &str, command: &str) -> String {
     // Parse thread id
-    let Ok(thread_id) = thread_id.parse::<ThreadId>() else {
-        return "Error: ill-formed thread ID (should be a 64-bit hexadecimal integer)".to_string();
+    let Ok(thread_id) = thread_id.parse::<ThreadToken>() else {
+        return "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string();
     };
 
     // Get message to send (splice off first 3 arguments) and check size lower bound
     let msg = command.splitn(4, ' ').nth(3).unwrap_or_default();
-    if msg.len() < 10 {
-        return "Usage: `msg reply <THREAD> <BODY...>`
-Message body must be at least 10 characters (mostly to avoid accidentally sending something. Use `msg help` (or just `msg`) to see some usage info.".to_string();
+    if msg.len() < MIN_REPLY_LENGTH {
+        return format!("Usage: `msg reply <THREAD> <BODY...>` (or just `msg reply <THREAD>` to write it in an editor)
+Message body must be at least {MIN_REPLY_LENGTH} characters (mostly to avoid accidentally sending something. Use `msg help` (or just `msg`) to see some usage info.");
     }
 
     // Send message, displaying result to user
@@ -60,40 +129,119 @@ Message body must be at least 10 characters (mostly to avoid accidentally sendin
     }
 }
 
-async fn msg_view(thread_id: &str) -> String {
+/// Views a thread, setting `follow` (if given) to follow the thread afterwards if it was loaded successfully.
+async fn msg_view(thread_id: &str, follow: Option<&mut Option<Follow>>) -> String {
     // Parse thread id
-    let Ok(thread_id) = thread_id.parse::<ThreadId>() else {
-        return "Error: ill-formed thread ID (should be a 64-bit hexadecimal integer)".to_string();
+    let Ok(thread_id) = thread_id.parse::<ThreadToken>() else {
+        return "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string();
     };
 
-    // Get messages, printing error if necessary
+    // Subscribe before loading so no messages are missed in between, then get messages, printing error if necessary
+    let receiver = follow.is_some().then(crate::contact::subscribe);
     let messages = match crate::contact::get_messages(thread_id).await {
         Ok(msgs) => msgs,
         Err(e) => return format!("Error loading thread: {e}"),
     };
     let mut result = format!("Thread {thread_id}:\n");
     for message in messages {
-        result.push_str(&format!(
-            "({}) {} {}\n",
-            message.timestamp,
-            if message.response { "Me: " } else { "You:" },
-            message.contents
-        ));
+        result.push_str(&format_message(&message));
+    }
+    if let ThreadToken::Legacy(_) = thread_id {
+        result.push_str(&format!("(old-style thread IDs will stop working soon, use `msg upgrade {thread_id}` to get a new one)\n"));
+    }
+    if let (Some(follow), Some(receiver)) = (follow, receiver) {
+        let thread = match crate::contact::resolve(thread_id).await {
+            Ok(thread) => thread,
+            Err(e) => return format!("Error loading thread: {e}"),
+        };
+        result.push_str("(following thread, press Ctrl-C to stop)\n");
+        *follow = Some(Follow { thread, receiver });
     }
     result
 }
 
+/// Exchanges an old-style thread ID for a new one.
+async fn msg_upgrade(thread_id: &str) -> String {
+    // Parse thread id
+    let Ok(thread_id) = thread_id.parse::<ThreadToken>() else {
+        return "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string();
+    };
+
+    // Upgrade, displaying result to user
+    match crate::contact::upgrade(thread_id).await {
+        Ok(token) => format!("New thread ID: {token} (don't lose that if you want a reply!)"),
+        Err(e) => format!("Error upgrading thread ID: {e}"),
+    }
+}
+
+/// Sets the email address my replies on a thread are sent to, or stops sending them if the address is `off`.
+async fn msg_email(thread_id: &str, address: &str) -> String {
+    // Parse thread id
+    let Ok(thread_id) = thread_id.parse::<ThreadToken>() else {
+        return "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string();
+    };
+
+    // Set address, displaying result to user
+    let address = (address != "off").then(|| address.to_string());
+    let enabled = address.is_some();
+    match crate::contact::set_email(thread_id, address).await {
+        Ok(()) if enabled => {
+            "Check your email for a link to confirm your address, and once you have, replies will be emailed to you (reply to those emails to respond on the thread)"
+                .to_string()
+        }
+        Ok(()) => "Replies will no longer be emailed to you".to_string(),
+        Err(e) => format!("Error setting email: {e}"),
+    }
+}
+
+/// Exports a thread in the given format (plain text by default), to be copied or redirected from the terminal.
+async fn msg_export(thread_id: &str, format: Option<&str>) -> String {
+    // Parse thread id and format
+    let Ok(thread_id) = thread_id.parse::<ThreadToken>() else {
+        return "Error: ill-formed thread ID (should be 48 hexadecimal digits)".to_string();
+    };
+    let format = match format.map(str::parse::<ExportFormat>) {
+        None => ExportFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return format!("Error: {e}"),
+    };
+
+    // Get messages, printing error if necessary
+    match crate::contact::get_messages(thread_id).await {
+        Ok(messages) => crate::contact::export::export(thread_id, &messages, format),
+        Err(e) => format!("Error loading thread: {e}"),
+    }
+}
+
+/// Formats a message as an entry in a thread, with any formatting rendered as ANSI styling and continuation lines indented.
+fn format_message(message: &Message) -> String {
+    format!(
+        "({}) {} {}\n",
+        format::format_timestamp(message.timestamp),
+        if message.response { "Me: " } else { "You:" },
+        format::to_ansi(&message.contents).replace('\n', "\n     ")
+    )
+}
+
 fn msg_usage() -> String {
-    "Usage: `msg send <BODY...>` or `msg view <THREAD>` or `msg reply <THREAD> <BODY...>`
+    "Usage: `msg send <BODY...>` or `msg view <THREAD> [--follow]` or `msg reply <THREAD> <BODY...>` or `msg export <THREAD> [json|mbox|text]` or `msg email <THREAD> <ADDRESS|off>`
 
 Have feedback on the site? A comment about a page? Just want to get in touch / send a message?
 This command allows you to send a message straight from your terminal to mine (see the project page (TODO) for more).
 
 To send your first message, just use `msg send` followed by any length of message, which will start a new thread and return the corresponding thread ID.
 Then, you can use `msg view` along with the thread ID to see your message and, eventually (hopefully), my reply.
+Add `--follow` to keep watching the thread, printing new messages as they arrive, until you press Ctrl-C.
 If you want to send a follow up to your initial message or a response to mine, you can use `msg reply` with the thread ID and your response.
+Leave out the message with `msg send` or `msg reply <THREAD>` to write it in a little editor instead, where it can have several lines (Ctrl-S sends it, and Ctrl-C cancels).
+To keep a copy of a thread, `msg export` prints the whole thread as plain text, JSON, or an mbox you can import into your email client.
+If you'd rather get my replies by email, `msg email` with the thread ID and your address will send them to you (once you've followed the confirmation link it sends), and you can reply to those emails to respond on the thread. Use `off` instead of an address to stop.
+
+Messages can use a little djot/markdown-style formatting: *strong*, _emphasis_, `inline code`, ``` code blocks ``` and <https://autolinks>.
 
 The thread IDs here are the same as those in my http/html website's contact form (TODO), so you can also view/send messages there.
 
+Threads started before thread IDs got longer have old-style 16-digit IDs, which will stop working at some point. Use `msg upgrade` with an old ID to get a new one for the same thread.
+
 If you have any questions, well, you should know how to get in touch now! I look forward to hearing from you!".to_string()
 }
@@ -1,9 +1,26 @@
-use std::{borrow::Cow, collections::BTreeMap};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use color_eyre::{eyre::eyre, Result};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{
+    image::IMAGES_DIR,
+    style::{Capabilities, Color, Line, Style, StyledText},
+};
 
 pub static WELCOME_MESSAGE: &[u8] = "Welcome to the SSH version of my website! This is very much a work in progress, but I hope you enjoy it nonetheless!\r
 To navigate, use the 'ls' and 'cd' commands to see the available pages and 'cat' or 'vi' to view them.\r
+You can also search and slice pages with 'grep', 'head', 'tail', 'wc', 'find' and 'less', combined with pipes (`|`) and globs (`*`), and Tab completes commands and paths.\r
+For a full-screen menu of the projects and blog posts (with previews, and mouse support), use 'menu'.\r
+To find a project or blog post by what's in it, use 'search' (quoting phrases, e.g. search 'flight computer').\r
+Images are in /images, and 'view' shows them right in your terminal (as does Enter in 'less', on a line with an image at the top).\r
+If the colours look wrong, 'colors' can change them.\r
 If you have any feedback, use the 'msg' command to send it (or view any replies to messages you've sent).\r
 To see this message again, just use `help`, and when you're ready to go, type 'exit' or 'logout' (or Ctrl-D).\r\n".as_bytes();
 
@@ -12,6 +29,8 @@ To see this message again, just use `help`, and when you're ready to go, type 'e
 pub struct SshContent {
     /// The directories of the virtual filesystem, with the root first.
     pub directories: Vec<Directory>,
+    /// The projects and blog posts, in order, as listed by `menu`.
+    pub menu: Vec<MenuEntry>,
 }
 impl SshContent {
     /// Render the SSH content from the given content.
@@ -22,6 +41,7 @@ impl SshContent {
                 path: "/".to_string(),
                 ..Default::default()
             }],
+            menu: vec![],
         };
 
         // Add home page and themes page
@@ -39,21 +59,44 @@ impl SshContent {
         // Add projects directory
         let projects_i = result.add_child(0, "projects".to_string());
         for project in content.projects.iter() {
-            result.add_file(
-                projects_i,
-                format!("{}.txt", project.url),
-                File::new(project.to_string().replace('\n', "\r\n")),
-            );
+            let filename = format!("{}.txt", project.url);
+            let mut preview = StyledText::default();
+            for section in project.content.sections.iter() {
+                section.terminal(&mut preview);
+                preview.text("\n\n");
+            }
+            result.menu.push(MenuEntry {
+                title: project.name.clone(),
+                kind: "Project",
+                date: project.date.clone(),
+                description: project.description.clone(),
+                skills: project.skills.skills.clone(),
+                path: format!("/projects/{filename}"),
+                preview,
+            });
+            result.add_file(projects_i, filename, File::new(styled(project)));
         }
 
         // Add blog directory
         let blog_i = result.add_child(0, "blog".to_string());
         for post in content.blog_posts.iter() {
-            result.add_file(
-                blog_i,
-                format!("{}_{}.txt", post.date.date().format("%Y%m%d"), post.url),
-                File::new(post.to_string().replace('\n', "\r\n")),
-            );
+            let filename = format!("{}_{}.txt", post.date.date().format("%Y%m%d"), post.url);
+            result.menu.push(MenuEntry {
+                title: post.title.clone(),
+                kind: "Blog post",
+                date: post.date.date().to_string(),
+                description: String::new(),
+                skills: vec![],
+                path: format!("/blog/{filename}"),
+                preview: styled(&post.content),
+            });
+            result.add_file(blog_i, filename, File::new(styled(post)));
+        }
+
+        // Add images directory, with the images from the content (shown with `view`)
+        let images_i = result.add_child(0, "images".to_string());
+        if Path::new(IMAGES_DIR).is_dir() {
+            result.add_images(images_i, Path::new(IMAGES_DIR))?;
         }
 
         Ok(result)
@@ -122,6 +165,46 @@ impl SshContent {
         self.directories.push(child);
         child_i
     }
+    /// Add the images in a directory on disk (and any subdirectories) to a `Directory` specified by index.
+    fn add_images(&mut self, dir_i: usize, path: &Path) -> Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            if path.is_dir() {
+                let child_i = self.add_child(dir_i, name);
+                self.add_images(child_i, &path)?;
+            } else if path.extension().is_some_and(|extension| {
+                ["jpg", "jpeg", "png", "svg"]
+                    .iter()
+                    .any(|e| extension == *e)
+            }) {
+                let src = path.strip_prefix(IMAGES_DIR)?.to_string_lossy().to_string();
+                let mut text = StyledText::default();
+                text.text(&format!(
+                    "Image: {src}\nUse `view /images/{src}` to see it here, or see it at "
+                ));
+                let url = absolute(&format!("/images/{src}"));
+                text.linked(&url, |text| text.text(&url));
+                text.text("\n");
+                let metadata = std::fs::metadata(&path)?;
+                self.add_file(
+                    dir_i,
+                    name,
+                    File {
+                        image: Some(path),
+                        size: metadata.len(),
+                        modified: unix_time(metadata.modified()?),
+                        ..File::new(text)
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
     /// Add a file to a `Directory` specified by index.
     fn add_file(&mut self, dir_i: usize, filename: String, contents: File) {
         let dir = &mut self.directories[dir_i];
@@ -142,6 +225,22 @@ pub struct Directory {
     pub files: BTreeMap<String, File>,
 }
 
+/// A project or blog post, as listed by `menu`.
+#[derive(Debug)]
+pub struct MenuEntry {
+    pub title: String,
+    /// What the entry is, e.g. "Project".
+    pub kind: &'static str,
+    pub date: String,
+    /// A short description (empty for blog posts, which don't have one).
+    pub description: String,
+    pub skills: Vec<String>,
+    /// The path of the entry's file, opened in the pager when the entry is chosen.
+    pub path: String,
+    /// The entry's text without its header (which the menu shows its own way), for previewing it.
+    pub preview: StyledText,
+}
+
 /// A file in the virtual filesystem, containing an array of lines.
 #[derive(Debug, Default)]
 pub struct File {
@@ -149,14 +248,329 @@ pub struct File {
     pub contents: String,
     /// The contents of the file, as an array of lines. There is always at least one (possibly-empty) line.
     pub lines: Vec<String>,
+    /// The contents of the file with styling, for showing on terminals that support it.
+    document: StyledText,
+    /// The image this file is, if it's one of the content's images, which can be shown with `view`.
+    pub image: Option<PathBuf>,
+    /// The size of the file as downloaded (with SFTP or SCP), in bytes.
+    pub size: u64,
+    /// When the file was last modified, as a Unix time: when the content was loaded, or for images, the image's own time.
+    pub modified: u64,
 }
 impl File {
-    pub fn new(contents: String) -> Self {
+    pub fn new(document: StyledText) -> Self {
+        let contents = document.plain().replace('\n', "\r\n");
         let lines: Vec<String> = contents.split("\r\n").map(|s| s.to_string()).collect();
-        Self { contents, lines }
+        let mut file = Self {
+            contents,
+            lines,
+            document,
+            image: None,
+            size: 0,
+            modified: unix_time(SystemTime::now()),
+        };
+        file.size = file.text().len() as u64;
+        file
+    }
+    /// Reads up to `length` bytes of the file as downloaded with SFTP (the image itself for images, or otherwise the text), starting at
+    /// `offset`, without reading the rest of an image.
+    pub async fn read_at(&self, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        match &self.image {
+            Some(path) => {
+                let mut file = tokio::fs::File::open(path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = vec![];
+                file.take(length as u64).read_to_end(&mut data).await?;
+                Ok(data)
+            }
+            None => {
+                let text = self.text();
+                let start = usize::try_from(offset)
+                    .unwrap_or(usize::MAX)
+                    .min(text.len());
+                let end = start.saturating_add(length).min(text.len());
+                Ok(text.as_bytes()[start..end].to_vec())
+            }
+        }
+    }
+    /// Gets the contents of the file with `\n` newlines, always ending in a newline.
+    pub fn text(&self) -> String {
+        let mut text = self.contents.replace("\r\n", "\n");
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+    /// Gets the lines of the file as shown on a terminal with the given capabilities (like the lines of `text`, so without any
+    /// empty line after a final newline).
+    pub fn styled(&self, capabilities: Capabilities) -> Vec<Line> {
+        let mut lines = self.document.lines(capabilities);
+        if lines.len() > 1 && lines.last().is_some_and(Vec::is_empty) {
+            lines.pop();
+        }
+        lines
+    }
+}
+
+/// A trait enabling a type to be written as styled text for the terminal. On terminals without styling this comes out exactly as
+/// the `Display` implementation does, which is what's searched by `grep` and shown in `vi`.
+pub trait TerminalContent {
+    /// Write the content to the given styled text.
+    fn terminal(&self, text: &mut StyledText);
+}
+
+/// Converts a time to a Unix time (in seconds).
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Renders some content to styled text.
+fn styled(content: &impl TerminalContent) -> StyledText {
+    let mut text = StyledText::default();
+    content.terminal(&mut text);
+    text
+}
+
+/// Makes a link from the content absolute, so it works from the terminal (as relative links are to the HTTP site).
+fn absolute(href: &str) -> String {
+    if href.starts_with('/') {
+        format!("https://{}{href}", crate::CONFIG.domain)
+    } else {
+        href.to_string()
+    }
+}
+
+/// The style of the title lines at the top of projects and blog posts.
+fn title_style() -> Style {
+    Style::default().bold().color(Color::Ansi(3))
+}
+
+impl TerminalContent for crate::project::Project {
+    fn terminal(&self, text: &mut StyledText) {
+        // Header
+        text.styled(title_style(), |text| {
+            text.text(&format!("=== {} ===", self.name))
+        });
+        text.newline();
+        let url = format!("https://{}/projects/{}", crate::CONFIG.domain, self.url);
+        text.linked(&url, |text| text.text(&url));
+        text.text(&format!("\n{}\n{}\n", self.description, self.date));
+        text.styled(Style::default().bold(), |text| text.text("Skills:"));
+        text.newline();
+        for skill in self.skills.skills.iter() {
+            text.text(&format!("- {}\n", skill));
+        }
+        text.styled(title_style(), |text| {
+            text.text(&"=".repeat(self.name.len() + 8))
+        });
+        text.text("\n\n");
+        // Content
+        for section in self.content.sections.iter() {
+            section.terminal(text);
+            text.text("\n\n");
+        }
+    }
+}
+
+impl TerminalContent for crate::project::Section {
+    fn terminal(&self, text: &mut StyledText) {
+        use crate::project::Section;
+        match self {
+            Section::Section { title, content } => {
+                text.heading(1, |text| text.text(title.as_deref().unwrap_or("Section")));
+                text.newline();
+                let mut newline = false;
+                for element in content.iter() {
+                    if newline {
+                        text.newline();
+                    }
+                    element.terminal(text);
+                    newline = true;
+                }
+            }
+            Section::Criteria { title, items } => {
+                text.heading(1, |text| {
+                    text.text(title.as_deref().unwrap_or("Design Criteria"))
+                });
+                for item in items.iter() {
+                    text.newline();
+                    text.heading(2, |text| text.text(&item.title));
+                    text.newline();
+                    item.description.terminal(text);
+                    text.newline();
+                }
+            }
+        }
+    }
+}
+
+impl TerminalContent for crate::project::Element {
+    fn terminal(&self, text: &mut StyledText) {
+        use crate::project::Element;
+        match self {
+            Element::Group { content } => {
+                let mut newline = false;
+                for element in content.iter() {
+                    if newline {
+                        text.newline();
+                    }
+                    element.terminal(text);
+                    newline = true;
+                }
+            }
+            Element::Gallery { content } => {
+                for element in content.iter() {
+                    element.terminal(text);
+                }
+            }
+            Element::Paragraph(paragraph) => {
+                paragraph.terminal(text);
+                text.newline();
+            }
+            Element::Image { src, alt, caption } => {
+                text.image(src, |text| {
+                    text.styled(Style::default().italic(), |text| {
+                        text.text("Image: ");
+                        text.linked(&absolute(&format!("/images/{src}")), |text| {
+                            text.text(&format!("{alt} ({src})"))
+                        });
+                    })
+                });
+                text.newline();
+                if let Some(caption) = caption {
+                    text.styled(Style::default().italic(), |text| text.text("Caption: "));
+                    caption.terminal(text);
+                    text.newline();
+                }
+            }
+        }
+    }
+}
+
+impl TerminalContent for crate::project::Text {
+    fn terminal(&self, text: &mut StyledText) {
+        for element in self.text.iter() {
+            element.terminal(text);
+        }
     }
-    pub fn raw_contents(&self) -> &[u8] {
-        self.contents.as_bytes()
+}
+
+impl TerminalContent for crate::project::TextElement {
+    fn terminal(&self, text: &mut StyledText) {
+        use crate::project::TextElement;
+        match self {
+            TextElement::Link {
+                href,
+                text: link_text,
+                leading_space,
+                trailing_space,
+            } => {
+                text.text(leading_space);
+                text.link(href, &absolute(href), |text| {
+                    for element in link_text.iter() {
+                        element.terminal(text);
+                    }
+                });
+                text.text(trailing_space);
+            }
+            TextElement::Text(plain) => text.text(plain),
+        }
+    }
+}
+
+impl TerminalContent for crate::blogpost::BlogPost {
+    fn terminal(&self, text: &mut StyledText) {
+        text.styled(title_style(), |text| {
+            text.text(&format!("=== {} ===", self.title))
+        });
+        text.newline();
+        let url = format!("https://{}/blog/{}", crate::CONFIG.domain, self.url);
+        text.linked(&url, |text| text.text(&url));
+        text.text(&format!("\n{}\n\n", self.date.date()));
+        self.content.terminal(text);
+        text.newline();
+    }
+}
+
+impl TerminalContent for crate::blogpost::Content {
+    fn terminal(&self, text: &mut StyledText) {
+        for element in self.content.iter() {
+            element.terminal(text);
+            text.newline();
+        }
+        text.newline();
+        if !self.footnotes.is_empty() {
+            text.styled(Style::default().bold(), |text| text.text("Footnotes:"));
+            text.newline();
+            for (i, (_, body)) in self.footnotes.iter().enumerate() {
+                text.styled(Style::default().color(Color::Ansi(6)), |text| {
+                    text.text(&format!("[^{}]:", i + 1))
+                });
+                text.text(" ");
+                for element in body.iter() {
+                    element.terminal(text);
+                    text.newline();
+                }
+            }
+        }
+    }
+}
+
+impl TerminalContent for crate::blogpost::Element {
+    fn terminal(&self, text: &mut StyledText) {
+        use crate::blogpost::Element;
+        match self {
+            Element::Paragraph { text: inline } => {
+                inline.iter().for_each(|element| element.terminal(text))
+            }
+            Element::Code { lang, content } => {
+                text.styled(Style::default().dim(), |text| text.text("```"));
+                text.newline();
+                text.code(lang.as_deref(), content);
+                text.newline();
+                text.styled(Style::default().dim(), |text| text.text("```"));
+            }
+            Element::Footnote { .. } => text.text("BUG: footnote"),
+            Element::Heading {
+                text: inline,
+                level,
+                ..
+            } => text.heading(*level as usize, |text| {
+                inline.iter().for_each(|element| element.terminal(text))
+            }),
+        }
+        text.newline();
+    }
+}
+
+impl TerminalContent for crate::blogpost::InlineElement {
+    fn terminal(&self, text: &mut StyledText) {
+        use crate::blogpost::InlineElement;
+        match self {
+            InlineElement::Text { content } => text.text(content),
+            InlineElement::Emph { text: inline } => {
+                text.emph(|text| inline.iter().for_each(|element| element.terminal(text)))
+            }
+            InlineElement::Strong { text: inline } => {
+                text.strong(|text| inline.iter().for_each(|element| element.terminal(text)))
+            }
+            InlineElement::Link { href, text: inline } => {
+                text.link(href, &absolute(href), |text| {
+                    inline.iter().for_each(|element| element.terminal(text))
+                })
+            }
+            InlineElement::InlineCode { content } => text.inline_code(content),
+            InlineElement::FootnoteRef { number, .. } => text
+                .styled(Style::default().color(Color::Ansi(6)), |text| {
+                    text.text(&format!("[{number}]"))
+                }),
+            InlineElement::Image { src, alt } => text.image(src, |text| {
+                text.styled(Style::default().italic(), |text| {
+                    text.text(&format!("<Image: {alt} ({src})>"))
+                })
+            }),
+        }
     }
 }
 
@@ -170,7 +584,7 @@ macro_rules! access_json {
 }
 
 /// Get the home page for the SSH server.
-fn get_home_page(content: &crate::Content) -> Result<String> {
+fn get_home_page(content: &crate::Content) -> Result<StyledText> {
     let ascii_art = "
 FFFFFFFFFFFFFFF     LLLL               EEEEEEEEEEEEEEE     TTTTTTTTTTTTTT     CCCCCCCCCCCCCC     HHHH       HHHH
 FFFFFFFFFFFFFFF     LLLL               EEEEEEEEEEEEEEE     TTTTTTTTTTTTTT     CCCCCCCCCCCCCC     HHHH       HHHH
@@ -190,33 +604,51 @@ Obviously, that's not completely true (see this text), but that's the idea. So,
 
 In this case, the entire idea of the home page is to be a landing page for the site with links to all the other pages, which doesn't translate well to SSH version. So, I've replaced all the links and lists of links with \"SEE FILESYSTEM\", because that's the only way to navigate the site. It's more fun to explore that way!
 So, go ahead and explore the site! You can find the home page content below, but as mentioned, it's not much use in this version:";
-    let homepage_content = format!(
-        "# Fletch Rydell\n*{}*\n\n## About me\n{}\n\n## Projects\n{}\nSEE FILESYSTEM\n\n",
-        access_json!(content.index_info, "subtitle"),
-        access_json!(content.index_info, "about_me"),
+    let subtitle = access_json!(content.index_info, "subtitle");
+    let mut page = StyledText::default();
+    page.styled(Style::default().bold().color(Color::Ansi(3)), |text| {
+        text.text(ascii_art)
+    });
+    page.text(&format!("\n{intro_blurb}\n\n"));
+    page.heading(1, |text| text.text("Fletch Rydell"));
+    page.newline();
+    page.strong(|text| text.text(subtitle));
+    page.text("\n\n");
+    page.heading(2, |text| text.text("About me"));
+    page.text(&format!(
+        "\n{}\n\n",
+        access_json!(content.index_info, "about_me")
+    ));
+    page.heading(2, |text| text.text("Projects"));
+    page.text(&format!(
+        "\n{}\nSEE FILESYSTEM\n\n",
         access_json!(content.index_info, "projects_caption")
-    );
-    Ok(format!("{}\n{}\n\n{}", ascii_art, intro_blurb, homepage_content).replace('\n', "\r\n"))
+    ));
+    Ok(page)
 }
 
 /// Get the themes page for the SSH server.
-fn get_themes_page(content: &crate::Content) -> Result<String> {
+fn get_themes_page(content: &crate::Content) -> Result<StyledText> {
     let disclaimer = "NOTE: This page is about the themes available on the HTTP version of the site. There are no themes here, but this page is still here to uphold my promise of including all content from the HTTP site.";
-    let mut result = format!(
-        "{disclaimer}\n\n# Themes\n## About Themes\n{}\n\n",
-        access_json!(content.themes_info, "about_text"),
-    );
+    let mut page = StyledText::default();
+    page.styled(Style::default().italic(), |text| text.text(disclaimer));
+    page.text("\n\n");
+    page.heading(1, |text| text.text("Themes"));
+    page.newline();
+    page.heading(2, |text| text.text("About Themes"));
+    page.text(&format!(
+        "\n{}\n\n",
+        access_json!(content.themes_info, "about_text")
+    ));
     for theme in content
         .themes_info
         .get("themes")
         .and_then(|x| x.as_array())
         .ok_or_else(|| eyre!("No themes found in themes_info"))?
     {
-        result.push_str(&format!(
-            "## {}\n{}\n\n",
-            access_json!(theme, "name"),
-            access_json!(theme, "description")
-        ));
+        let name = access_json!(theme, "name");
+        page.heading(2, |text| text.text(name));
+        page.text(&format!("\n{}\n\n", access_json!(theme, "description")));
     }
-    Ok(result.replace('\n', "\r\n"))
+    Ok(page)
 }
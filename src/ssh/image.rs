@@ -0,0 +1,310 @@
+//! Inline images for the terminal, drawn with sixel, kitty or iTerm2 graphics where the client's terminal supports them, or with
+//! coloured half blocks (or ASCII art, without colour) otherwise. Images are scaled to fit the terminal.
+
+use std::{
+    io::Cursor,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::Engine;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, Rgba, RgbaImage};
+use once_cell::sync::Lazy;
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{self, fontdb},
+};
+
+use super::style::{Capabilities, Color, Colors, Graphics};
+
+/// The directory the content's images are in (as served over HTTP at `/images/`).
+pub const IMAGES_DIR: &str = "content/images";
+/// The size of a character cell in pixels, for terminals that don't say.
+const DEFAULT_CELL_SIZE: (u32, u32) = (10, 20);
+/// Characters for ASCII art, from darkest to lightest.
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+/// The maximum size of each chunk of image data sent with the kitty graphics protocol.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Fonts for text in SVGs, loaded from the system once.
+static FONTS: Lazy<Arc<fontdb::Database>> = Lazy::new(|| {
+    let mut fonts = fontdb::Database::new();
+    fonts.load_system_fonts();
+    Arc::new(fonts)
+});
+
+/// Gets the path to one of the content's images from its path relative to `IMAGES_DIR` (as in the content), if it's safely inside.
+pub fn path(src: &str) -> Option<PathBuf> {
+    let src = Path::new(src);
+    src.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| Path::new(IMAGES_DIR).join(src))
+}
+
+/// Renders an image to fit a terminal of the given size (in characters and, if known, pixels), returning the output to draw it at
+/// the cursor, which ends up at the start of the line after it. This decodes and scales the image, so can take a while.
+pub fn render(
+    path: &Path,
+    term_size: (u32, u32),
+    pixel_size: (u32, u32),
+    capabilities: Capabilities,
+) -> Result<Vec<u8>, String> {
+    // Leave a line for the prompt (or status line) after the image
+    let (columns, rows) = (term_size.0.max(1), term_size.1.saturating_sub(1).max(1));
+    let cell_size = if pixel_size.0 > 0 && pixel_size.1 > 0 {
+        (
+            (pixel_size.0 / term_size.0.max(1)).max(1),
+            (pixel_size.1 / term_size.1.max(1)).max(1),
+        )
+    } else {
+        DEFAULT_CELL_SIZE
+    };
+    match capabilities.graphics {
+        Graphics::None => {
+            // Characters are about twice as tall as they are wide, so each is two pixels (one above the other)
+            let image = load(path, (columns, rows * 2))?;
+            Ok(
+                if capabilities.styles && capabilities.colors != Colors::None {
+                    half_blocks(&image, capabilities.colors)
+                } else {
+                    ascii(&image)
+                },
+            )
+        }
+        graphics => {
+            let image = load(path, (columns * cell_size.0, rows * cell_size.1))?;
+            let mut output = match graphics {
+                Graphics::Sixel => sixel(&image),
+                Graphics::Kitty => kitty(&image, image.width().div_ceil(cell_size.0))?,
+                _ => iterm(
+                    &image,
+                    image.width().div_ceil(cell_size.0),
+                    image.height().div_ceil(cell_size.1),
+                )?,
+            };
+            output.extend(b"\r\n");
+            Ok(output)
+        }
+    }
+}
+
+/// Loads an image, scaled down to fit in the given size (in pixels). SVGs are scaled up too, as they can be.
+fn load(path: &Path, max_size: (u32, u32)) -> Result<RgbaImage, String> {
+    let name = path.strip_prefix(IMAGES_DIR).unwrap_or(path).display();
+    let data = std::fs::read(path).map_err(|_| format!("{name}: can't read image"))?;
+    if path.extension().is_some_and(|extension| extension == "svg") {
+        let options = usvg::Options {
+            fontdb: FONTS.clone(),
+            ..Default::default()
+        };
+        let tree = usvg::Tree::from_data(&data, &options)
+            .map_err(|e| format!("{name}: invalid SVG ({e})"))?;
+        let size = tree.size();
+        let scale = (max_size.0 as f32 / size.width()).min(max_size.1 as f32 / size.height());
+        let (width, height) = (
+            ((size.width() * scale) as u32).max(1),
+            ((size.height() * scale) as u32).max(1),
+        );
+        let mut pixmap =
+            Pixmap::new(width, height).ok_or_else(|| format!("{name}: image too small"))?;
+        resvg::render(
+            &tree,
+            Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let pixel = pixel.demultiply();
+                [pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()]
+            })
+            .collect();
+        return RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| format!("{name}: invalid SVG"));
+    }
+    let image =
+        image::load_from_memory(&data).map_err(|e| format!("{name}: invalid image ({e})"))?;
+    let image = if image.width() > max_size.0 || image.height() > max_size.1 {
+        image.resize(max_size.0, max_size.1, FilterType::Triangle)
+    } else {
+        image
+    };
+    Ok(image.into_rgba8())
+}
+
+/// Whether a pixel is opaque enough to draw.
+fn opaque(pixel: &Rgba<u8>) -> bool {
+    pixel[3] >= 128
+}
+
+/// Draws an image with the upper and lower half block characters, coloured to show two pixels in each character.
+fn half_blocks(image: &RgbaImage, colors: Colors) -> Vec<u8> {
+    let color = |pixel: &Rgba<u8>, background: bool| {
+        Color::Rgb(pixel[0], pixel[1], pixel[2])
+            .sgr(colors, background)
+            .unwrap_or_default()
+    };
+    let mut output = String::new();
+    for y in (0..image.height()).step_by(2) {
+        let mut last = String::new();
+        for x in 0..image.width() {
+            let top = image.get_pixel(x, y);
+            let bottom = (y + 1 < image.height()).then(|| image.get_pixel(x, y + 1));
+            // Draw the opaque half (or halves) in the foreground, leaving the terminal's background for anything transparent
+            let (sgr, c) = match (opaque(top), bottom.filter(|pixel| opaque(pixel))) {
+                (true, Some(bottom)) => (
+                    format!("\x1b[{};{}m", color(top, false), color(bottom, true)),
+                    '▀',
+                ),
+                (true, None) => (format!("\x1b[0;{}m", color(top, false)), '▀'),
+                (false, Some(bottom)) => (format!("\x1b[0;{}m", color(bottom, false)), '▄'),
+                (false, None) => ("\x1b[0m".to_string(), ' '),
+            };
+            if sgr != last {
+                output.push_str(&sgr);
+                last = sgr;
+            }
+            output.push(c);
+        }
+        output.push_str("\x1b[0m\r\n");
+    }
+    output.into_bytes()
+}
+
+/// Draws an image as ASCII art (for a dark background), with each character showing two pixels.
+fn ascii(image: &RgbaImage) -> Vec<u8> {
+    let brightness = |pixel: &Rgba<u8>| {
+        let [r, g, b, a] = pixel.0.map(u32::from);
+        (r * 299 + g * 587 + b * 114) / 1000 * a / 255
+    };
+    let mut output = vec![];
+    for y in (0..image.height()).step_by(2) {
+        for x in 0..image.width() {
+            let top = brightness(image.get_pixel(x, y));
+            let bottom = if y + 1 < image.height() {
+                brightness(image.get_pixel(x, y + 1))
+            } else {
+                top
+            };
+            let index = (top + bottom) as usize * (ASCII_RAMP.len() - 1) / 510;
+            output.push(ASCII_RAMP[index]);
+        }
+        output.extend(b"\r\n");
+    }
+    output
+}
+
+/// Encodes an image as sixels, with colours from a 6x6x6 cube.
+fn sixel(image: &RgbaImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let level = |c: u8| (c as usize * 5 + 127) / 255;
+    let color = |x: u32, y: u32| {
+        let pixel = image.get_pixel(x, y);
+        opaque(pixel).then(|| level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2]))
+    };
+
+    // Leave transparent pixels as the background, and define the palette
+    let mut output = format!("\x1bP0;1;0q\"1;1;{width};{height}");
+    for i in 0..216 {
+        output.push_str(&format!(
+            "#{i};2;{};{};{}",
+            i / 36 * 20,
+            i / 6 % 6 * 20,
+            i % 6 * 20
+        ));
+    }
+
+    // Each band of six rows is drawn one colour at a time, returning to the start of the band between colours
+    for top in (0..height).step_by(6) {
+        let rows = (height - top).min(6);
+        let band: Vec<[Option<usize>; 6]> = (0..width)
+            .map(|x| {
+                let mut column = [None; 6];
+                for (row, pixel) in column.iter_mut().enumerate().take(rows as usize) {
+                    *pixel = color(x, top + row as u32);
+                }
+                column
+            })
+            .collect();
+        let mut used = [false; 216];
+        band.iter()
+            .flatten()
+            .flatten()
+            .for_each(|&i| used[i] = true);
+        for i in (0..216).filter(|&i| used[i]) {
+            let sixels: Vec<u8> = band
+                .iter()
+                .map(|column| {
+                    let bits = (0..6).filter(|&row| column[row] == Some(i));
+                    63 + bits.fold(0, |sixel, row| sixel | 1 << row)
+                })
+                .collect();
+            // Run-length encode the sixels, leaving off any empty ones at the end
+            output.push_str(&format!("#{i}"));
+            let end = sixels
+                .iter()
+                .rposition(|&sixel| sixel != 63)
+                .map_or(0, |i| i + 1);
+            for run in sixels[..end].chunk_by(|a, b| a == b) {
+                let c = run[0] as char;
+                match run.len() {
+                    1..=3 => output.extend(std::iter::repeat_n(c, run.len())),
+                    n => output.push_str(&format!("!{n}{c}")),
+                }
+            }
+            output.push('$');
+        }
+        output.push('-');
+    }
+    output.push_str("\x1b\\");
+    output.into_bytes()
+}
+
+/// Encodes an image as a PNG.
+fn png(image: &RgbaImage) -> Result<Vec<u8>, String> {
+    let mut png = vec![];
+    DynamicImage::ImageRgba8(image.clone())
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| format!("couldn't encode image ({e})"))?;
+    Ok(png)
+}
+
+/// Sends an image with the kitty graphics protocol, as a PNG (in chunks) shown `columns` wide.
+fn kitty(image: &RgbaImage, columns: u32) -> Result<Vec<u8>, String> {
+    let data = base64::engine::general_purpose::STANDARD.encode(png(image)?);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    let mut output = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        // Only the first chunk has the image's details (and `q=2` stops the terminal replying, which would come back as input)
+        let control = if i == 0 {
+            format!("a=T,f=100,q=2,c={columns},m={more}")
+        } else {
+            format!("m={more}")
+        };
+        output.extend(format!("\x1b_G{control};").as_bytes());
+        output.extend(*chunk);
+        output.extend(b"\x1b\\");
+    }
+    Ok(output)
+}
+
+/// Sends an image with iTerm2's inline images protocol, as a PNG shown in the given size (in characters).
+fn iterm(image: &RgbaImage, columns: u32, rows: u32) -> Result<Vec<u8>, String> {
+    let png = png(image)?;
+    Ok(format!(
+        "\x1b]1337;File=inline=1;size={};width={columns};height={rows};preserveAspectRatio=1:{}\x07",
+        png.len(),
+        base64::engine::general_purpose::STANDARD.encode(&png)
+    )
+    .into_bytes())
+}
+
+/// The output to remove any images drawn by `render` from the screen, for terminals where clearing the screen doesn't.
+pub fn clear(capabilities: Capabilities) -> &'static [u8] {
+    match capabilities.graphics {
+        Graphics::Kitty => b"\x1b_Ga=d,q=2\x1b\\",
+        _ => b"",
+    }
+}
@@ -0,0 +1,335 @@
+//! Parses input from the client's terminal (UTF-8 text, control characters and ANSI/VT escape sequences) into key presses, for the
+//! shell and running apps.
+
+use super::text::Utf8Decoder;
+
+/// The sequence starting a bracketed paste (which we ask for in `SshSession::pty_request`).
+const PASTE_START: &[u8] = b"\x1b[200~";
+/// The sequence ending a bracketed paste.
+const PASTE_END: &[u8] = b"\x1b[201~";
+/// The maximum size of a paste (in bytes), with any more dropped.
+const MAX_PASTE_SIZE: usize = 16 * 1024;
+/// The maximum length of an escape sequence, so unterminated ones are dropped rather than swallowing all input.
+const MAX_ESCAPE_LENGTH: usize = 32;
+
+/// Modifier keys held with a special key (e.g. Ctrl-Left), as reported by xterm-style terminals.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+impl Modifiers {
+    /// Decodes the modifier parameter of an escape sequence (1 plus a bitmask of shift, alt, ctrl, and meta, treated as alt).
+    fn from_param(param: u16) -> Self {
+        let bits = param.saturating_sub(1);
+        Self {
+            shift: bits & 1 != 0,
+            alt: bits & 2 != 0 || bits & 8 != 0,
+            ctrl: bits & 4 != 0,
+        }
+    }
+}
+
+/// A mouse button.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+/// What happened with the mouse in a `Mouse` event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseAction {
+    Press(MouseButton),
+    /// A button was released (SGR reporting says which, but the legacy protocol doesn't, so neither do we).
+    Release,
+    /// The mouse moved with a button held.
+    Drag(MouseButton),
+    ScrollUp,
+    ScrollDown,
+}
+
+/// A mouse event, reported by the terminal while an app has turned on mouse reporting (see `MOUSE_ON`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mouse {
+    pub action: MouseAction,
+    /// The column of the event, from 0 at the left.
+    pub x: u16,
+    /// The row of the event, from 0 at the top.
+    pub y: u16,
+    pub modifiers: Modifiers,
+}
+impl Mouse {
+    /// Decodes a mouse event from its button code (a button number, plus bits for modifiers, motion and the scroll wheel) and
+    /// 1-based position.
+    fn from_code(code: u16, x: u16, y: u16, release: bool) -> Option<Self> {
+        let button = match code & 3 {
+            0 => Some(MouseButton::Left),
+            1 => Some(MouseButton::Middle),
+            2 => Some(MouseButton::Right),
+            _ => None,
+        };
+        let action = if code & 64 != 0 {
+            match code & 3 {
+                0 => MouseAction::ScrollUp,
+                1 => MouseAction::ScrollDown,
+                _ => return None,
+            }
+        } else if release || button.is_none() && code & 32 == 0 {
+            MouseAction::Release
+        } else if code & 32 != 0 {
+            // Motion without a button held is only reported in modes we don't ask for
+            MouseAction::Drag(button?)
+        } else {
+            MouseAction::Press(button?)
+        };
+        Some(Self {
+            action,
+            x: x.saturating_sub(1),
+            y: y.saturating_sub(1),
+            modifiers: Modifiers {
+                shift: code & 4 != 0,
+                alt: code & 8 != 0,
+                ctrl: code & 16 != 0,
+            },
+        })
+    }
+}
+
+/// Turns on mouse reporting in the client's terminal, for clicks and the scroll wheel, with SGR encoding (so any position works).
+pub const MOUSE_ON: &[u8] = b"\x1b[?1000h\x1b[?1006h";
+/// Turns mouse reporting back off, so the terminal handles the mouse itself (e.g. for selecting text).
+pub const MOUSE_OFF: &[u8] = b"\x1b[?1006l\x1b[?1000l";
+
+/// A key press (or paste) from the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    /// A printable character.
+    Char(char),
+    /// A character typed with Ctrl held, with letters in lowercase (e.g. `Ctrl('c')`).
+    Ctrl(char),
+    /// A printable character typed with Alt held (sent as escape followed by the character).
+    Alt(char),
+    Enter,
+    Tab,
+    /// Shift-Tab.
+    BackTab,
+    Backspace,
+    Delete,
+    Insert,
+    Escape,
+    Up(Modifiers),
+    Down(Modifiers),
+    Left(Modifiers),
+    Right(Modifiers),
+    Home(Modifiers),
+    End(Modifiers),
+    PageUp,
+    PageDown,
+    /// A function key, from F1 to F12.
+    F(u8),
+    /// Text pasted in one go (with bracketed paste), which shouldn't be treated as typed commands.
+    Paste(String),
+    /// A mouse event, only sent while mouse reporting is on.
+    Mouse(Mouse),
+}
+
+/// Parses input from the client into `Key`s, keeping track of sequences split between packets.
+#[derive(Debug, Default)]
+pub struct InputParser {
+    /// The escape sequence so far, if we're in the middle of one.
+    escape: Vec<u8>,
+    /// Decoder for multi-byte characters.
+    utf8: Utf8Decoder,
+    /// The text pasted so far, if we're in the middle of a bracketed paste.
+    paste: Option<Vec<u8>>,
+}
+impl InputParser {
+    /// Parses a packet of input into key presses. Escape sequences split between packets are continued in the next one, except for
+    /// a lone escape at the end of a packet, which is taken to be the Escape key itself (as terminals send sequences in one go).
+    pub fn parse(&mut self, data: &[u8]) -> Vec<Key> {
+        let mut keys = vec![];
+        for &byte in data {
+            if let Some(paste) = &mut self.paste {
+                // Collect the paste until it ends, dropping anything past the size limit (but keeping enough to spot the end)
+                paste.push(byte);
+                if paste.ends_with(PASTE_END) {
+                    paste.truncate((paste.len() - PASTE_END.len()).min(MAX_PASTE_SIZE));
+                    keys.push(Key::Paste(String::from_utf8_lossy(paste).into_owned()));
+                    self.paste = None;
+                } else if paste.len() > MAX_PASTE_SIZE + PASTE_END.len() {
+                    paste.remove(MAX_PASTE_SIZE);
+                }
+                continue;
+            }
+            if !self.escape.is_empty() {
+                self.escape.push(byte);
+                if let Some(key) = self.parse_escape() {
+                    keys.extend(key);
+                }
+                continue;
+            }
+            let key = match byte {
+                27 => {
+                    self.escape.push(byte);
+                    None
+                }
+                0 => Some(Key::Ctrl(' ')),
+                8 | 127 => Some(Key::Backspace),
+                9 => Some(Key::Tab),
+                10 | 13 => Some(Key::Enter),
+                1..=26 => Some(Key::Ctrl((b'a' + byte - 1) as char)),
+                28..=31 => Some(Key::Ctrl((byte + 64) as char)),
+                _ => self
+                    .utf8
+                    .push(byte)
+                    .filter(|c| !c.is_control())
+                    .map(Key::Char),
+            };
+            keys.extend(key);
+        }
+        if self.escape == [27] {
+            self.escape.clear();
+            keys.push(Key::Escape);
+        }
+        keys
+    }
+
+    /// Parses the escape sequence so far. Returns `None` if it's incomplete, or otherwise clears it and returns its key (if any, as
+    /// unknown sequences are ignored).
+    fn parse_escape(&mut self) -> Option<Option<Key>> {
+        let key = match self.escape.as_slice() {
+            [27] | [27, b'[' | b'O'] => return None,
+            [27, 27] => {
+                // Escape pressed twice, so the first one was just the key
+                self.escape = vec![27];
+                return Some(Some(Key::Escape));
+            }
+            escape if escape == PASTE_START => {
+                self.paste = Some(vec![]);
+                None
+            }
+            [27, b'[', b'M', rest @ ..] => {
+                // Legacy mouse report, from terminals without SGR reporting: the button code, column and row, each plus 32
+                let &[code, x, y] = rest else {
+                    return None;
+                };
+                Mouse::from_code(
+                    u16::from(code.saturating_sub(32)),
+                    u16::from(x.saturating_sub(32)),
+                    u16::from(y.saturating_sub(32)),
+                    false,
+                )
+                .map(Key::Mouse)
+            }
+            [27, b'O', byte] => match byte {
+                b'A' => Some(Key::Up(Modifiers::default())),
+                b'B' => Some(Key::Down(Modifiers::default())),
+                b'C' => Some(Key::Right(Modifiers::default())),
+                b'D' => Some(Key::Left(Modifiers::default())),
+                b'H' => Some(Key::Home(Modifiers::default())),
+                b'F' => Some(Key::End(Modifiers::default())),
+                b'P'..=b'S' => Some(Key::F(byte - b'P' + 1)),
+                _ => None,
+            },
+            [27, b'[', rest @ ..] => {
+                let (&last, params) = rest.split_last()?;
+                if !(0x40..=0x7e).contains(&last) {
+                    // Not finished yet (unless it's too long to be real)
+                    if self.escape.len() < MAX_ESCAPE_LENGTH {
+                        return None;
+                    }
+                    self.escape.clear();
+                    return Some(None);
+                }
+                parse_csi(params, last)
+            }
+            [27, byte] => match *byte {
+                8 | 127 => Some(Key::Alt('\x7f')),
+                byte @ 32..=126 => Some(Key::Alt(byte as char)),
+                _ => None,
+            },
+            _ => None,
+        };
+        self.escape.clear();
+        Some(key)
+    }
+}
+
+/// Parses a CSI escape sequence (`ESC [ params final`) into a key, if it's one we know.
+fn parse_csi(params: &[u8], last: u8) -> Option<Key> {
+    if let Some(params) = params.strip_prefix(b"<") {
+        // SGR mouse report: `ESC [ < code ; x ; y` ending in `M` for a press (or motion) or `m` for a release
+        let params: Vec<u16> = std::str::from_utf8(params)
+            .ok()?
+            .split(';')
+            .map(|param| param.parse().ok())
+            .collect::<Option<_>>()?;
+        let &[code, x, y] = params.as_slice() else {
+            return None;
+        };
+        return match last {
+            b'M' | b'm' => Mouse::from_code(code, x, y, last == b'm').map(Key::Mouse),
+            _ => None,
+        };
+    }
+    let params: Vec<u16> = std::str::from_utf8(params)
+        .ok()?
+        .split(';')
+        .map(|param| param.parse().unwrap_or(0))
+        .collect();
+    let modifiers = Modifiers::from_param(params.get(1).copied().unwrap_or(1));
+    match last {
+        b'A' => Some(Key::Up(modifiers)),
+        b'B' => Some(Key::Down(modifiers)),
+        b'C' => Some(Key::Right(modifiers)),
+        b'D' => Some(Key::Left(modifiers)),
+        b'H' => Some(Key::Home(modifiers)),
+        b'F' => Some(Key::End(modifiers)),
+        b'Z' => Some(Key::BackTab),
+        b'P'..=b'S' => Some(Key::F(last - b'P' + 1)),
+        b'~' => match params[0] {
+            1 | 7 => Some(Key::Home(modifiers)),
+            2 => Some(Key::Insert),
+            3 => Some(Key::Delete),
+            4 | 8 => Some(Key::End(modifiers)),
+            5 => Some(Key::PageUp),
+            6 => Some(Key::PageDown),
+            n @ 11..=15 => Some(Key::F(n as u8 - 10)),
+            n @ 17..=21 => Some(Key::F(n as u8 - 11)),
+            n @ 23..=24 => Some(Key::F(n as u8 - 12)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Generates random input from a terminal, for property tests: typed text (any UTF-8), control characters, escape sequences (known
+/// or not), pastes, mouse reports, and stray bytes, in any order and possibly cut off.
+#[cfg(test)]
+pub fn arbitrary_input() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+    use proptest::prelude::*;
+
+    let piece = prop_oneof![
+        any::<u8>().prop_map(|byte| vec![byte]),
+        any::<String>().prop_map(String::into_bytes),
+        "[ -~]{1,8}".prop_map(String::into_bytes),
+        (1u8..32).prop_map(|byte| vec![byte]),
+        Just(vec![27]),
+        Just(vec![127]),
+        ("[0-9;<?]{0,8}", 0x40u8..=0x7e).prop_map(|(params, last)| [
+            b"\x1b[",
+            params.as_bytes(),
+            &[last]
+        ]
+        .concat()),
+        any::<u8>().prop_map(|byte| vec![27, b'O', byte]),
+        any::<u8>().prop_map(|byte| vec![27, byte]),
+        any::<[u8; 3]>().prop_map(|report| [b"\x1b[M", &report[..]].concat()),
+        any::<String>().prop_map(|pasted| [PASTE_START, pasted.as_bytes(), PASTE_END].concat()),
+        Just(PASTE_START.to_vec()),
+    ];
+    proptest::collection::vec(piece, 0..48).prop_map(|pieces| pieces.concat())
+}
@@ -7,26 +7,52 @@ use std::{
     time::Duration,
 };
 
+use async_trait::async_trait;
 use color_eyre::Result;
-use russh::server::{self};
+use russh::{
+    server::{self, Msg, Session},
+    Channel, ChannelId, CryptoVec,
+};
 use russh_keys::key;
 use tokio::{
     net::TcpListener,
-    sync::{broadcast, mpsc, oneshot},
+    sync::{broadcast, mpsc},
 };
 use tracing::{error, info};
 
-use crate::ssh::content::SshContent;
+use crate::{
+    limits::{ConnectionLimiter, RateLimiter, Rejection},
+    ssh::content::SshContent,
+};
 
-use self::session::SshSession;
+use self::session::{OpenChannels, SshSession};
 
+mod admin;
 mod apps;
+mod channel;
+mod commands;
 mod contact;
 mod content;
+mod image;
+mod input;
+mod scp;
 mod session;
+mod sftp;
+mod style;
 mod terminal;
+mod text;
+
+/// How long a rejected connection gets to read why before it's closed.
+const REJECTED_TIMEOUT: Duration = Duration::from_secs(10);
+/// The window over which `CONFIG.ssh_max_auth_attempts` applies.
+const AUTH_ATTEMPTS_WINDOW: Duration = Duration::from_secs(60);
+/// Sent to every channel when the server is shutting down.
+const SHUTDOWN_MESSAGE: &str = "\r\nThe server is going down for maintenance, so you've been disconnected. Please come back soon!\r\n";
 
-pub async fn main(_rx: broadcast::Receiver<()>) -> Result<Infallible> {
+pub async fn main(
+    _rx: broadcast::Receiver<()>,
+    shutdown_rx: broadcast::Receiver<()>,
+) -> Result<Infallible> {
     // TODO: add live-reload when we get message from _rx
     // Setup content, config, and listener
     let content = Arc::new(SshContent::new(&crate::CONTENT.read().unwrap())?);
@@ -37,8 +63,15 @@ pub async fn main(_rx: broadcast::Receiver<()>) -> Result<Infallible> {
     let config = Arc::new(config);
     let listener = TcpListener::bind(("0.0.0.0", crate::CONFIG.ssh_port)).await?;
 
-    // Setup connection handling, initializing all necessary variables (could later include Vec of all connections and connection time or other load-managing stuff)
-    let active_connections = Arc::new(AtomicUsize::new(0));
+    // Setup connection handling, with limits on how many connections can be open (overall and from each IP) and on auth attempts
+    let limiter = ConnectionLimiter::new(
+        crate::CONFIG.ssh_max_sessions,
+        crate::CONFIG.ssh_max_sessions_ip,
+    );
+    let auth_attempts = Arc::new(RateLimiter::new(
+        crate::CONFIG.ssh_max_auth_attempts,
+        AUTH_ATTEMPTS_WINDOW,
+    ));
     let total_connections: AtomicUsize = AtomicUsize::new(0);
 
     // Run server
@@ -47,56 +80,170 @@ pub async fn main(_rx: broadcast::Receiver<()>) -> Result<Infallible> {
     loop {
         let (stream, addr) = listener.accept().await?;
         let conn_id = total_connections.fetch_add(1, atomic::Ordering::Relaxed);
-        let conn_count = active_connections.fetch_add(1, atomic::Ordering::Relaxed) + 1;
-        info!("New connection (#{conn_id}) from {addr} ({conn_count} active)");
+        let connection = match limiter.admit(addr.ip()) {
+            Ok(connection) => connection,
+            Err(rejection) => {
+                info!("Rejected connection (#{conn_id}) from {addr} ({rejection})");
+                let Some(permit) = limiter.rejection_permit() else {
+                    continue;
+                };
+                // Let the client in just to say why (as clients only show auth banners once they've asked for a password)
+                let config = Arc::clone(&config);
+                tokio::spawn(async move {
+                    let _ = tokio::time::timeout(REJECTED_TIMEOUT, async move {
+                        if let Ok(session_fut) =
+                            server::run_stream(config, stream, Rejected(rejection)).await
+                        {
+                            let _ = session_fut.await;
+                        }
+                    })
+                    .await;
+                    drop(permit);
+                });
+                continue;
+            }
+        };
+        info!(
+            "New connection (#{conn_id}) from {addr} ({} active)",
+            limiter.active()
+        );
         // Clone vars for task
-        let active_connections = Arc::clone(&active_connections);
+        let limiter = Arc::clone(&limiter);
         let config = Arc::clone(&config);
         let content = Arc::clone(&content);
-        // Receiver for ChannelId to allow closing connection remotely
-        let (channel_tx, channel_rx) = oneshot::channel();
+        let auth_attempts = Arc::clone(&auth_attempts);
+        let mut shutdown_rx = shutdown_rx.resubscribe();
+        // The connection's open channels, to close them all if it times out or the server shuts down
+        let open_channels = OpenChannels::default();
         // Make channel to receive timeout resets
         let (timeout_reset, timeout_reset_rx) = mpsc::channel(1);
         tokio::spawn(async move {
-            match server::run_stream(
-                config,
-                stream,
-                SshSession::new(conn_id, addr, content, channel_tx, timeout_reset),
-            )
-            .await
-            {
-                Ok(session_fut) => {
+            let session = SshSession::new(
+                conn_id,
+                addr,
+                content,
+                open_channels.clone(),
+                auth_attempts,
+                timeout_reset,
+            );
+            match server::run_stream(config, stream, session).await {
+                Ok(mut session_fut) => {
                     info!("Connection #{conn_id} from {addr} successfully set up");
-                    let _ = session_fut.await;
+                    let handle = session_fut.handle();
+                    // Run until the connection closes, times out or the server shuts down, closing every channel in the last two cases
+                    // (which ends the connection once the client sees)
+                    let message = tokio::select! {
+                        _ = &mut session_fut => None,
+                        true = resetting_timeout(
+                            timeout_reset_rx,
+                            crate::CONFIG.ssh_timeout,
+                            crate::CONFIG.ssh_first_timeout,
+                        ) => {
+                            info!("Connection (#{conn_id}) from {addr} timed out");
+                            Some("")
+                        }
+                        _ = shutdown_rx.recv() => Some(SHUTDOWN_MESSAGE),
+                    };
+                    if let Some(message) = message {
+                        let channels: Vec<ChannelId> =
+                            open_channels.lock().unwrap().iter().copied().collect();
+                        for channel in channels {
+                            if !message.is_empty() {
+                                let _ = handle
+                                    .extended_data(
+                                        channel,
+                                        1,
+                                        CryptoVec::from_slice(message.as_bytes()),
+                                    )
+                                    .await;
+                            }
+                            if handle.close(channel).await.is_err() {
+                                error!("Error closing connection (#{conn_id}) from {addr}");
+                            }
+                        }
+                        let _ = session_fut.await;
+                    }
                 }
                 Err(_) => error!("Error while setting up connection (#{conn_id}) from {addr}"),
             };
-            let now_active = active_connections.fetch_sub(1, atomic::Ordering::Relaxed) - 1;
-            info!("Connection (#{conn_id}) from {addr} closed ({now_active} active)");
-        });
-        tokio::spawn(async move {
-            // Get channel for closing connection
-            let Ok(channel) = channel_rx.await else {
-                // The connection must have closed, dropping `channel_tx`
-                return;
-            };
-            // Wait for timeout and close connection
-            if resetting_timeout(
-                timeout_reset_rx,
-                crate::CONFIG.ssh_timeout,
-                crate::CONFIG.ssh_first_timeout,
-            )
-            .await
-            {
-                info!("Connection (#{conn_id}) from {addr} timed out");
-                if let Err(e) = channel.close().await {
-                    error!("Error closing connection (#{conn_id}) from {addr}: {e}");
-                }
-            }
+            drop(connection);
+            info!(
+                "Connection (#{conn_id}) from {addr} closed ({} active)",
+                limiter.active()
+            );
         });
     }
 }
 
+/// The handler for connections turned away by the limits, which accepts anyone, then closes any channel they open with why.
+struct Rejected(Rejection);
+impl Rejected {
+    /// Tells the client why it was turned away (on stderr, so it doesn't get mixed up with SFTP or SCP) and closes the channel.
+    fn refuse(&self, channel: ChannelId, session: &mut Session) {
+        session.channel_success(channel);
+        session.extended_data(
+            channel,
+            1,
+            CryptoVec::from_slice(self.0.message().as_bytes()),
+        );
+        session.exit_status_request(channel, 1);
+        session.eof(channel);
+        session.close(channel);
+    }
+}
+#[async_trait]
+impl server::Handler for Rejected {
+    type Error = russh::Error;
+
+    async fn channel_open_session(
+        self,
+        _channel: Channel<Msg>,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        Ok((self, true, session))
+    }
+    async fn auth_none(self, _: &str) -> Result<(Self, server::Auth), Self::Error> {
+        Ok((self, server::Auth::Accept))
+    }
+    async fn auth_password(self, _: &str, _: &str) -> Result<(Self, server::Auth), Self::Error> {
+        Ok((self, server::Auth::Accept))
+    }
+    async fn auth_publickey(
+        self,
+        _: &str,
+        _: &key::PublicKey,
+    ) -> Result<(Self, server::Auth), Self::Error> {
+        Ok((self, server::Auth::Accept))
+    }
+
+    async fn shell_request(
+        self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.refuse(channel, &mut session);
+        Ok((self, session))
+    }
+    async fn exec_request(
+        self,
+        channel: ChannelId,
+        _: &[u8],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.refuse(channel, &mut session);
+        Ok((self, session))
+    }
+    async fn subsystem_request(
+        self,
+        channel: ChannelId,
+        _: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.refuse(channel, &mut session);
+        Ok((self, session))
+    }
+}
+
 /// Helper function that times out (returning `true`) if no message is received within a certain duration. If the sender closes, the function returns `false`.
 async fn resetting_timeout(
     mut reset_signal: mpsc::Receiver<()>,
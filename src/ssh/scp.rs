@@ -0,0 +1,241 @@
+//! The source side of the legacy SCP protocol (`scp -f`, run by `scp -O`), for downloading files from the virtual filesystem.
+//! Uploading isn't allowed, as the site is read-only. (Newer versions of `scp` use SFTP instead, handled by `sftp`.)
+//!
+//! The protocol goes back and forth with the client (the sink): it acknowledges each line we send (a `C` line before each file,
+//! `D` and `E` lines around directories, and `T` lines with times) and the data of each file with a zero byte, or sends an error.
+
+use std::{collections::VecDeque, path::PathBuf};
+
+use super::commands::{Context, Entry};
+
+/// The maximum length of an error message from the client (in bytes), with anything longer taken as a broken connection.
+const MAX_ERROR_LENGTH: usize = 1024;
+
+/// Where the data of a file comes from.
+enum Source {
+    Text(Vec<u8>),
+    /// An image, read when it's sent.
+    Image(PathBuf),
+}
+
+/// Something to send to the client.
+enum Step {
+    /// A line of the protocol, which the client acknowledges.
+    Line(String),
+    /// An error, sent as a warning without waiting for the client (as it carries on to the next file).
+    Error(String),
+    /// A file, sent as its `C` line and then (once acknowledged) its data.
+    File { name: String, source: Source },
+    /// The data of a file, whose `C` line has been acknowledged.
+    Data(Vec<u8>),
+}
+
+/// The state of an SCP download.
+pub struct ScpSource {
+    /// What's left to send.
+    steps: VecDeque<Step>,
+    /// Data from the client that isn't a whole acknowledgement yet.
+    input: Vec<u8>,
+    /// The exit status, which is 1 if anything went wrong.
+    status: u32,
+}
+impl ScpSource {
+    /// Starts a download with the arguments given to `scp` (as sent by the client, such as `-f -- file`), or returns an error for the
+    /// client if it can't be done.
+    pub fn new(context: &Context, args: &[String]) -> Result<Self, String> {
+        let (mut source, mut recursive, mut times) = (false, false, false);
+        let mut paths = args;
+        while let Some((arg, rest)) = paths.split_first() {
+            if arg == "--" {
+                paths = rest;
+                break;
+            }
+            let Some(flags) = arg.strip_prefix('-') else {
+                break;
+            };
+            for flag in flags.chars() {
+                match flag {
+                    'f' => source = true,
+                    't' => {
+                        return Err(
+                            "the site is read-only, so files can only be downloaded".to_string()
+                        )
+                    }
+                    'r' => recursive = true,
+                    'p' => times = true,
+                    'd' | 'v' | 'q' => {}
+                    flag => return Err(format!("unknown option -{flag}")),
+                }
+            }
+            paths = rest;
+        }
+        if !source || paths.is_empty() {
+            return Err("usage: scp -f [-pr] [--] path...".to_string());
+        }
+
+        let mut scp = Self {
+            steps: VecDeque::new(),
+            input: vec![],
+            status: 0,
+        };
+        for path in paths {
+            let name = path
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default();
+            match context.resolve(path) {
+                Some(entry) => scp.add(context, path, name, entry, recursive, times),
+                None => scp
+                    .steps
+                    .push_back(Step::Error(format!("{path}: No such file or directory"))),
+            }
+        }
+        Ok(scp)
+    }
+
+    /// Adds the steps to send a file or directory (with the given path and name), along with its contents if `recursive`.
+    fn add(
+        &mut self,
+        context: &Context,
+        path: &str,
+        name: &str,
+        entry: Entry,
+        recursive: bool,
+        times: bool,
+    ) {
+        match entry {
+            Entry::File(file) => {
+                if times {
+                    self.steps
+                        .push_back(Step::Line(format!("T{0} 0 {0} 0\n", file.modified)));
+                }
+                let source = match &file.image {
+                    Some(image) => Source::Image(image.clone()),
+                    None => Source::Text(file.text().into_bytes()),
+                };
+                self.steps.push_back(Step::File {
+                    name: name.to_string(),
+                    source,
+                });
+            }
+            Entry::Directory(_) if !recursive => self
+                .steps
+                .push_back(Step::Error(format!("{path}: not a regular file"))),
+            Entry::Directory(dir) => {
+                let directory = context.content.get(dir);
+                // The root has no name, so is sent as if it were called what the site is
+                let name = if name.is_empty() || name == "." || name == ".." {
+                    crate::CONFIG.domain.as_str()
+                } else {
+                    name
+                };
+                if times {
+                    let modified = directory.files.values().map(|file| file.modified).max();
+                    self.steps
+                        .push_back(Step::Line(format!("T{0} 0 {0} 0\n", modified.unwrap_or(0))));
+                }
+                self.steps
+                    .push_back(Step::Line(format!("D0555 0 {name}\n")));
+                for (child_name, &child) in directory.directories.iter() {
+                    let child_path = format!("{}/{child_name}", path.trim_end_matches('/'));
+                    self.add(
+                        context,
+                        &child_path,
+                        child_name,
+                        Entry::Directory(child),
+                        recursive,
+                        times,
+                    );
+                }
+                for (child_name, file) in directory.files.iter() {
+                    let child_path = format!("{}/{child_name}", path.trim_end_matches('/'));
+                    self.add(
+                        context,
+                        &child_path,
+                        child_name,
+                        Entry::File(file),
+                        recursive,
+                        times,
+                    );
+                }
+                self.steps.push_back(Step::Line("E\n".to_string()));
+            }
+        }
+    }
+
+    /// Processes data from the client, returning what to send next, along with the exit status once it's finished.
+    pub async fn data(&mut self, data: &[u8]) -> (Vec<u8>, Option<u32>) {
+        self.input.extend(data);
+        let mut response = vec![];
+        while let Some(&first) = self.input.first() {
+            match first {
+                0 => {
+                    self.input.remove(0);
+                }
+                1 | 2 => {
+                    // An error (which ends everything if it's fatal), with a message we don't need
+                    let Some(end) = self.input.iter().position(|&byte| byte == b'\n') else {
+                        if self.input.len() > MAX_ERROR_LENGTH {
+                            return (response, Some(1));
+                        }
+                        break;
+                    };
+                    self.input.drain(..=end);
+                    self.status = 1;
+                    if first == 2 {
+                        return (response, Some(1));
+                    }
+                }
+                _ => return (response, Some(1)),
+            }
+            // Acknowledged, so on to the next step
+            let (output, finished) = self.next().await;
+            response.extend(output);
+            if finished {
+                return (response, Some(self.status));
+            }
+        }
+        (response, None)
+    }
+
+    /// Gets what to send next, up to something the client will acknowledge, and whether that's everything.
+    async fn next(&mut self) -> (Vec<u8>, bool) {
+        let mut output = vec![];
+        while let Some(step) = self.steps.pop_front() {
+            match step {
+                Step::Line(line) => {
+                    output.extend(line.as_bytes());
+                    return (output, false);
+                }
+                Step::Error(message) => {
+                    self.status = 1;
+                    output.push(1);
+                    output.extend(format!("scp: {message}\n").as_bytes());
+                }
+                Step::File { name, source } => {
+                    let data = match source {
+                        Source::Text(text) => Ok(text),
+                        Source::Image(path) => tokio::fs::read(path).await,
+                    };
+                    match data {
+                        Ok(data) => {
+                            output.extend(format!("C0444 {} {name}\n", data.len()).as_bytes());
+                            self.steps.push_front(Step::Data(data));
+                            return (output, false);
+                        }
+                        Err(_) => self
+                            .steps
+                            .push_front(Step::Error(format!("{name}: couldn't read file"))),
+                    }
+                }
+                Step::Data(data) => {
+                    output.extend(data);
+                    output.push(0);
+                    return (output, false);
+                }
+            }
+        }
+        (output, true)
+    }
+}
@@ -1,35 +1,47 @@
-use std::{net::SocketAddr, sync::Arc};
+//! A connection to the SSH server, which handles authentication and passes everything else to its channels.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
 use color_eyre::Result;
 use russh::{
     server::{self, Msg, Session},
-    Channel, ChannelId, CryptoVec, Disconnect,
+    Channel, ChannelId, MethodSet,
 };
 
 use russh_keys::key;
-use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, info, trace};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tracing::info;
 
-use crate::ssh::{apps::Vim, content::WELCOME_MESSAGE};
+use crate::limits::RateLimiter;
 
-use super::{
-    apps::RunningApp,
-    content::SshContent,
-    terminal::{Shell, TerminalUtils},
-};
+use super::{channel::SshChannel, content::SshContent};
+
+/// The maximum number of channels a connection can have open at once.
+const MAX_CHANNELS: usize = 8;
+
+/// The IDs of a connection's open channels, shared with the main loop so it can close them (and so the connection) when it times out.
+pub type OpenChannels = Arc<Mutex<HashSet<ChannelId>>>;
 
 pub struct SshSession {
-    id: usize,
-    shell: Shell,
-    addr: SocketAddr,
+    pub id: usize,
+    pub addr: SocketAddr,
     pub username: String,
+    /// Whether the user logged in as `CONFIG.ssh_admin_user` with the admin password, allowing admin commands.
+    pub admin: bool,
     pub content: Arc<SshContent>,
-    pub current_dir: usize,
-    pub term_size: (u32, u32),
-    pub running_app: Option<Box<dyn RunningApp>>,
-    /// A channel to send the russh `ChannelId` back to the main loop, allowing it to close the connection remotely. After sending the channel, this is set to `None`.
-    pub channel_tx: Option<oneshot::Sender<Channel<Msg>>>,
+    /// The state of each open channel, each of which runs independently.
+    channels: HashMap<ChannelId, SshChannel>,
+    /// The IDs of the open channels, as shared with the main loop.
+    open_channels: OpenChannels,
+    /// Limits how often each IP can try to authenticate, shared between connections.
+    auth_attempts: Arc<RateLimiter>,
     /// A channel to refresh the timeout on this session.
     pub timeout_refresh: mpsc::Sender<()>,
 }
@@ -38,20 +50,20 @@ impl SshSession {
         id: usize,
         addr: SocketAddr,
         content: Arc<SshContent>,
-        channel_tx: oneshot::Sender<Channel<Msg>>,
+        open_channels: OpenChannels,
+        auth_attempts: Arc<RateLimiter>,
         timeout_refresh: mpsc::Sender<()>,
     ) -> Self {
         Self {
             id,
-            shell: Shell::default(),
             addr,
             username: String::new(),
+            admin: false,
             content,
-            current_dir: 0,
-            term_size: (80, 24), // Just a guess, will be updated on connect anyway (TODO: make Option to do this right)
-            running_app: None,
+            channels: HashMap::new(),
+            open_channels,
+            auth_attempts,
             timeout_refresh,
-            channel_tx: Some(channel_tx),
         }
     }
     /// Handle auth, accepting everyone and setting the username.
@@ -63,15 +75,35 @@ impl SshSession {
         self.username = user.to_string();
         Ok((self, server::Auth::Accept))
     }
-    /// Get the current prompt.
-    pub fn prompt(&self) -> Vec<u8> {
-        let mut prompt = self.username.as_bytes().to_vec();
-        prompt.push(b'@');
-        prompt.extend(crate::CONFIG.domain.as_bytes());
-        prompt.push(b':');
-        prompt.extend(self.content.get(self.current_dir).path.as_bytes());
-        prompt.extend(b"> ");
-        prompt
+    /// Records an auth attempt, returning whether the client's IP has made too many recently (in which case it's rejected).
+    fn throttled(&self) -> bool {
+        if self.auth_attempts.attempt(self.addr.ip()) {
+            return false;
+        }
+        info!("Client {} has made too many auth attempts", self.id);
+        true
+    }
+    /// Rejects a throttled auth attempt.
+    fn reject_throttled(self) -> (Self, server::Auth) {
+        (
+            self,
+            server::Auth::Reject {
+                proceed_with_methods: None,
+            },
+        )
+    }
+    /// Whether the given username is reserved for the admin, requiring the admin password to log in.
+    fn is_admin_user(user: &str) -> bool {
+        crate::CONFIG.ssh_admin_password_hash.is_some() && user == crate::CONFIG.ssh_admin_user
+    }
+    /// Rejects an auth attempt for the admin user, asking for a password instead.
+    fn reject_admin(self) -> (Self, server::Auth) {
+        (
+            self,
+            server::Auth::Reject {
+                proceed_with_methods: Some(MethodSet::PASSWORD),
+            },
+        )
     }
 }
 
@@ -84,21 +116,55 @@ impl server::Handler for SshSession {
         channel: Channel<Msg>,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
-        // If we haven't opened a channel yet, send the channel ID back to the main loop and allow it. Otherwise, reject the channel (to keep only one going).
-        // Not sure if this is at all a good idea, but it works for now (chosen because it's the only way I could find to close the connection from the main loop).
-        if let Some(tx) = self.channel_tx.take() {
-            tx.send(channel)
-                .map_err(|_| color_eyre::eyre::eyre!("Failed to send channel ID to main loop"))?;
-            Ok((self, true, session))
-        } else {
-            Ok((self, false, session))
+        if self.channels.len() >= MAX_CHANNELS {
+            info!("Client {} has too many channels open", self.id);
+            return Ok((self, false, session));
         }
+        let id = channel.id();
+        self.channels.insert(id, SshChannel::new(id, &self));
+        self.open_channels.lock().unwrap().insert(id);
+        Ok((self, true, session))
+    }
+    async fn channel_close(
+        mut self,
+        channel: ChannelId,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.channels.remove(&channel);
+        self.open_channels.lock().unwrap().remove(&channel);
+        Ok((self, session))
     }
 
     async fn auth_none(self, user: &str) -> Result<(Self, server::Auth), Self::Error> {
+        if self.throttled() {
+            return Ok(self.reject_throttled());
+        }
+        if Self::is_admin_user(user) {
+            return Ok(self.reject_admin());
+        }
         self.auth(user).await
     }
-    async fn auth_password(self, user: &str, _: &str) -> Result<(Self, server::Auth), Self::Error> {
+    async fn auth_password(
+        mut self,
+        user: &str,
+        password: &str,
+    ) -> Result<(Self, server::Auth), Self::Error> {
+        if self.throttled() {
+            return Ok(self.reject_throttled());
+        }
+        if Self::is_admin_user(user) {
+            // Compare hashes in constant time, so the response time doesn't leak how much of the hash matched
+            let hash = format!("{:x}", Sha256::digest(password.as_bytes()));
+            let expected = crate::CONFIG
+                .ssh_admin_password_hash
+                .as_deref()
+                .unwrap_or_default();
+            if !bool::from(hash.as_bytes().ct_eq(expected.as_bytes())) {
+                info!("Client {} failed admin login", self.id);
+                return Ok(self.reject_admin());
+            }
+            self.admin = true;
+        }
         self.auth(user).await
     }
     async fn auth_publickey(
@@ -106,26 +172,82 @@ impl server::Handler for SshSession {
         user: &str,
         _: &key::PublicKey,
     ) -> Result<(Self, server::Auth), Self::Error> {
+        if self.throttled() {
+            return Ok(self.reject_throttled());
+        }
+        if Self::is_admin_user(user) {
+            return Ok(self.reject_admin());
+        }
         self.auth(user).await
     }
 
     async fn pty_request(
         mut self,
         channel: ChannelId,
-        _term: &str,
+        term: &str,
         col_width: u32,
         row_height: u32,
-        _pix_width: u32,
-        _pix_height: u32,
+        pix_width: u32,
+        pix_height: u32,
         _modes: &[(russh::Pty, u32)],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        debug!(
-            "got pty request (see russh/server/mod.rs: 497 for default impl, not sure if needed)"
-        );
-        self.term_size = (col_width, row_height);
-        session.data(channel, Vec::from(WELCOME_MESSAGE).into());
-        session.data(channel, CryptoVec::from(self.prompt()));
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.pty(
+                term,
+                (col_width, row_height),
+                (pix_width, pix_height),
+                &mut session,
+            );
+        }
+        Ok((self, session))
+    }
+
+    async fn shell_request(
+        mut self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.shell(&mut session);
+        }
+        Ok((self, session))
+    }
+
+    async fn exec_request(
+        mut self,
+        channel: ChannelId,
+        data: &[u8],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        self.timeout_refresh.send(()).await?;
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            let command = String::from_utf8_lossy(data).into_owned();
+            channel.exec(&command, &mut session).await;
+        }
+        Ok((self, session))
+    }
+
+    async fn subsystem_request(
+        mut self,
+        channel: ChannelId,
+        name: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.subsystem(name, &mut session);
+        }
+        Ok((self, session))
+    }
+
+    async fn channel_eof(
+        mut self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.eof(&mut session).await;
+        }
         Ok((self, session))
     }
 
@@ -134,14 +256,29 @@ impl server::Handler for SshSession {
         channel: ChannelId,
         col_width: u32,
         row_height: u32,
-        _pix_width: u32,
-        _pix_height: u32,
+        pix_width: u32,
+        pix_height: u32,
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        self.term_size = (col_width, row_height);
-        if let Some(ref mut running_app) = self.running_app {
-            let resp = running_app.resize(col_width, row_height);
-            session.data(channel, CryptoVec::from(resp));
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.resize(
+                (col_width, row_height),
+                (pix_width, pix_height),
+                &mut session,
+            );
+        }
+        Ok((self, session))
+    }
+
+    async fn env_request(
+        mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.env(variable_name, variable_value);
         }
         Ok((self, session))
     }
@@ -153,110 +290,9 @@ impl server::Handler for SshSession {
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
         self.timeout_refresh.send(()).await?;
-        trace!("Client {} sent data: {:?}", self.id, data);
-
-        // Process data
-        let mut response = vec![];
-        for i in data {
-            match self.running_app {
-                None => {
-                    // No app running, so shell handles input
-                    let (r, command) = self.shell.process(*i);
-                    response.extend(r);
-                    if let Some(command) = command {
-                        info!("Client {} ran command: {:?}", self.id, command);
-                        let command_name = command.split(' ').next().unwrap_or("");
-                        match command_name {
-                            "exit" | "logout" => {
-                                session.disconnect(Disconnect::ByApplication, "Goodbye!", "");
-                                return Ok((self, session));
-                            }
-                            "help" => response.extend(super::content::WELCOME_MESSAGE),
-                            "ls" => {
-                                let current_dir = self.content.get(self.current_dir);
-                                for (name, _) in current_dir.directories.iter() {
-                                    response.extend(format!("{}\r\n", name).as_bytes());
-                                }
-                                for (name, _) in current_dir.files.iter() {
-                                    response.extend(format!("{}\r\n", name).as_bytes());
-                                }
-                            }
-                            "cd" => {
-                                let dir = command.split(' ').nth(1).unwrap_or("");
-                                let current_dir = self.content.get(self.current_dir);
-                                if dir == ".." {
-                                    if let Some(id) = current_dir.parent {
-                                        self.current_dir = id;
-                                    }
-                                } else if let Some(&id) = current_dir.directories.get(dir) {
-                                    self.current_dir = id;
-                                } else {
-                                    response.extend(
-                                        format!("\"{}\": no such directory\r\n", dir).as_bytes(),
-                                    );
-                                }
-                            }
-                            "cat" => match command.split(' ').nth(1) {
-                                None => response.extend(b"cat: usage: cat <filename>\r\n"),
-                                Some(path) => {
-                                    match self.content.get_file(self.current_dir, path) {
-                                        None => response.extend(
-                                            format!(
-                                                "cat: cannot open \"{}\": No such file\r\n",
-                                                path
-                                            )
-                                            .as_bytes(),
-                                        ),
-                                        Some(file) => {
-                                            response.extend(file.raw_contents());
-                                        }
-                                    };
-                                }
-                            },
-                            "msg" => {
-                                response.extend(super::contact::msg(&command, self.addr.ip()).await)
-                            }
-                            "vi" => match Vim::startup(&self, command) {
-                                Ok((running_app, mut startup_resp)) => {
-                                    self.running_app = Some(running_app);
-                                    response.append(&mut startup_resp);
-                                }
-                                Err(mut error_resp) => {
-                                    response.append(&mut error_resp);
-                                }
-                            },
-                            "" => {}
-                            _ => {
-                                response.extend(
-                                    format!("{}: command not found\r\n", command).as_bytes(),
-                                );
-                            }
-                        }
-                        if self.running_app.is_none() {
-                            // No app was started, so reprompt
-                            response.extend(self.prompt());
-                        }
-                    }
-                }
-                Some(ref mut app) => {
-                    if *i == 3 {
-                        // CTRL-C, exit, clear screen and reprompt
-                        response.append(
-                            &mut TerminalUtils::new().clear().move_cursor(0, 0).into_data(),
-                        );
-                        response.extend(self.prompt());
-                        self.running_app = None;
-                    } else {
-                        response.extend(app.data(*i));
-                    }
-                }
-            }
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.data(data, &mut session).await;
         }
-
-        // Send back to client
-        let data = CryptoVec::from(response);
-        session.data(channel, data);
-
         Ok((self, session))
     }
 }
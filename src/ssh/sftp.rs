@@ -0,0 +1,703 @@
+//! A read-only SFTP server (protocol version 3, as OpenSSH uses) over the virtual filesystem in `SshContent`, for browsing and
+//! downloading pages and images with `sftp` (or `scp`, which uses SFTP by default). Anything that would change the filesystem is
+//! refused with a permission error.
+//!
+//! See [https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02] for the protocol.
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::{
+    commands::{Context, Entry},
+    content::SshContent,
+    style::Capabilities,
+};
+
+/// The version of the protocol we speak.
+const VERSION: u32 = 3;
+/// The maximum size of a packet from the client (in bytes), with anything bigger rejected.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+/// The maximum amount of data sent in reply to one read (in bytes).
+const MAX_READ_SIZE: u32 = 64 * 1024;
+/// The maximum number of files and directories a client can have open at once.
+const MAX_HANDLES: usize = 64;
+/// The owner and group files are shown as belonging to in listings.
+const OWNER: &str = "site";
+
+/// Packet types.
+mod packet {
+    pub const INIT: u8 = 1;
+    pub const VERSION: u8 = 2;
+    pub const OPEN: u8 = 3;
+    pub const CLOSE: u8 = 4;
+    pub const READ: u8 = 5;
+    pub const WRITE: u8 = 6;
+    pub const LSTAT: u8 = 7;
+    pub const FSTAT: u8 = 8;
+    pub const SETSTAT: u8 = 9;
+    pub const FSETSTAT: u8 = 10;
+    pub const OPENDIR: u8 = 11;
+    pub const READDIR: u8 = 12;
+    pub const REMOVE: u8 = 13;
+    pub const MKDIR: u8 = 14;
+    pub const RMDIR: u8 = 15;
+    pub const REALPATH: u8 = 16;
+    pub const STAT: u8 = 17;
+    pub const RENAME: u8 = 18;
+    pub const SYMLINK: u8 = 20;
+    pub const STATUS: u8 = 101;
+    pub const HANDLE: u8 = 102;
+    pub const DATA: u8 = 103;
+    pub const NAME: u8 = 104;
+    pub const ATTRS: u8 = 105;
+}
+
+/// Status codes, sent in `STATUS` packets.
+mod status {
+    pub const OK: u32 = 0;
+    pub const EOF: u32 = 1;
+    pub const NO_SUCH_FILE: u32 = 2;
+    pub const PERMISSION_DENIED: u32 = 3;
+    pub const FAILURE: u32 = 4;
+    pub const BAD_MESSAGE: u32 = 5;
+    pub const OP_UNSUPPORTED: u32 = 8;
+}
+
+/// Flags for which attributes are present.
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+/// The flag to open a file for reading, the only way it can be opened.
+const OPEN_READ: u32 = 0x1;
+
+/// The attributes of a file or directory.
+struct Attributes {
+    size: u64,
+    /// The type and permissions, as in `st_mode`.
+    permissions: u32,
+    /// The last modification (and access) time, as a Unix time.
+    modified: u64,
+}
+impl Attributes {
+    /// Gets the attributes of an entry in the filesystem, with directories being as new as the newest file in them.
+    fn of(content: &SshContent, entry: &Entry) -> Self {
+        match entry {
+            Entry::File(file) => Self {
+                size: file.size,
+                permissions: 0o100444,
+                modified: file.modified,
+            },
+            Entry::Directory(dir) => Self {
+                size: 0,
+                permissions: 0o40555,
+                modified: content
+                    .get(*dir)
+                    .files
+                    .values()
+                    .map(|file| file.modified)
+                    .max()
+                    .unwrap_or(0),
+            },
+        }
+    }
+
+    /// Encodes the attributes for a packet.
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_u32(out, ATTR_SIZE | ATTR_PERMISSIONS | ATTR_ACMODTIME);
+        out.extend(self.size.to_be_bytes());
+        put_u32(out, self.permissions);
+        put_u32(out, self.modified as u32);
+        put_u32(out, self.modified as u32);
+    }
+
+    /// Formats a line for a listing of the given file, like `ls -l` (which clients show as it is).
+    fn long_name(&self, name: &str) -> String {
+        let kind = if self.permissions & 0o40000 != 0 {
+            'd'
+        } else {
+            '-'
+        };
+        let permissions: String = (0..9)
+            .map(|i| match self.permissions >> (8 - i) & 1 {
+                0 => '-',
+                _ => ['r', 'w', 'x'][i % 3],
+            })
+            .collect();
+        let date = chrono::DateTime::from_timestamp(self.modified as i64, 0)
+            .unwrap_or_default()
+            .format("%b %e %H:%M");
+        format!(
+            "{kind}{permissions}    1 {OWNER:<8} {OWNER:<8} {:>8} {date} {name}",
+            self.size
+        )
+    }
+}
+
+/// Something the client has open.
+enum Handle {
+    /// A file, with its path (which it's read from, a piece at a time, as the client asks) and attributes.
+    File(String, Attributes),
+    /// A directory, with its entries (as names and attributes) if they haven't been read yet.
+    Directory(Option<Vec<(String, Attributes)>>),
+}
+
+/// An error in handling a request, sent back as a status.
+struct Error(u32, &'static str);
+
+/// The state of an SFTP session.
+pub struct Sftp {
+    content: Arc<SshContent>,
+    /// Data from the client that isn't a whole packet yet.
+    input: Vec<u8>,
+    /// The files and directories the client has open, by handle.
+    handles: HashMap<u32, Handle>,
+    /// The handle for the next file or directory opened.
+    next_handle: u32,
+}
+impl Sftp {
+    pub fn new(content: Arc<SshContent>) -> Self {
+        Self {
+            content,
+            input: vec![],
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// Processes data from the client, returning the responses to any packets it completes, along with an exit status if the session
+    /// can't continue.
+    pub async fn data(&mut self, data: &[u8]) -> (Vec<u8>, Option<u32>) {
+        self.input.extend(data);
+        let mut response = vec![];
+        while self.input.len() >= 4 {
+            let length =
+                u32::from_be_bytes([self.input[0], self.input[1], self.input[2], self.input[3]])
+                    as usize;
+            if length > MAX_PACKET_SIZE {
+                // Too big to be anything we'd accept (and without knowing the request ID, we can't say which request failed). We can't
+                // skip it without reading it all, so there's no way to find the next packet, so give up.
+                response.extend(status_packet(0, status::BAD_MESSAGE, "packet too large"));
+                return (response, Some(1));
+            }
+            if self.input.len() < 4 + length {
+                break;
+            }
+            let request: Vec<u8> = self.input.drain(..4 + length).skip(4).collect();
+            response.extend(self.handle_packet(&request).await);
+        }
+        (response, None)
+    }
+
+    /// Handles a packet, returning the response.
+    async fn handle_packet(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut reader = Reader(data);
+        let Some(kind) = reader.u8() else {
+            return vec![];
+        };
+        if kind == packet::INIT {
+            // No extensions, and we don't need to know the client's version (as all support 3)
+            let mut body = vec![];
+            put_u32(&mut body, VERSION);
+            return make_packet(packet::VERSION, &body);
+        }
+        let Some(id) = reader.u32() else {
+            return status_packet(0, status::BAD_MESSAGE, "missing request ID");
+        };
+        match self.request(kind, id, &mut reader).await {
+            Ok(response) => response,
+            Err(Error(code, message)) => status_packet(id, code, message),
+        }
+    }
+
+    /// Handles a request (any packet but `INIT`), returning the response.
+    async fn request(
+        &mut self,
+        kind: u8,
+        id: u32,
+        reader: &mut Reader<'_>,
+    ) -> Result<Vec<u8>, Error> {
+        const BAD_MESSAGE: Error = Error(status::BAD_MESSAGE, "malformed request");
+        match kind {
+            packet::OPEN => {
+                let path = reader.string().ok_or(BAD_MESSAGE)?;
+                let flags = reader.u32().ok_or(BAD_MESSAGE)?;
+                if flags & !OPEN_READ != 0 {
+                    return Err(Error(status::PERMISSION_DENIED, "read-only filesystem"));
+                }
+                let Entry::File(file) = self.resolve(&path)? else {
+                    return Err(Error(status::FAILURE, "is a directory"));
+                };
+                let attributes = Attributes::of(&self.content, &Entry::File(file));
+                self.open(id, Handle::File(path, attributes))
+            }
+            packet::OPENDIR => {
+                let path = reader.string().ok_or(BAD_MESSAGE)?;
+                let Entry::Directory(dir) = self.resolve(&path)? else {
+                    return Err(Error(status::FAILURE, "not a directory"));
+                };
+                let directory = self.content.get(dir);
+                let parent = Entry::Directory(directory.parent.unwrap_or(dir));
+                let mut entries = vec![
+                    (
+                        ".".to_string(),
+                        Attributes::of(&self.content, &Entry::Directory(dir)),
+                    ),
+                    ("..".to_string(), Attributes::of(&self.content, &parent)),
+                ];
+                for (name, &child) in directory.directories.iter() {
+                    entries.push((
+                        name.clone(),
+                        Attributes::of(&self.content, &Entry::Directory(child)),
+                    ));
+                }
+                for (name, file) in directory.files.iter() {
+                    entries.push((
+                        name.clone(),
+                        Attributes::of(&self.content, &Entry::File(file)),
+                    ));
+                }
+                self.open(id, Handle::Directory(Some(entries)))
+            }
+            packet::CLOSE => {
+                let handle = reader.handle().ok_or(BAD_MESSAGE)?;
+                self.handles.remove(&handle).ok_or(INVALID_HANDLE)?;
+                Ok(status_packet(id, status::OK, ""))
+            }
+            packet::READ => {
+                let handle = reader.handle().ok_or(BAD_MESSAGE)?;
+                let offset = reader.u64().ok_or(BAD_MESSAGE)?;
+                let length = reader.u32().ok_or(BAD_MESSAGE)?.min(MAX_READ_SIZE);
+                let Some(Handle::File(path, attributes)) = self.handles.get(&handle) else {
+                    return Err(INVALID_HANDLE);
+                };
+                if offset >= attributes.size {
+                    return Err(Error(status::EOF, "end of file"));
+                }
+                let Entry::File(file) = self.resolve(path)? else {
+                    return Err(Error(status::FAILURE, "is a directory"));
+                };
+                let data = file
+                    .read_at(offset, length as usize)
+                    .await
+                    .map_err(|_| Error(status::FAILURE, "couldn't read file"))?;
+                let mut body = vec![];
+                put_u32(&mut body, id);
+                put_string(&mut body, &data);
+                Ok(make_packet(packet::DATA, &body))
+            }
+            packet::READDIR => {
+                let handle = reader.handle().ok_or(BAD_MESSAGE)?;
+                let Some(Handle::Directory(entries)) = self.handles.get_mut(&handle) else {
+                    return Err(INVALID_HANDLE);
+                };
+                // Everything is sent the first time, so the next read is the end
+                let entries = entries
+                    .take()
+                    .ok_or(Error(status::EOF, "end of directory"))?;
+                let mut body = vec![];
+                put_u32(&mut body, id);
+                put_u32(&mut body, entries.len() as u32);
+                for (name, attributes) in entries {
+                    put_string(&mut body, name.as_bytes());
+                    put_string(&mut body, attributes.long_name(&name).as_bytes());
+                    attributes.encode(&mut body);
+                }
+                Ok(make_packet(packet::NAME, &body))
+            }
+            packet::STAT | packet::LSTAT => {
+                let path = reader.string().ok_or(BAD_MESSAGE)?;
+                let entry = self.resolve(&path)?;
+                Ok(attrs_packet(id, &Attributes::of(&self.content, &entry)))
+            }
+            packet::FSTAT => {
+                let handle = reader.handle().ok_or(BAD_MESSAGE)?;
+                match self.handles.get(&handle).ok_or(INVALID_HANDLE)? {
+                    Handle::File(_, attributes) => Ok(attrs_packet(id, attributes)),
+                    Handle::Directory(_) => Err(Error(
+                        status::OP_UNSUPPORTED,
+                        "can't stat a directory handle",
+                    )),
+                }
+            }
+            packet::REALPATH => {
+                // Everything is relative to the root, which is where clients start
+                let path = reader.string().ok_or(BAD_MESSAGE)?;
+                let mut parts = vec![];
+                for part in path.split('/') {
+                    match part {
+                        "" | "." => {}
+                        ".." => {
+                            parts.pop();
+                        }
+                        part => parts.push(part),
+                    }
+                }
+                let path = format!("/{}", parts.join("/"));
+                let mut body = vec![];
+                put_u32(&mut body, id);
+                put_u32(&mut body, 1);
+                put_string(&mut body, path.as_bytes());
+                put_string(&mut body, path.as_bytes());
+                // Attributes are ignored here, so none are sent
+                put_u32(&mut body, 0);
+                Ok(make_packet(packet::NAME, &body))
+            }
+            packet::WRITE
+            | packet::SETSTAT
+            | packet::FSETSTAT
+            | packet::REMOVE
+            | packet::MKDIR
+            | packet::RMDIR
+            | packet::RENAME
+            | packet::SYMLINK => Err(Error(status::PERMISSION_DENIED, "read-only filesystem")),
+            _ => Err(Error(status::OP_UNSUPPORTED, "unsupported request")),
+        }
+    }
+
+    /// Resolves a path (relative to the root) to an entry in the filesystem.
+    fn resolve(&self, path: &str) -> Result<Entry<'_>, Error> {
+        let context = Context {
+            content: &self.content,
+            current_dir: 0,
+            term_size: (0, 0),
+            pixel_size: (0, 0),
+            capabilities: Capabilities::default(),
+        };
+        context
+            .resolve(path)
+            .ok_or(Error(status::NO_SUCH_FILE, "no such file or directory"))
+    }
+
+    /// Opens a handle, returning the response with it.
+    fn open(&mut self, id: u32, handle: Handle) -> Result<Vec<u8>, Error> {
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(Error(status::FAILURE, "too many open files"));
+        }
+        let number = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(number, handle);
+        let mut body = vec![];
+        put_u32(&mut body, id);
+        put_string(&mut body, number.to_string().as_bytes());
+        Ok(make_packet(packet::HANDLE, &body))
+    }
+}
+
+/// The error for a handle the client doesn't have open.
+const INVALID_HANDLE: Error = Error(status::FAILURE, "invalid handle");
+
+/// Reads the fields of a packet, in order.
+struct Reader<'a>(&'a [u8]);
+impl Reader<'_> {
+    fn bytes(&mut self, length: usize) -> Option<&[u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(bytes)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|bytes| u64::from_be_bytes(bytes.try_into().expect("8 bytes")))
+    }
+    /// Reads a string, replacing any invalid UTF-8.
+    fn string(&mut self) -> Option<String> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+    /// Reads a handle, as sent back to the client by `Sftp::open`.
+    fn handle(&mut self) -> Option<u32> {
+        self.string()?.parse().ok()
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_be_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, value: &[u8]) {
+    put_u32(out, value.len() as u32);
+    out.extend(value);
+}
+
+/// Makes a packet of the given type, with its length at the start.
+fn make_packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![];
+    put_u32(&mut packet, body.len() as u32 + 1);
+    packet.push(kind);
+    packet.extend(body);
+    packet
+}
+
+/// Makes a `STATUS` packet in response to a request.
+fn status_packet(id: u32, code: u32, message: &str) -> Vec<u8> {
+    let mut body = vec![];
+    put_u32(&mut body, id);
+    put_u32(&mut body, code);
+    put_string(&mut body, message.as_bytes());
+    put_string(&mut body, b"en");
+    make_packet(packet::STATUS, &body)
+}
+
+/// Makes an `ATTRS` packet in response to a request.
+fn attrs_packet(id: u32, attributes: &Attributes) -> Vec<u8> {
+    let mut body = vec![];
+    put_u32(&mut body, id);
+    attributes.encode(&mut body);
+    make_packet(packet::ATTRS, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::{
+        content::{Directory, File},
+        style::StyledText,
+    };
+
+    const TEXT: &str = "Hello, world!\n";
+    /// A file bigger than `MAX_READ_SIZE`.
+    const BIG_SIZE: usize = 100_000;
+
+    /// Makes a session over a filesystem with a small and a big file.
+    fn session() -> Sftp {
+        let file = |text: &str| {
+            let mut document = StyledText::default();
+            document.text(text);
+            File::new(document)
+        };
+        let root = Directory {
+            path: "/".to_string(),
+            files: [
+                ("hello.txt".to_string(), file(TEXT)),
+                ("big.txt".to_string(), file(&"a".repeat(BIG_SIZE - 1))),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        Sftp::new(Arc::new(SshContent {
+            directories: vec![root],
+            menu: vec![],
+        }))
+    }
+
+    /// Sends a request, returning the response and exit status.
+    async fn send(sftp: &mut Sftp, kind: u8, body: &[u8]) -> (Vec<u8>, Option<u32>) {
+        sftp.data(&make_packet(kind, body)).await
+    }
+
+    /// Builds a request body from a request ID and strings.
+    fn body(id: u32, strings: &[&str]) -> Vec<u8> {
+        let mut body = vec![];
+        put_u32(&mut body, id);
+        for string in strings {
+            put_string(&mut body, string.as_bytes());
+        }
+        body
+    }
+
+    /// Splits a response into its type and body, checking its length.
+    fn parse(response: &[u8]) -> (u8, &[u8]) {
+        let mut reader = Reader(response);
+        let length = reader.u32().expect("length") as usize;
+        assert_eq!(reader.0.len(), length, "one whole packet");
+        (reader.u8().expect("type"), reader.0)
+    }
+
+    /// Gets the request ID and code of a `STATUS` response.
+    fn status(response: &[u8]) -> (u32, u32) {
+        let (kind, body) = parse(response);
+        assert_eq!(kind, packet::STATUS);
+        let mut reader = Reader(body);
+        (reader.u32().unwrap(), reader.u32().unwrap())
+    }
+
+    /// Opens a file, returning its handle.
+    async fn open(sftp: &mut Sftp, path: &str) -> String {
+        let mut request = body(1, &[path]);
+        put_u32(&mut request, OPEN_READ);
+        let (response, _) = send(sftp, packet::OPEN, &request).await;
+        let (kind, body) = parse(&response);
+        assert_eq!(kind, packet::HANDLE);
+        let mut reader = Reader(body);
+        assert_eq!(reader.u32(), Some(1));
+        reader.string().unwrap()
+    }
+
+    /// Reads from an open file, returning the response.
+    async fn read(sftp: &mut Sftp, handle: &str, offset: u64, length: u32) -> Vec<u8> {
+        let mut request = body(2, &[handle]);
+        request.extend(offset.to_be_bytes());
+        put_u32(&mut request, length);
+        send(sftp, packet::READ, &request).await.0
+    }
+
+    /// Gets the data of a `DATA` response.
+    fn data(response: &[u8]) -> Vec<u8> {
+        let (kind, body) = parse(response);
+        assert_eq!(kind, packet::DATA);
+        let mut reader = Reader(body);
+        assert_eq!(reader.u32(), Some(2));
+        let length = reader.u32().unwrap() as usize;
+        reader.bytes(length).unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn packets_can_be_split_and_joined() {
+        let mut sftp = session();
+        let mut init = vec![];
+        put_u32(&mut init, VERSION);
+        let init = make_packet(packet::INIT, &init);
+
+        // Nothing is sent until a packet is complete, even if its length is
+        let (response, exit) = sftp.data(&init[..2]).await;
+        assert!(response.is_empty() && exit.is_none());
+        let (response, exit) = sftp.data(&init[2..6]).await;
+        assert!(response.is_empty() && exit.is_none());
+        let (response, exit) = sftp.data(&init[6..]).await;
+        assert_eq!(parse(&response).0, packet::VERSION);
+        assert!(exit.is_none());
+
+        // Two packets at once get two responses
+        let realpath = make_packet(packet::REALPATH, &body(3, &["."]));
+        let (response, _) = sftp.data(&[realpath.clone(), realpath].concat()).await;
+        let (first, second) = response.split_at(response.len() / 2);
+        assert_eq!(first, second);
+        assert_eq!(parse(first).0, packet::NAME);
+    }
+
+    #[tokio::test]
+    async fn packets_up_to_the_maximum_size_are_accepted() {
+        let mut sftp = session();
+        let mut request = vec![];
+        put_u32(&mut request, MAX_PACKET_SIZE as u32);
+        request.push(packet::REALPATH);
+        request.extend(body(1, &[&"a".repeat(MAX_PACKET_SIZE - 9)]));
+        assert_eq!(request.len(), 4 + MAX_PACKET_SIZE);
+
+        let (response, exit) = sftp.data(&request[..4096]).await;
+        assert!(response.is_empty() && exit.is_none());
+        let (response, exit) = sftp.data(&request[4096..]).await;
+        assert_eq!(parse(&response).0, packet::NAME);
+        assert!(exit.is_none());
+    }
+
+    #[tokio::test]
+    async fn too_large_packets_end_the_session() {
+        let mut sftp = session();
+        let (response, exit) = sftp.data(&(MAX_PACKET_SIZE as u32 + 1).to_be_bytes()).await;
+        assert_eq!(status(&response), (0, status::BAD_MESSAGE));
+        assert_eq!(exit, Some(1));
+
+        let (response, exit) = session().data(&u32::MAX.to_be_bytes()).await;
+        assert_eq!(status(&response), (0, status::BAD_MESSAGE));
+        assert_eq!(exit, Some(1));
+    }
+
+    #[tokio::test]
+    async fn truncated_requests_are_bad_messages() {
+        let mut sftp = session();
+
+        // An empty packet has no type to respond to
+        let (response, exit) = sftp.data(&0u32.to_be_bytes()).await;
+        assert!(response.is_empty() && exit.is_none());
+
+        // No (or part of a) request ID
+        let (response, _) = send(&mut sftp, packet::STAT, &[]).await;
+        assert_eq!(status(&response), (0, status::BAD_MESSAGE));
+        let (response, _) = send(&mut sftp, packet::STAT, &[0, 0, 1]).await;
+        assert_eq!(status(&response), (0, status::BAD_MESSAGE));
+
+        // A string longer than the packet
+        let mut request = body(5, &[]);
+        put_u32(&mut request, 100);
+        request.extend(b"hello.txt");
+        let (response, _) = send(&mut sftp, packet::STAT, &request).await;
+        assert_eq!(status(&response), (5, status::BAD_MESSAGE));
+
+        // Missing fields after a string
+        let (response, _) = send(&mut sftp, packet::OPEN, &body(6, &["hello.txt"])).await;
+        assert_eq!(status(&response), (6, status::BAD_MESSAGE));
+        let handle = open(&mut sftp, "hello.txt").await;
+        let mut request = body(7, &[&handle]);
+        request.extend([0; 4]);
+        let (response, _) = send(&mut sftp, packet::READ, &request).await;
+        assert_eq!(status(&response), (7, status::BAD_MESSAGE));
+
+        // The session carries on afterwards
+        assert_eq!(
+            data(&read(&mut sftp, &handle, 0, 100).await),
+            TEXT.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_stop_at_the_end_of_files() {
+        let mut sftp = session();
+        let handle = open(&mut sftp, "/hello.txt").await;
+        assert_eq!(
+            data(&read(&mut sftp, &handle, 0, 100).await),
+            TEXT.as_bytes()
+        );
+        assert_eq!(data(&read(&mut sftp, &handle, 7, 5).await), b"world");
+        assert_eq!(data(&read(&mut sftp, &handle, 7, 100).await), b"world!\n");
+        for offset in [TEXT.len() as u64, TEXT.len() as u64 + 1, u64::MAX] {
+            let response = read(&mut sftp, &handle, offset, 100).await;
+            assert_eq!(status(&response), (2, status::EOF));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_are_limited_in_size() {
+        let mut sftp = session();
+        let handle = open(&mut sftp, "big.txt").await;
+        let first = data(&read(&mut sftp, &handle, 0, u32::MAX).await);
+        assert_eq!(first.len(), MAX_READ_SIZE as usize);
+        let rest = data(&read(&mut sftp, &handle, first.len() as u64, u32::MAX).await);
+        assert_eq!(first.len() + rest.len(), BIG_SIZE);
+    }
+
+    #[tokio::test]
+    async fn handles_are_checked_and_limited() {
+        let mut sftp = session();
+        let response = read(&mut sftp, "0", 0, 100).await;
+        assert_eq!(status(&response), (2, status::FAILURE));
+        let response = read(&mut sftp, "not a handle", 0, 100).await;
+        assert_eq!(status(&response), (2, status::BAD_MESSAGE));
+
+        let mut handles = vec![];
+        for _ in 0..MAX_HANDLES {
+            handles.push(open(&mut sftp, "hello.txt").await);
+        }
+        let mut request = body(3, &["hello.txt"]);
+        put_u32(&mut request, OPEN_READ);
+        let (response, _) = send(&mut sftp, packet::OPEN, &request).await;
+        assert_eq!(status(&response), (3, status::FAILURE));
+
+        // Closing one makes room for another, and closed handles can't be used
+        let (response, _) = send(&mut sftp, packet::CLOSE, &body(4, &[&handles[0]])).await;
+        assert_eq!(status(&response), (4, status::OK));
+        let response = read(&mut sftp, &handles[0], 0, 100).await;
+        assert_eq!(status(&response), (2, status::FAILURE));
+        open(&mut sftp, "hello.txt").await;
+    }
+
+    #[tokio::test]
+    async fn writes_are_refused() {
+        let mut sftp = session();
+        let mut request = body(1, &["hello.txt"]);
+        put_u32(&mut request, OPEN_READ | 0x2);
+        let (response, _) = send(&mut sftp, packet::OPEN, &request).await;
+        assert_eq!(status(&response), (1, status::PERMISSION_DENIED));
+        let (response, _) = send(&mut sftp, packet::REMOVE, &body(2, &["hello.txt"])).await;
+        assert_eq!(status(&response), (2, status::PERMISSION_DENIED));
+    }
+}
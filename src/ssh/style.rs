@@ -0,0 +1,639 @@
+//! Styled text for the terminal: bold, italic and underlined text, colours, OSC 8 hyperlinks and syntax-highlighted code, rendered
+//! according to what the client's terminal supports.
+//!
+//! Text is built up as lines of `Span`s, each with a `Style` and possibly a link. Some spans only exist to stand in for styling or
+//! links the terminal can't show (e.g. the `*`s around strong text, or the URL after a link), so the same text renders as it always
+//! has on terminals without styling, and without the markers on terminals with it.
+
+use once_cell::sync::Lazy;
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{FontStyle, Theme, ThemeSet},
+    parsing::SyntaxSet,
+};
+
+use super::text;
+
+/// The syntaxes code blocks can be highlighted with.
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+/// The theme code blocks are highlighted with. Its default foreground is left as the terminal's own, so code is still readable on
+/// light backgrounds.
+static THEME: Lazy<Theme> = Lazy::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("base16-ocean.dark")
+        .expect("base16-ocean.dark is a default theme")
+});
+
+/// The colours a terminal supports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colors {
+    #[default]
+    None,
+    /// The 16 standard ANSI colours.
+    Basic,
+    /// The 256-colour xterm palette.
+    Extended,
+    /// 24-bit RGB colour.
+    True,
+}
+impl Colors {
+    /// The names colour modes can be chosen by with the `colors` command (and are saved as), in the same order as the variants.
+    pub const NAMES: &'static [&'static str] = &["none", "16", "256", "true"];
+
+    /// The name of the mode, as in `NAMES`.
+    pub fn name(self) -> &'static str {
+        Self::NAMES[self as usize]
+    }
+    /// Parses a mode from its name in `NAMES`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "16" => Some(Self::Basic),
+            "256" => Some(Self::Extended),
+            "true" => Some(Self::True),
+            _ => None,
+        }
+    }
+}
+
+/// The protocol a terminal supports for showing images (as pixels, rather than characters).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Graphics {
+    /// None, so images are drawn with characters.
+    #[default]
+    None,
+    Sixel,
+    /// The kitty graphics protocol.
+    Kitty,
+    /// iTerm2's inline images protocol.
+    Iterm,
+}
+
+/// What a terminal supports, as detected from its `TERM` (from the pty request) and any environment variables identifying it. The
+/// default is a terminal supporting nothing, which gets plain text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether bold, italic, underlined etc. text is supported.
+    pub styles: bool,
+    pub colors: Colors,
+    /// Whether OSC 8 hyperlinks are supported (or at least safely ignored).
+    pub hyperlinks: bool,
+    pub graphics: Graphics,
+}
+impl Capabilities {
+    /// Guesses the capabilities of a terminal from its `TERM`.
+    pub fn from_term(term: &str) -> Self {
+        let term = term.to_ascii_lowercase();
+        if term.is_empty() || term == "dumb" || term == "unknown" {
+            return Self::default();
+        }
+        let colors = if [
+            "truecolor",
+            "24bit",
+            "direct",
+            "kitty",
+            "alacritty",
+            "wezterm",
+            "foot",
+        ]
+        .iter()
+        .any(|name| term.contains(name))
+        {
+            Colors::True
+        } else if term.contains("256color") {
+            Colors::Extended
+        } else if term.starts_with("vt") || term.ends_with("-m") || term.contains("mono") {
+            Colors::None
+        } else {
+            Colors::Basic
+        };
+        // Only terminals known to support (or ignore) OSC 8, as others (e.g. the Linux console) print it
+        let hyperlinks = [
+            "xterm",
+            "tmux",
+            "kitty",
+            "alacritty",
+            "wezterm",
+            "foot",
+            "contour",
+        ]
+        .iter()
+        .any(|name| term.starts_with(name));
+        let graphics = if term.contains("kitty") {
+            Graphics::Kitty
+        } else if term.contains("wezterm") {
+            Graphics::Iterm
+        } else if ["foot", "mlterm", "yaft", "contour"]
+            .iter()
+            .any(|name| term.starts_with(name))
+        {
+            Graphics::Sixel
+        } else {
+            Graphics::None
+        };
+        Self {
+            styles: true,
+            colors,
+            hyperlinks,
+            graphics,
+        }
+    }
+
+    /// Updates the capabilities from an environment variable sent by the client, for terminals that can't be told apart by `TERM`
+    /// alone (e.g. iTerm2, which uses `xterm-256color`).
+    pub fn update_from_env(&mut self, name: &str, value: &str) {
+        if !self.styles || !matches!(name, "LC_TERMINAL" | "TERM_PROGRAM") {
+            return;
+        }
+        match value {
+            "iTerm2" | "iTerm.app" | "WezTerm" => self.graphics = Graphics::Iterm,
+            "ghostty" => self.graphics = Graphics::Kitty,
+            _ => {}
+        }
+    }
+}
+
+/// A colour, as one of the 16 standard ANSI colours (0-15) or RGB (approximated on terminals without true colour).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Ansi(u8),
+    Rgb(u8, u8, u8),
+}
+impl Color {
+    /// The SGR parameters for this as a foreground (or background) colour, if the terminal supports colour.
+    pub fn sgr(self, colors: Colors, background: bool) -> Option<String> {
+        let offset = if background { 10 } else { 0 };
+        Some(match (self, colors) {
+            (_, Colors::None) => return None,
+            (Color::Ansi(n @ 0..=7), _) => format!("{}", 30 + offset + n),
+            (Color::Ansi(n), _) => format!("{}", 90 + offset + (n - 8) % 8),
+            (Color::Rgb(r, g, b), Colors::True) => format!("{};2;{r};{g};{b}", 38 + offset),
+            (Color::Rgb(r, g, b), Colors::Extended) => {
+                // The nearest colour in the 6x6x6 cube
+                let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+                format!(
+                    "{};5;{}",
+                    38 + offset,
+                    16 + 36 * level(r) + 6 * level(g) + level(b)
+                )
+            }
+            (Color::Rgb(r, g, b), Colors::Basic) => {
+                // The nearest of the standard colours, as xterm shows them
+                const PALETTE: [(u8, u8, u8); 16] = [
+                    (0, 0, 0),
+                    (205, 0, 0),
+                    (0, 205, 0),
+                    (205, 205, 0),
+                    (0, 0, 238),
+                    (205, 0, 205),
+                    (0, 205, 205),
+                    (229, 229, 229),
+                    (127, 127, 127),
+                    (255, 0, 0),
+                    (0, 255, 0),
+                    (255, 255, 0),
+                    (92, 92, 255),
+                    (255, 0, 255),
+                    (0, 255, 255),
+                    (255, 255, 255),
+                ];
+                let distance = |&(_, &(pr, pg, pb)): &(usize, &(u8, u8, u8))| {
+                    [(r, pr), (g, pg), (b, pb)]
+                        .iter()
+                        .map(|&(a, b)| (a as i32 - b as i32).pow(2))
+                        .sum::<i32>()
+                };
+                let (n, _) = PALETTE
+                    .iter()
+                    .enumerate()
+                    .min_by_key(distance)
+                    .expect("palette is nonempty");
+                return Color::Ansi(n as u8).sgr(colors, background);
+            }
+        })
+    }
+}
+
+/// The style of some text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    /// Reverse video, used by the pager to highlight search matches, so shown even without support for other styles (like the
+    /// pager's status line).
+    reverse: bool,
+    color: Option<Color>,
+}
+impl Style {
+    pub fn bold(self) -> Self {
+        Self { bold: true, ..self }
+    }
+    pub fn dim(self) -> Self {
+        Self { dim: true, ..self }
+    }
+    pub fn italic(self) -> Self {
+        Self {
+            italic: true,
+            ..self
+        }
+    }
+    pub fn underline(self) -> Self {
+        Self {
+            underline: true,
+            ..self
+        }
+    }
+    pub fn reverse(self) -> Self {
+        Self {
+            reverse: true,
+            ..self
+        }
+    }
+    pub fn color(self, color: Color) -> Self {
+        Self {
+            color: Some(color),
+            ..self
+        }
+    }
+
+    /// Combines this style with another nested inside it, whose colour (if any) takes precedence.
+    fn merge(self, inner: Self) -> Self {
+        Self {
+            bold: self.bold || inner.bold,
+            dim: self.dim || inner.dim,
+            italic: self.italic || inner.italic,
+            underline: self.underline || inner.underline,
+            reverse: self.reverse || inner.reverse,
+            color: inner.color.or(self.color),
+        }
+    }
+
+    /// This style, without anything the terminal doesn't support.
+    fn supported(self, capabilities: Capabilities) -> Self {
+        if !capabilities.styles {
+            return Self {
+                reverse: self.reverse,
+                ..Self::default()
+            };
+        }
+        Self {
+            color: self.color.filter(|_| capabilities.colors != Colors::None),
+            ..self
+        }
+    }
+
+    /// The escape sequence switching to this style (from any other).
+    fn sgr(self, colors: Colors) -> String {
+        let mut params = vec!["0".to_string()];
+        for (enabled, param) in [
+            (self.bold, "1"),
+            (self.dim, "2"),
+            (self.italic, "3"),
+            (self.underline, "4"),
+            (self.reverse, "7"),
+        ] {
+            if enabled {
+                params.push(param.to_string());
+            }
+        }
+        params.extend(self.color.and_then(|color| color.sgr(colors, false)));
+        format!("\x1b[{}m", params.join(";"))
+    }
+}
+
+/// When a span is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Show {
+    #[default]
+    Always,
+    /// Only on terminals without styling, standing in for it (e.g. the `_`s around emphasised text).
+    Unstyled,
+    /// Only on terminals without hyperlinks, standing in for them (e.g. the URL after a link's text).
+    Unlinked,
+}
+
+/// A piece of text in a single style, possibly linking somewhere.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+    pub link: Option<String>,
+    /// The image (as its path in the content's images) this text stands for, if any.
+    pub image: Option<String>,
+    pub show: Show,
+}
+
+/// A line of styled text.
+pub type Line = Vec<Span>;
+
+/// Some styled text, built up a piece at a time with the current style and link applying to everything added.
+#[derive(Clone, Debug)]
+pub struct StyledText {
+    /// The lines of the text. There is always at least one (possibly-empty) line.
+    lines: Vec<Line>,
+    /// The style of text being added.
+    style: Style,
+    /// Where text being added links to, if anywhere.
+    link: Option<String>,
+    /// The image text being added stands for, if any.
+    image: Option<String>,
+}
+impl Default for StyledText {
+    fn default() -> Self {
+        Self {
+            lines: vec![vec![]],
+            style: Style::default(),
+            link: None,
+            image: None,
+        }
+    }
+}
+impl StyledText {
+    /// Adds text in the current style, starting new lines at any `\n`s.
+    pub fn text(&mut self, text: &str) {
+        self.push(text, Show::Always);
+    }
+
+    /// Adds text standing in for styling, shown only on terminals without it.
+    pub fn marker(&mut self, text: &str) {
+        self.push(text, Show::Unstyled);
+    }
+
+    /// Starts a new line.
+    pub fn newline(&mut self) {
+        self.lines.push(vec![]);
+    }
+
+    /// Adds whatever `f` adds in the given style (on top of the current one).
+    pub fn styled(&mut self, style: Style, f: impl FnOnce(&mut Self)) {
+        let outer = self.style;
+        self.style = outer.merge(style);
+        f(self);
+        self.style = outer;
+    }
+
+    /// Adds a heading of the given level, marked with `#`s, with the text `f` adds.
+    pub fn heading(&mut self, level: usize, f: impl FnOnce(&mut Self)) {
+        let color = match level {
+            1 => Color::Ansi(5),
+            2 => Color::Ansi(4),
+            _ => Color::Ansi(6),
+        };
+        self.styled(Style::default().bold().color(color), |text| {
+            text.text(&format!("{} ", "#".repeat(level)));
+            f(text);
+        });
+    }
+
+    /// Adds a link to `url` with the text `f` adds, shown as `[text](href)` on terminals without hyperlinks (where `href` is the link
+    /// as written, which may be relative).
+    pub fn link(&mut self, href: &str, url: &str, f: impl FnOnce(&mut Self)) {
+        self.push("[", Show::Unlinked);
+        self.linked(url, f);
+        self.push(&format!("]({href})"), Show::Unlinked);
+    }
+
+    /// Adds emphasised text with the text `f` adds, marked with `_`s on terminals without styling.
+    pub fn emph(&mut self, f: impl FnOnce(&mut Self)) {
+        self.marker("_");
+        self.styled(Style::default().italic(), f);
+        self.marker("_");
+    }
+
+    /// Adds strong text with the text `f` adds, marked with `*`s on terminals without styling.
+    pub fn strong(&mut self, f: impl FnOnce(&mut Self)) {
+        self.marker("*");
+        self.styled(Style::default().bold(), f);
+        self.marker("*");
+    }
+
+    /// Adds inline code, marked with backticks on terminals without styling.
+    pub fn inline_code(&mut self, code: &str) {
+        self.marker("`");
+        self.styled(Style::default().color(Color::Ansi(6)), |text| {
+            text.text(code)
+        });
+        self.marker("`");
+    }
+
+    /// Adds the text `f` adds linking to `href`, which the text should already show (e.g. the URL itself).
+    pub fn linked(&mut self, href: &str, f: impl FnOnce(&mut Self)) {
+        let outer = self.link.replace(href.to_string());
+        self.styled(Style::default().underline(), f);
+        self.link = outer;
+    }
+
+    /// Adds the text `f` adds as standing for an image (given by its path in the content's images), which can be viewed from the
+    /// pager.
+    pub fn image(&mut self, src: &str, f: impl FnOnce(&mut Self)) {
+        let outer = self.image.replace(src.to_string());
+        f(self);
+        self.image = outer;
+    }
+
+    /// Adds a block of code, highlighted as the given language (if it's one we know).
+    pub fn code(&mut self, language: Option<&str>, code: &str) {
+        let syntax = language
+            .and_then(|language| SYNTAXES.find_syntax_by_token(language))
+            .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+        let mut highlighter = HighlightLines::new(syntax, &THEME);
+        for (i, line) in code.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+            // The syntaxes expect lines to end in newlines
+            let line = format!("{line}\n");
+            let Ok(regions) = highlighter.highlight_line(&line, &SYNTAXES) else {
+                self.text(line.trim_end_matches('\n'));
+                continue;
+            };
+            for (highlight, region) in regions {
+                let mut style = Style::default();
+                let foreground = highlight.foreground;
+                if Some(foreground) != THEME.settings.foreground {
+                    style = style.color(Color::Rgb(foreground.r, foreground.g, foreground.b));
+                }
+                if highlight.font_style.contains(FontStyle::BOLD) {
+                    style = style.bold();
+                }
+                if highlight.font_style.contains(FontStyle::ITALIC) {
+                    style = style.italic();
+                }
+                if highlight.font_style.contains(FontStyle::UNDERLINE) {
+                    style = style.underline();
+                }
+                self.styled(style, |text| text.text(region.trim_end_matches('\n')));
+            }
+        }
+    }
+
+    /// The text as shown on a terminal without styling, with `\n` newlines.
+    pub fn plain(&self) -> String {
+        self.lines(Capabilities::default())
+            .iter()
+            .map(|line| self::text(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The lines of the text as shown on a terminal with the given capabilities.
+    pub fn lines(&self, capabilities: Capabilities) -> Vec<Line> {
+        self.lines
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .filter(|span| match span.show {
+                        Show::Always => true,
+                        Show::Unstyled => !capabilities.styles,
+                        Show::Unlinked => !capabilities.hyperlinks,
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Adds text in the current style and link, shown as given.
+    fn push(&mut self, text: &str, show: Show) {
+        for (i, text) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.newline();
+            }
+            if !text.is_empty() {
+                self.lines
+                    .last_mut()
+                    .expect("there is always a line")
+                    .push(Span {
+                        text: text.to_string(),
+                        style: self.style,
+                        link: self.link.clone(),
+                        image: self.image.clone(),
+                        show,
+                    });
+            }
+        }
+    }
+}
+
+/// A line of unstyled text.
+pub fn plain(text: &str) -> Line {
+    vec![Span {
+        text: text.to_string(),
+        ..Default::default()
+    }]
+}
+
+/// The text of a line, without its styling.
+pub fn text(line: &[Span]) -> String {
+    line.iter().map(|span| span.text.as_str()).collect()
+}
+
+/// Makes a line safe to lay out, as `text::sanitize` does for unstyled text.
+pub fn sanitize(line: &[Span]) -> Line {
+    line.iter()
+        .map(|span| Span {
+            text: text::sanitize(&span.text),
+            ..span.clone()
+        })
+        .filter(|span| !span.text.is_empty())
+        .collect()
+}
+
+/// Splits a line into rows at most `width` columns wide, as laid out by `text::layout`. Always returns at least one row.
+pub fn wrap(line: &[Span], width: usize) -> Vec<Line> {
+    let graphemes: Vec<(usize, &str)> = line
+        .iter()
+        .enumerate()
+        .flat_map(|(i, span)| text::graphemes(&span.text).map(move |grapheme| (i, grapheme)))
+        .collect();
+    let (positions, rows) = text::layout(graphemes.iter().map(|&(_, grapheme)| grapheme), width);
+    let mut result = vec![Line::new(); rows];
+    // The span each row's last piece came from
+    let mut last = vec![None; rows];
+    for (&(i, grapheme), &(row, _)) in graphemes.iter().zip(&positions) {
+        if last[row] != Some(i) {
+            result[row].push(Span {
+                text: String::new(),
+                ..line[i].clone()
+            });
+            last[row] = Some(i);
+        }
+        result[row]
+            .last_mut()
+            .expect("a span was just added")
+            .text
+            .push_str(grapheme);
+    }
+    result
+}
+
+/// Highlights the given byte ranges of a line's text (in reverse video), splitting spans where needed.
+pub fn highlight(line: &[Span], ranges: &[std::ops::Range<usize>]) -> Line {
+    let mut result = vec![];
+    let mut start = 0;
+    for span in line {
+        let end = start + span.text.len();
+        // Split the span wherever a range starts or ends within it
+        let mut cuts: Vec<usize> = ranges
+            .iter()
+            .flat_map(|range| [range.start, range.end])
+            .filter(|&cut| start < cut && cut < end)
+            .chain([start, end])
+            .collect();
+        cuts.sort_unstable();
+        cuts.dedup();
+        for piece in cuts.windows(2) {
+            let highlighted = ranges
+                .iter()
+                .any(|range| range.start <= piece[0] && piece[1] <= range.end);
+            result.push(Span {
+                text: span.text[piece[0] - start..piece[1] - start].to_string(),
+                style: if highlighted {
+                    span.style.reverse()
+                } else {
+                    span.style
+                },
+                ..span.clone()
+            });
+        }
+        start = end;
+    }
+    result
+}
+
+/// Renders a line for a terminal with the given capabilities, with escape sequences for its styles and links. Everything is reset
+/// by the end of the line.
+pub fn render(line: &[Span], capabilities: Capabilities) -> String {
+    let mut result = String::new();
+    let mut style = Style::default();
+    let mut link = None;
+    for span in line {
+        let span_link = span.link.as_deref().filter(|_| capabilities.hyperlinks);
+        if span_link != link {
+            // Links can't contain control characters, which would end the sequence early
+            let url: String = span_link
+                .unwrap_or_default()
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect();
+            result.push_str(&format!("\x1b]8;;{url}\x1b\\"));
+            link = span_link;
+        }
+        let span_style = span.style.supported(capabilities);
+        if span_style != style {
+            result.push_str(&span_style.sgr(capabilities.colors));
+            style = span_style;
+        }
+        result.push_str(&span.text);
+    }
+    if link.is_some() {
+        result.push_str("\x1b]8;;\x1b\\");
+    }
+    if style != Style::default() {
+        result.push_str("\x1b[0m");
+    }
+    result
+}
@@ -1,7 +1,14 @@
-/// A virtual shell implementing line discipline, echoing, and backspace, receiving individual character inputs and passing output back to the client.
+use std::ops::Range;
+
+use super::{
+    input::{Key, Modifiers},
+    text,
+};
+
+/// A virtual shell implementing line discipline, echoing, and line editing, receiving key presses and passing output back to the client.
 #[derive(Debug)]
 pub struct Shell {
-    /// The current cursor position
+    /// The current cursor position, as a byte index into the line (always on a grapheme boundary)
     cursor: usize,
     /// Command history
     history: Vec<String>,
@@ -11,8 +18,8 @@ pub struct Shell {
     current_history: Vec<String>,
     /// Index into the current_history specifying what we're editing now
     history_index: usize,
-    /// Escape sequence buffer
-    escape: Vec<u8>,
+    /// The reverse history search (Ctrl-R) in progress, if any
+    search: Option<HistorySearch>,
 }
 impl Default for Shell {
     fn default() -> Self {
@@ -21,26 +28,43 @@ impl Default for Shell {
             history: vec![],
             current_history: vec![String::new()],
             history_index: 0,
-            escape: vec![],
+            search: None,
         }
     }
 }
 
+/// A reverse history search in progress, as with Ctrl-R in bash.
+#[derive(Debug, Default)]
+struct HistorySearch {
+    /// The text being searched for.
+    query: String,
+    /// The index into `Shell::history` of the current match, if any.
+    found: Option<usize>,
+}
+
+/// Tab completion for a `Shell`, provided by the session.
+pub trait Complete {
+    /// Gets the possible completions of the last word of `line` (the line up to the cursor), each as the whole completed word.
+    fn complete(&self, line: &str) -> Vec<String>;
+    /// Gets the prompt, to redraw the line after listing completions.
+    fn prompt(&self) -> Vec<u8>;
+}
+
 impl Shell {
-    /// Processes a byte of input, returning a response to send back as well as optionally a command to run.
+    /// Processes a key press, returning a response to send back as well as optionally a command to run.
     /// If the command is "", no command is run, but the prompt is resent.
     ///
     /// (Some logic taken from [https://github.com/offirgolan/Shell/blob/master/read-line.c])
-    pub fn process(&mut self, data: u8) -> (Vec<u8>, Option<String>) {
-        if !self.escape.is_empty() {
-            return (self.process_escape(data), None);
+    pub fn process(&mut self, key: Key, completer: &dyn Complete) -> (Vec<u8>, Option<String>) {
+        if self.search.is_some() {
+            return self.process_search(key, completer);
         }
         let line = {
             self.get_line();
             self.current_history.get_mut(self.history_index).unwrap()
         };
-        match data {
-            13 | 10 => {
+        match key {
+            Key::Enter => {
                 // Newline, echo and run command
                 let command = std::mem::take(line);
                 self.history.push(command.clone());
@@ -50,27 +74,35 @@ impl Shell {
                 self.cursor = 0;
                 (vec![13, 10], Some(command))
             }
-            8 | 127 => {
-                // Backspace, remove character from buffer and overwrite as necessary
-                if self.cursor > 0 {
-                    line.remove(self.cursor - 1);
-                    self.cursor -= 1;
-                    if self.cursor == line.len() {
-                        // At end of line, so go back, overwrite with space, go back again
-                        (vec![8, 32, 8], None)
-                    } else {
-                        // Middle of line, so go back, overwrite with rest of line, go back to original location
-                        let mut response = vec![8];
-                        response.extend(line[self.cursor..].bytes());
-                        response.push(32); // Overwrite last character (since new line has one fewer than old)
-                        response.extend(std::iter::repeat(8).take(line.len() - self.cursor + 1));
-                        (response, None)
-                    }
-                } else {
-                    (vec![], None)
-                }
+            Key::Backspace => {
+                // Backspace, remove grapheme before cursor
+                let start = text::prev_boundary(line, self.cursor);
+                (self.delete(start..self.cursor), None)
+            }
+            Key::Ctrl('d') if line.is_empty() => {
+                // CTRL-D on an empty line, close session
+                (vec![], Some("exit".to_string()))
+            }
+            Key::Delete | Key::Ctrl('d') => {
+                // Delete (or CTRL-D), remove grapheme under cursor
+                let end = text::next_boundary(line, self.cursor);
+                (self.delete(self.cursor..end), None)
+            }
+            Key::Ctrl('w') | Key::Alt('\x7f') => {
+                // CTRL-W, remove word (anything but whitespace) before cursor
+                let start = prev_word(line, self.cursor, |c| !c.is_whitespace());
+                (self.delete(start..self.cursor), None)
+            }
+            Key::Ctrl('u') => {
+                // CTRL-U, remove everything before cursor
+                (self.delete(0..self.cursor), None)
+            }
+            Key::Ctrl('k') => {
+                // CTRL-K, remove everything after cursor
+                let end = line.len();
+                (self.delete(self.cursor..end), None)
             }
-            3 => {
+            Key::Ctrl('c') => {
                 // CTRL-C, clear line and reset without running command
                 // Reset current history/command
                 self.current_history = vec![String::new()];
@@ -79,110 +111,286 @@ impl Shell {
                 // Send newline and empty command (for prompt)
                 (vec![13, 10], Some(String::new()))
             }
-            4 => {
-                // CTRL-D, close session
-                (vec![], Some("exit".to_string()))
-            }
-            1 => {
+            Key::Ctrl('a') | Key::Home(_) => {
                 // CTRL-A, move cursor to start of line
-                let response = vec![8; self.cursor];
-                self.cursor = 0;
-                (response, None)
+                (self.move_to(0), None)
             }
-            5 => {
+            Key::Ctrl('e') | Key::End(_) => {
                 // CTRL-E, move cursor to end of line
-                let response = vec![8; line.len() - self.cursor];
-                self.cursor = line.len();
+                let end = line.len();
+                (self.move_to(end), None)
+            }
+            Key::Ctrl('b')
+            | Key::Left(Modifiers {
+                ctrl: false,
+                alt: false,
+                ..
+            }) => {
+                // Left arrow, move cursor back a grapheme
+                let start = text::prev_boundary(line, self.cursor);
+                (self.move_to(start), None)
+            }
+            Key::Ctrl('f')
+            | Key::Right(Modifiers {
+                ctrl: false,
+                alt: false,
+                ..
+            }) => {
+                // Right arrow, move cursor forward a grapheme
+                let end = text::next_boundary(line, self.cursor);
+                (self.move_to(end), None)
+            }
+            Key::Alt('b') | Key::Left(_) => {
+                // ALT-B or CTRL-left, move cursor back to start of word (letters and numbers)
+                let start = prev_word(line, self.cursor, char::is_alphanumeric);
+                (self.move_to(start), None)
+            }
+            Key::Alt('f') | Key::Right(_) => {
+                // ALT-F or CTRL-right, move cursor forward to end of word (letters and numbers)
+                let end = next_word(line, self.cursor, char::is_alphanumeric);
+                (self.move_to(end), None)
+            }
+            Key::Ctrl('p') | Key::Up(_) => {
+                // Up arrow, move back in history
+                (self.move_history(self.history_index + 1), None)
+            }
+            Key::Ctrl('n') | Key::Down(_) => {
+                // Down arrow, move forward in history, unless already at current
+                if self.history_index == 0 {
+                    return (vec![], None);
+                }
+                (self.move_history(self.history_index - 1), None)
+            }
+            Key::Ctrl('l') => {
+                // CTRL-L, clear screen and redraw line at top
+                let mut response = TerminalUtils::new().clear().move_cursor(0, 0).into_data();
+                response.extend(self.redraw(&completer.prompt()));
                 (response, None)
             }
-            27 => {
-                // Escape sequence, wait for next two bytes
-                self.escape = vec![27];
-                (vec![], None)
+            Key::Ctrl('r') => {
+                // CTRL-R, start reverse history search
+                self.search = Some(HistorySearch::default());
+                (self.render_search(), None)
             }
-            32.. => {
-                // Normal character, insert and echo
-                line.insert(self.cursor, data as char);
-                self.cursor += 1;
-                if self.cursor < line.len() {
-                    // Inserted in the middle, send [inserted, rest of line, move cursor back]
-                    let mut response = vec![];
-                    response.push(data);
-                    response.extend(line[self.cursor..].bytes());
-                    response.extend(vec![8; line.len() - self.cursor]);
-                    (response, None)
-                } else {
-                    // Inserted at the end, send [inserted]
-                    (vec![data], None)
+            Key::Tab => {
+                // Tab, complete the word before the cursor
+                let before = &line[..self.cursor];
+                let word_start = before.rfind([' ', '|']).map_or(0, |i| i + 1);
+                let word = &before[word_start..];
+                let completions = completer.complete(before);
+                // Extend to the longest common prefix of the completions, ending the word if there's only one (and it isn't a directory)
+                let mut common = completions.first().cloned().unwrap_or_default();
+                for completion in &completions[1.min(completions.len())..] {
+                    let len = common
+                        .char_indices()
+                        .zip(completion.chars())
+                        .find(|((_, a), b)| a != b)
+                        .map_or(common.len().min(completion.len()), |((i, _), _)| i);
+                    common.truncate(len);
+                }
+                if completions.len() == 1 && !common.ends_with('/') {
+                    common.push(' ');
+                }
+                match common.strip_prefix(word) {
+                    Some(addition) if !addition.is_empty() => {
+                        let addition = addition.to_string();
+                        (self.insert(&addition), None)
+                    }
+                    _ if completions.len() > 1 => {
+                        // No progress to make, so list the options (just their last component) and redraw the line
+                        let mut response = b"\r\n".to_vec();
+                        for completion in &completions {
+                            let name = completion.trim_end_matches('/').rsplit('/').next();
+                            let suffix = if completion.ends_with('/') { "/" } else { "" };
+                            response.extend(
+                                format!("{}{suffix}  ", name.unwrap_or_default()).as_bytes(),
+                            );
+                        }
+                        response.extend(b"\r\n");
+                        response.extend(self.redraw(&completer.prompt()));
+                        (response, None)
+                    }
+                    _ => (vec![], None),
                 }
             }
+            Key::Char(c) => {
+                // Normal character, insert and echo
+                (self.insert(c.encode_utf8(&mut [0; 4])), None)
+            }
+            Key::Paste(pasted) => {
+                // Pasted text, inserted on one line (so it can be checked before running) without anything that could mess with it
+                let pasted = text::sanitize(&pasted.replace(['\r', '\n'], " "));
+                (self.insert(pasted.trim_end()), None)
+            }
             _ => (vec![], None),
         }
     }
 
-    /// Processes a byte of data while in the middle of an escape sequence
-    fn process_escape(&mut self, data: u8) -> Vec<u8> {
-        self.escape.push(data);
-        if self.escape.len() == 3 {
-            // Escape sequence complete
-            // Get current line, updating histories if necessary
-            let line = {
-                self.get_line();
-                self.current_history.get(self.history_index).unwrap()
-            };
-            let escape = std::mem::take(&mut self.escape);
-            match escape.as_slice() {
-                [27, 91, 68] => {
-                    // Left arrow, move cursor back
-                    if self.cursor > 0 {
-                        self.cursor -= 1;
-                        vec![27, 91, 68]
-                    } else {
-                        vec![]
-                    }
-                }
-                [27, 91, 67] => {
-                    // Right arrow, move cursor forward
-                    if self.cursor < line.len() {
-                        self.cursor += 1;
-                        vec![27, 91, 67]
-                    } else {
-                        vec![]
-                    }
-                }
-                [27, 91, 65] | [27, 91, 66] => {
-                    // Up or down arrow, move in history
-                    if escape[2] == 65 {
-                        // Up arrow
-                        self.history_index += 1;
-                    } else {
-                        // Down arrow, return if already at current
-                        if self.history_index == 0 {
-                            return vec![];
-                        }
-                        self.history_index -= 1;
-                    }
-                    // Clear current line
-                    let mut response = vec![8; self.cursor];
-                    response.extend(std::iter::repeat(32).take(line.len()));
-                    response.extend(std::iter::repeat(8).take(line.len()));
-                    // Get new line
-                    let line = {
-                        self.get_line();
-                        self.current_history.get(self.history_index).unwrap()
-                    };
-                    // Write new line and update cursor
+    /// Processes a key press during a reverse history search, returning a response to send back as well as optionally a command to
+    /// run (as for `process`).
+    fn process_search(&mut self, key: Key, completer: &dyn Complete) -> (Vec<u8>, Option<String>) {
+        let Some(search) = &mut self.search else {
+            return (vec![], None);
+        };
+        match key {
+            Key::Char(c) => {
+                search.query.push(c);
+                self.find_history(false);
+                (self.render_search(), None)
+            }
+            Key::Paste(pasted) => {
+                search
+                    .query
+                    .push_str(&text::sanitize(&pasted.replace(['\r', '\n'], " ")));
+                self.find_history(false);
+                (self.render_search(), None)
+            }
+            Key::Backspace => {
+                // Remove from the query, starting the search again from the newest line
+                search.query.pop();
+                search.found = None;
+                self.find_history(false);
+                (self.render_search(), None)
+            }
+            Key::Ctrl('r') => {
+                // Find the next (older) match
+                self.find_history(true);
+                (self.render_search(), None)
+            }
+            Key::Ctrl('g') | Key::Ctrl('c') | Key::Escape => {
+                // Cancel, going back to the line as it was
+                self.search = None;
+                (self.redraw(&completer.prompt()), None)
+            }
+            key => {
+                // Anything else accepts the match, then is processed as usual (so Enter runs it)
+                if let Some(found) = search.found {
+                    let line = self.history[found].clone();
                     self.cursor = line.len();
-                    response.extend(line.bytes());
-                    response
+                    self.current_history[self.history_index] = line;
                 }
-                _ => vec![],
+                self.search = None;
+                let mut response = self.redraw(&completer.prompt());
+                let (process_response, command) = self.process(key, completer);
+                response.extend(process_response);
+                (response, command)
             }
-        } else {
-            vec![]
         }
     }
 
+    /// Finds the newest line of history containing the search query, older than the current match if `older` (or otherwise as old as
+    /// it, so it's kept if it still matches).
+    fn find_history(&mut self, older: bool) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        if search.query.is_empty() {
+            return;
+        }
+        let end = match search.found {
+            Some(found) if older => found,
+            Some(found) => found + 1,
+            None => self.history.len(),
+        };
+        if let Some(found) = self.history[..end]
+            .iter()
+            .rposition(|line| line.contains(&search.query))
+        {
+            search.found = Some(found);
+        }
+    }
+
+    /// Renders the reverse history search in place of the prompt and line.
+    fn render_search(&self) -> Vec<u8> {
+        let Some(search) = &self.search else {
+            return vec![];
+        };
+        let found = search
+            .found
+            .map_or("", |found| self.history[found].as_str());
+        let failed = if search.query.is_empty() || found.contains(&search.query) {
+            ""
+        } else {
+            "failed "
+        };
+        format!(
+            "\r\x1b[K({failed}reverse-i-search)`{}': {found}",
+            search.query
+        )
+        .into_bytes()
+    }
+
+    /// Redraws the prompt and line from the start of the terminal line, putting the cursor back where it was.
+    fn redraw(&self, prompt: &[u8]) -> Vec<u8> {
+        let line = &self.current_history[self.history_index];
+        let mut response = b"\r\x1b[K".to_vec();
+        response.extend(prompt);
+        response.extend(line.bytes());
+        response.extend(vec![8; text::width(&line[self.cursor..])]);
+        response
+    }
+
+    /// Inserts a string at the cursor, returning the response to echo it.
+    fn insert(&mut self, inserted: &str) -> Vec<u8> {
+        let line = &mut self.current_history[self.history_index];
+        line.insert_str(self.cursor, inserted);
+        // Skip past anything the inserted text joined onto (e.g. a combining mark after it)
+        self.cursor = text::ceil_boundary(line, self.cursor + inserted.len());
+        // Send [inserted, rest of line, move cursor back]
+        let mut response = inserted.as_bytes().to_vec();
+        response.extend(line[self.cursor..].bytes());
+        response.extend(vec![8; text::width(&line[self.cursor..])]);
+        response
+    }
+
+    /// Removes a range of the line (starting or ending at the cursor), returning the response to update it.
+    fn delete(&mut self, range: Range<usize>) -> Vec<u8> {
+        let mut response = self.move_to(range.start);
+        let line = &mut self.current_history[self.history_index];
+        let removed = text::width(&line[range.clone()]);
+        line.replace_range(range, "");
+        // Overwrite with rest of line and spaces for the removed columns (since new line is shorter than old), then go back to the cursor
+        let rest = &line[self.cursor..];
+        response.extend(rest.bytes());
+        response.extend(vec![32; removed]);
+        response.extend(vec![8; text::width(rest) + removed]);
+        // The graphemes either side may have joined together (e.g. regional indicators), so skip past the cursor's new one
+        let boundary = text::ceil_boundary(line, self.cursor);
+        response.extend(self.move_to(boundary));
+        response
+    }
+
+    /// Moves the cursor to a new position in the line, returning the response to do so.
+    fn move_to(&mut self, cursor: usize) -> Vec<u8> {
+        let line = &self.current_history[self.history_index];
+        let response = if cursor < self.cursor {
+            vec![8; text::width(&line[cursor..self.cursor])]
+        } else {
+            move_right(text::width(&line[self.cursor..cursor]))
+        };
+        self.cursor = cursor;
+        response
+    }
+
+    /// Moves to another line of history, replacing the current one, and returning the response to do so.
+    fn move_history(&mut self, history_index: usize) -> Vec<u8> {
+        // Clear current line
+        let line = &self.current_history[self.history_index];
+        let mut response = vec![8; text::width(&line[..self.cursor])];
+        response.extend(vec![32; text::width(line)]);
+        response.extend(vec![8; text::width(line)]);
+        // Get new line
+        self.history_index = history_index;
+        let line = {
+            self.get_line();
+            self.current_history.get(self.history_index).unwrap()
+        };
+        // Write new line and update cursor
+        self.cursor = line.len();
+        response.extend(line.bytes());
+        response
+    }
+
     /// Get the current line of input, updating the current history and clamping the history index if necessary.
     /// Rather then returning a reference, we just update `self.current_history` and `self.history_index` to avoid borrowing issues.
     fn get_line(&mut self) {
@@ -198,6 +406,36 @@ impl Shell {
     }
 }
 
+/// Finds the start of the word before `cursor` in a line (or the start of the line), with words made of graphemes starting with
+/// characters matching `in_word`.
+fn prev_word(line: &str, cursor: usize, in_word: fn(char) -> bool) -> usize {
+    let is_word = |grapheme: &str| grapheme.chars().next().is_some_and(in_word);
+    text::grapheme_indices(&line[..cursor])
+        .rev()
+        .skip_while(|(_, grapheme)| !is_word(grapheme))
+        .take_while(|(_, grapheme)| is_word(grapheme))
+        .last()
+        .map_or(0, |(i, _)| i)
+}
+
+/// Finds the end of the word after `cursor` in a line (or the end of the line), as for `prev_word`.
+fn next_word(line: &str, cursor: usize, in_word: fn(char) -> bool) -> usize {
+    let is_word = |grapheme: &str| grapheme.chars().next().is_some_and(in_word);
+    text::grapheme_indices(&line[cursor..])
+        .skip_while(|(_, grapheme)| !is_word(grapheme))
+        .find(|(_, grapheme)| !is_word(grapheme))
+        .map_or(line.len(), |(i, _)| cursor + i)
+}
+
+/// Moves the cursor right some number of columns (which, unlike moving left with backspace, needs an escape sequence).
+fn move_right(columns: usize) -> Vec<u8> {
+    if columns == 0 {
+        vec![]
+    } else {
+        format!("\x1b[{columns}C").into_bytes()
+    }
+}
+
 /// Some utilities for fancy terminal output.
 ///
 /// ## Example
@@ -254,9 +492,198 @@ impl TerminalUtils {
         self.data.extend(b"\x1b[2J");
         self
     }
+    /// Clears the line the cursor is on (doesn't move cursor).
+    pub fn clear_line(mut self) -> Self {
+        self.data.extend(b"\x1b[2K");
+        self
+    }
 
     /// Gets the data for all the operations done.
     pub fn into_data(self) -> Vec<u8> {
         self.data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::ssh::input::{arbitrary_input, InputParser};
+
+    /// The prompt the test shell is drawn after.
+    const PROMPT: &str = "$ ";
+
+    /// Completes from a few fixed commands and paths.
+    struct Completer;
+    impl Complete for Completer {
+        fn complete(&self, line: &str) -> Vec<String> {
+            let word = line.rsplit([' ', '|']).next().unwrap_or_default();
+            [
+                "cat",
+                "cd",
+                "colors",
+                "blog/",
+                "blog/post.txt",
+                "blog/posts/",
+            ]
+            .into_iter()
+            .filter(|completion| completion.starts_with(word))
+            .map(String::from)
+            .collect()
+        }
+        fn prompt(&self) -> Vec<u8> {
+            PROMPT.as_bytes().to_vec()
+        }
+    }
+
+    /// Checks the shell's cursor is on a grapheme boundary in the line being edited.
+    fn check_cursor(shell: &Shell) -> Result<(), TestCaseError> {
+        prop_assert!(shell.history_index < shell.current_history.len());
+        let line = &shell.current_history[shell.history_index];
+        prop_assert!(
+            shell.cursor == line.len()
+                || text::grapheme_indices(line).any(|(i, _)| i == shell.cursor),
+            "cursor {} not on a boundary of {:?}",
+            shell.cursor,
+            line
+        );
+        Ok(())
+    }
+
+    /// Tracks the column a terminal's cursor would be on after the shell's output (on an infinitely wide terminal).
+    fn move_column(column: &mut usize, output: &[u8]) {
+        let mut rest = output;
+        while let Some((&byte, after)) = rest.split_first() {
+            rest = after;
+            match byte {
+                b'\r' => *column = 0,
+                b'\n' => {}
+                8 => *column = column.saturating_sub(1),
+                27 => {
+                    // A CSI sequence: we only send ones moving right, to an absolute position, or clearing
+                    let end = rest
+                        .iter()
+                        .position(|byte| (0x40..=0x7e).contains(byte) && *byte != b'[');
+                    let end = end.expect("unterminated escape sequence");
+                    let params = std::str::from_utf8(&rest[1..end]).expect("ASCII parameters");
+                    match rest[end] {
+                        b'C' => *column += params.parse::<usize>().expect("columns to move"),
+                        b'H' => {
+                            let x = params.split(';').nth(1).expect("column");
+                            *column = x.parse::<usize>().expect("column") - 1;
+                        }
+                        b'J' | b'K' => {}
+                        other => panic!("unexpected escape sequence ending {:?}", other as char),
+                    }
+                    rest = &rest[end + 1..];
+                }
+                _ => {
+                    let length = rest
+                        .iter()
+                        .position(|&byte| byte < 32 || byte == 127)
+                        .unwrap_or(rest.len());
+                    let printed =
+                        std::str::from_utf8(&output[output.len() - rest.len() - 1..][..length + 1])
+                            .expect("UTF-8 output");
+                    *column += text::width(printed);
+                    rest = &rest[length..];
+                }
+            }
+        }
+    }
+
+    /// Typed text whose width is the sum of its characters' (so echoing it a character at a time puts the terminal's cursor where
+    /// the shell expects), along with the keys for editing it.
+    fn editing_input() -> impl Strategy<Value = Vec<u8>> {
+        let piece = prop_oneof![
+            "[ -~]{1,6}".prop_map(String::into_bytes),
+            "[éü日本語한]".prop_map(String::into_bytes),
+            Just("e\u{301}".as_bytes().to_vec()),
+            prop::sample::select(vec![
+                &b"\x1b[D"[..],
+                b"\x1b[C",
+                b"\x1b[A",
+                b"\x1b[B",
+                b"\x1b[H",
+                b"\x1b[F",
+                b"\x1b[3~",
+                b"\x1b[1;5D",
+                b"\x1b[1;5C",
+                b"\x1bb",
+                b"\x1bf",
+                b"\x1b\x7f",
+                b"\x7f",
+                b"\t",
+                b"\r",
+                b"\x01",
+                b"\x02",
+                b"\x03",
+                b"\x04",
+                b"\x05",
+                b"\x06",
+                b"\x0b",
+                b"\x0c",
+                b"\x0e",
+                b"\x10",
+                b"\x12",
+                b"\x15",
+                b"\x17",
+                b"\x07",
+                b"\x1b",
+            ])
+            .prop_map(<[u8]>::to_vec),
+            "[a-z ]{0,12}"
+                .prop_map(|pasted| [&b"\x1b[200~"[..], pasted.as_bytes(), b"\x1b[201~"].concat()),
+        ];
+        proptest::collection::vec(piece, 0..64).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn shell_survives_any_input(
+            history in proptest::collection::vec(any::<String>(), 0..4),
+            input in arbitrary_input(),
+            split in any::<prop::sample::Index>(),
+        ) {
+            let mut shell = Shell {
+                history,
+                ..Shell::default()
+            };
+            let mut parser = InputParser::default();
+            let (first, second) = input.split_at(split.index(input.len() + 1));
+            for packet in [first, second] {
+                for key in parser.parse(packet) {
+                    shell.process(key, &Completer);
+                    check_cursor(&shell)?;
+                }
+            }
+        }
+
+        #[test]
+        fn shell_echo_follows_cursor(
+            history in proptest::collection::vec("[ -~]{0,12}", 0..4),
+            input in editing_input(),
+        ) {
+            let mut shell = Shell {
+                history,
+                ..Shell::default()
+            };
+            let mut parser = InputParser::default();
+            let mut column = PROMPT.len();
+            for key in parser.parse(&input) {
+                let (output, command) = shell.process(key, &Completer);
+                move_column(&mut column, &output);
+                if command.is_some() {
+                    // The prompt is shown again after the command runs
+                    column = PROMPT.len();
+                }
+                check_cursor(&shell)?;
+                if shell.search.is_none() {
+                    let line = &shell.current_history[shell.history_index];
+                    prop_assert_eq!(column, PROMPT.len() + text::width(&line[..shell.cursor]), "line {:?}", line);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Unicode-aware text handling for the terminal, so multi-byte, wide (e.g. CJK) and combining characters are edited and laid out
+//! the way the client's terminal will draw them.
+//!
+//! Text is handled in graphemes (what users see as one character, e.g. a letter with its accents), each taking up zero, one or two
+//! columns on screen.
+
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
+
+/// The number of spaces tabs are expanded to by `sanitize`.
+const TAB_WIDTH: usize = 4;
+
+/// Iterates over the graphemes in some text.
+pub fn graphemes(text: &str) -> impl DoubleEndedIterator<Item = &str> {
+    text.graphemes(true)
+}
+
+/// Iterates over the graphemes in some text, along with their byte offsets.
+pub fn grapheme_indices(text: &str) -> impl DoubleEndedIterator<Item = (usize, &str)> {
+    text.grapheme_indices(true)
+}
+
+/// The number of columns a grapheme takes up on screen. Sequences of several wide characters (e.g. emoji joined with ZWJ) are
+/// drawn as one by most terminals, so this is at most two.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    grapheme.width().min(2)
+}
+
+/// The number of columns some text takes up on screen.
+pub fn width(text: &str) -> usize {
+    graphemes(text).map(grapheme_width).sum()
+}
+
+/// Makes text safe to lay out, expanding tabs and dropping control characters (which could move the cursor or worse) and
+/// zero-width graphemes (e.g. combining marks with nothing to combine with), so every grapheme left takes up one or two columns.
+pub fn sanitize(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .flat_map(|c| match c {
+            '\t' => vec![' '; TAB_WIDTH],
+            c if c.is_control() => vec![],
+            c => vec![c],
+        })
+        .collect();
+    graphemes(&text)
+        .filter(|grapheme| grapheme_width(grapheme) > 0)
+        .collect()
+}
+
+/// Lays out graphemes in rows at most `width` columns wide, moving wide graphemes that don't fit at the end of a row onto the next
+/// one (as terminals do). Returns the (row, column) each grapheme starts at, along with the number of rows (at least one).
+pub fn layout<'a>(
+    graphemes: impl IntoIterator<Item = &'a str>,
+    width: usize,
+) -> (Vec<(usize, usize)>, usize) {
+    let width = width.max(2);
+    let (mut row, mut column) = (0, 0);
+    let positions = graphemes
+        .into_iter()
+        .map(|grapheme| {
+            let grapheme_width = grapheme_width(grapheme);
+            if column + grapheme_width > width {
+                row += 1;
+                column = 0;
+            }
+            let position = (row, column);
+            column += grapheme_width;
+            position
+        })
+        .collect();
+    (positions, row + 1)
+}
+
+/// Truncates text to fit in `width` columns, without splitting any graphemes.
+pub fn truncate(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (index, grapheme) in text.grapheme_indices(true) {
+        used += grapheme_width(grapheme);
+        if used > width {
+            return &text[..index];
+        }
+    }
+    text
+}
+
+/// The grapheme boundary before byte `index` in some text (or 0 if there is none).
+pub fn prev_boundary(text: &str, index: usize) -> usize {
+    GraphemeCursor::new(index, text.len(), true)
+        .prev_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// The grapheme boundary after byte `index` in some text (or its length if there is none).
+pub fn next_boundary(text: &str, index: usize) -> usize {
+    GraphemeCursor::new(index, text.len(), true)
+        .next_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(text.len())
+}
+
+/// The first grapheme boundary at or after byte `index` in some text, for keeping a cursor on one after an edit joins graphemes
+/// together (e.g. a letter typed before a combining mark).
+pub fn ceil_boundary(text: &str, index: usize) -> usize {
+    match GraphemeCursor::new(index, text.len(), true).is_boundary(text, 0) {
+        Ok(true) => index,
+        _ => next_boundary(text, index),
+    }
+}
+
+/// Decodes UTF-8 arriving a byte at a time (as input from the client does), so multi-byte characters can be handled whole.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    /// The bytes of the character so far.
+    buffer: Vec<u8>,
+}
+impl Utf8Decoder {
+    /// Adds a byte, returning the character once it's complete. Invalid sequences are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<char> {
+        if byte.is_ascii() {
+            // Can't be part of a multi-byte character, so drop anything unfinished
+            self.buffer.clear();
+            return Some(byte as char);
+        }
+        self.buffer.push(byte);
+        match std::str::from_utf8(&self.buffer) {
+            Ok(text) => {
+                let c = text.chars().next();
+                self.buffer.clear();
+                c
+            }
+            // Incomplete, so wait for the rest
+            Err(e) if e.error_len().is_none() => None,
+            Err(_) => {
+                // Invalid, so drop it (starting again from this byte, if it could start a new character)
+                self.buffer.clear();
+                if std::str::from_utf8(&[byte]).is_err_and(|e| e.error_len().is_none()) {
+                    self.buffer.push(byte);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Generates text mixing ASCII with wide characters, combining marks, emoji sequences, control characters and anything else, for
+/// property tests.
+#[cfg(test)]
+pub fn tricky_text() -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+
+    let piece = prop_oneof![
+        "[ -~]{1,4}",
+        "[\t\r\n\x00-\x1f\x7f\u{80}-\u{9f}]",
+        "[é日本語한국어]",
+        "[\u{300}-\u{36f}\u{200b}-\u{200d}\u{fe0f}]",
+        "[\u{1f1e6}-\u{1f1ff}]{1,3}",
+        "[👩👨👧❤]",
+        any::<char>().prop_map(String::from),
+    ];
+    proptest::collection::vec(piece, 0..24).prop_map(|pieces| pieces.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn utf8_decoder_decodes_valid_text(text in any::<String>()) {
+            let mut decoder = Utf8Decoder::default();
+            let decoded: String = text.bytes().filter_map(|byte| decoder.push(byte)).collect();
+            prop_assert_eq!(decoded, text);
+            prop_assert!(decoder.buffer.is_empty());
+        }
+
+        #[test]
+        fn utf8_decoder_survives_any_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut decoder = Utf8Decoder::default();
+            let mut decoded = vec![];
+            for &byte in &bytes {
+                decoded.extend(decoder.push(byte));
+                // Never more than an unfinished character is kept
+                prop_assert!(decoder.buffer.len() < 4);
+            }
+            // ASCII always comes through as it is, whatever's around it
+            let ascii: Vec<char> = bytes.iter().filter(|byte| byte.is_ascii()).map(|&byte| byte as char).collect();
+            let decoded_ascii: Vec<char> = decoded.iter().copied().filter(char::is_ascii).collect();
+            prop_assert_eq!(decoded_ascii, ascii);
+        }
+
+        #[test]
+        fn widths_add_up(text in tricky_text()) {
+            prop_assert_eq!(width(&text), graphemes(&text).map(grapheme_width).sum::<usize>());
+            prop_assert!(graphemes(&text).all(|grapheme| grapheme_width(grapheme) <= 2));
+            prop_assert_eq!(graphemes(&text).collect::<String>(), text.clone());
+            prop_assert!(graphemes(&text).rev().eq(graphemes(&text).collect::<Vec<_>>().into_iter().rev()));
+        }
+
+        #[test]
+        fn sanitize_leaves_only_visible_graphemes(text in tricky_text()) {
+            let sanitized = sanitize(&text);
+            prop_assert!(!sanitized.chars().any(char::is_control));
+            for grapheme in graphemes(&sanitized) {
+                prop_assert!((1..=2).contains(&grapheme_width(grapheme)), "{:?} in {:?}", grapheme, sanitized);
+            }
+        }
+
+        #[test]
+        fn truncate_fits(text in tricky_text(), max_width in 0usize..40) {
+            let truncated = truncate(&text, max_width);
+            prop_assert!(text.starts_with(truncated));
+            prop_assert!(width(truncated) <= max_width);
+            if width(&text) <= max_width {
+                prop_assert_eq!(truncated, text.as_str());
+            } else {
+                // Only stops short if the next grapheme wouldn't fit
+                let next = graphemes(&text[truncated.len()..]).next().unwrap_or_default();
+                prop_assert!(width(truncated) + grapheme_width(next) > max_width);
+            }
+        }
+
+        #[test]
+        fn layout_fits_in_rows(text in tricky_text(), row_width in 0usize..20) {
+            let text = sanitize(&text);
+            let (positions, rows) = layout(graphemes(&text), row_width);
+            prop_assert_eq!(positions.len(), graphemes(&text).count());
+            prop_assert!(rows >= 1);
+            let mut previous = (0, 0);
+            for (&(row, column), grapheme) in positions.iter().zip(graphemes(&text)) {
+                prop_assert!(row < rows);
+                prop_assert!(column + grapheme_width(grapheme) <= row_width.max(2));
+                prop_assert!((row, column) >= previous);
+                previous = (row, column);
+            }
+            prop_assert_eq!(positions.last().map_or(0, |&(row, _)| row) + 1, rows);
+        }
+
+        #[test]
+        fn boundaries_are_graphemes(text in tricky_text(), index in any::<prop::sample::Index>()) {
+            let boundaries: Vec<usize> = grapheme_indices(&text).map(|(i, _)| i).chain([text.len()]).collect();
+            let i = index.index(boundaries.len());
+            let prev = prev_boundary(&text, boundaries[i]);
+            let next = next_boundary(&text, boundaries[i]);
+            prop_assert_eq!(prev, boundaries[i.saturating_sub(1)]);
+            prop_assert_eq!(next, boundaries[(i + 1).min(boundaries.len() - 1)]);
+        }
+    }
+}